@@ -0,0 +1,342 @@
+// INTERNET ARCHIVE - Public-domain and openly licensed texts from archive.org
+use crate::config::ArchiveConfig;
+use crate::guard::GuardedClient;
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Items are fetched in excess of the limit since rights filtering drops some
+const SEARCH_OVERFETCH: usize = 3;
+const MAX_ROWS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rights {
+    PublicDomain,
+    OpenLicense,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveFile {
+    pub name: String,
+    pub format: String,
+    // PDF, EPUB or TXT
+    pub kind: &'static str,
+    pub size: Option<u64>,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveItem {
+    pub identifier: String,
+    pub title: String,
+    pub creators: Vec<String>,
+    pub year: Option<String>,
+    pub language: Vec<String>,
+    pub rights: Rights,
+    pub license_url: Option<String>,
+    pub files: Vec<ArchiveFile>,
+}
+
+impl ArchiveItem {
+    pub fn file(&self, kind: &str) -> Option<&ArchiveFile> {
+        self.files.iter().find(|f| f.kind == kind)
+    }
+}
+
+// Search texts and return the matching items we are allowed to hand out, most downloaded first
+pub async fn search(client: &GuardedClient, settings: &ArchiveConfig, query: &str, limit: usize) -> Result<Vec<ArchiveItem>, String> {
+    if !settings.enabled || query.trim().is_empty() || limit == 0 {
+        return Ok(vec![]);
+    }
+    let base = settings.base_url.trim_end_matches('/');
+    let q = format!("({}) AND mediatype:(texts)", escape_query(query.trim()));
+    let url = format!(
+        "{}/advancedsearch.php?q={}&fl[]=identifier&sort[]=downloads+desc&rows={}&page=1&output=json",
+        base,
+        urlencoding::encode(&q),
+        (limit * SEARCH_OVERFETCH).min(MAX_ROWS)
+    );
+    let found: Value = client.get_json(&url).await.map_err(|e| e.to_string())?;
+    let identifiers: Vec<String> = found["response"]["docs"]
+        .as_array()
+        .map(|docs| docs.iter().filter_map(|d| d["identifier"].as_str().map(str::to_string)).collect())
+        .unwrap_or_default();
+
+    let items = join_all(identifiers.iter().map(|id| item(client, settings, id))).await;
+    Ok(items.into_iter().filter_map(|item| item.ok().flatten()).take(limit).collect())
+}
+
+// User words are searched as words: Lucene syntax is escaped and operators lowercased,
+// so a query can't widen itself past mediatype:(texts) or break the parser
+fn escape_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| {
+            let word = if matches!(word, "AND" | "OR" | "NOT" | "TO") { word.to_ascii_lowercase() } else { word.to_string() };
+            let mut escaped = String::with_capacity(word.len());
+            for c in word.chars() {
+                if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Ok(None) for items that exist but aren't ours to serve (rights, lending-only, no book files)
+pub async fn item(client: &GuardedClient, settings: &ArchiveConfig, identifier: &str) -> Result<Option<ArchiveItem>, String> {
+    let base = settings.base_url.trim_end_matches('/');
+    let url = format!("{}/metadata/{}", base, urlencoding::encode(identifier));
+    let found: Value = client.get_json(&url).await.map_err(|e| e.to_string())?;
+    let metadata = &found["metadata"];
+    if metadata.is_null() {
+        return Ok(None);
+    }
+    if metadata["access-restricted-item"].as_str() == Some("true") {
+        return Ok(None);
+    }
+    let Some(rights) = rights(metadata) else { return Ok(None) };
+
+    let files: Vec<ArchiveFile> = found["files"]
+        .as_array()
+        .map(|files| files.iter().filter_map(|f| book_file(base, identifier, f)).collect())
+        .unwrap_or_default();
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(ArchiveItem {
+        identifier: identifier.to_string(),
+        title: strings(&metadata["title"]).into_iter().next().unwrap_or_else(|| identifier.to_string()),
+        creators: strings(&metadata["creator"]),
+        year: strings(&metadata["year"]).into_iter().next().or_else(|| strings(&metadata["date"]).into_iter().next()),
+        language: strings(&metadata["language"]),
+        rights,
+        license_url: strings(&metadata["licenseurl"]).into_iter().next(),
+        files,
+    }))
+}
+
+// An explicit license decides: the Public Domain Mark, CC0 and CC BY pass; anything
+// else (SA, NC, ND, all rights reserved) is refused. Without one, the item must be
+// marked NOT_IN_COPYRIGHT. Free-text rights statements never count ("not in the
+// public domain" reads much like "in the public domain").
+fn rights(metadata: &Value) -> Option<Rights> {
+    if let Some(license) = strings(&metadata["licenseurl"]).into_iter().next() {
+        let license = license.to_ascii_lowercase();
+        if let Some(tool) = license.split("creativecommons.org/publicdomain/").nth(1) {
+            return matches!(tool.split('/').next()?, "mark" | "zero").then_some(Rights::PublicDomain);
+        }
+        let kind = license.split("creativecommons.org/licenses/").nth(1)?.split('/').next()?;
+        return (kind == "by").then_some(Rights::OpenLicense);
+    }
+
+    let status = strings(&metadata["possible-copyright-status"]).join(" ").to_ascii_uppercase();
+    status.contains("NOT_IN_COPYRIGHT").then_some(Rights::PublicDomain)
+}
+
+fn book_file(base: &str, identifier: &str, file: &Value) -> Option<ArchiveFile> {
+    let name = file["name"].as_str()?;
+    let format = file["format"].as_str()?;
+    let kind = match format {
+        f if f.contains("PDF") => "PDF",
+        "EPUB" => "EPUB",
+        "DjVuTXT" => "TXT",
+        _ => return None,
+    };
+    Some(ArchiveFile {
+        name: name.to_string(),
+        format: format.to_string(),
+        kind,
+        size: file["size"].as_str().and_then(|s| s.parse().ok()).or_else(|| file["size"].as_u64()),
+        url: format!("{}/download/{}/{}", base, urlencoding::encode(identifier), encode_path(name)),
+    })
+}
+
+// File names may sit in subfolders; keep the slashes
+fn encode_path(name: &str) -> String {
+    name.split('/').map(|part| urlencoding::encode(part).into_owned()).collect::<Vec<_>>().join("/")
+}
+
+// Archive metadata fields are a string or a list of strings
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(values) => values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => vec![],
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+// GET /api/archive/search - items with their rights and book files
+pub async fn search_items(
+    Query(params): Query<ArchiveQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ArchiveItem>>, (StatusCode, String)> {
+    let settings = state.config.snapshot().archive.clone();
+    search(&state.client, &settings, &params.q, params.limit.unwrap_or(10))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e))
+}
+
+// GET /api/archive/items/:identifier - 404 also covers items withheld for their rights
+pub async fn get_item(
+    Path(identifier): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<ArchiveItem>, (StatusCode, String)> {
+    let settings = state.config.snapshot().archive.clone();
+    match item(&state.client, &settings, &identifier).await {
+        Ok(Some(item)) => Ok(Json(item)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("No openly available item {}", identifier))),
+        Err(e) => Err((StatusCode::BAD_GATEWAY, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigStore, ServerConfig};
+    use axum::routing::get;
+    use axum::Router;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn metadata(extra: Value) -> Value {
+        let mut item = json!({
+            "metadata": {"title": "Pride and Prejudice", "creator": ["Austen, Jane"], "year": "1813", "language": "eng"},
+            "files": [
+                {"name": "pride.pdf", "format": "Text PDF", "size": "2048"},
+                {"name": "sub dir/pride.epub", "format": "EPUB", "size": 1024},
+                {"name": "pride_djvu.txt", "format": "DjVuTXT"},
+                {"name": "pride.jpg", "format": "JPEG"}
+            ]
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            item["metadata"][key] = value.clone();
+        }
+        item
+    }
+
+    // Stand-in for advancedsearch.php and /metadata, with one item per rights case.
+    // Also returns the q of every search it got.
+    async fn stand_in() -> (GuardedClient, ArchiveConfig, Arc<Mutex<Vec<String>>>) {
+        let items: Vec<(&str, Value)> = vec![
+            ("pd_status", metadata(json!({"possible-copyright-status": "NOT_IN_COPYRIGHT"}))),
+            ("cc_by", metadata(json!({"licenseurl": "https://creativecommons.org/licenses/by/4.0/"}))),
+            ("cc_zero", metadata(json!({"licenseurl": "http://creativecommons.org/publicdomain/zero/1.0/"}))),
+            ("cc_by_nc", metadata(json!({"licenseurl": "https://creativecommons.org/licenses/by-nc/4.0/"}))),
+            ("not_pd_statement", metadata(json!({"rights": "This work is not in the public domain."}))),
+            ("maybe_pd_statement", metadata(json!({"rights": "May not be public domain in your country"}))),
+            ("restricted", metadata(json!({"possible-copyright-status": "NOT_IN_COPYRIGHT", "access-restricted-item": "true"}))),
+        ];
+        let docs: Vec<Value> = items.iter().map(|(id, _)| json!({"identifier": id})).collect();
+        let items: Arc<Vec<(&str, Value)>> = Arc::new(items);
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let seen = queries.clone();
+
+        let app = Router::new()
+            .route(
+                "/advancedsearch.php",
+                get(move |Query(params): Query<HashMap<String, String>>| async move {
+                    seen.lock().unwrap().push(params.get("q").cloned().unwrap_or_default());
+                    Json(json!({"response": {"docs": docs}}))
+                }),
+            )
+            .route(
+                "/metadata/:id",
+                get(move |Path(id): Path<String>| async move {
+                    Json(items.iter().find(|(i, _)| *i == id).map(|(_, item)| item.clone()).unwrap_or(json!({})))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        let settings = ArchiveConfig { enabled: true, base_url };
+        (GuardedClient::new(Arc::new(ConfigStore::fixed(config))), settings, queries)
+    }
+
+    #[tokio::test]
+    async fn search_keeps_only_open_items() {
+        let (client, settings, _) = stand_in().await;
+        let found = search(&client, &settings, "pride", 10).await.unwrap();
+        let ids: Vec<(&str, Rights)> = found.iter().map(|i| (i.identifier.as_str(), i.rights)).collect();
+        assert_eq!(ids, vec![("pd_status", Rights::PublicDomain), ("cc_by", Rights::OpenLicense), ("cc_zero", Rights::PublicDomain)]);
+    }
+
+    #[tokio::test]
+    async fn user_queries_cant_change_the_search() {
+        let (client, settings, queries) = stand_in().await;
+        for query in ["pride", "pride OR mediatype:(movies)", "a) OR (b", "title:\"x\" AND NOT y*"] {
+            search(&client, &settings, query, 1).await.unwrap();
+        }
+        assert_eq!(
+            *queries.lock().unwrap(),
+            vec![
+                "(pride) AND mediatype:(texts)",
+                "(pride or mediatype\\:\\(movies\\)) AND mediatype:(texts)",
+                "(a\\) or \\(b) AND mediatype:(texts)",
+                "(title\\:\\\"x\\\" and not y\\*) AND mediatype:(texts)",
+            ]
+        );
+    }
+
+    #[test]
+    fn query_escaping() {
+        assert_eq!(escape_query("  pride   and prejudice "), "pride and prejudice");
+        assert_eq!(escape_query("AND OR NOT TO Andy"), "and or not to Andy");
+        assert_eq!(escape_query("+a -b && c || !d"), "\\+a \\-b \\&\\& c \\|\\| \\!d");
+        assert_eq!(escape_query("{x} [y] ^2 ~3 a/b c\\d"), "\\{x\\} \\[y\\] \\^2 \\~3 a\\/b c\\\\d");
+        assert_eq!(escape_query("café?"), "café\\?");
+    }
+
+    #[tokio::test]
+    async fn item_lists_real_book_files() {
+        let (client, settings, _) = stand_in().await;
+        let item = item(&client, &settings, "pd_status").await.unwrap().unwrap();
+        assert_eq!(item.title, "Pride and Prejudice");
+        assert_eq!(item.creators, vec!["Austen, Jane"]);
+        assert_eq!(item.year.as_deref(), Some("1813"));
+        let files: Vec<(&str, Option<u64>)> = item.files.iter().map(|f| (f.kind, f.size)).collect();
+        assert_eq!(files, vec![("PDF", Some(2048)), ("EPUB", Some(1024)), ("TXT", None)]);
+        assert_eq!(item.file("EPUB").unwrap().url, format!("{}/download/pd_status/sub%20dir/pride.epub", settings.base_url));
+    }
+
+    #[tokio::test]
+    async fn withheld_items_are_none() {
+        let (client, settings, _) = stand_in().await;
+        for id in ["cc_by_nc", "not_pd_statement", "restricted", "missing"] {
+            assert!(item(&client, &settings, id).await.unwrap().is_none(), "{} should be withheld", id);
+        }
+    }
+
+    #[test]
+    fn rights_need_status_or_license() {
+        let rights_of = |metadata: Value| rights(&metadata);
+        assert_eq!(rights_of(json!({"rights": "Public Domain"})), None);
+        assert_eq!(rights_of(json!({"licenseurl": "https://creativecommons.org/publicdomain/mark/1.0/"})), Some(Rights::PublicDomain));
+        assert_eq!(rights_of(json!({"licenseurl": "https://creativecommons.org/licenses/by-sa/4.0/"})), None);
+        assert_eq!(rights_of(json!({"licenseurl": "https://creativecommons.org/licenses/by-nd/4.0/"})), None);
+        // A restrictive license wins over a copyright status
+        assert_eq!(rights_of(json!({"licenseurl": "https://creativecommons.org/licenses/by-nc/4.0/", "possible-copyright-status": "NOT_IN_COPYRIGHT"})), None);
+    }
+}
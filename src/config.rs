@@ -0,0 +1,633 @@
+// SERVER CONFIG - Operator settings loaded from JSON with hot reload
+use crate::guard::BodyKind;
+use crate::health::Heartbeat;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_CONFIG_PATH: &str = "content-server.json";
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub playlists: Vec<PlaylistSource>,
+    pub segment_cache: SegmentCacheConfig,
+    pub dvr: DvrConfig,
+    pub verification: VerificationConfig,
+    pub outbound: OutboundConfig,
+    pub limits: FetchLimits,
+    pub health: HealthConfig,
+    pub history: HistoryConfig,
+    pub gutenberg: GutenbergConfig,
+    pub archive: ArchiveConfig,
+    pub openlibrary: OpenLibraryConfig,
+    pub opds_feeds: OpdsFeedsConfig,
+    pub opds_catalog: OpdsCatalogConfig,
+    pub epub: EpubConfig,
+    pub fulltext: FullTextConfig,
+    pub librivox: LibriVoxConfig,
+    pub mirror: MirrorConfig,
+    // Test plan run by /test and `content-server test`
+    pub test_plan: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        // Same channels the server shipped with before sources became configurable
+        Self {
+            playlists: vec![PlaylistSource {
+                name: "news".to_string(),
+                location: PlaylistLocation::File(PathBuf::from("playlists/news.m3u")),
+                refresh_secs: default_refresh_secs(),
+                headers: HashMap::new(),
+                enabled: true,
+                dvr: true,
+            }],
+            segment_cache: SegmentCacheConfig::default(),
+            dvr: DvrConfig::default(),
+            verification: VerificationConfig::default(),
+            outbound: OutboundConfig::default(),
+            limits: FetchLimits::default(),
+            health: HealthConfig::default(),
+            history: HistoryConfig::default(),
+            gutenberg: GutenbergConfig::default(),
+            archive: ArchiveConfig::default(),
+            openlibrary: OpenLibraryConfig::default(),
+            opds_feeds: OpdsFeedsConfig::default(),
+            opds_catalog: OpdsCatalogConfig::default(),
+            epub: EpubConfig::default(),
+            fulltext: FullTextConfig::default(),
+            librivox: LibriVoxConfig::default(),
+            mirror: MirrorConfig::default(),
+            test_plan: PathBuf::from("test-plan.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistSource {
+    pub name: String,
+    #[serde(flatten)]
+    pub location: PlaylistLocation,
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // Channels from this source may be recorded by the DVR
+    #[serde(default)]
+    pub dvr: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistLocation {
+    Url(String),
+    File(PathBuf),
+}
+
+// Shared cache for proxied live segments; sized for a few minutes of popular channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentCacheConfig {
+    pub enabled: bool,
+    pub max_bytes: u64,
+    pub max_segment_bytes: u64,
+    pub ttl_secs: u64,
+}
+
+impl Default for SegmentCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_bytes: 256 * 1024 * 1024,
+            max_segment_bytes: 16 * 1024 * 1024,
+            ttl_secs: 30,
+        }
+    }
+}
+
+// Recordings may be scheduled up to max_lead_secs ahead. A failed playlist or segment
+// fetch is retried after retry_secs, doubling each time, and the recording stops after
+// max_failures failures in a row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DvrConfig {
+    pub directory: PathBuf,
+    pub max_duration_secs: u64,
+    pub max_lead_secs: u64,
+    pub retention_secs: u64,
+    pub quota_bytes: u64,
+    pub retry_secs: u64,
+    pub max_failures: u32,
+}
+
+impl Default for DvrConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            max_duration_secs: 4 * 3600,
+            max_lead_secs: 7 * 24 * 3600,
+            retention_secs: 7 * 24 * 3600,
+            quota_bytes: 10 * 1024 * 1024 * 1024,
+            retry_secs: 2,
+            max_failures: 5,
+        }
+    }
+}
+
+// Download checks fetch files up to checksum_max_bytes whole and hash them (0 turns it off),
+// and PDFs up to pdf_max_bytes to read their metadata, page count and text layer, at most
+// pdf_concurrency of them at once (read at startup).
+// Bulk jobs run job_concurrency checks at once, at most per_host_concurrency against one host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerificationConfig {
+    pub checksum_max_bytes: u64,
+    pub pdf_max_bytes: u64,
+    pub pdf_concurrency: usize,
+    pub job_concurrency: usize,
+    pub per_host_concurrency: usize,
+    pub max_job_items: usize,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            checksum_max_bytes: 10 * 1024 * 1024,
+            pdf_max_bytes: 64 * 1024 * 1024,
+            pdf_concurrency: 2,
+            job_concurrency: 8,
+            per_host_concurrency: 2,
+            max_job_items: 5000,
+        }
+    }
+}
+
+// Which URLs the server may fetch on behalf of users and playlists. Internal
+// addresses are refused unless listed in allow (hosts, "*.domain", IPs or CIDRs);
+// deny entries are refused no matter what.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+    pub allowed_schemes: Vec<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+}
+
+// Caps on what an upstream response may cost us, per kind of body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FetchLimits {
+    pub playlist_bytes: u64,
+    pub manifest_bytes: u64,
+    pub segment_bytes: u64,
+    pub catalog_bytes: u64,
+    pub api_bytes: u64,
+    pub book_bytes: u64,
+    // Inflated size may be at most this many times the compressed size
+    pub max_decompression_ratio: u64,
+    pub max_redirects: usize,
+    pub max_header_bytes: u64,
+    // Connecting is capped when the client is built; requests get request_timeout_secs
+    // from start to end of body, large downloads (books, catalogs, mirror copies) download_timeout_secs
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub download_timeout_secs: u64,
+}
+
+impl FetchLimits {
+    pub fn body_bytes(&self, kind: BodyKind) -> u64 {
+        match kind {
+            BodyKind::Playlist => self.playlist_bytes,
+            BodyKind::Manifest => self.manifest_bytes,
+            BodyKind::Segment => self.segment_bytes,
+            BodyKind::Catalog => self.catalog_bytes,
+            BodyKind::Api => self.api_bytes,
+            BodyKind::Book => self.book_bytes,
+        }
+    }
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            playlist_bytes: 16 * 1024 * 1024,
+            manifest_bytes: 4 * 1024 * 1024,
+            segment_bytes: 64 * 1024 * 1024,
+            catalog_bytes: 256 * 1024 * 1024,
+            api_bytes: 8 * 1024 * 1024,
+            book_bytes: 128 * 1024 * 1024,
+            max_decompression_ratio: 100,
+            max_redirects: 10,
+            max_header_bytes: 64 * 1024,
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            download_timeout_secs: 30 * 60,
+        }
+    }
+}
+
+// A provider is down after down_after_failures failed checks in a row; a background
+// loop is stalled once it is heartbeat_grace_secs past its next expected pass.
+// The segment cache is degraded when over the last five minutes at least
+// cache_error_ratio of its upstream fetches failed or more than cache_max_evictions
+// segments were pushed out by its memory budget, and down when every fetch failed.
+// Jobs are degraded once one has run for job_lag_secs and down after job_down_secs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub down_after_failures: u32,
+    pub heartbeat_grace_secs: u64,
+    pub cache_error_ratio: f64,
+    pub cache_max_evictions: usize,
+    pub job_lag_secs: u64,
+    pub job_down_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            down_after_failures: 3,
+            heartbeat_grace_secs: 30,
+            cache_error_ratio: 0.25,
+            cache_max_evictions: 100,
+            job_lag_secs: 30 * 60,
+            job_down_secs: 2 * 3600,
+        }
+    }
+}
+
+// Every test plan run is appended to path as one JSON line and kept for retention_days
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub retention_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from("history/test-runs.jsonl"),
+            retention_days: 90,
+        }
+    }
+}
+
+// The Project Gutenberg catalog: a directory of extracted RDF files (rdf-files.tar.bz2,
+// the default), a single RDF file, or pg_catalog.csv (optionally .gz), read from disk or
+// fetched from a mirror. Only RDF lists each book's real files and download counts; the
+// CSV gets guessed file URLs. Download links point at mirror.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GutenbergConfig {
+    pub catalog: Option<PlaylistLocation>,
+    pub mirror: String,
+    pub refresh_secs: u64,
+}
+
+impl Default for GutenbergConfig {
+    fn default() -> Self {
+        Self {
+            catalog: Some(PlaylistLocation::File(PathBuf::from("catalog/cache/epub"))),
+            mirror: "https://www.gutenberg.org".to_string(),
+            refresh_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+// Internet Archive texts. base_url can point at a stand-in serving the same
+// advancedsearch.php, /metadata and /download paths.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    pub base_url: String,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            base_url: "https://archive.org".to_string(),
+        }
+    }
+}
+
+// LibriVox audiobooks. Search results check the first verify_chapters chapter
+// streams; a single book's endpoint checks them all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibriVoxConfig {
+    pub enabled: bool,
+    pub base_url: String,
+    pub verify_chapters: usize,
+}
+
+impl Default for LibriVoxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            base_url: "https://librivox.org".to_string(),
+            verify_chapters: 3,
+        }
+    }
+}
+
+// Local copies of open-licensed books and media. Every category has its own disk
+// quota; failed downloads resume after retry_base_secs, doubling up to
+// retry_max_secs, until max_attempts. Only results from open-licensed providers
+// and URLs on open_hosts (domains and their subdomains) can be mirrored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    pub concurrency: usize,
+    pub quotas: HashMap<String, u64>,
+    pub max_attempts: u32,
+    pub retry_base_secs: u64,
+    pub retry_max_secs: u64,
+    pub open_hosts: Vec<String>,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            directory: PathBuf::from("mirror"),
+            concurrency: 2,
+            quotas: HashMap::from([
+                ("book".to_string(), 5 * 1024 * 1024 * 1024),
+                ("audiobook".to_string(), 20 * 1024 * 1024 * 1024),
+                ("media".to_string(), 20 * 1024 * 1024 * 1024),
+            ]),
+            max_attempts: 8,
+            retry_base_secs: 60,
+            retry_max_secs: 6 * 3600,
+            open_hosts: ["gutenberg.org", "archive.org", "librivox.org", "standardebooks.org"].map(str::to_string).to_vec(),
+        }
+    }
+}
+
+// Book results are enriched from Open Library. Lookups are cached (misses for less
+// time) and a search waits at most budget_ms for them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenLibraryConfig {
+    pub enabled: bool,
+    pub base_url: String,
+    pub covers_url: String,
+    pub cache_secs: u64,
+    pub miss_cache_secs: u64,
+    pub max_cache_entries: usize,
+    pub budget_ms: u64,
+}
+
+impl Default for OpenLibraryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            base_url: "https://openlibrary.org".to_string(),
+            covers_url: "https://covers.openlibrary.org".to_string(),
+            cache_secs: 7 * 24 * 3600,
+            miss_cache_secs: 24 * 3600,
+            max_cache_entries: 10_000,
+            budget_ms: 2000,
+        }
+    }
+}
+
+// External OPDS catalogs searched for books. A feed with a search link is queried
+// directly; one without is crawled through its navigation links, up to max_pages
+// feed pages, and the crawl is kept for cache_secs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpdsFeedsConfig {
+    pub feeds: Vec<OpdsFeed>,
+    pub max_pages: usize,
+    pub results_per_feed: usize,
+    pub cache_secs: u64,
+}
+
+impl Default for OpdsFeedsConfig {
+    fn default() -> Self {
+        Self {
+            feeds: vec![],
+            max_pages: 10,
+            results_per_feed: 1,
+            cache_secs: 3600,
+        }
+    }
+}
+
+// The server's own OPDS catalog keeps verified facet pages and searches for cache_secs,
+// at most max_cache_entries of them; the oldest go first when it's full.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpdsCatalogConfig {
+    pub cache_secs: u64,
+    pub max_cache_entries: usize,
+}
+
+impl Default for OpdsCatalogConfig {
+    fn default() -> Self {
+        Self {
+            cache_secs: 3600,
+            max_cache_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpdsFeed {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+// Books opened by the reader API are downloaded and parsed once, then kept under
+// directory until nobody has opened them for retention_secs. A chapter or resource
+// inflating past max_entry_bytes isn't served.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EpubConfig {
+    pub directory: PathBuf,
+    pub retention_secs: u64,
+    pub max_entry_bytes: u64,
+}
+
+impl Default for EpubConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("cache/epub"),
+            retention_secs: 30 * 24 * 3600,
+            max_entry_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+// Optional full-text index of Gutenberg plain-text editions. The indexer takes the
+// listed books, then the max_books most downloaded in the catalog, books_per_tick at
+// a time. A book that fails to index is left alone for retry_secs. enabled and
+// directory are read at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FullTextConfig {
+    pub enabled: bool,
+    pub directory: PathBuf,
+    pub books: Vec<u32>,
+    pub max_books: usize,
+    pub books_per_tick: usize,
+    pub retry_secs: u64,
+}
+
+impl Default for FullTextConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("cache/fulltext"),
+            books: vec![],
+            max_books: 100,
+            books_per_tick: 5,
+            retry_secs: 6 * 3600,
+        }
+    }
+}
+
+fn default_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+pub struct ConfigStore {
+    path: PathBuf,
+    current: RwLock<Arc<ServerConfig>>,
+    modified: RwLock<Option<SystemTime>>,
+    // Why the file on disk isn't the config in use, if it isn't
+    error: RwLock<Option<String>>,
+    pub heartbeat: Heartbeat,
+}
+
+impl ConfigStore {
+    pub fn load() -> Self {
+        let path = std::env::var("CONTENT_SERVER_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let mut error = None;
+        let (config, modified) = match read_config(&path) {
+            Ok(Some(loaded)) => {
+                log!("⚙️  Loaded config from {}", path.display());
+                loaded
+            }
+            Ok(None) => {
+                log!("⚙️  No config at {}, using defaults", path.display());
+                (ServerConfig::default(), None)
+            }
+            Err(e) => {
+                eprintln!("❌ Invalid config {}: {} - using defaults", path.display(), e);
+                error = Some(e);
+                (ServerConfig::default(), None)
+            }
+        };
+
+        Self {
+            path,
+            current: RwLock::new(Arc::new(config)),
+            modified: RwLock::new(modified),
+            error: RwLock::new(error),
+            heartbeat: Heartbeat::new(WATCH_INTERVAL),
+        }
+    }
+
+    // Fixed settings for tests, with no file behind them
+    #[cfg(test)]
+    pub fn fixed(config: ServerConfig) -> Self {
+        Self {
+            path: PathBuf::new(),
+            current: RwLock::new(Arc::new(config)),
+            modified: RwLock::new(None),
+            error: RwLock::new(None),
+            heartbeat: Heartbeat::new(WATCH_INTERVAL),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.error.read().unwrap().clone()
+    }
+
+    // Poll the config file and swap in new settings when it changes on disk.
+    // A file that fails to parse keeps the previous settings in place.
+    pub fn spawn_watcher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.reload_if_changed();
+                self.heartbeat.beat();
+            }
+        });
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == *self.modified.read().unwrap() {
+            return;
+        }
+        *self.modified.write().unwrap() = modified;
+
+        match read_config(&self.path) {
+            Ok(Some((config, _))) => {
+                log!("🔄 Reloaded config from {}", self.path.display());
+                *self.current.write().unwrap() = Arc::new(config);
+                *self.error.write().unwrap() = None;
+            }
+            Ok(None) => {
+                log!("🔄 Config {} removed, using defaults", self.path.display());
+                *self.current.write().unwrap() = Arc::new(ServerConfig::default());
+                *self.error.write().unwrap() = None;
+            }
+            Err(e) => {
+                eprintln!("❌ Invalid config {}: {} - keeping previous", self.path.display(), e);
+                *self.error.write().unwrap() = Some(e);
+            }
+        }
+    }
+}
+
+fn read_config(path: &Path) -> Result<Option<(ServerConfig, Option<SystemTime>)>, String> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let config = serde_json::from_str(&raw).map_err(|e| e.to_string())?;
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Ok(Some((config, modified)))
+}
@@ -0,0 +1,315 @@
+// DASH MANIFESTS - MPD parsing for stream verification and metadata
+use crate::media::{Rendition, RenditionKind, StreamMetadata, StreamProtocol};
+use reqwest::Url;
+use roxmltree::Node;
+
+#[derive(Debug, Clone, Default)]
+pub struct SegmentTemplate {
+    pub initialization: Option<String>,
+    pub media: Option<String>,
+    pub start_number: Option<u64>,
+    pub first_time: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub id: String,
+    pub bandwidth: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codecs: Option<String>,
+    pub base_url: Url,
+    pub template: Option<SegmentTemplate>,
+    // SegmentBase/SegmentList initialization: URL plus optional byte range
+    pub initialization: Option<(Url, Option<String>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptationSet {
+    pub kind: RenditionKind,
+    pub language: Option<String>,
+    pub representations: Vec<Representation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Period {
+    pub adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub live: bool,
+    pub periods: Vec<Period>,
+}
+
+// A request that proves a representation is really served
+#[derive(Debug, Clone)]
+pub struct SegmentRequest {
+    pub url: Url,
+    pub range: Option<String>,
+}
+
+pub fn is_dash(content_type: Option<&str>, url: &Url, body: &str) -> bool {
+    content_type.map(|t| t.contains("dash+xml")).unwrap_or(false)
+        || url.path().to_ascii_lowercase().ends_with(".mpd")
+        || body.trim_start().starts_with("<?xml") && body.contains("<MPD")
+}
+
+pub fn parse_mpd(body: &str, manifest_url: &Url) -> Result<Manifest, String> {
+    let doc = roxmltree::Document::parse(body).map_err(|e| e.to_string())?;
+    let mpd = doc.root_element();
+    if !mpd.has_tag_name("MPD") {
+        return Err("Not an MPD document".to_string());
+    }
+
+    let mpd_base = base_url(mpd, manifest_url);
+    let mut periods = Vec::new();
+
+    for period in children(mpd, "Period") {
+        let period_base = base_url(period, &mpd_base);
+        let period_template = child(period, "SegmentTemplate").map(|t| parse_template(t, None));
+        let mut adaptation_sets = Vec::new();
+
+        for set in children(period, "AdaptationSet") {
+            let set_base = base_url(set, &period_base);
+            let set_template = child(set, "SegmentTemplate").map(|t| parse_template(t, period_template.as_ref()));
+            let set_mime = set.attribute("mimeType").or_else(|| set.attribute("contentType"));
+
+            let mut representations = Vec::new();
+            for rep in children(set, "Representation") {
+                let rep_base = base_url(rep, &set_base);
+                let template = match child(rep, "SegmentTemplate") {
+                    Some(t) => Some(parse_template(t, set_template.as_ref())),
+                    None => set_template.clone().or_else(|| period_template.clone()),
+                };
+                representations.push(Representation {
+                    id: rep.attribute("id").unwrap_or_default().to_string(),
+                    bandwidth: rep.attribute("bandwidth").and_then(|b| b.parse().ok()),
+                    width: rep.attribute("width").or(set.attribute("width")).and_then(|w| w.parse().ok()),
+                    height: rep.attribute("height").or(set.attribute("height")).and_then(|h| h.parse().ok()),
+                    codecs: rep.attribute("codecs").or(set.attribute("codecs")).map(str::to_string),
+                    initialization: explicit_initialization(rep, &rep_base).or_else(|| explicit_initialization(set, &rep_base)),
+                    base_url: rep_base,
+                    template,
+                });
+            }
+
+            let mime = set_mime.or_else(|| rep_mime(set)).unwrap_or_default();
+            let kind = if mime.starts_with("audio") {
+                RenditionKind::Audio
+            } else if mime.starts_with("text") || mime.contains("ttml") || mime.contains("vtt") {
+                RenditionKind::Subtitles
+            } else {
+                RenditionKind::Video
+            };
+
+            adaptation_sets.push(AdaptationSet {
+                kind,
+                language: set.attribute("lang").map(str::to_string),
+                representations,
+            });
+        }
+
+        periods.push(Period { adaptation_sets });
+    }
+
+    Ok(Manifest {
+        live: mpd.attribute("type") == Some("dynamic"),
+        periods,
+    })
+}
+
+impl Manifest {
+    pub fn metadata(&self) -> StreamMetadata {
+        let mut metadata = StreamMetadata::new(StreamProtocol::Dash, self.live);
+        for set in self.periods.iter().flat_map(|p| &p.adaptation_sets) {
+            for rep in &set.representations {
+                metadata.renditions.push(Rendition {
+                    kind: set.kind,
+                    bandwidth: rep.bandwidth,
+                    width: rep.width,
+                    height: rep.height,
+                    codecs: rep.codecs.clone(),
+                    language: set.language.clone(),
+                });
+            }
+        }
+        metadata.finish()
+    }
+
+    // Initialization segment of the first video representation, or its first
+    // media segment for self-initializing streams
+    pub fn probe_request(&self) -> Option<SegmentRequest> {
+        let rep = self
+            .periods
+            .iter()
+            .flat_map(|p| &p.adaptation_sets)
+            .filter(|s| s.kind == RenditionKind::Video)
+            .chain(self.periods.iter().flat_map(|p| &p.adaptation_sets))
+            .flat_map(|s| &s.representations)
+            .next()?;
+
+        if let Some((url, range)) = &rep.initialization {
+            return Some(SegmentRequest { url: url.clone(), range: range.clone() });
+        }
+        let template = rep.template.as_ref()?;
+        let number = template.start_number.unwrap_or(1);
+        let path = match (&template.initialization, &template.media) {
+            (Some(init), _) => expand_template(init, rep, number, template.first_time),
+            (None, Some(media)) => expand_template(media, rep, number, template.first_time),
+            (None, None) => return Some(SegmentRequest { url: rep.base_url.clone(), range: Some("bytes=0-1023".to_string()) }),
+        };
+        rep.base_url.join(&path).ok().map(|url| SegmentRequest { url, range: None })
+    }
+}
+
+fn children<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+fn base_url(node: Node, parent: &Url) -> Url {
+    child(node, "BaseURL")
+        .and_then(|b| b.text())
+        .and_then(|text| parent.join(text.trim()).ok())
+        .unwrap_or_else(|| parent.clone())
+}
+
+fn rep_mime<'a>(set: Node<'a, '_>) -> Option<&'a str> {
+    children(set, "Representation").find_map(|r| r.attribute("mimeType"))
+}
+
+fn parse_template(node: Node, inherited: Option<&SegmentTemplate>) -> SegmentTemplate {
+    let inherited = inherited.cloned().unwrap_or_default();
+    let first_time = child(node, "SegmentTimeline")
+        .and_then(|timeline| child(timeline, "S"))
+        .and_then(|s| s.attribute("t"))
+        .and_then(|t| t.parse().ok());
+
+    SegmentTemplate {
+        initialization: node.attribute("initialization").map(str::to_string).or(inherited.initialization),
+        media: node.attribute("media").map(str::to_string).or(inherited.media),
+        start_number: node.attribute("startNumber").and_then(|n| n.parse().ok()).or(inherited.start_number),
+        first_time: first_time.unwrap_or(inherited.first_time),
+    }
+}
+
+fn explicit_initialization(node: Node, base: &Url) -> Option<(Url, Option<String>)> {
+    let init = child(node, "SegmentBase")
+        .or_else(|| child(node, "SegmentList"))
+        .and_then(|s| child(s, "Initialization"))?;
+    let url = match init.attribute("sourceURL") {
+        Some(source) => base.join(source).ok()?,
+        None => base.clone(),
+    };
+    Some((url, init.attribute("range").map(|r| format!("bytes={}", r))))
+}
+
+// Widths past this are a hostile manifest asking us to allocate, not a real segment name
+const MAX_WIDTH: usize = 32;
+
+// Fill $RepresentationID$, $Bandwidth$, $Number$ and $Time$, including %0Nd widths
+fn expand_template(template: &str, rep: &Representation, number: u64, time: u64) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut parts = template.split('$').peekable();
+    if let Some(first) = parts.next() {
+        out.push_str(first);
+    }
+    let mut in_identifier = true;
+
+    while let Some(part) = parts.next() {
+        // A `$` with no closing one is just text
+        if in_identifier && parts.peek().is_none() {
+            out.push('$');
+            out.push_str(part);
+            break;
+        }
+        if in_identifier {
+            let (name, width) = match part.split_once('%') {
+                Some((name, format)) => (name, format.trim_start_matches('0').trim_end_matches('d').parse().unwrap_or(0).min(MAX_WIDTH)),
+                None => (part, 0),
+            };
+            let value = match name {
+                "" => "$".to_string(),
+                "RepresentationID" => rep.id.clone(),
+                "Bandwidth" => rep.bandwidth.unwrap_or(0).to_string(),
+                "Number" => number.to_string(),
+                "Time" => time.to_string(),
+                other => format!("${}$", other),
+            };
+            out.push_str(&format!("{:0>width$}", value, width = width));
+        } else {
+            out.push_str(part);
+        }
+        in_identifier = !in_identifier;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest_url() -> Url {
+        Url::parse("https://cdn.example/live/manifest.mpd").unwrap()
+    }
+
+    fn representation() -> Representation {
+        Representation {
+            id: "v1".to_string(),
+            bandwidth: Some(800000),
+            width: None,
+            height: None,
+            codecs: None,
+            base_url: manifest_url(),
+            template: None,
+            initialization: None,
+        }
+    }
+
+    #[test]
+    fn templates_expand() {
+        let rep = representation();
+        assert_eq!(expand_template("$RepresentationID$/seg-$Number%05d$-$Time$.m4s", &rep, 7, 9000), "v1/seg-00007-9000.m4s");
+        assert_eq!(expand_template("$Bandwidth$$$x", &rep, 1, 0), "800000$x");
+    }
+
+    #[test]
+    fn hostile_templates_stay_small() {
+        let rep = representation();
+        let path = expand_template("seg-$Number%0999999999999d$.m4s", &rep, 1, 0);
+        assert_eq!(path.len(), "seg-.m4s".len() + MAX_WIDTH);
+        assert_eq!(expand_template("seg-$Number", &rep, 1, 0), "seg-$Number");
+        assert_eq!(expand_template("seg-$Unknown$-$Number$", &rep, 3, 0), "seg-$Unknown$-3");
+    }
+
+    #[test]
+    fn malformed_manifests_are_errors() {
+        let url = manifest_url();
+        assert!(parse_mpd("", &url).is_err());
+        assert!(parse_mpd("<MPD><Period>", &url).is_err());
+        assert!(parse_mpd("<html><body/></html>", &url).is_err());
+        // Entity expansion bombs need a DTD, which isn't accepted
+        let bomb = r#"<?xml version="1.0"?><!DOCTYPE MPD [<!ENTITY a "aaaaaaaaaa"><!ENTITY b "&a;&a;&a;&a;&a;&a;&a;&a;&a;&a;">]><MPD>&b;</MPD>"#;
+        assert!(parse_mpd(bomb, &url).is_err());
+    }
+
+    #[test]
+    fn empty_manifests_have_nothing_to_probe() {
+        let url = manifest_url();
+        let manifest = parse_mpd("<MPD type=\"dynamic\"><Period><AdaptationSet/></Period></MPD>", &url).unwrap();
+        assert!(manifest.live);
+        assert!(manifest.probe_request().is_none());
+
+        let mpd = r#"<MPD><Period><AdaptationSet mimeType="video/mp4">
+            <SegmentTemplate initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number$.m4s"/>
+            <Representation id="../../../../admin" bandwidth="1"/></AdaptationSet></Period></MPD>"#;
+        let request = parse_mpd(mpd, &url).unwrap().probe_request().unwrap();
+        // Relative paths resolve against the manifest; the outbound policy decides what may be fetched
+        assert_eq!(request.url.as_str(), "https://cdn.example/admin/init.mp4");
+    }
+}
@@ -0,0 +1,900 @@
+// LIVE DVR - Records channel windows to disk and replays them as VOD playlists
+use crate::config::{ConfigStore, DvrConfig};
+use crate::health::Heartbeat;
+use crate::hls;
+use crate::playlists::{Channel, PlaylistRegistry};
+use crate::{AppState, Content};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use crate::guard::{BodyKind, GuardedClient};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+
+const METADATA_FILE: &str = "recording.json";
+const PLAYLIST_FILE: &str = "index.m3u8";
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingStatus {
+    Scheduled,
+    Recording,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedSegment {
+    pub file: String,
+    pub duration: f64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub id: String,
+    pub channel_id: String,
+    pub channel_name: String,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub status: RecordingStatus,
+    pub error: Option<String>,
+    pub init_segment: Option<String>,
+    #[serde(default)]
+    pub init_bytes: u64,
+    pub segments: Vec<RecordedSegment>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Recording {
+    pub fn bytes(&self) -> u64 {
+        self.init_bytes + self.segments.iter().map(|s| s.bytes).sum::<u64>()
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        self.segments.iter().map(|s| s.duration).sum()
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(self.status, RecordingStatus::Scheduled | RecordingStatus::Recording)
+    }
+
+    // VOD playlist while recording (EVENT) and once finished (ENDLIST)
+    fn playlist(&self) -> String {
+        let target = self.segments.iter().map(|s| s.duration.ceil() as u64).max().unwrap_or(1);
+        let mut out = format!("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n", target);
+        out.push_str(if self.is_active() { "#EXT-X-PLAYLIST-TYPE:EVENT\n" } else { "#EXT-X-PLAYLIST-TYPE:VOD\n" });
+        if let Some(init) = &self.init_segment {
+            out.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init));
+        }
+        for segment in &self.segments {
+            out.push_str(&format!("#EXTINF:{:.3},\n{}\n", segment.duration, segment.file));
+        }
+        if !self.is_active() {
+            out.push_str("#EXT-X-ENDLIST\n");
+        }
+        out
+    }
+
+    pub fn to_content(&self) -> Content {
+        Content {
+            id: self.id.clone(),
+            title: format!("{} ({})", self.channel_name, self.starts_at.format("%Y-%m-%d %H:%M UTC")),
+            source: "dvr".to_string(),
+            stream_url: format!("/dvr/{}/{}", self.id, PLAYLIST_FILE),
+            download_url: "".to_string(),
+            verified: self.status == RecordingStatus::Completed && !self.segments.is_empty(),
+            quality: "Recording".to_string(),
+            size: Some(format!("{}MB", self.bytes() / 1024 / 1024)),
+            rating: None,
+            proxy_url: None,
+            metadata: None,
+            book: None,
+            audiobook: None,
+            verification: None,
+        }
+    }
+}
+
+// Why a poll of the live playlist didn't get through
+enum Failure {
+    // A blip upstream; the next poll may well work
+    Retry(String),
+    // Polling again won't help (encrypted stream, quota)
+    Permanent(String),
+}
+
+#[derive(Deserialize)]
+pub struct RecordRequest {
+    channel_id: String,
+    duration_secs: u64,
+    starts_at: Option<DateTime<Utc>>,
+}
+
+pub struct DvrManager {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    playlists: Arc<PlaylistRegistry>,
+    recordings: RwLock<HashMap<String, Recording>>,
+    stopping: RwLock<HashSet<String>>,
+    pub heartbeat: Heartbeat,
+}
+
+impl DvrManager {
+    pub async fn new(client: GuardedClient, config: Arc<ConfigStore>, playlists: Arc<PlaylistRegistry>) -> Self {
+        let manager = Self {
+            client,
+            config,
+            playlists,
+            recordings: RwLock::new(HashMap::new()),
+            stopping: RwLock::new(HashSet::new()),
+            heartbeat: Heartbeat::new(JANITOR_INTERVAL),
+        };
+        manager.load_existing().await;
+        manager
+    }
+
+    fn settings(&self) -> DvrConfig {
+        self.config.snapshot().dvr.clone()
+    }
+
+    fn recording_dir(&self, id: &str) -> PathBuf {
+        self.settings().directory.join(id)
+    }
+
+    // Pick up recordings from earlier runs. Ones that haven't started yet stay scheduled
+    // (resume_scheduled starts them); ones cut off by a restart are closed as-is, and
+    // failed if nothing was captured.
+    async fn load_existing(&self) {
+        let Ok(mut dirs) = tokio::fs::read_dir(&self.settings().directory).await else { return };
+        let mut recordings = self.recordings.write().await;
+
+        while let Ok(Some(entry)) = dirs.next_entry().await {
+            let Ok(raw) = tokio::fs::read_to_string(entry.path().join(METADATA_FILE)).await else { continue };
+            let Ok(mut recording) = serde_json::from_str::<Recording>(&raw) else { continue };
+            let upcoming = recording.status == RecordingStatus::Scheduled && recording.starts_at > Utc::now();
+            if recording.is_active() && !upcoming {
+                recording.status = if recording.segments.is_empty() { RecordingStatus::Failed } else { RecordingStatus::Completed };
+                recording.error = Some("Interrupted by server restart".to_string());
+                recording.finished_at = Some(Utc::now());
+                self.persist(&recording).await;
+                self.write_playlist(&recording).await;
+            }
+            recordings.insert(recording.id.clone(), recording);
+        }
+        log!("📼 Loaded {} recordings", recordings.len());
+    }
+
+    // Start the timers for recordings scheduled before a restart, once the channels are loaded
+    pub fn spawn_resume(self: Arc<Self>) {
+        tokio::spawn(async move {
+            self.playlists.refresh_due().await;
+            self.resume_scheduled().await;
+        });
+    }
+
+    async fn resume_scheduled(self: &Arc<Self>) {
+        let scheduled: Vec<Recording> =
+            self.recordings.read().await.values().filter(|r| r.status == RecordingStatus::Scheduled).cloned().collect();
+        for recording in scheduled {
+            let channel = self.playlists.channel(&recording.channel_id).await.filter(|c| c.recordable);
+            let Some(channel) = channel else {
+                self.update(&recording.id, |r| {
+                    r.status = RecordingStatus::Failed;
+                    r.error = Some("Channel is gone or no longer recordable".to_string());
+                    r.finished_at = Some(Utc::now());
+                })
+                .await;
+                continue;
+            };
+            log!("📼 Resuming scheduled recording {} of {}", recording.id, channel.name);
+            let manager = self.clone();
+            tokio::spawn(async move { manager.record(recording.id, channel).await });
+        }
+    }
+
+    pub async fn list(&self) -> Vec<Recording> {
+        let mut recordings: Vec<Recording> = self.recordings.read().await.values().cloned().collect();
+        recordings.sort_by_key(|r| std::cmp::Reverse(r.starts_at));
+        recordings
+    }
+
+    pub async fn start(self: &Arc<Self>, request: RecordRequest) -> Result<Recording, (StatusCode, String)> {
+        let settings = self.settings();
+        let channel = self
+            .playlists
+            .channel(&request.channel_id)
+            .await
+            .ok_or((StatusCode::NOT_FOUND, "Unknown channel".to_string()))?;
+        if !channel.recordable {
+            return Err((StatusCode::FORBIDDEN, "Recording is not enabled for this channel".to_string()));
+        }
+        if request.duration_secs == 0 || request.duration_secs > settings.max_duration_secs {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("duration_secs must be between 1 and {}", settings.max_duration_secs),
+            ));
+        }
+
+        let starts_at = request.starts_at.unwrap_or_else(Utc::now).max(Utc::now());
+        let latest = Utc::now() + chrono::Duration::seconds(settings.max_lead_secs as i64);
+        if starts_at > latest {
+            return Err((StatusCode::BAD_REQUEST, format!("starts_at may be at most {} seconds ahead", settings.max_lead_secs)));
+        }
+        let ends_at = starts_at + chrono::Duration::seconds(request.duration_secs as i64);
+        let digest = format!("{:x}", md5::compute(format!("{}|{}", channel.id, starts_at.to_rfc3339())));
+        let recording = Recording {
+            id: format!("rec_{}", &digest[..12]),
+            channel_id: channel.id.clone(),
+            channel_name: channel.name.clone(),
+            starts_at,
+            ends_at,
+            status: RecordingStatus::Scheduled,
+            error: None,
+            init_segment: None,
+            init_bytes: 0,
+            segments: vec![],
+            finished_at: None,
+        };
+
+        tokio::fs::create_dir_all(self.recording_dir(&recording.id))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        self.persist(&recording).await;
+        self.recordings.write().await.insert(recording.id.clone(), recording.clone());
+
+        log!("📼 Scheduled recording {} of {} until {}", recording.id, channel.name, ends_at);
+        let manager = self.clone();
+        let id = recording.id.clone();
+        tokio::spawn(async move { manager.record(id, channel).await });
+
+        Ok(recording)
+    }
+
+    // Stops an active recording, or deletes a finished one from disk
+    pub async fn remove(&self, id: &str) -> bool {
+        let active = match self.recordings.read().await.get(id) {
+            Some(recording) => recording.is_active(),
+            None => return false,
+        };
+        if active {
+            self.stopping.write().await.insert(id.to_string());
+        } else {
+            self.delete(id).await;
+        }
+        true
+    }
+
+    async fn delete(&self, id: &str) {
+        self.recordings.write().await.remove(id);
+        if let Err(e) = tokio::fs::remove_dir_all(self.recording_dir(id)).await {
+            eprintln!("❌ Failed to delete recording {}: {}", id, e);
+        }
+    }
+
+    async fn record(&self, id: String, channel: Channel) {
+        let result = self.capture(&id, &channel).await;
+        self.stopping.write().await.remove(&id);
+
+        let finished = self
+            .update(&id, |recording| {
+                recording.finished_at = Some(Utc::now());
+                match &result {
+                    Ok(()) => recording.status = RecordingStatus::Completed,
+                    Err(e) => {
+                        // Whatever was captured before the failure stays playable
+                        recording.status = if recording.segments.is_empty() {
+                            RecordingStatus::Failed
+                        } else {
+                            RecordingStatus::Completed
+                        };
+                        recording.error = Some(e.clone());
+                    }
+                }
+            })
+            .await;
+
+        match (&result, finished) {
+            (Ok(()), Some(r)) => log!(
+                "✅ Recording {} finished: {} segments, {:.0}s",
+                id,
+                r.segments.len(),
+                r.duration_secs()
+            ),
+            (Err(e), _) => eprintln!("❌ Recording {} stopped: {}", id, e),
+            _ => {}
+        }
+    }
+
+    // Polls the live playlist until ends_at. Failed polls are retried with backoff;
+    // only max_failures of them in a row end the recording early.
+    async fn capture(&self, id: &str, channel: &Channel) -> Result<(), String> {
+        let (starts_at, ends_at) = match self.recordings.read().await.get(id) {
+            Some(r) => (r.starts_at, r.ends_at),
+            None => return Ok(()),
+        };
+        if let Ok(wait) = (starts_at - Utc::now()).to_std() {
+            tokio::time::sleep(wait).await;
+        }
+        self.update(id, |r| r.status = RecordingStatus::Recording).await;
+
+        let mut media_url: Option<Url> = None;
+        let mut next_sequence: Option<u64> = None;
+        let mut failures = 0;
+
+        while Utc::now() < ends_at {
+            if self.stopping.read().await.contains(id) {
+                return Ok(());
+            }
+
+            let wait = match self.poll(id, channel, &mut media_url, &mut next_sequence).await {
+                Ok(None) => return Ok(()),
+                Ok(Some(wait)) => {
+                    failures = 0;
+                    wait
+                }
+                Err(Failure::Permanent(e)) => return Err(e),
+                Err(Failure::Retry(e)) => {
+                    let settings = self.settings();
+                    failures += 1;
+                    if failures >= settings.max_failures.max(1) {
+                        return Err(format!("{} ({} failures in a row)", e, failures));
+                    }
+                    let backoff = settings.retry_secs.max(1).saturating_mul(1 << (failures - 1).min(6));
+                    eprintln!("⚠️  Recording {}: {}, retrying in {}s", id, e, backoff);
+                    backoff
+                }
+            };
+            let left = (ends_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(Duration::from_secs(wait).min(left)).await;
+        }
+
+        Ok(())
+    }
+
+    // One pass over the live playlist: saves new segments and returns how long to wait
+    // before the next pass, or None once the stream has ended
+    async fn poll(&self, id: &str, channel: &Channel, media_url: &mut Option<Url>, next_sequence: &mut Option<u64>) -> Result<Option<u64>, Failure> {
+        let url = match media_url {
+            Some(url) => url.clone(),
+            None => media_url.insert(self.resolve_media_playlist(channel).await.map_err(Failure::Retry)?).clone(),
+        };
+        let body = self.fetch_text(channel, &url).await.map_err(Failure::Retry)?;
+        let playlist = hls::parse_media_playlist(&body, &url);
+        if playlist.encrypted {
+            return Err(Failure::Permanent("Encrypted streams can't be recorded".to_string()));
+        }
+
+        if let Some(map_uri) = &playlist.map_uri {
+            let missing = self.recordings.read().await.get(id).map(|r| r.init_segment.is_none()).unwrap_or(false);
+            if missing {
+                let file = format!("init.{}", extension(map_uri, "mp4"));
+                let room = self.ensure_quota(id).await?;
+                let bytes = self.download(id, channel, map_uri, &file, room).await?;
+                self.update(id, |r| {
+                    r.init_segment = Some(file);
+                    r.init_bytes = bytes;
+                })
+                .await;
+            }
+        }
+
+        // Live playlists slide; start from the newest segment on the first pass
+        let first_pass = next_sequence.is_none();
+        let last = playlist.segments.last().map(|s| s.sequence);
+        for segment in &playlist.segments {
+            if first_pass && Some(segment.sequence) != last {
+                continue;
+            }
+            if next_sequence.is_some_and(|next| segment.sequence < next) {
+                continue;
+            }
+
+            let file = format!("seg_{:08}.{}", segment.sequence, extension(&segment.uri, "ts"));
+            let room = self.ensure_quota(id).await?;
+            let bytes = self.download(id, channel, &segment.uri, &file, room).await?;
+            *next_sequence = Some(segment.sequence + 1);
+
+            let duration = segment.duration;
+            let recording = self
+                .update(id, |r| r.segments.push(RecordedSegment { file, duration, bytes }))
+                .await;
+            if let Some(recording) = recording {
+                self.write_playlist(&recording).await;
+            }
+        }
+
+        if playlist.ended {
+            return Ok(None);
+        }
+        Ok(Some((playlist.target_duration / 2).max(1)))
+    }
+
+    // Master playlists are recorded at their highest-bandwidth variant
+    async fn resolve_media_playlist(&self, channel: &Channel) -> Result<Url, String> {
+        let url = Url::parse(&channel.url).map_err(|e| e.to_string())?;
+        let body = self.fetch_text(channel, &url).await?;
+        if !hls::is_master(&body) {
+            return Ok(url);
+        }
+        hls::parse_variants(&body, &url)
+            .into_iter()
+            .max_by_key(|v| v.bandwidth)
+            .map(|v| v.uri)
+            .ok_or_else(|| "Master playlist has no variants".to_string())
+    }
+
+    async fn fetch_text(&self, channel: &Channel, url: &Url) -> Result<String, String> {
+        let response = self.client.send(self.request(channel, url)).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {} for {}", response.status(), url));
+        }
+        self.client.read_text(response, BodyKind::Manifest).await.map_err(|e| e.to_string())
+    }
+
+    // Saves one segment, at most `room` bytes of it; nothing is left behind if it fails
+    async fn download(&self, id: &str, channel: &Channel, url: &Url, file: &str, room: u64) -> Result<u64, Failure> {
+        let path = self.recording_dir(id).join(file);
+        let result = self.write_segment(channel, url, &path, room).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+        result
+    }
+
+    async fn write_segment(&self, channel: &Channel, url: &Url, path: &std::path::Path, room: u64) -> Result<u64, Failure> {
+        let retry = |e: &dyn std::fmt::Display| Failure::Retry(e.to_string());
+        let mut response = self.client.send(self.request(channel, url)).await.map_err(|e| retry(&e))?;
+        if !response.status().is_success() {
+            return Err(Failure::Retry(format!("HTTP {} for {}", response.status(), url)));
+        }
+
+        let max = self.config.snapshot().limits.body_bytes(BodyKind::Segment);
+        let mut out = tokio::fs::File::create(path).await.map_err(|e| retry(&e))?;
+        let mut written = 0u64;
+        while let Some(chunk) = response.chunk().await.map_err(|e| retry(&e))? {
+            written += chunk.len() as u64;
+            if written > max {
+                return Err(Failure::Retry(format!("Segment {} larger than {} bytes", url, max)));
+            }
+            if written > room {
+                return Err(Failure::Permanent("DVR disk quota exceeded".to_string()));
+            }
+            out.write_all(&chunk).await.map_err(|e| retry(&e))?;
+        }
+        out.flush().await.map_err(|e| retry(&e))?;
+        Ok(written)
+    }
+
+    fn request(&self, channel: &Channel, url: &Url) -> reqwest::RequestBuilder {
+        let mut request = self.client.get(url.clone());
+        for (name, value) in &channel.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request
+    }
+
+    // Make room for another segment about the size of the largest so far by dropping the
+    // oldest finished recordings; fail if that isn't enough. Returns the bytes still free,
+    // which the download may not go past.
+    async fn ensure_quota(&self, active_id: &str) -> Result<u64, Failure> {
+        let quota = self.settings().quota_bytes;
+        loop {
+            let (used, needed, oldest) = {
+                let recordings = self.recordings.read().await;
+                let used: u64 = recordings.values().map(Recording::bytes).sum();
+                let needed = recordings
+                    .get(active_id)
+                    .and_then(|r| r.segments.iter().map(|s| s.bytes).max())
+                    .unwrap_or(1);
+                let oldest = recordings
+                    .values()
+                    .filter(|r| !r.is_active() && r.id != active_id)
+                    .min_by_key(|r| r.starts_at)
+                    .map(|r| r.id.clone());
+                (used, needed, oldest)
+            };
+            if used.saturating_add(needed) <= quota {
+                return Ok(quota - used);
+            }
+            match oldest {
+                Some(old_id) => {
+                    log!("🧹 DVR quota reached, deleting recording {}", old_id);
+                    self.delete(&old_id).await;
+                }
+                None => return Err(Failure::Permanent("DVR disk quota exceeded".to_string())),
+            }
+        }
+    }
+
+    pub fn spawn_janitor(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(JANITOR_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.expire_old().await;
+                self.heartbeat.beat();
+            }
+        });
+    }
+
+    async fn expire_old(&self) {
+        let retention = chrono::Duration::seconds(self.settings().retention_secs as i64);
+        let expired: Vec<String> = self
+            .recordings
+            .read()
+            .await
+            .values()
+            .filter(|r| r.finished_at.is_some_and(|f| Utc::now() - f > retention))
+            .map(|r| r.id.clone())
+            .collect();
+        for id in expired {
+            log!("🧹 Recording {} passed retention, deleting", id);
+            self.delete(&id).await;
+        }
+    }
+
+    async fn update(&self, id: &str, change: impl FnOnce(&mut Recording)) -> Option<Recording> {
+        let recording = {
+            let mut recordings = self.recordings.write().await;
+            let recording = recordings.get_mut(id)?;
+            change(recording);
+            recording.clone()
+        };
+        self.persist(&recording).await;
+        if !recording.is_active() {
+            self.write_playlist(&recording).await;
+        }
+        Some(recording)
+    }
+
+    async fn persist(&self, recording: &Recording) {
+        let path = self.recording_dir(&recording.id).join(METADATA_FILE);
+        if let Ok(json) = serde_json::to_string_pretty(recording) {
+            if let Err(e) = tokio::fs::write(&path, json).await {
+                eprintln!("❌ Failed to save recording {}: {}", recording.id, e);
+            }
+        }
+    }
+
+    async fn write_playlist(&self, recording: &Recording) {
+        let path = self.recording_dir(&recording.id).join(PLAYLIST_FILE);
+        if let Err(e) = tokio::fs::write(&path, recording.playlist()).await {
+            eprintln!("❌ Failed to write playlist for {}: {}", recording.id, e);
+        }
+    }
+}
+
+pub fn extension(url: &Url, fallback: &str) -> String {
+    url.path()
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty() && ext.len() <= 4 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+// POST /api/dvr/recordings
+pub async fn create_recording(
+    State(state): State<AppState>,
+    Json(request): Json<RecordRequest>,
+) -> Result<Json<Recording>, (StatusCode, String)> {
+    state.dvr.start(request).await.map(Json)
+}
+
+// GET /api/dvr/recordings
+pub async fn list_recordings(State(state): State<AppState>) -> Json<Vec<Recording>> {
+    Json(state.dvr.list().await)
+}
+
+// DELETE /api/dvr/recordings/:id
+pub async fn delete_recording(Path(id): Path<String>, State(state): State<AppState>) -> StatusCode {
+    if state.dvr.remove(&id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// GET /dvr/:id/:file - playlist and segments of a recording
+pub async fn recording_file(Path((id, file)): Path<(String, String)>, State(state): State<AppState>) -> Response {
+    let safe = |name: &str| !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if !safe(&id) || !safe(&file) || file == METADATA_FILE {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !state.dvr.recordings.read().await.contains_key(&id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let content_type = match file.rsplit('.').next() {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("ts") => "video/mp2t",
+        Some("mp4") | Some("m4s") => "video/mp4",
+        Some("aac") => "audio/aac",
+        _ => "application/octet-stream",
+    };
+    match tokio::fs::read(state.dvr.recording_dir(&id).join(&file)).await {
+        Ok(body) => ([(header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PlaylistLocation, PlaylistSource, ServerConfig};
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A fresh scratch directory per test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("content-server-dvr-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn recording(id: &str, status: RecordingStatus, starts_in_secs: i64, segments: &[u64]) -> Recording {
+        let starts_at = Utc::now() + chrono::Duration::seconds(starts_in_secs);
+        Recording {
+            id: id.to_string(),
+            channel_id: "live_missing".to_string(),
+            channel_name: "News".to_string(),
+            starts_at,
+            ends_at: starts_at + chrono::Duration::seconds(60),
+            status,
+            error: None,
+            init_segment: None,
+            init_bytes: 0,
+            segments: segments
+                .iter()
+                .enumerate()
+                .map(|(i, &bytes)| RecordedSegment { file: format!("seg_{:08}.ts", i), duration: 6.0, bytes })
+                .collect(),
+            finished_at: None,
+        }
+    }
+
+    // A live origin whose /flaky playlist fails its first two fetches and /down always fails
+    async fn origin() -> String {
+        let flaky = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/flaky.m3u8",
+                get(move || {
+                    let flaky = flaky.clone();
+                    async move {
+                        if flaky.fetch_add(1, Ordering::SeqCst) < 2 {
+                            return Err(StatusCode::BAD_GATEWAY);
+                        }
+                        Ok("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:2,\n/seg/7.ts\n#EXTINF:2,\n/seg/8.ts\n#EXT-X-ENDLIST\n")
+                    }
+                }),
+            )
+            .route("/down.m3u8", get(|| async { StatusCode::SERVICE_UNAVAILABLE }))
+            .route("/seg/:n", get(|| async { vec![b'x'; 100] }))
+            .route("/broken.ts", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        base
+    }
+
+    // A manager recording into `dir`, with Flaky and Down channels on the origin at `base`
+    async fn manager(dir: &std::path::Path, base: &str, tweak: impl FnOnce(&mut ServerConfig)) -> Arc<DvrManager> {
+        let list = dir.join("channels.m3u");
+        std::fs::write(&list, format!("#EXTM3U\n#EXTINF:-1,Flaky\n{0}/flaky.m3u8\n#EXTINF:-1,Down\n{0}/down.m3u8\n", base)).unwrap();
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        config.dvr.directory = dir.join("recordings");
+        config.dvr.retry_secs = 1;
+        config.playlists = vec![PlaylistSource {
+            name: "test".to_string(),
+            location: PlaylistLocation::File(list),
+            refresh_secs: 3600,
+            headers: HashMap::new(),
+            enabled: true,
+            dvr: true,
+        }];
+        tweak(&mut config);
+
+        let config = Arc::new(ConfigStore::fixed(config));
+        let client = GuardedClient::new(config.clone());
+        let playlists = Arc::new(PlaylistRegistry::new(client.clone(), config.clone()));
+        playlists.refresh_due().await;
+        Arc::new(DvrManager::new(client, config, playlists).await)
+    }
+
+    async fn channel(manager: &DvrManager, name: &str) -> Channel {
+        manager.playlists.channels().await.into_iter().find(|c| c.name == name).unwrap()
+    }
+
+    async fn finished(manager: &DvrManager, id: &str) -> Recording {
+        for _ in 0..200 {
+            let recording = manager.recordings.read().await.get(id).cloned().unwrap();
+            if !recording.is_active() {
+                return recording;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("recording {} never finished", id);
+    }
+
+    #[test]
+    fn playlists_and_extensions() {
+        let mut rec = recording("rec_1", RecordingStatus::Recording, 0, &[100, 200]);
+        rec.init_segment = Some("init.mp4".to_string());
+        rec.init_bytes = 50;
+        assert_eq!(rec.bytes(), 350);
+        assert_eq!(rec.duration_secs(), 12.0);
+
+        let live = rec.playlist();
+        assert!(live.contains("#EXT-X-PLAYLIST-TYPE:EVENT\n"));
+        assert!(live.contains("#EXT-X-MAP:URI=\"init.mp4\"\n"));
+        assert!(live.contains("#EXTINF:6.000,\nseg_00000001.ts\n"));
+        assert!(!live.contains("#EXT-X-ENDLIST"));
+
+        rec.status = RecordingStatus::Completed;
+        let vod = rec.playlist();
+        assert!(vod.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+        assert!(vod.ends_with("#EXT-X-ENDLIST\n"));
+
+        let url = |u: &str| Url::parse(u).unwrap();
+        assert_eq!(extension(&url("http://cdn.example/live/seg12.TS?token=1"), "ts"), "ts");
+        assert_eq!(extension(&url("http://cdn.example/live/init.m4s"), "mp4"), "m4s");
+        assert_eq!(extension(&url("http://cdn.example/live/segment"), "ts"), "ts");
+        assert_eq!(extension(&url("http://cdn.example/live/seg.toolong"), "ts"), "ts");
+        assert_eq!(extension(&url("http://cdn.example/live/seg.t%2Fs"), "ts"), "ts");
+        assert_eq!(extension(&url("http://cdn.example/v1.2/"), "ts"), "ts");
+    }
+
+    #[tokio::test]
+    async fn restart_recovers_recordings() {
+        let dir = scratch("restart");
+        let recordings = dir.join("recordings");
+        for rec in [
+            recording("upcoming", RecordingStatus::Scheduled, 3600, &[]),
+            recording("missed", RecordingStatus::Scheduled, -3600, &[]),
+            recording("partial", RecordingStatus::Recording, -30, &[100]),
+            recording("empty", RecordingStatus::Recording, -30, &[]),
+            recording("done", RecordingStatus::Completed, -7200, &[100]),
+        ] {
+            std::fs::create_dir_all(recordings.join(&rec.id)).unwrap();
+            std::fs::write(recordings.join(&rec.id).join(METADATA_FILE), serde_json::to_string(&rec).unwrap()).unwrap();
+        }
+
+        let manager = manager(&dir, "http://127.0.0.1:9", |_| {}).await;
+        let status = |id: &str| {
+            let manager = manager.clone();
+            let id = id.to_string();
+            async move { manager.recordings.read().await.get(&id).map(|r| (r.status, r.error.clone())).unwrap() }
+        };
+        let interrupted = Some("Interrupted by server restart".to_string());
+        assert_eq!(status("upcoming").await, (RecordingStatus::Scheduled, None));
+        assert_eq!(status("missed").await, (RecordingStatus::Failed, interrupted.clone()));
+        assert_eq!(status("partial").await, (RecordingStatus::Completed, interrupted.clone()));
+        assert_eq!(status("empty").await, (RecordingStatus::Failed, interrupted));
+        assert_eq!(status("done").await, (RecordingStatus::Completed, None));
+
+        // What was captured before the restart is still playable, and the change is saved
+        let playlist = std::fs::read_to_string(recordings.join("partial").join(PLAYLIST_FILE)).unwrap();
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+        let saved: Recording = serde_json::from_str(&std::fs::read_to_string(recordings.join("empty").join(METADATA_FILE)).unwrap()).unwrap();
+        assert_eq!(saved.status, RecordingStatus::Failed);
+
+        // The upcoming recording's channel is gone, so it can't be resumed
+        manager.resume_scheduled().await;
+        assert_eq!(status("upcoming").await.0, RecordingStatus::Failed);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn capture_retries_until_the_stream_answers() {
+        let dir = scratch("retry");
+        let base = origin().await;
+        let manager = manager(&dir, &base, |config| config.dvr.max_failures = 4).await;
+
+        let flaky = channel(&manager, "Flaky").await;
+        let rec = manager.start(RecordRequest { channel_id: flaky.id, duration_secs: 60, starts_at: None }).await.unwrap();
+        let rec = finished(&manager, &rec.id).await;
+        assert_eq!(rec.status, RecordingStatus::Completed);
+        assert_eq!(rec.error, None);
+        // Joining a live stream starts at its newest segment
+        assert_eq!(rec.segments.len(), 1);
+        assert_eq!(rec.segments[0].file, "seg_00000008.ts");
+        assert_eq!(rec.bytes(), 100);
+        assert!(dir.join("recordings").join(&rec.id).join("seg_00000008.ts").exists());
+
+        let down = channel(&manager, "Down").await;
+        let rec = manager.start(RecordRequest { channel_id: down.id, duration_secs: 60, starts_at: None }).await.unwrap();
+        let rec = finished(&manager, &rec.id).await;
+        assert_eq!(rec.status, RecordingStatus::Failed);
+        assert!(rec.error.unwrap().contains("4 failures in a row"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn start_limits_the_lead_time() {
+        let dir = scratch("lead");
+        let manager = manager(&dir, "http://127.0.0.1:9", |config| config.dvr.max_lead_secs = 3600).await;
+        let flaky = channel(&manager, "Flaky").await;
+        let request = |secs: i64| RecordRequest {
+            channel_id: flaky.id.clone(),
+            duration_secs: 60,
+            starts_at: Some(Utc::now() + chrono::Duration::seconds(secs)),
+        };
+
+        let err = manager.start(request(7200)).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = manager.start(RecordRequest { starts_at: Some(DateTime::<Utc>::MAX_UTC), ..request(0) }).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let rec = manager.start(request(1800)).await.unwrap();
+        assert_eq!(rec.status, RecordingStatus::Scheduled);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn quota_counts_init_segments_and_drops_the_oldest() {
+        let dir = scratch("quota");
+        let manager = manager(&dir, "http://127.0.0.1:9", |config| config.dvr.quota_bytes = 1000).await;
+
+        let mut old = recording("old", RecordingStatus::Completed, -7200, &[300]);
+        old.init_bytes = 400;
+        let newer = recording("newer", RecordingStatus::Completed, -3600, &[100]);
+        let active = recording("active", RecordingStatus::Recording, -10, &[150, 200]);
+        for rec in [old, newer, active] {
+            std::fs::create_dir_all(manager.recording_dir(&rec.id)).unwrap();
+            manager.recordings.write().await.insert(rec.id.clone(), rec);
+        }
+
+        // 1050 bytes in use: the oldest goes, leaving room for another 200-byte segment
+        assert!(matches!(manager.ensure_quota("active").await, Ok(550)));
+        let ids: Vec<String> = manager.list().await.into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["active", "newer"]);
+        assert!(!manager.recording_dir("old").exists());
+
+        // Dropping the other finished recording still isn't enough for a 500-byte segment
+        manager.recordings.write().await.get_mut("active").unwrap().segments.push(RecordedSegment {
+            file: "seg_00000002.ts".to_string(),
+            duration: 6.0,
+            bytes: 500,
+        });
+        assert!(matches!(manager.ensure_quota("active").await, Err(Failure::Permanent(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_leave_nothing_behind() {
+        let dir = scratch("download");
+        let base = origin().await;
+        let manager = manager(&dir, &base, |_| {}).await;
+        let flaky = channel(&manager, "Flaky").await;
+        std::fs::create_dir_all(manager.recording_dir("rec")).unwrap();
+        let url = |path: &str| Url::parse(&format!("{}{}", base, path)).unwrap();
+
+        assert!(matches!(manager.download("rec", &flaky, &url("/seg/1.ts"), "ok.ts", 1000).await, Ok(100)));
+        assert!(manager.recording_dir("rec").join("ok.ts").exists());
+
+        // Past the room left under the quota
+        assert!(matches!(manager.download("rec", &flaky, &url("/seg/2.ts"), "big.ts", 50).await, Err(Failure::Permanent(_))));
+        assert!(!manager.recording_dir("rec").join("big.ts").exists());
+
+        assert!(matches!(manager.download("rec", &flaky, &url("/broken.ts"), "broken.ts", 1000).await, Err(Failure::Retry(_))));
+        assert!(!manager.recording_dir("rec").join("broken.ts").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,856 @@
+// EPUB READER - Verified EPUBs opened for the in-app reader: package, contents, sanitized chapters and resources
+use crate::config::{ConfigStore, EpubConfig};
+use crate::guard::{BodyKind, GuardedClient};
+use crate::sniff::MediaKind;
+use crate::{verify, AppState};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read, Seek};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use zip::ZipArchive;
+
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const OPS_NS: &str = "http://www.idpf.org/2007/ops";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+const XHTML_TYPE: &str = "application/xhtml+xml";
+// Sanitizing is the first line; the policy keeps anything that slipped through from running or calling out
+const CONTENT_POLICY: &str = "default-src 'none'; img-src 'self'; style-src 'self'; font-src 'self'";
+
+// Chapter markup we pass through; anything else is unwrapped to its children
+const ELEMENTS: &[&str] = &[
+    "html", "head", "title", "body", "div", "span", "p", "br", "hr", "h1", "h2", "h3", "h4", "h5", "h6", "a", "img", "ul", "ol", "li",
+    "dl", "dt", "dd", "blockquote", "pre", "code", "em", "strong", "i", "b", "u", "s", "sub", "sup", "small", "big", "cite", "q", "abbr",
+    "table", "thead", "tbody", "tfoot", "tr", "th", "td", "caption", "colgroup", "col", "figure", "figcaption", "section", "article",
+    "aside", "nav", "header", "footer", "main", "svg", "image",
+];
+// Dropped along with everything inside them
+const REMOVED: &[&str] = &[
+    "script", "style", "iframe", "frame", "frameset", "object", "embed", "applet", "form", "input", "button", "textarea", "select",
+    "noscript", "audio", "video", "canvas", "base", "meta", "math",
+];
+const VOID: &[&str] = &["br", "hr", "img", "col", "link", "image"];
+const ATTRIBUTES: &[&str] = &[
+    "id", "class", "title", "lang", "dir", "alt", "width", "height", "colspan", "rowspan", "span", "start", "reversed", "value", "abbr",
+    "scope", "headers", "viewBox", "preserveAspectRatio",
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EpubMetadata {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub identifier: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TocEntry {
+    pub title: String,
+    // Reader URL of the chapter, with the fragment the entry points at
+    pub href: Option<String>,
+    pub children: Vec<TocEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub index: usize,
+    pub href: String,
+    pub path: String,
+    // Non-linear items (notes, answer keys) are reached by links, not by paging
+    pub linear: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub url: String,
+    pub opened_at: DateTime<Utc>,
+    pub metadata: EpubMetadata,
+    pub cover: Option<String>,
+    pub toc: Vec<TocEntry>,
+    pub chapters: Vec<Chapter>,
+    // Every file in the manifest, by its path in the archive, with its media type
+    pub resources: BTreeMap<String, String>,
+}
+
+impl Package {
+    fn chapter_url(&self, path: &str, fragment: Option<&str>) -> Option<String> {
+        let url = match self.chapters.iter().find(|c| c.path == path) {
+            Some(chapter) => chapter.href.clone(),
+            None if self.resources.contains_key(path) => file_url(&self.id, path),
+            None => return None,
+        };
+        Some(match fragment {
+            Some(fragment) => format!("{}#{}", url, fragment),
+            None => url,
+        })
+    }
+}
+
+fn file_url(id: &str, path: &str) -> String {
+    let path: Vec<String> = path.split('/').map(|part| urlencoding::encode(part).into_owned()).collect();
+    format!("/api/epub/{}/files/{}", id, path.join("/"))
+}
+
+#[derive(Debug)]
+pub enum OpenError {
+    Unverified(String),
+    Fetch(String),
+    Invalid(String),
+    Io(String),
+}
+
+impl OpenError {
+    fn status(&self) -> StatusCode {
+        match self {
+            OpenError::Unverified(_) | OpenError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            OpenError::Fetch(_) => StatusCode::BAD_GATEWAY,
+            OpenError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(self) -> String {
+        match self {
+            OpenError::Unverified(e) => format!("EPUB failed verification: {}", e),
+            OpenError::Fetch(e) => format!("EPUB download failed: {}", e),
+            OpenError::Invalid(e) => format!("Not a readable EPUB: {}", e),
+            OpenError::Io(e) => e,
+        }
+    }
+}
+
+pub struct EpubLibrary {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+}
+
+impl EpubLibrary {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self { client, config }
+    }
+
+    fn settings(&self) -> EpubConfig {
+        self.config.snapshot().epub.clone()
+    }
+
+    fn dir(&self, id: &str) -> PathBuf {
+        self.settings().directory.join(id)
+    }
+
+    // Download, verify and parse a book once; later opens read the package from disk
+    pub async fn open(&self, url: &str) -> Result<Package, OpenError> {
+        let id = format!("{:x}", md5::compute(url.as_bytes()));
+        if let Ok(package) = self.package(&id).await {
+            return Ok(package);
+        }
+
+        let checksum_max_bytes = self.config.snapshot().verification.checksum_max_bytes;
+        let verification = verify::check_download(&self.client, url, &[MediaKind::Epub], checksum_max_bytes).await;
+        if !verification.ok {
+            let failure = verification.failure.map(|f| format!("{:?}", f)).unwrap_or_default();
+            return Err(OpenError::Unverified(format!("{} {}", failure, verification.error.unwrap_or_default()).trim().to_string()));
+        }
+
+        log!("📖 Opening EPUB {}", url);
+        let response = self.client.send(self.client.download(url)).await.map_err(|e| OpenError::Fetch(e.to_string()))?;
+        let response = response.error_for_status().map_err(|e| OpenError::Fetch(e.to_string()))?;
+        let bytes = self.client.read_body(response, BodyKind::Book).await.map_err(|e| OpenError::Fetch(e.to_string()))?;
+
+        let settings = self.settings();
+        let (package_id, source) = (id.clone(), url.to_string());
+        let max = settings.max_entry_bytes;
+        let (package, bytes) = tokio::task::spawn_blocking(move || {
+            let package = parse_package(&package_id, &source, &mut ZipArchive::new(Cursor::new(&bytes[..])).map_err(|e| e.to_string())?, max);
+            package.map(|package| (package, bytes))
+        })
+        .await
+        .map_err(|e| OpenError::Io(e.to_string()))?
+        .map_err(OpenError::Invalid)?;
+
+        let dir = self.dir(&id);
+        tokio::fs::create_dir_all(&dir).await.map_err(|e| OpenError::Io(e.to_string()))?;
+        let json = serde_json::to_vec_pretty(&package).map_err(|e| OpenError::Io(e.to_string()))?;
+        // Written aside and renamed so a reader never sees half a file
+        for (name, body) in [("book.epub", &bytes[..]), ("package.json", &json[..])] {
+            let partial = dir.join(format!("{}.partial", name));
+            tokio::fs::write(&partial, body).await.map_err(|e| OpenError::Io(e.to_string()))?;
+            tokio::fs::rename(&partial, dir.join(name)).await.map_err(|e| OpenError::Io(e.to_string()))?;
+        }
+        log!("✅ EPUB {} ready: {} chapters", id, package.chapters.len());
+
+        self.prune(&settings).await;
+        Ok(package)
+    }
+
+    // A book opened before; reading it keeps it from expiring
+    pub async fn package(&self, id: &str) -> Result<Package, String> {
+        if !is_id(id) {
+            return Err(format!("No open book {}", id));
+        }
+        let path = self.dir(id).join("package.json");
+        let raw = tokio::fs::read(&path).await.map_err(|_| format!("No open book {}", id))?;
+        if let Ok(file) = std::fs::File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        serde_json::from_slice(&raw).map_err(|e| e.to_string())
+    }
+
+    // One file from the archive, inflated within max_entry_bytes
+    pub async fn entry(&self, id: &str, path: &str) -> Result<Vec<u8>, String> {
+        let file = self.dir(id).join("book.epub");
+        let max = self.settings().max_entry_bytes;
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(file).map_err(|e| e.to_string())?;
+            let mut zip = ZipArchive::new(file).map_err(|e| e.to_string())?;
+            read_entry(&mut zip, &path, max)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    async fn prune(&self, settings: &EpubConfig) {
+        let Ok(mut entries) = tokio::fs::read_dir(&settings.directory).await else { return };
+        let retention = Duration::from_secs(settings.retention_secs);
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let marker = path.join("package.json");
+            let metadata = match tokio::fs::metadata(&marker).await {
+                Ok(metadata) => metadata,
+                Err(_) => match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                },
+            };
+            let age = metadata.modified().ok().and_then(|at| at.elapsed().ok()).unwrap_or_default();
+            if path.is_dir() && age > retention {
+                match tokio::fs::remove_dir_all(&path).await {
+                    Ok(()) => log!("🗑️  Removed unread EPUB {}", path.display()),
+                    Err(e) => eprintln!("❌ Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
+
+// Package ids are hex digests; anything else can't name a cache directory
+fn is_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn read_entry<R: Read + Seek>(zip: &mut ZipArchive<R>, path: &str, max: u64) -> Result<Vec<u8>, String> {
+    let entry = zip.by_name(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut body = Vec::new();
+    entry.take(max + 1).read_to_end(&mut body).map_err(|e| format!("{}: {}", path, e))?;
+    if body.len() as u64 > max {
+        return Err(format!("{} inflates past {} bytes", path, max));
+    }
+    Ok(body)
+}
+
+fn read_text<R: Read + Seek>(zip: &mut ZipArchive<R>, path: &str, max: u64) -> Result<String, String> {
+    read_entry(zip, path, max).map(|body| String::from_utf8_lossy(&body).into_owned())
+}
+
+fn dir_of(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+// A relative href inside the archive to the path it names; None for absolute URLs
+// and for paths climbing out of the archive
+fn join_path(dir: &str, href: &str) -> Option<String> {
+    let href = href.split('#').next()?;
+    let href = urlencoding::decode(href).ok()?;
+    if href.is_empty() || href.starts_with('/') || href.contains(':') {
+        return None;
+    }
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+fn fragment(href: &str) -> Option<&str> {
+    href.split_once('#').map(|(_, fragment)| fragment).filter(|f| !f.is_empty())
+}
+
+// XHTML often leans on HTML entities an XML parser doesn't know; common ones become
+// character references and the rest are escaped so the document still parses.
+// The DOCTYPE goes first: entities declared in it could expand without bound.
+fn prepare(xml: &str) -> String {
+    let stripped = strip_doctype(xml);
+    let xml = stripped.as_ref();
+    const ENTITIES: &[(&str, u32)] = &[
+        ("nbsp", 160), ("iexcl", 161), ("pound", 163), ("sect", 167), ("copy", 169), ("laquo", 171), ("shy", 173), ("reg", 174),
+        ("deg", 176), ("para", 182), ("middot", 183), ("raquo", 187), ("iquest", 191), ("agrave", 224), ("aacute", 225),
+        ("auml", 228), ("aelig", 230), ("ccedil", 231), ("egrave", 232), ("eacute", 233), ("ouml", 246), ("uuml", 252),
+        ("szlig", 223), ("times", 215), ("oelig", 339), ("ensp", 8194), ("emsp", 8195), ("thinsp", 8201), ("ndash", 8211),
+        ("mdash", 8212), ("lsquo", 8216), ("rsquo", 8217), ("ldquo", 8220), ("rdquo", 8221), ("dagger", 8224), ("hellip", 8230),
+        ("euro", 8364),
+    ];
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let name = rest[1..].split(';').next().filter(|n| n.len() <= 10 && rest[1..].contains(';'));
+        match name {
+            Some(name) if matches!(name, "amp" | "lt" | "gt" | "quot" | "apos") || char_ref(name) => out.push('&'),
+            Some(name) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                match ENTITIES.iter().find(|(entity, _)| *entity == name) {
+                    Some((_, code)) => out.push_str(&format!("&#{};", code)),
+                    None => out.push_str(&format!("&amp;{};", name)),
+                }
+                rest = &rest[name.len() + 2..];
+                continue;
+            }
+            _ => out.push_str("&amp;"),
+        }
+        rest = &rest[1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Drops `<!DOCTYPE ...>`, internal subset and all; quoted `>` and `]` don't end it
+fn strip_doctype(xml: &str) -> std::borrow::Cow<'_, str> {
+    let Some(start) = xml.find("<!DOCTYPE") else { return xml.into() };
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in xml[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, '>') if depth <= 0 => return format!("{}{}", &xml[..start], &xml[start + i + 1..]).into(),
+            _ => {}
+        }
+    }
+    // Unterminated: nothing after it can be trusted to be markup
+    xml[..start].to_string().into()
+}
+
+// `#233` or `#xE9` naming a character XML allows; anything else would fail the parse
+fn char_ref(name: &str) -> bool {
+    let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+    };
+    code.and_then(char::from_u32)
+        .is_some_and(|c| matches!(c, '\t' | '\n' | '\r') || (c >= ' ' && !matches!(c, '\u{FFFE}' | '\u{FFFF}')))
+}
+
+// Expects `prepare`d text; a DOCTYPE left in it is an error
+fn parse_xml(text: &str) -> Result<roxmltree::Document<'_>, String> {
+    roxmltree::Document::parse(text).map_err(|e| e.to_string())
+}
+
+fn named<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &str) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    let name = name.to_string();
+    node.descendants().filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn all_text(node: roxmltree::Node) -> String {
+    collapse(&node.descendants().filter(|n| n.is_text()).filter_map(|n| n.text()).collect::<String>())
+}
+
+struct ManifestItem {
+    id: String,
+    path: String,
+    media_type: String,
+    properties: String,
+}
+
+fn parse_package<R: Read + Seek>(id: &str, url: &str, zip: &mut ZipArchive<R>, max: u64) -> Result<Package, String> {
+    let container = prepare(&read_text(zip, "META-INF/container.xml", max)?);
+    let container = parse_xml(&container)?;
+    let opf_path = named(container.root(), "rootfile")
+        .find_map(|n| n.attribute("full-path"))
+        .ok_or("container.xml names no package")?
+        .to_string();
+    let opf = prepare(&read_text(zip, &opf_path, max)?);
+    let opf = parse_xml(&opf)?;
+    let root = opf.root_element();
+    let opf_dir = dir_of(&opf_path);
+
+    let dc = |name: &str| -> Vec<String> {
+        root.descendants()
+            .filter(|n| n.is_element() && n.tag_name().namespace() == Some(DC_NS) && n.tag_name().name() == name)
+            .map(all_text)
+            .filter(|t| !t.is_empty())
+            .collect()
+    };
+    let metadata = EpubMetadata {
+        title: dc("title").into_iter().next(),
+        authors: dc("creator"),
+        language: dc("language").into_iter().next(),
+        publisher: dc("publisher").into_iter().next(),
+        date: dc("date").into_iter().next(),
+        identifier: dc("identifier").into_iter().next(),
+        description: dc("description").into_iter().next(),
+        subjects: dc("subject"),
+    };
+
+    let manifest: Vec<ManifestItem> = named(root, "item")
+        .filter_map(|item| {
+            Some(ManifestItem {
+                id: item.attribute("id")?.to_string(),
+                path: join_path(opf_dir, item.attribute("href")?)?,
+                media_type: item.attribute("media-type").unwrap_or("application/octet-stream").to_string(),
+                properties: item.attribute("properties").unwrap_or("").to_string(),
+            })
+        })
+        .collect();
+    let by_id: HashMap<&str, &ManifestItem> = manifest.iter().map(|item| (item.id.as_str(), item)).collect();
+
+    let chapters: Vec<Chapter> = named(root, "itemref")
+        .filter_map(|itemref| by_id.get(itemref.attribute("idref")?).map(|item| (itemref, *item)))
+        .enumerate()
+        .map(|(index, (itemref, item))| Chapter {
+            index,
+            href: format!("/api/epub/{}/chapters/{}", id, index),
+            path: item.path.clone(),
+            linear: itemref.attribute("linear") != Some("no"),
+        })
+        .collect();
+    if chapters.is_empty() {
+        return Err("the package has no spine".to_string());
+    }
+
+    // EPUB 3 marks the cover image; EPUB 2 points at it from a meta element
+    let cover_meta = named(root, "meta").find(|m| m.attribute("name") == Some("cover")).and_then(|m| m.attribute("content"));
+    let cover = manifest
+        .iter()
+        .find(|item| item.properties.split_whitespace().any(|p| p == "cover-image"))
+        .or_else(|| cover_meta.and_then(|id| by_id.get(id).copied()))
+        .or_else(|| manifest.iter().find(|item| item.media_type.starts_with("image/") && item.id.to_ascii_lowercase().contains("cover")))
+        .filter(|item| item.media_type.starts_with("image/"))
+        .map(|item| file_url(id, &item.path));
+
+    let mut package = Package {
+        id: id.to_string(),
+        url: url.to_string(),
+        opened_at: Utc::now(),
+        metadata,
+        cover,
+        toc: vec![],
+        chapters,
+        resources: manifest.iter().map(|item| (item.path.clone(), item.media_type.clone())).collect(),
+    };
+
+    // The EPUB 3 navigation document, else the EPUB 2 NCX the spine names
+    let nav = manifest.iter().find(|item| item.properties.split_whitespace().any(|p| p == "nav"));
+    let ncx = named(root, "spine")
+        .find_map(|spine| spine.attribute("toc"))
+        .and_then(|toc| by_id.get(toc).copied())
+        .or_else(|| manifest.iter().find(|item| item.media_type == "application/x-dtbncx+xml"));
+    package.toc = match (nav, ncx) {
+        (Some(nav), _) => read_text(zip, &nav.path, max).and_then(|text| nav_toc(&package, &nav.path, &prepare(&text)))?,
+        (None, Some(ncx)) => read_text(zip, &ncx.path, max).and_then(|text| ncx_toc(&package, &ncx.path, &prepare(&text)))?,
+        (None, None) => vec![],
+    };
+    Ok(package)
+}
+
+fn toc_href(package: &Package, document: &str, href: Option<&str>) -> Option<String> {
+    let href = href?;
+    let path = join_path(dir_of(document), href)?;
+    package.chapter_url(&path, fragment(href))
+}
+
+fn nav_toc(package: &Package, path: &str, text: &str) -> Result<Vec<TocEntry>, String> {
+    let doc = parse_xml(text)?;
+    let navs: Vec<_> = named(doc.root(), "nav").collect();
+    let toc = navs
+        .iter()
+        .find(|nav| nav.attribute((OPS_NS, "type")).is_some_and(|t| t.split_whitespace().any(|t| t == "toc")))
+        .or(navs.first());
+    let Some(list) = toc.and_then(|nav| nav.children().find(|n| n.tag_name().name() == "ol")) else { return Ok(vec![]) };
+
+    fn items(package: &Package, path: &str, list: roxmltree::Node) -> Vec<TocEntry> {
+        list.children()
+            .filter(|n| n.tag_name().name() == "li")
+            .filter_map(|li| {
+                let label = li.children().find(|n| matches!(n.tag_name().name(), "a" | "span"))?;
+                Some(TocEntry {
+                    title: all_text(label),
+                    href: toc_href(package, path, label.attribute("href")),
+                    children: li.children().find(|n| n.tag_name().name() == "ol").map(|ol| items(package, path, ol)).unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+    Ok(items(package, path, list))
+}
+
+fn ncx_toc(package: &Package, path: &str, text: &str) -> Result<Vec<TocEntry>, String> {
+    let doc = parse_xml(text)?;
+    let Some(map) = named(doc.root(), "navMap").next() else { return Ok(vec![]) };
+
+    fn points(package: &Package, path: &str, parent: roxmltree::Node) -> Vec<TocEntry> {
+        parent
+            .children()
+            .filter(|n| n.tag_name().name() == "navPoint")
+            .map(|point| TocEntry {
+                title: point.children().find(|n| n.tag_name().name() == "navLabel").map(all_text).unwrap_or_default(),
+                href: toc_href(package, path, point.children().find(|n| n.tag_name().name() == "content").and_then(|c| c.attribute("src"))),
+                children: points(package, path, point),
+            })
+            .collect()
+    }
+    Ok(points(package, path, map))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Rebuilds a chapter from an allowlist of elements and attributes. Links, images and
+// stylesheets must name files in the package and are pointed back through this API;
+// scripts, forms, embeds, event handlers and inline styles don't survive.
+struct Sanitizer<'a> {
+    package: &'a Package,
+    dir: &'a str,
+    out: String,
+}
+
+impl Sanitizer<'_> {
+    fn resource(&self, href: &str, kind: &str) -> Option<String> {
+        let path = join_path(self.dir, href)?;
+        self.package.resources.get(&path).filter(|media_type| media_type.starts_with(kind))?;
+        Some(file_url(&self.package.id, &path))
+    }
+
+    fn link(&self, href: &str) -> Option<String> {
+        let href = href.trim();
+        let scheme = href.split_once(':').map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            _ if href.starts_with('#') => Some(href.to_string()),
+            Some("http") | Some("https") | Some("mailto") => Some(href.to_string()),
+            Some(_) => None,
+            None => self.package.chapter_url(&join_path(self.dir, href)?, fragment(href)),
+        }
+    }
+
+    fn node(&mut self, node: roxmltree::Node) {
+        if node.is_text() {
+            self.out.push_str(&escape(node.text().unwrap_or("")));
+            return;
+        }
+        if !node.is_element() {
+            return;
+        }
+        let tag = node.tag_name();
+        let name = tag.name();
+        let known = matches!(tag.namespace(), None | Some(XHTML_NS) | Some(SVG_NS));
+        if !known || REMOVED.contains(&name) {
+            return;
+        }
+        if name == "link" {
+            let stylesheet = node.attribute("rel").is_some_and(|rel| rel.to_ascii_lowercase().contains("stylesheet"));
+            if let Some(href) = node.attribute("href").filter(|_| stylesheet).and_then(|href| self.resource(href, "text/css")) {
+                self.out.push_str(&format!("<link rel=\"stylesheet\" type=\"text/css\" href=\"{}\"/>", escape(&href)));
+            }
+            return;
+        }
+        if !ELEMENTS.contains(&name) {
+            node.children().for_each(|child| self.node(child));
+            return;
+        }
+        // An image from outside the package would be a request to someone else's server
+        if name == "img" && node.attribute("src").and_then(|src| self.resource(src, "image/")).is_none() {
+            return;
+        }
+
+        self.out.push('<');
+        self.out.push_str(name);
+        match name {
+            "html" => self.out.push_str(&format!(" xmlns=\"{}\" xmlns:epub=\"{}\"", XHTML_NS, OPS_NS)),
+            "svg" => self.out.push_str(&format!(" xmlns=\"{}\" xmlns:xlink=\"{}\"", SVG_NS, XLINK_NS)),
+            _ => {}
+        }
+        for attribute in node.attributes() {
+            let value = match (attribute.namespace(), attribute.name()) {
+                (None, attr) if ATTRIBUTES.contains(&attr) => Some((attr.to_string(), attribute.value().to_string())),
+                (Some(XML_NS), "lang") => Some(("xml:lang".to_string(), attribute.value().to_string())),
+                (Some(OPS_NS), "type") => Some(("epub:type".to_string(), attribute.value().to_string())),
+                (None, "href") if name == "a" => self.link(attribute.value()).map(|href| ("href".to_string(), href)),
+                (None, "src") if name == "img" => self.resource(attribute.value(), "image/").map(|src| ("src".to_string(), src)),
+                (Some(XLINK_NS), "href") | (None, "href") if name == "image" => {
+                    self.resource(attribute.value(), "image/").map(|href| ("xlink:href".to_string(), href))
+                }
+                _ => None,
+            };
+            if let Some((attr, value)) = value {
+                self.out.push_str(&format!(" {}=\"{}\"", attr, escape(&value)));
+            }
+        }
+        if VOID.contains(&name) {
+            self.out.push_str("/>");
+            return;
+        }
+        self.out.push('>');
+        node.children().for_each(|child| self.node(child));
+        self.out.push_str(&format!("</{}>", name));
+    }
+}
+
+fn sanitize_xhtml(package: &Package, path: &str, raw: &[u8]) -> Result<String, String> {
+    let text = prepare(&String::from_utf8_lossy(raw));
+    let doc = parse_xml(&text)?;
+    let mut sanitizer = Sanitizer { package, dir: dir_of(path), out: String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n") };
+    sanitizer.node(doc.root_element());
+    Ok(sanitizer.out)
+}
+
+// Stylesheets keep their rules, but imports go and url()s must name files in the package
+fn sanitize_css(package: &Package, path: &str, raw: &[u8]) -> String {
+    let css = String::from_utf8_lossy(raw);
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css.as_ref();
+    while let Some(at) = rest.find("@import") {
+        out.push_str(&rest[..at]);
+        rest = rest[at..].split_once(';').map(|(_, after)| after).unwrap_or("");
+    }
+    out.push_str(rest);
+
+    let mut css = String::with_capacity(out.len());
+    let mut rest = out.as_str();
+    while let Some(at) = rest.find("url(") {
+        css.push_str(&rest[..at]);
+        let Some(end) = rest[at..].find(')').map(|end| at + end) else {
+            rest = "";
+            break;
+        };
+        let target = rest[at + 4..end].trim().trim_matches(|c| c == '"' || c == '\'');
+        let resolved = join_path(dir_of(path), target)
+            .filter(|p| package.resources.contains_key(p))
+            .map(|p| file_url(&package.id, &p))
+            .unwrap_or_default();
+        css.push_str(&format!("url(\"{}\")", resolved));
+        rest = &rest[end + 1..];
+    }
+    css.push_str(rest);
+    css
+}
+
+fn served(media_type: &str, body: impl IntoResponse) -> Response {
+    let headers = [
+        (header::CONTENT_TYPE, media_type.to_string()),
+        (header::CONTENT_SECURITY_POLICY, CONTENT_POLICY.to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ];
+    (headers, body).into_response()
+}
+
+async fn serve(state: &AppState, package: &Package, path: &str) -> Response {
+    let Some(media_type) = package.resources.get(path) else {
+        return (StatusCode::NOT_FOUND, format!("No file {} in the book", path)).into_response();
+    };
+    let kind = media_type.as_str();
+    let readable = kind == XHTML_TYPE || kind == "text/css" || kind.starts_with("image/") || kind.starts_with("font/") || kind.contains("font");
+    // SVG images can carry script; chapters embed them through the sanitized markup instead
+    if !readable || kind == "image/svg+xml" {
+        return (StatusCode::FORBIDDEN, format!("{} files aren't served", kind)).into_response();
+    }
+    let raw = match state.epub.entry(&package.id, path).await {
+        Ok(raw) => raw,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    };
+    match kind {
+        XHTML_TYPE => match sanitize_xhtml(package, path, &raw) {
+            Ok(xhtml) => served("application/xhtml+xml; charset=utf-8", xhtml),
+            Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{}: {}", path, e)).into_response(),
+        },
+        "text/css" => served("text/css; charset=utf-8", sanitize_css(package, path, &raw)),
+        _ => served(kind, raw),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OpenQuery {
+    url: Option<String>,
+    id: Option<String>, // an EPUB result from an earlier search
+}
+
+// GET /api/epub/open?url=|id= - verify, download and parse a book for reading
+pub async fn open_book(Query(params): Query<OpenQuery>, State(state): State<AppState>) -> Result<Json<Package>, (StatusCode, String)> {
+    let url = match (params.url, params.id) {
+        (Some(url), _) => url,
+        (None, Some(id)) => {
+            let cache = state.cache.read().await;
+            let content = cache.values().flatten().find(|c| c.id == id && c.quality == "EPUB");
+            content.map(|c| c.download_url.clone()).ok_or((StatusCode::NOT_FOUND, format!("No EPUB result {}", id)))?
+        }
+        (None, None) => return Err((StatusCode::BAD_REQUEST, "Pass url or id".to_string())),
+    };
+    state.epub.open(&url).await.map(Json).map_err(|e| (e.status(), e.message()))
+}
+
+// GET /api/epub/:id - metadata, cover, contents and chapters of an opened book
+pub async fn get_package(Path(id): Path<String>, State(state): State<AppState>) -> Result<Json<Package>, (StatusCode, String)> {
+    state.epub.package(&id).await.map(Json).map_err(|e| (StatusCode::NOT_FOUND, e))
+}
+
+// GET /api/epub/:id/chapters/:index - one spine item as sanitized XHTML
+pub async fn get_chapter(Path((id, index)): Path<(String, usize)>, State(state): State<AppState>) -> Response {
+    let package = match state.epub.package(&id).await {
+        Ok(package) => package,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+    match package.chapters.get(index) {
+        Some(chapter) => serve(&state, &package, &chapter.path.clone()).await,
+        None => (StatusCode::NOT_FOUND, format!("No chapter {}", index)).into_response(),
+    }
+}
+
+// GET /api/epub/:id/files/*path - images, stylesheets, fonts and linked documents
+pub async fn get_file(Path((id, path)): Path<(String, String)>, State(state): State<AppState>) -> Response {
+    match state.epub.package(&id).await {
+        Ok(package) => serve(&state, &package, &path).await,
+        Err(e) => (StatusCode::NOT_FOUND, e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package() -> Package {
+        let resources = [
+            ("OEBPS/text/ch1.xhtml", XHTML_TYPE),
+            ("OEBPS/text/ch2.xhtml", XHTML_TYPE),
+            ("OEBPS/images/plate.png", "image/png"),
+            ("OEBPS/style.css", "text/css"),
+        ];
+        Package {
+            id: "0123456789abcdef0123456789abcdef".to_string(),
+            url: "https://example.com/book.epub".to_string(),
+            opened_at: Utc::now(),
+            metadata: EpubMetadata::default(),
+            cover: None,
+            toc: vec![],
+            chapters: ["OEBPS/text/ch1.xhtml", "OEBPS/text/ch2.xhtml"]
+                .iter()
+                .enumerate()
+                .map(|(index, path)| Chapter { index, href: format!("/api/epub/x/chapters/{}", index), path: path.to_string(), linear: true })
+                .collect(),
+            resources: resources.iter().map(|(path, media_type)| (path.to_string(), media_type.to_string())).collect(),
+        }
+    }
+
+    fn sanitize(body: &str) -> String {
+        let xhtml = format!("<html xmlns=\"{}\"><body>{}</body></html>", XHTML_NS, body);
+        sanitize_xhtml(&package(), "OEBPS/text/ch1.xhtml", xhtml.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn script_links_and_handlers_are_dropped() {
+        let out = sanitize(concat!(
+            "<a href=\"javascript:alert(1)\">a</a>",
+            "<a href=\" JaVaScRiPt:alert(1)\">b</a>",
+            "<a href=\"jav&#x09;ascript:alert(1)\">c</a>",
+            "<a href=\"javascript%3Aalert(1)\">d</a>",
+            "<a href=\"data:text/html,x\">e</a>",
+            "<p onclick=\"alert(1)\" style=\"x\">f</p>",
+            "<script>alert(1)</script>",
+            "<a href=\"ch2.xhtml#n1\">g</a>",
+        ));
+        assert!(!out.to_ascii_lowercase().contains("script"), "{}", out);
+        assert!(!out.contains("data:") && !out.contains("onclick") && !out.contains("style="), "{}", out);
+        assert!(out.contains("<a>a</a>") && out.contains("<a>e</a>"), "{}", out);
+        assert!(out.contains("href=\"/api/epub/x/chapters/1#n1\""), "{}", out);
+    }
+
+    #[test]
+    fn resources_outside_the_package_are_refused() {
+        let out = sanitize(concat!(
+            "<img src=\"../images/plate.png\" alt=\"ok\"/>",
+            "<img src=\"../../../../etc/passwd\" alt=\"escape\"/>",
+            "<img src=\"https://tracker.example/pixel.png\" alt=\"remote\"/>",
+            "<img src=\"/OEBPS/images/plate.png\" alt=\"absolute\"/>",
+        ));
+        assert!(out.contains("alt=\"ok\""), "{}", out);
+        assert!(!out.contains("escape") && !out.contains("remote") && !out.contains("absolute"), "{}", out);
+
+        let css = sanitize_css(
+            &package(),
+            "OEBPS/style.css",
+            b"@import url(https://evil.example/x.css); body { background: url('../../secret.png') } p { background: url(images/plate.png) }",
+        );
+        assert!(!css.contains("evil") && !css.contains("secret"), "{}", css);
+        assert!(css.contains("url(\"/api/epub/0123456789abcdef0123456789abcdef/files/OEBPS/images/plate.png\")"), "{}", css);
+    }
+
+    #[test]
+    fn join_path_stays_inside_the_archive() {
+        assert_eq!(join_path("OEBPS/text", "../images/a.png").as_deref(), Some("OEBPS/images/a.png"));
+        assert_eq!(join_path("OEBPS/text", "./b%20c.xhtml#x").as_deref(), Some("OEBPS/text/b c.xhtml"));
+        assert_eq!(join_path("OEBPS", "../../etc/passwd"), None);
+        assert_eq!(join_path("", ".."), None);
+        assert_eq!(join_path("OEBPS", "%2E%2E/%2E%2E/etc/passwd"), None);
+        assert_eq!(join_path("OEBPS", "/etc/passwd"), None);
+        assert_eq!(join_path("OEBPS", "file:///etc/passwd"), None);
+        assert_eq!(join_path("OEBPS", "#only-a-fragment"), None);
+    }
+
+    #[test]
+    fn stray_entities_still_parse() {
+        for body in ["AT&T", "a &nbsp b", "trailing &amp", "&;", "&unknown;", "&#T;", "&#1;", "&#xD800;", "&#99999999999;", "&verylongentityname;"] {
+            let text = prepare(&format!("<p>{}</p>", body));
+            assert!(parse_xml(&text).is_ok(), "{:?} became {:?}", body, text);
+        }
+        assert_eq!(prepare("&nbsp;&eacute;&#233;&#xE9;&lt;"), "&#160;&#233;&#233;&#xE9;&lt;");
+        assert_eq!(prepare("&bogus; &#T; & end"), "&amp;bogus; &amp;#T; &amp; end");
+    }
+
+    #[test]
+    fn doctypes_are_dropped_before_parsing() {
+        let xhtml = r#"<?xml version="1.0"?><!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd"><html><p>Hi&nbsp;</p></html>"#;
+        let text = prepare(xhtml);
+        assert!(!text.contains("DOCTYPE"), "{}", text);
+        assert_eq!(parse_xml(&text).unwrap().root_element().tag_name().name(), "html");
+
+        // Billion laughs: each level multiplies the one below it by ten
+        let mut bomb = String::from("<?xml version=\"1.0\"?><!DOCTYPE html [<!ENTITY l0 \"lol\">");
+        for level in 1..10 {
+            bomb.push_str(&format!("<!ENTITY l{} \"{}\">", level, format!("&l{};", level - 1).repeat(10)));
+        }
+        bomb.push_str("<!ENTITY tricky \"]>\">]><html><p>&l9;</p></html>");
+        let text = prepare(&bomb);
+        let doc = parse_xml(&text).unwrap();
+        assert_eq!(all_text(doc.root_element()), "&l9;");
+
+        // A DOCTYPE that never ends takes the rest of the document with it
+        assert!(parse_xml(&prepare("<html><!DOCTYPE [ <p>x</p></html>")).is_err());
+        assert!(parse_xml("<!DOCTYPE html><html/>").is_err());
+    }
+}
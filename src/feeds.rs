@@ -0,0 +1,763 @@
+// OPDS FEEDS - Books from external OPDS 1.x (Atom) and 2.0 (JSON) catalogs listed in config
+use crate::config::{ConfigStore, OpdsFeed, OpdsFeedsConfig};
+use crate::guard::{BodyKind, GuardedClient};
+use crate::AppState;
+use axum::{
+    extract::{Query, State},
+    response::Json,
+};
+use futures_util::future::join_all;
+use reqwest::header::ACCEPT;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+const DCTERMS_NS: &str = "http://purl.org/dc/terms/";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const OPENSEARCH_NS: &str = "http://a9.com/-/spec/opensearch/1.1/";
+const ACCEPT_FEEDS: &str = "application/atom+xml, application/opds+json;q=0.9, application/json;q=0.8, */*;q=0.1";
+
+// Free downloads only; buy, borrow, subscribe and sample links are left alone
+const ACQUISITION_RELS: [&str; 2] = ["http://opds-spec.org/acquisition", "http://opds-spec.org/acquisition/open-access"];
+const COVER_RELS: [&str; 3] = ["http://opds-spec.org/image", "http://opds-spec.org/cover", "http://opds-spec.org/image/thumbnail"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedFile {
+    // EPUB, PDF, KINDLE, TXT or HTML
+    pub format: &'static str,
+    pub mime: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedEntry {
+    // Name of the feed in config
+    pub feed: String,
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: Vec<String>,
+    pub subjects: Vec<String>,
+    pub year: Option<i32>,
+    pub publisher: Option<String>,
+    pub isbn: Option<String>,
+    pub cover: Option<String>,
+    pub files: Vec<FeedFile>,
+}
+
+impl FeedEntry {
+    pub fn file(&self, format: &str) -> Option<&FeedFile> {
+        self.files.iter().find(|f| f.format == format)
+    }
+
+    // One file per format, the first the feed lists
+    fn add_file(&mut self, file: FeedFile) {
+        if self.file(file.format).is_none() {
+            self.files.push(file);
+        }
+    }
+}
+
+fn format(mime: &str) -> Option<&'static str> {
+    let mime = mime.to_ascii_lowercase();
+    match mime.split(';').next().unwrap_or("").trim() {
+        "application/epub+zip" => Some("EPUB"),
+        "application/pdf" => Some("PDF"),
+        "application/x-mobipocket-ebook" | "application/x-mobi8-ebook" | "application/vnd.amazon.ebook" => Some("KINDLE"),
+        "text/plain" => Some("TXT"),
+        "text/html" | "application/xhtml+xml" => Some("HTML"),
+        _ => None,
+    }
+}
+
+// Templates are expanded before they're resolved, since resolving would encode the braces
+#[derive(Debug, Clone)]
+struct SearchTemplate {
+    template: String,
+    base: Url,
+}
+
+impl SearchTemplate {
+    // Fills OpenSearch {searchTerms} and OPDS 2.0 {?query} with the query; other
+    // parameters are optional in both and dropped
+    fn expand(&self, query: &str) -> Option<String> {
+        let encoded = urlencoding::encode(query);
+        let mut url = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            url.push_str(&rest[..start]);
+            let end = rest[start..].find('}').map(|e| start + e)?;
+            let expression = &rest[start + 1..end];
+            if expression == "searchTerms" {
+                url.push_str(&encoded);
+            } else if let Some(names) = expression.strip_prefix('?').or_else(|| expression.strip_prefix('&')) {
+                if names.split(',').any(|name| name == "query") {
+                    let separator = if expression.starts_with('?') { '?' } else { '&' };
+                    url.push_str(&format!("{}query={}", separator, encoded));
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        url.push_str(rest);
+        self.base.join(&url).ok().map(String::from)
+    }
+}
+
+enum SearchLink {
+    Template(SearchTemplate),
+    // An OpenSearch description holding the template
+    Description(String),
+}
+
+#[derive(Default)]
+struct Page {
+    entries: Vec<FeedEntry>,
+    navigation: Vec<String>,
+    next: Option<String>,
+    search: Option<SearchLink>,
+}
+
+struct Discovered {
+    at: Instant,
+    search: Option<SearchTemplate>,
+    // Every entry the crawl reached, for feeds without search
+    crawled: Vec<FeedEntry>,
+}
+
+pub struct OpdsFeeds {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    // Keyed by feed URL
+    discovered: Mutex<HashMap<String, Arc<Discovered>>>,
+}
+
+impl OpdsFeeds {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self { client, config, discovered: Mutex::new(HashMap::new()) }
+    }
+
+    // Search every enabled feed at once; a feed that fails is logged and skipped
+    pub async fn search(&self, query: &str, limit: usize) -> Vec<FeedEntry> {
+        let settings = self.config.snapshot().opds_feeds.clone();
+        if query.trim().is_empty() || limit == 0 {
+            return vec![];
+        }
+        let feeds = settings.feeds.iter().filter(|feed| feed.enabled);
+        let settings = &settings;
+        let found = join_all(feeds.map(|feed| async move { (feed, self.search_feed(settings, feed, query.trim(), limit).await) })).await;
+
+        let mut entries = Vec::new();
+        for (feed, result) in found {
+            match result {
+                Ok(found) => entries.extend(found),
+                Err(e) => eprintln!("❌ OPDS feed {} failed: {}", feed.name, e),
+            }
+        }
+        entries
+    }
+
+    async fn search_feed(&self, settings: &OpdsFeedsConfig, feed: &OpdsFeed, query: &str, limit: usize) -> Result<Vec<FeedEntry>, String> {
+        let discovered = self.discover(settings, feed).await?;
+        let Some(template) = &discovered.search else {
+            let words = tokenize(query);
+            return Ok(discovered.crawled.iter().filter(|entry| matches(entry, &words)).take(limit).cloned().collect());
+        };
+
+        let mut entries = Vec::new();
+        let mut next = template.expand(query);
+        let mut pages = 0;
+        while let Some(url) = next.take() {
+            if pages >= settings.max_pages || entries.len() >= limit {
+                break;
+            }
+            pages += 1;
+            let page = self.fetch(&feed.name, &url).await?;
+            entries.extend(page.entries);
+            next = page.next;
+        }
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    // Find the feed's search template, or crawl it when it has none
+    async fn discover(&self, settings: &OpdsFeedsConfig, feed: &OpdsFeed) -> Result<Arc<Discovered>, String> {
+        let ttl = Duration::from_secs(settings.cache_secs);
+        if let Some(discovered) = self.discovered.lock().await.get(&feed.url) {
+            if discovered.at.elapsed() < ttl {
+                return Ok(discovered.clone());
+            }
+        }
+
+        let mut root = self.fetch(&feed.name, &feed.url).await?;
+        let search = match root.search.take() {
+            Some(SearchLink::Template(template)) => Some(template),
+            Some(SearchLink::Description(url)) => match self.description(&url).await {
+                Ok(template) => template,
+                Err(e) => {
+                    eprintln!("❌ OPDS feed {} search description failed: {}", feed.name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let crawled = if search.is_none() { self.crawl(settings, feed, root).await } else { vec![] };
+        log!(
+            "📚 OPDS feed {}: {}",
+            feed.name,
+            if search.is_some() { "searchable".to_string() } else { format!("crawled {} entries", crawled.len()) }
+        );
+
+        let discovered = Arc::new(Discovered { at: Instant::now(), search, crawled });
+        self.discovered.lock().await.insert(feed.url.clone(), discovered.clone());
+        Ok(discovered)
+    }
+
+    // Breadth-first through navigation and next links on the feed's own host
+    async fn crawl(&self, settings: &OpdsFeedsConfig, feed: &OpdsFeed, root: Page) -> Vec<FeedEntry> {
+        let host = Url::parse(&feed.url).ok().and_then(|url| url.host_str().map(str::to_string));
+        let mut visited = HashSet::from([feed.url.clone()]);
+        let mut queue: VecDeque<String> = root.next.into_iter().chain(root.navigation).collect();
+        let mut entries = root.entries;
+        let mut pages = 1;
+
+        while let Some(url) = queue.pop_front() {
+            if pages >= settings.max_pages {
+                break;
+            }
+            let same_host = Url::parse(&url).ok().and_then(|u| u.host_str().map(str::to_string)) == host;
+            if !same_host || !visited.insert(url.clone()) {
+                continue;
+            }
+            pages += 1;
+            match self.fetch(&feed.name, &url).await {
+                Ok(page) => {
+                    entries.extend(page.entries);
+                    queue.extend(page.next.into_iter().chain(page.navigation));
+                }
+                Err(e) => eprintln!("❌ OPDS feed {} page {} failed: {}", feed.name, url, e),
+            }
+        }
+
+        let mut seen = HashSet::new();
+        entries.retain(|entry| seen.insert(entry.id.clone()));
+        entries
+    }
+
+    async fn get(&self, url: &str) -> Result<(Url, String), String> {
+        let request = self.client.get(url).header(ACCEPT, ACCEPT_FEEDS);
+        let response = self.client.send(request).await.map_err(|e| e.to_string())?;
+        let response = response.error_for_status().map_err(|e| e.to_string())?;
+        let base = response.url().clone();
+        let body = self.client.read_text(response, BodyKind::Api).await.map_err(|e| e.to_string())?;
+        Ok((base, body))
+    }
+
+    async fn fetch(&self, feed: &str, url: &str) -> Result<Page, String> {
+        let (base, body) = self.get(url).await?;
+        if body.trim_start().starts_with('{') {
+            parse_json(feed, &base, &body)
+        } else {
+            parse_atom(feed, &base, &body)
+        }
+    }
+
+    // The OpenSearch URL that returns a feed, preferring Atom
+    async fn description(&self, url: &str) -> Result<Option<SearchTemplate>, String> {
+        let (base, body) = self.get(url).await?;
+        let doc = roxmltree::Document::parse(&body).map_err(|e| e.to_string())?;
+        let urls: Vec<(String, String)> = doc
+            .descendants()
+            .filter(|n| is(n, OPENSEARCH_NS, "Url"))
+            .filter_map(|n| Some((n.attribute("type").unwrap_or("").to_string(), n.attribute("template")?.to_string())))
+            .collect();
+        let template = ["atom+xml", "opds+json"]
+            .iter()
+            .find_map(|kind| urls.iter().find(|(t, _)| t.contains(kind)))
+            .map(|(_, template)| SearchTemplate { template: template.clone(), base });
+        Ok(template)
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// Every query word appears in the title or an author
+fn matches(entry: &FeedEntry, words: &[String]) -> bool {
+    let text: HashSet<String> = tokenize(&entry.title).into_iter().chain(entry.authors.iter().flat_map(|a| tokenize(a))).collect();
+    !words.is_empty() && words.iter().all(|w| text.contains(w))
+}
+
+fn year(date: &str) -> Option<i32> {
+    date.trim().get(..4)?.parse().ok()
+}
+
+fn resolve(base: &Url, href: &str) -> Option<String> {
+    base.join(href).ok().map(String::from)
+}
+
+fn is(node: &roxmltree::Node, ns: &str, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(ns) && node.tag_name().name() == name
+}
+
+fn children<'a, 'input>(node: roxmltree::Node<'a, 'input>, ns: &str, name: &str) -> Vec<roxmltree::Node<'a, 'input>> {
+    node.children().filter(|n| is(n, ns, name)).collect()
+}
+
+fn text(node: roxmltree::Node) -> Option<String> {
+    node.text().map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+// Dublin Core values, whether a feed uses the terms or the older elements namespace
+fn dc(node: roxmltree::Node, name: &str) -> Vec<String> {
+    children(node, DCTERMS_NS, name).into_iter().chain(children(node, DC_NS, name)).filter_map(text).collect()
+}
+
+fn parse_atom(feed: &str, base: &Url, body: &str) -> Result<Page, String> {
+    let doc = roxmltree::Document::parse(body).map_err(|e| e.to_string())?;
+    let root = doc.root_element();
+    if !is(&root, ATOM_NS, "feed") {
+        return Err("not an OPDS feed".to_string());
+    }
+
+    let mut page = Page::default();
+    for link in children(root, ATOM_NS, "link") {
+        let (Some(rel), Some(href)) = (link.attribute("rel"), link.attribute("href")) else { continue };
+        let kind = link.attribute("type").unwrap_or("");
+        match rel {
+            "next" => page.next = resolve(base, href),
+            "search" if kind.contains("opensearchdescription") => page.search = resolve(base, href).map(SearchLink::Description),
+            "search" if href.contains("{searchTerms}") => {
+                page.search = Some(SearchLink::Template(SearchTemplate { template: href.to_string(), base: base.clone() }))
+            }
+            _ => {}
+        }
+    }
+
+    for node in children(root, ATOM_NS, "entry") {
+        let mut entry = FeedEntry {
+            feed: feed.to_string(),
+            id: children(node, ATOM_NS, "id").into_iter().find_map(text).unwrap_or_default(),
+            title: children(node, ATOM_NS, "title").into_iter().find_map(text).unwrap_or_default(),
+            authors: children(node, ATOM_NS, "author")
+                .into_iter()
+                .flat_map(|author| children(author, ATOM_NS, "name"))
+                .filter_map(text)
+                .collect(),
+            language: dc(node, "language"),
+            subjects: children(node, ATOM_NS, "category")
+                .into_iter()
+                .filter_map(|c| c.attribute("label").or_else(|| c.attribute("term")).map(str::to_string))
+                .collect(),
+            year: dc(node, "issued").first().and_then(|d| year(d)).or_else(|| children(node, ATOM_NS, "published").into_iter().find_map(text).and_then(|d| year(&d))),
+            publisher: dc(node, "publisher").into_iter().next(),
+            isbn: dc(node, "identifier").iter().find_map(|id| id.strip_prefix("urn:isbn:").map(str::to_string)),
+            cover: None,
+            files: vec![],
+        };
+
+        let mut navigation = None;
+        for link in children(node, ATOM_NS, "link") {
+            let Some(href) = link.attribute("href").and_then(|href| resolve(base, href)) else { continue };
+            let rel = link.attribute("rel").unwrap_or("");
+            let kind = link.attribute("type").unwrap_or("");
+            if ACQUISITION_RELS.contains(&rel) {
+                if let Some(format) = format(kind) {
+                    entry.add_file(FeedFile { format, mime: kind.to_string(), url: href });
+                }
+            } else if COVER_RELS.contains(&rel) {
+                entry.cover.get_or_insert(href);
+            } else if kind.contains("profile=opds-catalog") && !matches!(rel, "self" | "start" | "up" | "alternate") {
+                navigation.get_or_insert(href);
+            }
+        }
+
+        if !entry.files.is_empty() {
+            if entry.id.is_empty() {
+                entry.id = entry.files[0].url.clone();
+            }
+            page.entries.push(entry);
+        } else if let Some(href) = navigation {
+            page.navigation.push(href);
+        }
+    }
+    Ok(page)
+}
+
+// OPDS 2.0 metadata values: a string, a language map, an object with a name, or a list of those
+fn names(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(values) => values.iter().flat_map(names).collect(),
+        Value::Object(object) => match object.get("name") {
+            Some(name) => names(name).into_iter().take(1).collect(),
+            None => object.values().filter_map(|v| v.as_str().map(str::to_string)).take(1).collect(),
+        },
+        _ => vec![],
+    }
+}
+
+fn rels(link: &Value) -> Vec<String> {
+    names(&link["rel"])
+}
+
+fn parse_json(feed: &str, base: &Url, body: &str) -> Result<Page, String> {
+    let doc: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let links = |value: &Value| value.as_array().cloned().unwrap_or_default();
+
+    let mut page = Page::default();
+    for link in links(&doc["links"]) {
+        let Some(href) = link["href"].as_str() else { continue };
+        let rels = rels(&link);
+        if rels.iter().any(|r| r == "next") {
+            page.next = resolve(base, href);
+        } else if rels.iter().any(|r| r == "search") && link["templated"].as_bool() == Some(true) {
+            page.search = Some(SearchLink::Template(SearchTemplate { template: href.to_string(), base: base.clone() }));
+        }
+    }
+
+    let groups = links(&doc["groups"]);
+    let sections = std::iter::once(&doc).chain(groups.iter());
+    for section in sections {
+        for link in links(&section["navigation"]) {
+            if let Some(href) = link["href"].as_str().and_then(|href| resolve(base, href)) {
+                page.navigation.push(href);
+            }
+        }
+        for publication in links(&section["publications"]) {
+            if let Some(entry) = publication_entry(feed, base, &publication) {
+                page.entries.push(entry);
+            }
+        }
+    }
+    Ok(page)
+}
+
+fn publication_entry(feed: &str, base: &Url, publication: &Value) -> Option<FeedEntry> {
+    let metadata = &publication["metadata"];
+    let identifier = metadata["identifier"].as_str().unwrap_or("").to_string();
+    let mut entry = FeedEntry {
+        feed: feed.to_string(),
+        isbn: identifier.strip_prefix("urn:isbn:").map(str::to_string),
+        id: identifier,
+        title: names(&metadata["title"]).into_iter().next()?,
+        authors: names(&metadata["author"]),
+        language: names(&metadata["language"]),
+        subjects: names(&metadata["subject"]),
+        year: metadata["published"].as_str().and_then(year),
+        publisher: names(&metadata["publisher"]).into_iter().next(),
+        cover: publication["images"].as_array().and_then(|images| images.first()).and_then(|i| i["href"].as_str()).and_then(|href| resolve(base, href)),
+        files: vec![],
+    };
+    for link in publication["links"].as_array().into_iter().flatten() {
+        if !rels(link).iter().any(|r| ACQUISITION_RELS.contains(&r.as_str())) {
+            continue;
+        }
+        let mime = link["type"].as_str().unwrap_or("");
+        if let (Some(format), Some(url)) = (format(mime), link["href"].as_str().and_then(|href| resolve(base, href))) {
+            entry.add_file(FeedFile { format, mime: mime.to_string(), url });
+        }
+    }
+    if entry.files.is_empty() {
+        return None;
+    }
+    if entry.id.is_empty() {
+        entry.id = entry.files[0].url.clone();
+    }
+    Some(entry)
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    q: String,
+    limit: Option<usize>, // per feed
+}
+
+// GET /api/opds/search - matching entries from each configured feed, with files and covers
+pub async fn search_feeds(Query(params): Query<FeedQuery>, State(state): State<AppState>) -> Json<Vec<FeedEntry>> {
+    Json(state.feeds.search(&params.q, params.limit.unwrap_or(10)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::extract::{Path, State as Shared};
+    use axum::http::Uri;
+    use axum::routing::get;
+    use axum::Router;
+
+    fn base() -> Url {
+        Url::parse("https://books.example/opds/root.xml").unwrap()
+    }
+
+    fn atom_feed(links: &str, entries: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/">
+  <id>urn:test</id><title>Test</title>
+  {links}
+  {entries}
+</feed>"#
+        )
+    }
+
+    fn atom_book(id: &str, title: &str) -> String {
+        format!(
+            r#"<entry><id>{id}</id><title>{title}</title><author><name>Jane Austen</name></author>
+<link rel="http://opds-spec.org/acquisition/open-access" href="/books/{id}.epub" type="application/epub+zip"/></entry>"#
+        )
+    }
+
+    #[test]
+    fn atom_entries() {
+        let body = atom_feed(
+            r#"<link rel="next" href="page2.xml" type="application/atom+xml;profile=opds-catalog"/>
+<link rel="search" href="/opensearch.xml" type="application/opensearchdescription+xml"/>"#,
+            r#"<entry>
+  <id>urn:uuid:1</id><title>Pride and Prejudice</title>
+  <author><name>Jane Austen</name></author><author><name>Editor</name></author>
+  <dc:language>en</dc:language><dc:issued>1813-01-28</dc:issued><dc:publisher>Egerton</dc:publisher>
+  <dc:identifier>urn:isbn:9780141439518</dc:identifier>
+  <category term="FIC" label="Fiction"/><category term="Romance"/>
+  <link rel="http://opds-spec.org/image/thumbnail" href="/covers/1-small.jpg" type="image/jpeg"/>
+  <link rel="http://opds-spec.org/image" href="/covers/1.jpg" type="image/jpeg"/>
+  <link rel="http://opds-spec.org/acquisition/buy" href="/buy/1.epub" type="application/epub+zip"/>
+  <link rel="http://opds-spec.org/acquisition/borrow" href="/borrow/1.pdf" type="application/pdf"/>
+  <link rel="http://opds-spec.org/acquisition/open-access" href="/books/1.epub" type="application/epub+zip"/>
+  <link rel="http://opds-spec.org/acquisition" href="/books/1-other.epub" type="application/epub+zip"/>
+  <link rel="http://opds-spec.org/acquisition" href="https://cdn.example/1.pdf" type="application/pdf"/>
+  <link rel="http://opds-spec.org/acquisition" href="/books/1.cbz" type="application/x-cbz"/>
+</entry>
+<entry>
+  <id>urn:uuid:2</id><title>For Sale Only</title>
+  <link rel="http://opds-spec.org/acquisition/buy" href="/buy/2.epub" type="application/epub+zip"/>
+</entry>
+<entry>
+  <title>Fiction</title>
+  <link rel="subsection" href="/opds/fiction.xml" type="application/atom+xml;profile=opds-catalog;kind=acquisition"/>
+</entry>
+<entry>
+  <title>No id</title>
+  <link rel="http://opds-spec.org/acquisition" href="/books/3.txt" type="text/plain; charset=utf-8"/>
+</entry>"#,
+        );
+        let page = parse_atom("Test Feed", &base(), &body).unwrap();
+        assert_eq!(page.next.as_deref(), Some("https://books.example/opds/page2.xml"));
+        assert!(matches!(&page.search, Some(SearchLink::Description(url)) if url == "https://books.example/opensearch.xml"));
+        assert_eq!(page.navigation, vec!["https://books.example/opds/fiction.xml"]);
+
+        assert_eq!(page.entries.len(), 2);
+        let book = &page.entries[0];
+        assert_eq!(book.feed, "Test Feed");
+        assert_eq!(book.id, "urn:uuid:1");
+        assert_eq!(book.authors, vec!["Jane Austen", "Editor"]);
+        assert_eq!(book.language, vec!["en"]);
+        assert_eq!(book.subjects, vec!["Fiction", "Romance"]);
+        assert_eq!(book.year, Some(1813));
+        assert_eq!(book.publisher.as_deref(), Some("Egerton"));
+        assert_eq!(book.isbn.as_deref(), Some("9780141439518"));
+        assert_eq!(book.cover.as_deref(), Some("https://books.example/covers/1-small.jpg"));
+        // Buy and borrow links aren't downloads; the first free file of each format is kept
+        let files: Vec<(&str, &str)> = book.files.iter().map(|f| (f.format, f.url.as_str())).collect();
+        assert_eq!(files, vec![("EPUB", "https://books.example/books/1.epub"), ("PDF", "https://cdn.example/1.pdf")]);
+
+        assert_eq!(page.entries[1].id, "https://books.example/books/3.txt");
+        assert_eq!(page.entries[1].files[0].format, "TXT");
+
+        let templated = atom_feed(r#"<link rel="search" href="/search?q={searchTerms}" type="application/atom+xml"/>"#, "");
+        let page = parse_atom("Test", &base(), &templated).unwrap();
+        assert!(matches!(page.search, Some(SearchLink::Template(t)) if t.template == "/search?q={searchTerms}"));
+
+        assert!(parse_atom("Test", &base(), "<rss/>").is_err());
+        assert!(parse_atom("Test", &base(), "not xml").is_err());
+    }
+
+    #[test]
+    fn json_entries() {
+        let body = r#"{
+  "metadata": { "title": "Test" },
+  "links": [
+    { "rel": "self", "href": "/opds2/root.json", "type": "application/opds+json" },
+    { "rel": ["next"], "href": "page2.json" },
+    { "rel": "search", "href": "/opds2/search{?query,page}", "templated": true }
+  ],
+  "navigation": [{ "title": "Fiction", "href": "/opds2/fiction.json" }],
+  "publications": [
+    {
+      "metadata": {
+        "identifier": "urn:isbn:9780141439518",
+        "title": { "en": "Emma", "fr": "Emma" },
+        "author": [{ "name": "Jane Austen" }, "Another Author"],
+        "language": "en",
+        "subject": [{ "name": "Fiction", "code": "FIC" }],
+        "published": "1815-12-23",
+        "publisher": { "name": { "en": "John Murray" } }
+      },
+      "links": [
+        { "rel": "http://opds-spec.org/acquisition/buy", "href": "/buy/emma.epub", "type": "application/epub+zip" },
+        { "rel": "http://opds-spec.org/acquisition/open-access", "href": "/books/emma.epub", "type": "application/epub+zip" },
+        { "rel": ["http://opds-spec.org/acquisition"], "href": "/books/emma.azw3", "type": "application/x-mobi8-ebook" }
+      ],
+      "images": [{ "href": "/covers/emma.jpg", "type": "image/jpeg" }]
+    },
+    {
+      "metadata": { "title": "Sample only" },
+      "links": [{ "rel": "http://opds-spec.org/acquisition/sample", "href": "/sample.epub", "type": "application/epub+zip" }]
+    }
+  ],
+  "groups": [
+    {
+      "navigation": [{ "href": "/opds2/new.json" }],
+      "publications": [
+        { "metadata": { "title": "Persuasion" }, "links": [{ "rel": "http://opds-spec.org/acquisition", "href": "/books/persuasion.txt", "type": "text/plain" }] }
+      ]
+    }
+  ]
+}"#;
+        let page = parse_json("Test Feed", &base(), body).unwrap();
+        assert_eq!(page.next.as_deref(), Some("https://books.example/opds/page2.json"));
+        assert!(matches!(&page.search, Some(SearchLink::Template(t)) if t.template == "/opds2/search{?query,page}"));
+        assert_eq!(page.navigation, vec!["https://books.example/opds2/fiction.json", "https://books.example/opds2/new.json"]);
+
+        let titles: Vec<&str> = page.entries.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, vec!["Emma", "Persuasion"]);
+        let emma = &page.entries[0];
+        assert_eq!(emma.id, "urn:isbn:9780141439518");
+        assert_eq!(emma.isbn.as_deref(), Some("9780141439518"));
+        assert_eq!(emma.authors, vec!["Jane Austen", "Another Author"]);
+        assert_eq!(emma.subjects, vec!["Fiction"]);
+        assert_eq!(emma.year, Some(1815));
+        assert_eq!(emma.publisher.as_deref(), Some("John Murray"));
+        assert_eq!(emma.cover.as_deref(), Some("https://books.example/covers/emma.jpg"));
+        let files: Vec<(&str, &str)> = emma.files.iter().map(|f| (f.format, f.url.as_str())).collect();
+        assert_eq!(files, vec![("EPUB", "https://books.example/books/emma.epub"), ("KINDLE", "https://books.example/books/emma.azw3")]);
+        assert_eq!(page.entries[1].id, "https://books.example/books/persuasion.txt");
+
+        assert!(parse_json("Test", &base(), "{").is_err());
+    }
+
+    #[test]
+    fn search_templates() {
+        let template = |t: &str| SearchTemplate { template: t.to_string(), base: base() };
+        assert_eq!(
+            template("/search?q={searchTerms}&page={startPage?}").expand("pride & prejudice").as_deref(),
+            Some("https://books.example/search?q=pride%20%26%20prejudice&page=")
+        );
+        assert_eq!(template("search{?query}").expand("emma").as_deref(), Some("https://books.example/opds/search?query=emma"));
+        assert_eq!(template("/search{?query,page}").expand("emma").as_deref(), Some("https://books.example/search?query=emma"));
+        assert_eq!(template("/search?lang=en{&query}").expand("emma").as_deref(), Some("https://books.example/search?lang=en&query=emma"));
+        // Templates without the query's parameter just drop the expression
+        assert_eq!(template("/search{?page}").expand("emma").as_deref(), Some("https://books.example/search"));
+        assert_eq!(template("https://other.example/s?q={searchTerms}").expand("a/b").as_deref(), Some("https://other.example/s?q=a%2Fb"));
+        assert_eq!(template("/search?q={searchTerms").expand("emma"), None);
+    }
+
+    // A stand-in catalog: /root links to five sections and an off-host page; every request is logged
+    async fn catalog() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base = format!("http://127.0.0.1:{}", port);
+
+        let section = |href: String| {
+            format!(r#"<entry><title>Section</title><link rel="subsection" href="{}" type="application/atom+xml;profile=opds-catalog"/></entry>"#, href)
+        };
+        let root = atom_feed(
+            "",
+            &(1..=5)
+                .map(|n| section(format!("/section/{}", n)))
+                .chain([section(format!("http://localhost:{}/section/9", port)), section("/section/1".to_string())])
+                .collect::<String>(),
+        );
+        let searchable = atom_feed(r#"<link rel="search" href="/opensearch.xml" type="application/opensearchdescription+xml"/>"#, "");
+        let description = r#"<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+<Url type="text/html" template="/html?q={searchTerms}"/>
+<Url type="application/atom+xml;profile=opds-catalog" template="/results?q={searchTerms}"/>
+</OpenSearchDescription>"#;
+
+        let app = Router::new()
+            .route("/root", get(move || async move { root }))
+            .route("/section/:n", get(|Path(n): Path<u32>| async move { atom_feed("", &atom_book(&format!("book{}", n), &format!("Book {}", n))) }))
+            .route("/searchable", get(move || async move { searchable }))
+            .route("/opensearch.xml", get(move || async move { description }))
+            .route(
+                "/results",
+                get(|| async { atom_feed(r#"<link rel="next" href="/results"/>"#, &(atom_book("hit1", "Emma") + &atom_book("hit2", "Emma Again"))) }),
+            )
+            .layer(axum::middleware::from_fn_with_state(requests.clone(), log_request));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base, requests)
+    }
+
+    async fn log_request(
+        Shared(requests): Shared<Arc<std::sync::Mutex<Vec<String>>>>,
+        uri: Uri,
+        request: axum::extract::Request,
+        next: axum::middleware::Next,
+    ) -> axum::response::Response {
+        requests.lock().unwrap().push(uri.to_string());
+        next.run(request).await
+    }
+
+    fn feeds(base: &str, path: &str, max_pages: usize) -> OpdsFeeds {
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string(), "localhost".to_string()];
+        config.opds_feeds = OpdsFeedsConfig {
+            feeds: vec![OpdsFeed { name: "Stand-in".to_string(), url: format!("{}{}", base, path), enabled: true }],
+            max_pages,
+            ..Default::default()
+        };
+        let config = Arc::new(ConfigStore::fixed(config));
+        OpdsFeeds::new(GuardedClient::new(config.clone()), config)
+    }
+
+    #[tokio::test]
+    async fn crawls_stay_on_host_and_stop_at_max_pages() {
+        let (base, requests) = catalog().await;
+        let opds = feeds(&base, "/root", 4);
+
+        let found = opds.search("book", 10).await;
+        let ids: Vec<&str> = found.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["book1", "book2", "book3"]);
+        assert_eq!(*requests.lock().unwrap(), vec!["/root", "/section/1", "/section/2", "/section/3"]);
+
+        // The crawl is kept, and searched by title and author words
+        assert_eq!(opds.search("book 2", 10).await.len(), 1);
+        assert_eq!(opds.search("austen", 2).await.len(), 2);
+        assert!(opds.search("tolstoy", 10).await.is_empty());
+        assert_eq!(requests.lock().unwrap().len(), 4);
+
+        // With room for every page, the off-host section and the repeated one are still skipped
+        requests.lock().unwrap().clear();
+        assert_eq!(feeds(&base, "/root", 100).search("book", 10).await.len(), 5);
+        let requested = requests.lock().unwrap().clone();
+        assert_eq!(requested.len(), 6);
+        assert!(!requested.contains(&"/section/9".to_string()));
+    }
+
+    #[tokio::test]
+    async fn searches_follow_the_opensearch_template() {
+        let (base, requests) = catalog().await;
+        let opds = feeds(&base, "/searchable", 2);
+
+        let found = opds.search(" emma ", 3).await;
+        let ids: Vec<&str> = found.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[..2], ["hit1", "hit2"]);
+        assert_eq!(found[0].files[0].url, format!("{}/books/hit1.epub", base));
+        // The Atom template, and next links only up to max_pages
+        assert_eq!(*requests.lock().unwrap(), vec!["/searchable", "/opensearch.xml", "/results?q=emma", "/results"]);
+
+        assert!(opds.search("  ", 3).await.is_empty());
+        assert!(opds.search("emma", 0).await.is_empty());
+    }
+}
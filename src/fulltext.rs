@@ -0,0 +1,520 @@
+// FULL-TEXT SEARCH - Optional index of Gutenberg plain-text editions, searched by chapter with highlighted snippets
+use crate::config::{ConfigStore, FullTextConfig};
+use crate::guard::{BodyKind, GuardedClient};
+use crate::gutenberg::{FileKind, GutenbergCatalog};
+use crate::health::Heartbeat;
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, TantivyDocument, Value, INDEXED, STORED, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, Term};
+
+const INDEX_TICK: Duration = Duration::from_secs(60);
+const WRITER_MEMORY: usize = 50 * 1024 * 1024;
+const SNIPPET_CHARS: usize = 200;
+// Texts without chapter headings are indexed in sections of about this many bytes
+const SECTION_BYTES: usize = 64 * 1024;
+
+struct Fields {
+    book: Field,
+    title: Field,
+    chapter: Field,
+    heading: Field,
+    body: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Fields) {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            book: builder.add_u64_field("book", INDEXED | STORED),
+            title: builder.add_text_field("title", STORED),
+            chapter: builder.add_u64_field("chapter", STORED),
+            heading: builder.add_text_field("heading", STORED),
+            body: builder.add_text_field("body", TEXT | STORED),
+        };
+        (builder.build(), fields)
+    }
+}
+
+struct Store {
+    index: Index,
+    reader: IndexReader,
+    // Tantivy allows one writer per index; indexing a book holds it until the commit
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Hit {
+    pub book_id: u32,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub chapter: u64,
+    pub chapter_title: String,
+    // Characters into the chapter where the first highlighted term starts
+    pub offset: usize,
+    pub score: f32,
+    // HTML with matches in <b>
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Indexed {
+    pub book_id: u32,
+    pub title: String,
+    pub encoding: String,
+    pub chapters: usize,
+    pub bytes: usize,
+}
+
+pub struct FullTextIndex {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    catalog: Arc<GutenbergCatalog>,
+    // None when the index is turned off or couldn't be opened
+    store: Option<Arc<Store>>,
+    // Books whose last indexing failed, and when the indexer may try them again
+    failed: Mutex<HashMap<u32, Instant>>,
+    heartbeat: Heartbeat,
+}
+
+impl FullTextIndex {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>, catalog: Arc<GutenbergCatalog>) -> Self {
+        let settings = config.snapshot().fulltext.clone();
+        let store = if settings.enabled {
+            match open_store(&settings) {
+                Ok(store) => Some(Arc::new(store)),
+                Err(e) => {
+                    eprintln!("❌ Full-text index at {} unavailable: {}", settings.directory.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Self { client, config, catalog, store, failed: Mutex::new(HashMap::new()), heartbeat: Heartbeat::new(INDEX_TICK) }
+    }
+
+    // Only there while the indexer runs
+    pub fn heartbeat(&self) -> Option<&Heartbeat> {
+        self.store.as_ref().map(|_| &self.heartbeat)
+    }
+
+    fn store(&self) -> Result<&Arc<Store>, String> {
+        self.store.as_ref().ok_or_else(|| "Full-text search is disabled".to_string())
+    }
+
+    pub fn spawn_indexer(self: Arc<Self>) {
+        if self.store.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(INDEX_TICK);
+            loop {
+                interval.tick().await;
+                self.index_due().await;
+                self.heartbeat.beat();
+            }
+        });
+    }
+
+    // The configured books, then the most downloaded ones, that aren't indexed yet
+    // and didn't fail recently
+    async fn index_due(&self) {
+        let settings = self.config.snapshot().fulltext.clone();
+        let popular = self.catalog.popular(settings.max_books).into_iter().map(|book| book.id);
+        let mut due = Vec::new();
+        for id in settings.books.iter().copied().chain(popular) {
+            if due.len() >= settings.books_per_tick {
+                break;
+            }
+            if !due.contains(&id) && !self.backing_off(id) && !self.is_indexed(id) {
+                due.push(id);
+            }
+        }
+        for id in due {
+            match self.index_book(id).await {
+                Ok(indexed) => {
+                    self.failed.lock().unwrap().remove(&id);
+                    log!("🔎 Indexed Gutenberg #{} ({} chapters, {})", id, indexed.chapters, indexed.encoding);
+                }
+                Err(e) => {
+                    let retry_at = Instant::now() + Duration::from_secs(settings.retry_secs);
+                    self.failed.lock().unwrap().insert(id, retry_at);
+                    eprintln!("❌ Full-text indexing of Gutenberg #{} failed, retrying in {}s: {}", id, settings.retry_secs, e);
+                }
+            }
+        }
+    }
+
+    fn backing_off(&self, id: u32) -> bool {
+        let mut failed = self.failed.lock().unwrap();
+        let now = Instant::now();
+        failed.retain(|_, retry_at| *retry_at > now);
+        failed.contains_key(&id)
+    }
+
+    fn is_indexed(&self, id: u32) -> bool {
+        let Ok(store) = self.store() else { return false };
+        let term = Term::from_field_u64(store.fields.book, id as u64);
+        store.reader.searcher().doc_freq(&term).map(|n| n > 0).unwrap_or(false)
+    }
+
+    // Download the plain-text edition and replace whatever the index had for the book
+    pub async fn index_book(&self, id: u32) -> Result<Indexed, String> {
+        let store = self.store()?.clone();
+        let book = self.catalog.book(id).ok_or_else(|| format!("Gutenberg #{} isn't in the catalog", id))?;
+        // Books listing only Latin-1 editions still have the generated UTF-8 text on the mirror
+        let url = match book.file(FileKind::Text) {
+            Some(file) => file.url.clone(),
+            None => {
+                let mirror = self.config.snapshot().gutenberg.mirror.trim_end_matches('/').to_string();
+                format!("{}/cache/epub/{}/pg{}.txt", mirror, id, id)
+            }
+        };
+
+        let response = self.client.send(self.client.download(&url)).await.map_err(|e| e.to_string())?;
+        let response = response.error_for_status().map_err(|e| e.to_string())?;
+        let charset = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split("charset=").nth(1))
+            .map(|c| c.trim_matches(|c: char| c == '"' || c.is_whitespace()).to_string());
+        let raw = self.client.read_body(response, BodyKind::Book).await.map_err(|e| e.to_string())?;
+
+        // Decoding, splitting and the tantivy commit are all CPU and disk bound
+        tokio::task::spawn_blocking(move || {
+            let (text, encoding) = decode(&raw, charset.as_deref());
+            let text = strip_boilerplate(&text);
+            let chapters = chapters(text);
+            let bytes = text.len();
+
+            let mut writer = store.writer.lock().unwrap();
+            writer.delete_term(Term::from_field_u64(store.fields.book, id as u64));
+            for (i, (heading, body)) in chapters.iter().enumerate() {
+                let fields = &store.fields;
+                writer
+                    .add_document(doc!(
+                        fields.book => id as u64,
+                        fields.title => book.title.clone(),
+                        fields.chapter => i as u64,
+                        fields.heading => heading.to_string(),
+                        fields.body => body.to_string(),
+                    ))
+                    .map_err(|e| e.to_string())?;
+            }
+            writer.commit().map_err(|e| e.to_string())?;
+            drop(writer);
+            store.reader.reload().map_err(|e| e.to_string())?;
+
+            Ok(Indexed { book_id: id, title: book.title, encoding: encoding.to_string(), chapters: chapters.len(), bytes })
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    pub fn search(&self, query: &str, phrase: bool, limit: usize) -> Result<Vec<Hit>, SearchError> {
+        let store = self.store().map_err(SearchError::Disabled)?;
+        let fields = &store.fields;
+        let searcher = store.reader.searcher();
+        let mut parser = QueryParser::for_index(&store.index, vec![fields.body]);
+        parser.set_conjunction_by_default();
+        let text = if phrase { format!("\"{}\"", query.replace('"', " ")) } else { query.to_string() };
+        let query = parser.parse_query(&text).map_err(|e| SearchError::Query(e.to_string()))?;
+
+        let top = searcher.search(&query, &TopDocs::with_limit(limit)).map_err(|e| SearchError::Index(e.to_string()))?;
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, fields.body).map_err(|e| SearchError::Index(e.to_string()))?;
+        snippets.set_max_num_chars(SNIPPET_CHARS);
+
+        let mut hits = Vec::new();
+        for (score, address) in top {
+            let doc: TantivyDocument = searcher.doc(address).map_err(|e| SearchError::Index(e.to_string()))?;
+            let text = |field: Field| doc.get_first(field).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            let number = |field: Field| doc.get_first(field).and_then(|v| v.as_u64()).unwrap_or(0);
+            let body = text(fields.body);
+            let snippet = snippets.snippet_from_doc(&doc);
+            let offset = body
+                .find(snippet.fragment())
+                .map(|start| start + snippet.highlighted().first().map(|r| r.start).unwrap_or(0))
+                .and_then(|at| body.get(..at))
+                .map(|before| before.chars().count())
+                .unwrap_or(0);
+            let book_id = number(fields.book) as u32;
+            hits.push(Hit {
+                book_id,
+                title: text(fields.title),
+                authors: self.catalog.book(book_id).map(|b| b.authors).unwrap_or_default(),
+                chapter: number(fields.chapter),
+                chapter_title: text(fields.heading),
+                offset,
+                score,
+                snippet: snippet.to_html(),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+fn open_store(settings: &FullTextConfig) -> Result<Store, String> {
+    std::fs::create_dir_all(&settings.directory).map_err(|e| e.to_string())?;
+    let (schema, fields) = Fields::schema();
+    let directory = MmapDirectory::open(&settings.directory).map_err(|e| e.to_string())?;
+    let index = Index::open_or_create(directory, schema).map_err(|e| e.to_string())?;
+    let writer = index.writer(WRITER_MEMORY).map_err(|e| e.to_string())?;
+    let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into().map_err(|e: tantivy::TantivyError| e.to_string())?;
+    Ok(Store { index, reader, writer: Mutex::new(writer), fields })
+}
+
+// Valid UTF-8 is taken as is. Otherwise the encoding the text declares in its
+// Gutenberg header, then the one the server sent, then Windows-1252, which
+// also reads ISO-8859-1 files correctly.
+fn decode(raw: &[u8], charset: Option<&str>) -> (String, &'static str) {
+    if let Some((encoding, bom)) = Encoding::for_bom(raw) {
+        return (encoding.decode_without_bom_handling(&raw[bom..]).0.into_owned(), encoding.name());
+    }
+    if let Ok(text) = std::str::from_utf8(raw) {
+        return (text.to_string(), UTF_8.name());
+    }
+    let head = String::from_utf8_lossy(&raw[..raw.len().min(8192)]);
+    let declared = head
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Character set encoding:"))
+        .map(str::trim)
+        .or(charset)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .filter(|encoding| *encoding != UTF_8);
+    let encoding = declared.unwrap_or(WINDOWS_1252);
+    (encoding.decode_without_bom_handling(raw).0.into_owned(), encoding.name())
+}
+
+// The text between the "*** START OF ..." and "*** END OF ..." markers (or the
+// older small-print and "End of Project Gutenberg's" forms), when they're there
+fn strip_boilerplate(text: &str) -> &str {
+    let mut start = 0;
+    let mut end = text.len();
+    let mut at = 0;
+    for line in text.split_inclusive('\n') {
+        let upper = line.trim().to_ascii_uppercase();
+        let line_end = at + line.len();
+        if start == 0 && ((upper.starts_with("***") && upper.contains("START OF") && upper.contains("PROJECT GUTENBERG")) || upper.starts_with("*END*THE SMALL PRINT")) {
+            start = line_end;
+        } else if start > 0
+            && ((upper.starts_with("***") && upper.contains("END OF") && upper.contains("PROJECT GUTENBERG"))
+                || upper.starts_with("END OF THE PROJECT GUTENBERG")
+                || upper.starts_with("END OF PROJECT GUTENBERG"))
+        {
+            end = at;
+            break;
+        }
+        at = line_end;
+    }
+    text[start..end].trim()
+}
+
+fn is_heading(line: &str) -> bool {
+    let line = line.trim();
+    if line.is_empty() || line.len() > 80 {
+        return false;
+    }
+    let lower = line.to_lowercase();
+    let words = ["chapter ", "book ", "part ", "act ", "stave ", "letter ", "canto "];
+    if words.iter().any(|w| lower.starts_with(w)) {
+        return true;
+    }
+    // A Roman numeral on its own line, as many Gutenberg texts number chapters
+    let numeral = line.trim_end_matches('.');
+    !numeral.is_empty() && numeral.len() <= 8 && numeral.chars().all(|c| "IVXLC".contains(c))
+}
+
+// Split at heading lines that follow a blank line; without any, in sections at paragraph breaks
+fn chapters(text: &str) -> Vec<(String, &str)> {
+    let mut starts = Vec::new();
+    let mut at = 0;
+    let mut blank_before = true;
+    for line in text.split_inclusive('\n') {
+        if blank_before && is_heading(line) {
+            starts.push((at, line.trim().to_string()));
+        }
+        blank_before = line.trim().is_empty();
+        at += line.len();
+    }
+
+    if starts.is_empty() {
+        let mut sections = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let cut = if rest.len() <= SECTION_BYTES {
+                rest.len()
+            } else {
+                let window = &rest[..rest.floor_char_boundary(SECTION_BYTES)];
+                window.rfind("\n\n").map(|i| i + 2).unwrap_or(window.len()).max(1)
+            };
+            sections.push((String::new(), &rest[..cut]));
+            rest = &rest[cut..];
+        }
+        return sections;
+    }
+
+    let mut chapters = Vec::new();
+    if starts[0].0 > 0 && !text[..starts[0].0].trim().is_empty() {
+        chapters.push((String::new(), &text[..starts[0].0]));
+    }
+    for (i, (start, heading)) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map(|(next, _)| *next).unwrap_or(text.len());
+        chapters.push((heading.clone(), &text[*start..end]));
+    }
+    chapters
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    Disabled(String),
+    Query(String),
+    Index(String),
+}
+
+#[derive(Deserialize)]
+pub struct FullTextQuery {
+    q: String,
+    phrase: Option<bool>, // match the words in order, as a quotation
+    limit: Option<usize>,
+}
+
+// GET /api/fulltext/search?q=&phrase=true - chapters containing the words, best first
+pub async fn search_text(Query(params): Query<FullTextQuery>, State(state): State<AppState>) -> Result<Json<Vec<Hit>>, (StatusCode, String)> {
+    let index = state.fulltext.clone();
+    let limit = params.limit.unwrap_or(10).min(100);
+    let phrase = params.phrase.unwrap_or(false);
+    let hits = tokio::task::spawn_blocking(move || index.search(&params.q, phrase, limit))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match hits {
+        Ok(hits) => Ok(Json(hits)),
+        Err(SearchError::Disabled(e)) => Err((StatusCode::SERVICE_UNAVAILABLE, e)),
+        Err(SearchError::Query(e)) => Err((StatusCode::BAD_REQUEST, format!("Invalid query: {}", e))),
+        Err(SearchError::Index(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+// POST /api/fulltext/books/:id - index (or re-index) one Gutenberg book now
+pub async fn index_book(Path(id): Path<u32>, State(state): State<AppState>) -> Result<Json<Indexed>, (StatusCode, String)> {
+    if state.fulltext.store.is_none() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Full-text search is disabled".to_string()));
+    }
+    if state.gutenberg.book(id).is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("Gutenberg #{} isn't in the catalog", id)));
+    }
+    state.fulltext.index_book(id).await.map(Json).map_err(|e| (StatusCode::BAD_GATEWAY, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode(b"\xEF\xBB\xBFcaf\xC3\xA9", None), ("café".to_string(), "UTF-8"));
+        assert_eq!(decode(b"\xFF\xFEc\0a\0f\0\xE9\0", None), ("café".to_string(), "UTF-16LE"));
+        assert_eq!(decode("café “quoted”".as_bytes(), Some("iso-8859-1")), ("café “quoted”".to_string(), "UTF-8"));
+
+        // The header's declaration wins over the server's charset
+        let latin1 = b"Title: X\r\nCharacter set encoding: ISO-8859-1\r\n\r\ncaf\xE9";
+        assert!(decode(latin1, Some("iso-8859-2")).0.ends_with("café"));
+        let latin2 = b"Character set encoding: ISO-8859-2\n\nza\xBF\xF3\xB3\xE6";
+        assert_eq!(decode(latin2, None), ("Character set encoding: ISO-8859-2\n\nzażółć".to_string(), "ISO-8859-2"));
+        // Then the server's
+        assert_eq!(decode(b"za\xBF\xF3\xB3\xE6", Some("iso-8859-2")), ("zażółć".to_string(), "ISO-8859-2"));
+
+        // Nothing declared: Windows-1252, smart quotes and all
+        assert_eq!(decode(b"\x93caf\xE9\x94", None), ("“café”".to_string(), "windows-1252"));
+        assert_eq!(decode(b"caf\xE9", Some("utf-8")), ("café".to_string(), "windows-1252"));
+        assert_eq!(decode(b"caf\xE9", Some("no-such-charset")), ("café".to_string(), "windows-1252"));
+    }
+
+    #[test]
+    fn boilerplate() {
+        let modern = "The Project Gutenberg eBook of Emma\r\n\r\n*** START OF THE PROJECT GUTENBERG EBOOK EMMA ***\r\n\r\nVOLUME I\r\n\r\nEmma Woodhouse\r\n\r\n*** END OF THE PROJECT GUTENBERG EBOOK EMMA ***\r\nSection 1. General Terms of Use\r\n";
+        assert_eq!(strip_boilerplate(modern), "VOLUME I\r\n\r\nEmma Woodhouse");
+
+        let small_print = "**The Project Gutenberg Etext of Persuasion**\n\n*END*THE SMALL PRINT! FOR PUBLIC DOMAIN ETEXTS*Ver.04.29.93*END*\n\nPersuasion\n\nChapter 1\n\nEnd of Project Gutenberg's Persuasion, by Jane Austen\n";
+        assert_eq!(strip_boilerplate(small_print), "Persuasion\n\nChapter 1");
+        let older_end = "*** START OF THIS PROJECT GUTENBERG EBOOK X ***\nText\nEnd of the Project Gutenberg EBook of X\nlicense";
+        assert_eq!(strip_boilerplate(older_end), "Text");
+
+        // Without the start marker an end-like line is just text
+        assert_eq!(strip_boilerplate("  Text\nEND OF PROJECT GUTENBERG is mentioned\n"), "Text\nEND OF PROJECT GUTENBERG is mentioned");
+        // A start marker with no end keeps the rest
+        assert_eq!(strip_boilerplate("header\n*** START OF THE PROJECT GUTENBERG EBOOK X ***\nText to the end\n"), "Text to the end");
+    }
+
+    #[test]
+    fn chapter_splitting() {
+        let text = "Preface text.\n\nCHAPTER I\nIt was a dark night.\nChapter 2 mentioned mid-paragraph.\n\nII.\nMorning came.\n\nAct 3\nCurtain.\n";
+        let found = chapters(text);
+        let headings: Vec<&str> = found.iter().map(|(heading, _)| heading.as_str()).collect();
+        assert_eq!(headings, vec!["", "CHAPTER I", "II.", "Act 3"]);
+        assert_eq!(found[0].1, "Preface text.\n\n");
+        assert_eq!(found[1].1, "CHAPTER I\nIt was a dark night.\nChapter 2 mentioned mid-paragraph.\n\n");
+        assert_eq!(found[3].1, "Act 3\nCurtain.\n");
+        // Every byte lands in exactly one chapter
+        assert_eq!(found.iter().map(|(_, body)| body.len()).sum::<usize>(), text.len());
+
+        // Long headings and numerals outside I-C aren't headings
+        assert!(!is_heading("MIX"));
+        assert!(!is_heading(&format!("Chapter {}", "x".repeat(80))));
+        assert!(is_heading("  Letter 4  "));
+        assert!(is_heading("XLII"));
+
+        // No headings: sections of at most SECTION_BYTES, cut at paragraph breaks
+        let paragraph = format!("{}\n\n", "é".repeat(999));
+        let text = paragraph.repeat(100);
+        let sections = chapters(&text);
+        assert!(sections.len() > 1);
+        assert!(sections.iter().all(|(heading, body)| heading.is_empty() && body.len() <= SECTION_BYTES && body.ends_with("\n\n")));
+        assert_eq!(sections.iter().map(|(_, body)| body.len()).sum::<usize>(), text.len());
+
+        // Not even a paragraph break: cut on a character boundary
+        let text = "é".repeat(SECTION_BYTES);
+        assert_eq!(chapters(&text).iter().map(|(_, body)| body.len()).sum::<usize>(), text.len());
+    }
+
+    #[tokio::test]
+    async fn failed_books_wait_before_another_try() {
+        let directory = std::env::temp_dir().join(format!("content-server-fulltext-{}", std::process::id()));
+        let mut config = ServerConfig::default();
+        config.fulltext.enabled = true;
+        config.fulltext.directory = directory.clone();
+        config.fulltext.books = vec![42];
+        let config = Arc::new(ConfigStore::fixed(config));
+        let client = GuardedClient::new(config.clone());
+        let catalog = Arc::new(GutenbergCatalog::new(client.clone(), config.clone()));
+        let index = FullTextIndex::new(client, config, catalog);
+
+        // #42 isn't in the (empty) catalog, so indexing it fails
+        assert!(!index.backing_off(42));
+        index.index_due().await;
+        assert!(index.backing_off(42));
+        assert!(!index.backing_off(43));
+
+        // Past its retry time it's due again
+        index.failed.lock().unwrap().insert(42, Instant::now());
+        assert!(!index.backing_off(42));
+        assert!(index.failed.lock().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,655 @@
+// OUTBOUND GUARD - SSRF protection and resource limits for URLs that come from users and playlists
+//
+// Every fetch goes through GuardedClient. The request URL and each redirect are
+// checked for scheme, deny-list and literal IPs; hostnames are checked when our
+// resolver looks them up. reqwest connects to exactly the addresses the resolver
+// approved, so a name can't be re-pointed at an internal host between check and use.
+// Bodies are read through read_body, which caps size and decompression per kind.
+use crate::config::{ConfigStore, FetchLimits, OutboundConfig};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{Client, IntoUrl, Request, RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Error bodies are only skimmed for hints, never read whole
+const ERROR_BODY_BYTES: usize = 64 * 1024;
+
+// Why a URL was refused; also carried inside reqwest errors from the resolver and redirects
+#[derive(Debug)]
+pub struct Blocked(pub String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "blocked by outbound policy: {}", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    BodySize,
+    Decompression,
+    Redirects,
+    HeaderSize,
+}
+
+// An upstream response went past one of the configured limits
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub max: u64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            Limit::BodySize => write!(f, "response body larger than {} bytes", self.max),
+            Limit::Decompression => write!(f, "decompressed body larger than {} bytes", self.max),
+            Limit::Redirects => write!(f, "more than {} redirects", self.max),
+            Limit::HeaderSize => write!(f, "response headers larger than {} bytes", self.max),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// What a body is for decides how much of it we are willing to read
+#[derive(Debug, Clone, Copy)]
+pub enum BodyKind {
+    Playlist,
+    Manifest,
+    Segment,
+    Catalog,
+    Api,
+    Book,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Blocked(Blocked),
+    Limit(LimitExceeded),
+    Decode(std::io::Error),
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Blocked(blocked) => blocked.fmt(f),
+            FetchError::Limit(limit) => limit.fmt(f),
+            FetchError::Decode(e) => write!(f, "could not decode response body: {}", e),
+            FetchError::Request(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Request(e)
+    }
+}
+
+// Policy and limit errors raised inside reqwest (resolver, redirects) end up in its source chain
+pub fn find_cause<'a, T: std::error::Error + 'static>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a T> {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if let Some(found) = e.downcast_ref::<T>() {
+            return Some(found);
+        }
+        current = e.source();
+    }
+    None
+}
+
+pub struct OutboundGuard {
+    config: Arc<ConfigStore>,
+}
+
+impl OutboundGuard {
+    // Scheme, deny list and literal IP hosts; hostnames are resolved and checked later
+    pub fn check_url(&self, url: &Url) -> Result<(), Blocked> {
+        let config = self.config.snapshot();
+        let policy = &config.outbound;
+        if !policy.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(url.scheme())) {
+            return Err(Blocked(format!("scheme {} is not allowed", url.scheme())));
+        }
+        let host = url.host_str().ok_or_else(|| Blocked("URL has no host".to_string()))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        if policy.deny.iter().any(|rule| matches_host(rule, &host)) {
+            return Err(Blocked(format!("host {} is denied", host)));
+        }
+        match host.parse::<IpAddr>() {
+            Ok(ip) => check_ip(policy, &host, ip),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+fn check_ip(policy: &OutboundConfig, host: &str, ip: IpAddr) -> Result<(), Blocked> {
+    if policy.deny.iter().any(|rule| matches_ip(rule, ip)) {
+        return Err(Blocked(format!("address {} is denied", ip)));
+    }
+    // Allow entries are the operator's exceptions to the internal-range rule
+    let allowed = policy.allow.iter().any(|rule| matches_host(rule, host) || matches_ip(rule, ip));
+    if is_internal(ip) && !allowed {
+        return Err(Blocked(format!("{} resolves to internal address {}", host, ip)));
+    }
+    Ok(())
+}
+
+// Loopback, private, link-local, CGNAT, multicast and reserved ranges, including
+// IPv4 addresses smuggled inside IPv6 (mapped and NAT64)
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || o[0] == 0
+                || o[0] >= 240
+                || (o[0] == 100 && (o[1] & 0xC0) == 64)
+                || (o[0] == 192 && o[1] == 0 && o[2] == 0)
+                || (o[0] == 198 && (o[1] & 0xFE) == 18)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(v4));
+            }
+            let s = v6.segments();
+            if s[0] == 0x64 && s[1] == 0xff9b && s[2..6] == [0, 0, 0, 0] {
+                return is_internal(IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8)));
+            }
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (s[0] & 0xFE00) == 0xFC00
+                || (s[0] & 0xFFC0) == 0xFE80
+                || (s[0] == 0x2001 && s[1] == 0x0DB8)
+                || s[..6] == [0, 0, 0, 0, 0, 0]
+        }
+    }
+}
+
+// "example.com" matches exactly, "*.example.com" matches the domain and its subdomains
+fn matches_host(rule: &str, host: &str) -> bool {
+    let rule = rule.trim().to_ascii_lowercase();
+    match rule.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+        None => host == rule,
+    }
+}
+
+// "10.0.0.5" or "10.0.0.0/8" style rules; anything else never matches an address.
+// IPv4-mapped IPv6 addresses and rules are compared as the IPv4 address they carry.
+fn matches_ip(rule: &str, ip: IpAddr) -> bool {
+    let rule = rule.trim();
+    let (base, prefix) = match rule.split_once('/') {
+        Some((base, prefix)) => match prefix.parse::<u32>() {
+            Ok(prefix) => (base, Some(prefix)),
+            Err(_) => return false,
+        },
+        None => (rule, None),
+    };
+    let Ok(base) = base.parse::<IpAddr>() else { return false };
+    let (base, prefix) = match base {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => (IpAddr::V4(v4), prefix.map(|p| p.saturating_sub(96))),
+            None => (base, prefix),
+        },
+        IpAddr::V4(_) => (base, prefix),
+    };
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    };
+    match (base, ip) {
+        (IpAddr::V4(base), IpAddr::V4(ip)) => prefix_match(u32::from(base) as u128, u32::from(ip) as u128, prefix.unwrap_or(32), 32),
+        (IpAddr::V6(base), IpAddr::V6(ip)) => prefix_match(u128::from(base), u128::from(ip), prefix.unwrap_or(128), 128),
+        _ => false,
+    }
+}
+
+fn prefix_match(base: u128, ip: u128, prefix: u32, bits: u32) -> bool {
+    let prefix = prefix.min(bits);
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    (base >> shift) == (ip >> shift)
+}
+
+// Looks names up itself so every address reqwest will connect to has been checked
+struct GuardedResolver {
+    guard: Arc<OutboundGuard>,
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let guard = self.guard.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_ascii_lowercase();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            let config = guard.config.snapshot();
+            if config.outbound.deny.iter().any(|rule| matches_host(rule, &host)) {
+                return Err(Box::new(Blocked(format!("host {} is denied", host))) as Box<dyn std::error::Error + Send + Sync>);
+            }
+            // One internal address is enough to refuse the name outright
+            for addr in &addrs {
+                check_ip(&config.outbound, &host, addr.ip())?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct GuardedClient {
+    client: Client,
+    guard: Arc<OutboundGuard>,
+}
+
+impl GuardedClient {
+    pub fn new(config: Arc<ConfigStore>) -> Self {
+        let connect_timeout = Duration::from_secs(config.snapshot().limits.connect_timeout_secs);
+        let guard = Arc::new(OutboundGuard { config });
+        let redirect_guard = guard.clone();
+        let client = Client::builder()
+            .connect_timeout(connect_timeout)
+            .dns_resolver(Arc::new(GuardedResolver { guard: guard.clone() }))
+            // A proxy would resolve names itself, out of the resolver's reach
+            .no_proxy()
+            .redirect(Policy::custom(move |attempt: Attempt| {
+                let max = redirect_guard.config.snapshot().limits.max_redirects;
+                if attempt.previous().len() > max {
+                    return attempt.error(LimitExceeded { limit: Limit::Redirects, max: max as u64 });
+                }
+                match redirect_guard.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(blocked) => attempt.error(blocked),
+                }
+            }))
+            .build()
+            // Falling back to an unguarded client would defeat the point
+            .expect("failed to build the outbound HTTP client");
+        Self { client, guard }
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn head<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.head(url)
+    }
+
+    // A GET for a whole book, catalog or mirrored file, with the longer download timeout
+    pub fn download<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url).timeout(Duration::from_secs(self.limits().download_timeout_secs))
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, FetchError> {
+        let mut request: Request = match request.build() {
+            Ok(request) => request,
+            // reqwest rejects odd schemes itself; report those as policy blocks too
+            Err(e) => match e.url().map(|url| self.guard.check_url(url)) {
+                Some(Err(blocked)) => return Err(FetchError::Blocked(blocked)),
+                _ => return Err(e.into()),
+            },
+        };
+        self.guard.check_url(request.url()).map_err(FetchError::Blocked)?;
+        // A hung upstream must not hold up a search, job or health check for good
+        if request.timeout().is_none() {
+            *request.timeout_mut() = Some(Duration::from_secs(self.limits().request_timeout_secs));
+        }
+        let response = self.client.execute(request).await?;
+
+        let max = self.limits().max_header_bytes;
+        let header_bytes: u64 = response.headers().iter().map(|(name, value)| (name.as_str().len() + value.len() + 4) as u64).sum();
+        if header_bytes > max {
+            return Err(FetchError::Limit(LimitExceeded { limit: Limit::HeaderSize, max }));
+        }
+        Ok(response)
+    }
+
+    fn limits(&self) -> FetchLimits {
+        self.guard.config.snapshot().limits.clone()
+    }
+
+    // Read a whole body within the limit for its kind. Compressed bodies are
+    // inflated here (reqwest is built without decompression) so both the output
+    // size and the compression ratio can be capped.
+    pub async fn read_body(&self, mut response: Response, kind: BodyKind) -> Result<Vec<u8>, FetchError> {
+        let limits = self.limits();
+        let max = limits.body_bytes(kind);
+        let too_large = || FetchError::Limit(LimitExceeded { limit: Limit::BodySize, max });
+        if response.content_length().is_some_and(|length| length > max) {
+            return Err(too_large());
+        }
+
+        let encoding = response
+            .headers()
+            .get("content-encoding")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_ascii_lowercase());
+        let mut raw = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            raw.extend_from_slice(&chunk);
+            if raw.len() as u64 > max {
+                return Err(too_large());
+            }
+        }
+
+        let inflated_max = max.min((raw.len() as u64).saturating_mul(limits.max_decompression_ratio));
+        match encoding.as_deref() {
+            Some("gzip") | Some("x-gzip") => inflate_blocking(raw, Compression::Gzip, inflated_max).await,
+            Some("deflate") => inflate_blocking(raw, Compression::Zlib, inflated_max).await,
+            _ => Ok(raw),
+        }
+    }
+
+    // For files that are gzipped themselves rather than sent with Content-Encoding
+    pub async fn gunzip(&self, raw: Vec<u8>, kind: BodyKind) -> Result<Vec<u8>, FetchError> {
+        let limits = self.limits();
+        let max = limits.body_bytes(kind).min((raw.len() as u64).saturating_mul(limits.max_decompression_ratio));
+        inflate_blocking(raw, Compression::Gzip, max).await
+    }
+
+    pub async fn read_text(&self, response: Response, kind: BodyKind) -> Result<String, FetchError> {
+        let body = self.read_body(response, kind).await?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    // GET a JSON API response; error statuses and bodies past the API limit are errors
+    pub async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, FetchError> {
+        let response = self.send(self.get(url)).await?.error_for_status()?;
+        let body = self.read_body(response, BodyKind::Api).await?;
+        serde_json::from_slice(&body).map_err(|e| FetchError::Decode(e.into()))
+    }
+
+    // The start of an error page, for hints like geo-blocking notices
+    pub async fn error_text(&self, mut response: Response) -> String {
+        let mut body = Vec::new();
+        while body.len() < ERROR_BODY_BYTES {
+            match response.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&body).into_owned()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zlib,
+}
+
+// Inflating a catalog-sized body takes long enough to stall other tasks on the worker
+async fn inflate_blocking(raw: Vec<u8>, compression: Compression, max: u64) -> Result<Vec<u8>, FetchError> {
+    tokio::task::spawn_blocking(move || match compression {
+        Compression::Gzip => inflate(GzDecoder::new(&raw[..]), max),
+        Compression::Zlib => inflate(ZlibDecoder::new(&raw[..]), max),
+    })
+    .await
+    .map_err(|e| FetchError::Decode(std::io::Error::other(e)))?
+}
+
+fn inflate(decoder: impl Read, max: u64) -> Result<Vec<u8>, FetchError> {
+    let mut out = Vec::new();
+    decoder.take(max + 1).read_to_end(&mut out).map_err(FetchError::Decode)?;
+    if out.len() as u64 > max {
+        return Err(FetchError::Limit(LimitExceeded { limit: Limit::Decompression, max }));
+    }
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::response::{IntoResponse, Redirect};
+    use axum::routing::get;
+    use axum::Router;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn policy(allow: &[&str], deny: &[&str]) -> OutboundConfig {
+        OutboundConfig {
+            allow: allow.iter().map(|r| r.to_string()).collect(),
+            deny: deny.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn internal_ranges() {
+        for internal in [
+            "169.254.169.254", "127.0.0.1", "127.255.0.9", "10.0.0.1", "10.255.255.255", "172.16.0.1", "192.168.1.1", "100.64.0.1",
+            "100.127.255.255", "0.0.0.0", "255.255.255.255", "224.0.0.1", "::1", "::", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+            "64:ff9b::a00:1", "64:ff9b::7f00:1", "fc00::1", "fd12:3456::1", "fe80::1", "febf::1", "::127.0.0.1",
+        ] {
+            assert!(is_internal(ip(internal)), "{} should be internal", internal);
+        }
+        for public in ["8.8.8.8", "100.63.255.255", "100.128.0.1", "172.32.0.1", "2606:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808", "fec0::1"] {
+            assert!(!is_internal(ip(public)), "{} should be public", public);
+        }
+    }
+
+    #[test]
+    fn ip_rules() {
+        assert!(matches_ip("10.0.0.0/8", ip("10.1.2.3")));
+        assert!(!matches_ip("10.0.0.0/8", ip("11.0.0.1")));
+        assert!(matches_ip("203.0.113.7", ip("203.0.113.7")));
+        assert!(!matches_ip("203.0.113.7", ip("203.0.113.8")));
+        assert!(matches_ip("0.0.0.0/0", ip("8.8.8.8")));
+        assert!(matches_ip("2001:db8::/32", ip("2001:db8:1::5")));
+        assert!(!matches_ip("2001:db8::/32", ip("2001:db9::5")));
+        // The IPv4-mapped form of an address is the same address
+        assert!(matches_ip("10.0.0.0/8", ip("::ffff:10.9.8.7")));
+        assert!(matches_ip("::ffff:10.0.0.0/104", ip("10.9.8.7")));
+        assert!(matches_ip("::ffff:10.0.0.1", ip("10.0.0.1")));
+        assert!(!matches_ip("10.0.0.0/8", ip("::10.9.8.7")));
+        // Junk never matches, rather than matching everything
+        assert!(!matches_ip("10.0.0.0/x", ip("10.0.0.1")));
+        assert!(!matches_ip("example.com", ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn host_rules() {
+        assert!(matches_host("*.example.com", "example.com"));
+        assert!(matches_host("*.example.com", "cdn.eu.example.com"));
+        assert!(!matches_host("*.example.com", "badexample.com"));
+        assert!(!matches_host("*.example.com", "example.com.evil.net"));
+        assert!(matches_host(" Example.COM ", "example.com"));
+        assert!(!matches_host("example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let open = policy(&[], &[]);
+        assert!(check_ip(&open, "8.8.8.8", ip("8.8.8.8")).is_ok());
+        assert!(check_ip(&open, "metadata", ip("169.254.169.254")).is_err());
+
+        // Allow entries make exceptions to the internal-range rule, by address, range or name
+        let allowed = policy(&["10.1.0.0/16", "*.lan", "::ffff:192.168.1.5"], &[]);
+        assert!(check_ip(&allowed, "10.1.2.3", ip("10.1.2.3")).is_ok());
+        assert!(check_ip(&allowed, "10.2.0.1", ip("10.2.0.1")).is_err());
+        assert!(check_ip(&allowed, "nas.lan", ip("192.168.1.20")).is_ok());
+        assert!(check_ip(&allowed, "192.168.1.5", ip("192.168.1.5")).is_ok());
+
+        // Deny wins over allow and applies to public addresses too
+        let denied = policy(&["10.0.0.0/8"], &["10.0.0.0/24", "8.8.8.8"]);
+        assert!(check_ip(&denied, "10.0.0.5", ip("10.0.0.5")).is_err());
+        assert!(check_ip(&denied, "mapped", ip("::ffff:10.0.0.5")).is_err());
+        assert!(check_ip(&denied, "10.0.1.5", ip("10.0.1.5")).is_ok());
+        assert!(check_ip(&denied, "dns.google", ip("8.8.8.8")).is_err());
+    }
+
+    #[test]
+    fn urls() {
+        let mut config = ServerConfig::default();
+        config.outbound.deny = vec!["*.blocked.example".to_string()];
+        let guard = OutboundGuard { config: Arc::new(ConfigStore::fixed(config)) };
+        let check = |url: &str| guard.check_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/a").is_ok());
+        assert!(check("ftp://example.com/a").is_err());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check("http://cdn.blocked.example/").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data/").is_err());
+        assert!(check("http://[::ffff:7f00:1]/").is_err());
+        assert!(check("http://2130706433/").is_err());
+        assert!(check("http://0x7f.1/").is_err());
+    }
+
+    // Sixteen letters in no order: compresses about 2:1, unlike a run of one byte
+    fn noise(n: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..n)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                b'a' + (state % 16) as u8
+            })
+            .collect()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Stand-in with big, compressed and redirecting responses, behind tight limits
+    async fn limited() -> (GuardedClient, String) {
+        use axum::body::Body;
+        use axum::extract::Path;
+        use axum::http::header;
+        use futures_util::stream;
+
+        let app = Router::new()
+            .route("/sized/:n", get(|Path(n): Path<usize>| async move { vec![b'x'; n] }))
+            .route(
+                "/chunked/:n",
+                get(|Path(n): Path<usize>| async move {
+                    let chunks = (0..n / 100).map(|_| Ok::<_, std::io::Error>(vec![b'x'; 100]));
+                    Body::from_stream(stream::iter(chunks))
+                }),
+            )
+            .route("/gzip/:n", get(|Path(n): Path<usize>| async move { ([(header::CONTENT_ENCODING, "gzip")], gzip(&noise(n))) }))
+            .route("/bomb/:n", get(|Path(n): Path<usize>| async move { ([(header::CONTENT_ENCODING, "gzip")], gzip(&vec![0; n])) }))
+            .route(
+                "/redirect/:n",
+                get(|Path(n): Path<usize>| async move {
+                    match n {
+                        0 => "done".into_response(),
+                        n => Redirect::temporary(&format!("/redirect/{}", n - 1)).into_response(),
+                    }
+                }),
+            )
+            .route("/headers/:n", get(|Path(n): Path<usize>| async move { ([("x-padding", "p".repeat(n))], "ok") }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        config.limits.api_bytes = 1000;
+        config.limits.catalog_bytes = 1_000_000;
+        config.limits.max_decompression_ratio = 100;
+        config.limits.max_redirects = 3;
+        config.limits.max_header_bytes = 1024;
+        (GuardedClient::new(Arc::new(ConfigStore::fixed(config))), base)
+    }
+
+    fn limit(result: Result<impl fmt::Debug, FetchError>) -> Option<Limit> {
+        match result {
+            Err(FetchError::Limit(exceeded)) => Some(exceeded.limit),
+            Err(FetchError::Request(e)) => find_cause::<LimitExceeded>(&e).map(|exceeded| exceeded.limit),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn body_size_limit() {
+        let (client, base) = limited().await;
+        let read = |path: &str| {
+            let (client, url) = (client.clone(), format!("{}{}", base, path));
+            async move { client.read_body(client.send(client.get(url)).await?, BodyKind::Api).await }
+        };
+        assert_eq!(read("/sized/1000").await.unwrap().len(), 1000);
+        // Refused on Content-Length, and counted while reading when there isn't one
+        assert_eq!(limit(read("/sized/1001").await), Some(Limit::BodySize));
+        assert_eq!(read("/chunked/1000").await.unwrap().len(), 1000);
+        assert_eq!(limit(read("/chunked/1100").await), Some(Limit::BodySize));
+    }
+
+    #[tokio::test]
+    async fn decompression_limit() {
+        let (client, base) = limited().await;
+        let read = |path: &str| {
+            let (client, url) = (client.clone(), format!("{}{}", base, path));
+            async move { client.read_body(client.send(client.get(url)).await?, BodyKind::Catalog).await }
+        };
+        assert_eq!(read("/gzip/5000").await.unwrap(), noise(5000));
+        // Under a kilobyte that inflates to 900 KB: within the body limit, far past the ratio
+        assert_eq!(limit(read("/bomb/900000").await), Some(Limit::Decompression));
+        // Within the ratio but past the body limit once inflated
+        assert_eq!(limit(read("/gzip/1100000").await), Some(Limit::Decompression));
+
+        assert_eq!(client.gunzip(gzip(&noise(5000)), BodyKind::Catalog).await.unwrap(), noise(5000));
+        assert_eq!(limit(client.gunzip(gzip(&vec![0; 900_000]), BodyKind::Catalog).await), Some(Limit::Decompression));
+        assert!(matches!(client.gunzip(b"not gzip".to_vec(), BodyKind::Catalog).await, Err(FetchError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn redirect_and_header_limits() {
+        let (client, base) = limited().await;
+        let get = |path: &str| client.send(client.get(format!("{}{}", base, path)));
+        assert_eq!(get("/redirect/3").await.unwrap().url().path(), "/redirect/0");
+        assert_eq!(limit(get("/redirect/4").await), Some(Limit::Redirects));
+        assert!(get("/headers/500").await.is_ok());
+        assert_eq!(limit(get("/headers/2000").await), Some(Limit::HeaderSize));
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_again() {
+        let app = Router::new()
+            .route("/metadata", get(|| async { Redirect::temporary("http://169.254.169.254/latest/meta-data/") }))
+            .route("/mapped", get(|| async { Redirect::temporary("http://[::ffff:a9fe:a9fe]/") }))
+            .route("/ok", get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        let client = GuardedClient::new(Arc::new(ConfigStore::fixed(config)));
+
+        assert!(client.send(client.get(format!("{}/ok", base))).await.is_ok());
+        for path in ["/metadata", "/mapped"] {
+            match client.send(client.get(format!("{}{}", base, path))).await {
+                Err(FetchError::Request(e)) => assert!(find_cause::<Blocked>(&e).is_some(), "{}: {}", path, e),
+                other => panic!("{}: expected a blocked redirect, got {:?}", path, other.map(|r| r.status())),
+            }
+        }
+    }
+}
@@ -0,0 +1,734 @@
+// GUTENBERG CATALOG - Project Gutenberg books indexed locally from the official catalog files
+use crate::config::{ConfigStore, GutenbergConfig, PlaylistLocation};
+use crate::guard::{BodyKind, GuardedClient};
+use crate::health::Heartbeat;
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+const REFRESH_TICK: Duration = Duration::from_secs(60);
+const DEFAULT_MIRROR: &str = "https://www.gutenberg.org";
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const DCTERMS_NS: &str = "http://purl.org/dc/terms/";
+const PGTERMS_NS: &str = "http://www.gutenberg.org/2009/pgterms/";
+const DCAM_NS: &str = "http://purl.org/dc/dcam/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Epub,
+    Kindle,
+    Text,
+    Html,
+}
+
+impl FileKind {
+    // Label used in result titles and source names
+    pub fn format(self) -> &'static str {
+        match self {
+            FileKind::Epub => "EPUB",
+            FileKind::Kindle => "KINDLE",
+            FileKind::Text => "TXT",
+            FileKind::Html => "HTML",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<FileKind> {
+        let mime = mime.to_ascii_lowercase();
+        let (base, params) = mime.split_once(';').unwrap_or((&mime, ""));
+        match base.trim() {
+            "application/epub+zip" => Some(FileKind::Epub),
+            "application/x-mobipocket-ebook" => Some(FileKind::Kindle),
+            "text/html" => Some(FileKind::Html),
+            // Only UTF-8 (or its ASCII subset); the Latin-1 editions are left out
+            "text/plain" if params.contains("utf-8") || params.contains("us-ascii") => Some(FileKind::Text),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BookFile {
+    pub kind: FileKind,
+    pub url: String,
+    pub mime: String,
+    pub size: Option<u64>,
+    // Built from the mirror layout rather than listed by the catalog, so it may not exist
+    pub derived: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Book {
+    pub id: u32,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub subjects: Vec<String>,
+    pub languages: Vec<String>,
+    pub issued: Option<String>,
+    pub downloads: Option<u64>,
+    pub files: Vec<BookFile>,
+}
+
+impl Book {
+    pub fn file(&self, kind: FileKind) -> Option<&BookFile> {
+        self.files.iter().find(|f| f.kind == kind)
+    }
+
+    // Keep one file per kind, preferring the editions with images (EPUB3/KF8 over older builds)
+    fn add_file(&mut self, file: BookFile) {
+        let rank = |url: &str| usize::from(url.contains("images")) + usize::from(url.contains("epub3") || url.contains("kf8"));
+        match self.files.iter_mut().find(|f| f.kind == file.kind) {
+            Some(existing) if rank(&file.url) > rank(&existing.url) => *existing = file,
+            Some(_) => {}
+            None => self.files.push(file),
+        }
+    }
+}
+
+// Ways to browse the catalog besides search
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Facet {
+    Subject,
+    Author,
+    Language,
+}
+
+impl Facet {
+    fn values(self, book: &Book) -> &[String] {
+        match self {
+            Facet::Subject => &book.subjects,
+            Facet::Author => &book.authors,
+            Facet::Language => &book.languages,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    books: Vec<Book>,
+    by_id: HashMap<u32, usize>,
+    // Title and author words to the books containing them, in book order
+    terms: HashMap<String, Vec<usize>>,
+    // Each subject, author and language to its books, most downloaded first
+    facets: HashMap<Facet, HashMap<String, Vec<usize>>>,
+}
+
+impl Index {
+    fn new(mut books: Vec<Book>) -> Self {
+        books.sort_by_key(|b| b.id);
+        books.dedup_by_key(|b| b.id);
+        let mut index = Index::default();
+        for (i, book) in books.iter().enumerate() {
+            index.by_id.insert(book.id, i);
+            for facet in [Facet::Subject, Facet::Author, Facet::Language] {
+                let values = index.facets.entry(facet).or_default();
+                for value in facet.values(book) {
+                    let postings = values.entry(value.clone()).or_default();
+                    if postings.last() != Some(&i) {
+                        postings.push(i);
+                    }
+                }
+            }
+            let words = tokenize(&book.title).into_iter().chain(book.authors.iter().flat_map(|a| tokenize(a)));
+            for word in words {
+                let postings = index.terms.entry(word).or_default();
+                if postings.last() != Some(&i) {
+                    postings.push(i);
+                }
+            }
+        }
+        for postings in index.facets.values_mut().flat_map(|values| values.values_mut()) {
+            postings.sort_by(|&a, &b| books[b].downloads.cmp(&books[a].downloads).then(books[a].id.cmp(&books[b].id)));
+        }
+        index.books = books;
+        index
+    }
+
+    // Every query word must appear in the title or an author; exact and leading title
+    // matches come first, then the most downloaded. No match means no results.
+    fn search(&self, query: &str, language: Option<&str>, limit: usize) -> Vec<Book> {
+        let words = tokenize(query);
+        let mut postings: Vec<&Vec<usize>> = Vec::new();
+        for word in &words {
+            match self.terms.get(word) {
+                Some(list) => postings.push(list),
+                None => return vec![],
+            }
+        }
+        postings.sort_by_key(|list| list.len());
+        let Some((first, rest)) = postings.split_first() else { return vec![] };
+
+        let query = words.join(" ");
+        let mut matches: Vec<(usize, &Book)> = first
+            .iter()
+            .filter(|i| rest.iter().all(|list| list.binary_search(i).is_ok()))
+            .map(|&i| &self.books[i])
+            .filter(|book| language.map(|l| book.languages.iter().any(|b| b.eq_ignore_ascii_case(l))).unwrap_or(true))
+            .map(|book| {
+                let title = tokenize(&book.title).join(" ");
+                let score = if title == query {
+                    3
+                } else if title.starts_with(&format!("{} ", query)) {
+                    2
+                } else if words.iter().all(|w| title.split(' ').any(|t| t == w)) {
+                    1
+                } else {
+                    0
+                };
+                (score, book)
+            })
+            .collect();
+
+        matches.sort_by(|(a_score, a), (b_score, b)| {
+            b_score.cmp(a_score).then(b.downloads.cmp(&a.downloads)).then(a.id.cmp(&b.id))
+        });
+        matches.into_iter().take(limit).map(|(_, book)| book.clone()).collect()
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+struct Loaded {
+    settings: GutenbergConfig,
+    at: Instant,
+    modified: Option<SystemTime>,
+}
+
+pub struct GutenbergCatalog {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    index: RwLock<Arc<Index>>,
+    loaded: Mutex<Option<Loaded>>,
+    pub heartbeat: Heartbeat,
+}
+
+impl GutenbergCatalog {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self {
+            client,
+            config,
+            index: RwLock::new(Arc::new(Index::default())),
+            loaded: Mutex::new(None),
+            heartbeat: Heartbeat::new(REFRESH_TICK),
+        }
+    }
+
+    pub fn search(&self, query: &str, language: Option<&str>, limit: usize) -> Vec<Book> {
+        let index = self.index.read().unwrap().clone();
+        index.search(query, language, limit)
+    }
+
+    pub fn book(&self, id: u32) -> Option<Book> {
+        let index = self.index.read().unwrap().clone();
+        index.by_id.get(&id).map(|&i| index.books[i].clone())
+    }
+
+    // The most downloaded books in the catalog
+    pub fn popular(&self, limit: usize) -> Vec<Book> {
+        let index = self.index.read().unwrap().clone();
+        let mut books: Vec<&Book> = index.books.iter().collect();
+        books.sort_by(|a, b| b.downloads.cmp(&a.downloads).then(a.id.cmp(&b.id)));
+        books.into_iter().take(limit).cloned().collect()
+    }
+
+    // Every value of a facet with its number of books, largest first
+    pub fn facet_values(&self, facet: Facet) -> Vec<(String, usize)> {
+        let index = self.index.read().unwrap().clone();
+        let mut values: Vec<(String, usize)> = index
+            .facets
+            .get(&facet)
+            .map(|values| values.iter().map(|(value, books)| (value.clone(), books.len())).collect())
+            .unwrap_or_default();
+        values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        values
+    }
+
+    // One page of the books under a facet value, most downloaded first, and the total count
+    pub fn books_with(&self, facet: Facet, value: &str, offset: usize, limit: usize) -> (Vec<Book>, usize) {
+        let index = self.index.read().unwrap().clone();
+        let Some(postings) = index.facets.get(&facet).and_then(|values| values.get(value)) else { return (vec![], 0) };
+        let books = postings.iter().skip(offset).take(limit).map(|&i| index.books[i].clone()).collect();
+        (books, postings.len())
+    }
+
+    pub fn spawn_refresher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_TICK);
+            loop {
+                interval.tick().await;
+                self.refresh_due().await;
+                self.heartbeat.beat();
+            }
+        });
+    }
+
+    // Reload when the catalog settings or files change, or the refresh interval passes.
+    // A catalog that fails to load keeps the previous index in place.
+    pub async fn refresh_due(&self) {
+        let settings = self.config.snapshot().gutenberg.clone();
+        let modified = match &settings.catalog {
+            Some(PlaylistLocation::File(path)) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || newest_modified(&path)).await.ok().flatten()
+            }
+            _ => None,
+        };
+
+        let mut loaded = self.loaded.lock().await;
+        let due = match loaded.as_ref() {
+            Some(last) => {
+                last.settings != settings
+                    || last.modified != modified
+                    || last.at.elapsed() >= Duration::from_secs(settings.refresh_secs)
+            }
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        *loaded = Some(Loaded { settings: settings.clone(), at: Instant::now(), modified });
+
+        let Some(location) = &settings.catalog else {
+            *self.index.write().unwrap() = Arc::new(Index::default());
+            return;
+        };
+        match self.load(location, &settings.mirror).await {
+            Ok(books) => {
+                log!("📚 Gutenberg catalog: {} books", books.len());
+                let index = tokio::task::spawn_blocking(move || Index::new(books)).await.unwrap_or_default();
+                *self.index.write().unwrap() = Arc::new(index);
+            }
+            Err(e) => eprintln!("❌ Gutenberg catalog failed: {}", e),
+        }
+    }
+
+    async fn load(&self, location: &PlaylistLocation, mirror: &str) -> Result<Vec<Book>, String> {
+        let mirror = mirror.trim_end_matches('/').to_string();
+        let (name, data) = match location {
+            PlaylistLocation::File(path) if path.is_dir() => {
+                let path = path.clone();
+                return tokio::task::spawn_blocking(move || read_rdf_dir(path, &mirror)).await.map_err(|e| e.to_string())?;
+            }
+            PlaylistLocation::File(path) => {
+                let data = match tokio::fs::read(path).await {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        log!("📚 No Gutenberg catalog at {}, Gutenberg results are off", path.display());
+                        return Ok(vec![]);
+                    }
+                    Err(e) => return Err(format!("{}: {}", path.display(), e)),
+                };
+                (path.to_string_lossy().to_lowercase(), data)
+            }
+            PlaylistLocation::Url(url) => {
+                let response = self.client.send(self.client.download(url)).await.map_err(|e| e.to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("HTTP {}", response.status()));
+                }
+                let data = self.client.read_body(response, BodyKind::Catalog).await.map_err(|e| e.to_string())?;
+                (url.split(['?', '#']).next().unwrap_or(url).to_lowercase(), data)
+            }
+        };
+
+        let (name, data) = match name.strip_suffix(".gz") {
+            Some(name) => (name.to_string(), self.client.gunzip(data, BodyKind::Catalog).await.map_err(|e| e.to_string())?),
+            None => (name, data),
+        };
+        tokio::task::spawn_blocking(move || {
+            if name.ends_with(".csv") {
+                parse_csv(&data, &mirror)
+            } else {
+                parse_rdf(&String::from_utf8_lossy(&data), &mirror)
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
+
+// pg_catalog.csv: Text#, Type, Issued, Title, Language, Authors, Subjects, LoCC, Bookshelves.
+// It carries no file list or download counts, so files are derived from the layout every
+// mirror serves under /cache/epub (and checked before use), and popular() goes by id.
+fn parse_csv(data: &[u8], mirror: &str) -> Result<Vec<Book>, String> {
+    let mut reader = csv::Reader::from_reader(data);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim() == name).ok_or_else(|| format!("catalog has no {} column", name));
+    let (id_col, type_col, issued_col, title_col) = (column("Text#")?, column("Type")?, column("Issued")?, column("Title")?);
+    let (language_col, authors_col, subjects_col) = (column("Language")?, column("Authors")?, column("Subjects")?);
+    let list = |field: &str| -> Vec<String> { field.split(';').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect() };
+
+    let mut books = Vec::new();
+    for record in reader.records() {
+        let Ok(record) = record else { continue };
+        let field = |col: usize| record.get(col).unwrap_or("").trim();
+        let Ok(id) = field(id_col).parse::<u32>() else { continue };
+        if field(type_col) != "Text" {
+            continue;
+        }
+
+        let mut book = Book {
+            id,
+            title: collapse(field(title_col)),
+            authors: list(field(authors_col)).iter().map(|a| author_name(a)).collect(),
+            subjects: list(field(subjects_col)),
+            languages: list(field(language_col)),
+            issued: Some(field(issued_col).to_string()).filter(|s| !s.is_empty()),
+            downloads: None,
+            files: vec![],
+        };
+        let base = format!("{}/cache/epub/{}/pg{}", mirror, id, id);
+        for (kind, suffix, mime) in [
+            (FileKind::Epub, "-images-3.epub", "application/epub+zip"),
+            (FileKind::Kindle, "-images-kf8.azw3", "application/x-mobipocket-ebook"),
+            (FileKind::Text, ".txt", "text/plain; charset=utf-8"),
+            (FileKind::Html, "-images.html", "text/html"),
+        ] {
+            book.add_file(BookFile { kind, url: format!("{}{}", base, suffix), mime: mime.to_string(), size: None, derived: true });
+        }
+        books.push(book);
+    }
+    Ok(books)
+}
+
+// "Austen, Jane, 1775-1817" or "Rolfe, W. J., 1850-1910 [Editor]" -> the name alone
+fn author_name(author: &str) -> String {
+    let author = author.split(" [").next().unwrap_or(author).trim();
+    match author.rsplit_once(", ") {
+        Some((name, dates)) if dates.chars().any(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => author.to_string(),
+    }
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A directory's own mtime only moves when entries are added or removed directly in it,
+// so for an RDF tree this is the newest mtime of anything under it
+fn newest_modified(path: &std::path::Path) -> Option<SystemTime> {
+    let mut newest = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            }
+            if let Ok(modified) = metadata.modified() {
+                newest = newest.max(modified);
+            }
+        }
+    }
+    Some(newest)
+}
+
+fn read_rdf_dir(dir: PathBuf, mirror: &str) -> Result<Vec<Book>, String> {
+    let mut books = Vec::new();
+    let mut pending = vec![dir];
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|e| e == "rdf") {
+                // One unreadable record shouldn't sink the whole catalog
+                match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|xml| parse_rdf(&xml, mirror)) {
+                    Ok(found) => books.extend(found),
+                    Err(e) => eprintln!("❌ Skipping {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+    Ok(books)
+}
+
+// One or more pgterms:ebook records, as in cache/epub/<id>/pg<id>.rdf
+fn parse_rdf(xml: &str, mirror: &str) -> Result<Vec<Book>, String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+    let is = |node: &roxmltree::Node, ns: &str, name: &str| node.is_element() && node.tag_name().namespace() == Some(ns) && node.tag_name().name() == name;
+    let text = |node: roxmltree::Node| node.text().map(collapse).filter(|t| !t.is_empty());
+    // Values are either direct text or wrapped in rdf:Description/rdf:value
+    let value = |node: roxmltree::Node| text(node).or_else(|| node.descendants().find(|n| is(n, RDF_NS, "value")).and_then(text));
+
+    let mut books = Vec::new();
+    for ebook in doc.descendants().filter(|n| is(n, PGTERMS_NS, "ebook")) {
+        let Some(id) = ebook
+            .attribute((RDF_NS, "about"))
+            .and_then(|about| about.rsplit('/').next())
+            .and_then(|id| id.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let children = || ebook.children().filter(|n| n.is_element());
+        if let Some(kind) = children().find(|n| is(n, DCTERMS_NS, "type")).and_then(value) {
+            if kind != "Text" {
+                continue;
+            }
+        }
+
+        let mut book = Book {
+            id,
+            title: children().find(|n| is(n, DCTERMS_NS, "title")).and_then(text).unwrap_or_default(),
+            authors: children()
+                .filter(|n| is(n, DCTERMS_NS, "creator"))
+                .filter_map(|n| n.descendants().find(|d| is(d, PGTERMS_NS, "name")).and_then(text))
+                .collect(),
+            // Library of Congress subject headings; the LoCC class codes are left out
+            subjects: children()
+                .filter(|n| is(n, DCTERMS_NS, "subject"))
+                .filter(|n| n.descendants().any(|d| is(&d, DCAM_NS, "memberOf") && d.attribute((RDF_NS, "resource")).is_some_and(|r| r.ends_with("LCSH"))))
+                .filter_map(value)
+                .collect(),
+            languages: children().filter(|n| is(n, DCTERMS_NS, "language")).filter_map(value).collect(),
+            issued: children().find(|n| is(n, DCTERMS_NS, "issued")).and_then(text),
+            downloads: children().find(|n| is(n, PGTERMS_NS, "downloads")).and_then(text).and_then(|d| d.parse().ok()),
+            files: vec![],
+        };
+
+        for file in ebook.descendants().filter(|n| is(n, PGTERMS_NS, "file")) {
+            let Some(url) = file.attribute((RDF_NS, "about")) else { continue };
+            let field = |name: &str| file.children().find(|n| is(n, DCTERMS_NS, name));
+            let Some(mime) = field("format").and_then(value) else { continue };
+            let Some(kind) = FileKind::from_mime(&mime) else { continue };
+            // Zipped HTML is listed as text/html too; only the page itself is wanted
+            if url.ends_with(".zip") {
+                continue;
+            }
+            book.add_file(BookFile {
+                kind,
+                url: on_mirror(url, mirror),
+                mime,
+                size: field("extent").and_then(text).and_then(|s| s.parse().ok()),
+                derived: false,
+            });
+        }
+        books.push(book);
+    }
+    Ok(books)
+}
+
+// RDF records link to gutenberg.org; the mirror must serve the same paths
+fn on_mirror(url: &str, mirror: &str) -> String {
+    for origin in [DEFAULT_MIRROR, "http://www.gutenberg.org"] {
+        if let Some(path) = url.strip_prefix(origin) {
+            return format!("{}{}", mirror, path);
+        }
+    }
+    url.to_string()
+}
+
+#[derive(Deserialize)]
+pub struct CatalogQuery {
+    q: String,
+    lang: Option<String>, // ISO 639 code, e.g. "en"
+    limit: Option<usize>,
+}
+
+// GET /api/gutenberg/search - catalog matches with authors, subjects and files
+pub async fn search_catalog(Query(params): Query<CatalogQuery>, State(state): State<AppState>) -> Json<Vec<Book>> {
+    Json(state.gutenberg.search(&params.q, params.lang.as_deref(), params.limit.unwrap_or(10)))
+}
+
+// GET /api/gutenberg/books/:id
+pub async fn get_book(Path(id): Path<u32>, State(state): State<AppState>) -> Result<Json<Book>, StatusCode> {
+    state.gutenberg.book(id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    const MIRROR: &str = "https://mirror.example/gutenberg";
+
+    fn rdf(id: u32, title: &str, kind: &str, files: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns:dcterms="http://purl.org/dc/terms/"
+  xmlns:pgterms="http://www.gutenberg.org/2009/pgterms/" xmlns:dcam="http://purl.org/dc/dcam/">
+  <pgterms:ebook rdf:about="ebooks/{id}">
+    <dcterms:title>{title}</dcterms:title>
+    <dcterms:type><rdf:Description><rdf:value>{kind}</rdf:value></rdf:Description></dcterms:type>
+    <dcterms:creator><pgterms:agent><pgterms:name>Austen, Jane</pgterms:name></pgterms:agent></dcterms:creator>
+    <dcterms:subject><rdf:Description><dcam:memberOf rdf:resource="http://purl.org/dc/terms/LCSH"/><rdf:value>Courtship -- Fiction</rdf:value></rdf:Description></dcterms:subject>
+    <dcterms:subject><rdf:Description><dcam:memberOf rdf:resource="http://purl.org/dc/terms/LCC"/><rdf:value>PR</rdf:value></rdf:Description></dcterms:subject>
+    <dcterms:language><rdf:Description><rdf:value>en</rdf:value></rdf:Description></dcterms:language>
+    <dcterms:issued>1998-06-01</dcterms:issued>
+    <pgterms:downloads>52000</pgterms:downloads>
+    {files}
+  </pgterms:ebook>
+</rdf:RDF>"#
+        )
+    }
+
+    fn file(url: &str, mime: &str) -> String {
+        format!(
+            r#"<dcterms:hasFormat><pgterms:file rdf:about="{url}"><dcterms:extent>1024</dcterms:extent>
+<dcterms:format><rdf:Description><rdf:value>{mime}</rdf:value></rdf:Description></dcterms:format></pgterms:file></dcterms:hasFormat>"#
+        )
+    }
+
+    fn book(id: u32, title: &str, authors: &[&str], language: &str, downloads: u64) -> Book {
+        Book {
+            id,
+            title: title.to_string(),
+            authors: authors.iter().map(|a| a.to_string()).collect(),
+            subjects: vec!["Fiction".to_string()],
+            languages: vec![language.to_string()],
+            issued: None,
+            downloads: Some(downloads),
+            files: vec![],
+        }
+    }
+
+    fn catalog(books: Vec<Book>, location: Option<PlaylistLocation>) -> GutenbergCatalog {
+        let mut config = ServerConfig::default();
+        config.gutenberg.catalog = location;
+        config.gutenberg.mirror = MIRROR.to_string();
+        let config = Arc::new(ConfigStore::fixed(config));
+        let catalog = GutenbergCatalog::new(GuardedClient::new(config.clone()), config);
+        *catalog.index.write().unwrap() = Arc::new(Index::new(books));
+        catalog
+    }
+
+    #[test]
+    fn rdf_records() {
+        let files = [
+            file("https://www.gutenberg.org/ebooks/1342.epub.noimages", "application/epub+zip"),
+            file("https://www.gutenberg.org/ebooks/1342.epub3.images", "application/epub+zip"),
+            file("https://www.gutenberg.org/ebooks/1342.txt.utf-8", "text/plain; charset=utf-8"),
+            file("https://www.gutenberg.org/files/1342/1342-0.txt", "text/plain; charset=iso-8859-1"),
+            file("https://www.gutenberg.org/cache/epub/1342/pg1342-h.zip", "text/html"),
+            file("http://www.gutenberg.org/ebooks/1342.html.images", "text/html"),
+            file("https://www.gutenberg.org/ebooks/1342.rdf", "application/rdf+xml"),
+        ]
+        .concat();
+        let books = parse_rdf(&rdf(1342, "Pride and\n   Prejudice", "Text", &files), MIRROR).unwrap();
+        assert_eq!(books.len(), 1);
+        let book = &books[0];
+        assert_eq!(book.id, 1342);
+        assert_eq!(book.title, "Pride and Prejudice");
+        assert_eq!(book.authors, vec!["Austen, Jane"]);
+        assert_eq!(book.subjects, vec!["Courtship -- Fiction"]);
+        assert_eq!(book.languages, vec!["en"]);
+        assert_eq!(book.issued.as_deref(), Some("1998-06-01"));
+        assert_eq!(book.downloads, Some(52000));
+
+        let urls: Vec<(FileKind, &str)> = book.files.iter().map(|f| (f.kind, f.url.as_str())).collect();
+        assert_eq!(
+            urls,
+            vec![
+                (FileKind::Epub, "https://mirror.example/gutenberg/ebooks/1342.epub3.images"),
+                (FileKind::Text, "https://mirror.example/gutenberg/ebooks/1342.txt.utf-8"),
+                (FileKind::Html, "https://mirror.example/gutenberg/ebooks/1342.html.images"),
+            ]
+        );
+        assert!(book.files.iter().all(|f| f.size == Some(1024) && !f.derived));
+
+        assert!(parse_rdf(&rdf(1, "Audio", "Sound", ""), MIRROR).unwrap().is_empty());
+        assert!(parse_rdf("<rdf:RDF", MIRROR).is_err());
+    }
+
+    #[test]
+    fn csv_records() {
+        let csv = "Text#,Type,Issued,Title,Language,Authors,Subjects,LoCC,Bookshelves
+1342,Text,1998-06-01,\"Pride and Prejudice\",en,\"Austen, Jane, 1775-1817\",\"Courtship -- Fiction; England -- Fiction\",PR,
+100,Text,1994-01-01,\"The Complete Works\",en,\"Shakespeare, William, 1564-1616; Rolfe, W. J., 1850-1910 [Editor]\",,PR,
+200,Sound,2003-01-01,\"An Audio Book\",en,\"Nobody\",,,
+abc,Text,2003-01-01,\"Bad id\",en,,,,
+";
+        let books = parse_csv(csv.as_bytes(), MIRROR).unwrap();
+        assert_eq!(books.iter().map(|b| b.id).collect::<Vec<_>>(), vec![1342, 100]);
+        assert_eq!(books[0].authors, vec!["Austen, Jane"]);
+        assert_eq!(books[0].subjects, vec!["Courtship -- Fiction", "England -- Fiction"]);
+        assert_eq!(books[1].authors, vec!["Shakespeare, William", "Rolfe, W. J."]);
+        assert!(books[1].subjects.is_empty());
+        assert_eq!(books[0].downloads, None);
+
+        // Files come from the mirror layout
+        let epub = books[0].file(FileKind::Epub).unwrap();
+        assert_eq!(epub.url, "https://mirror.example/gutenberg/cache/epub/1342/pg1342-images-3.epub");
+        assert!(epub.derived);
+        assert_eq!(books[0].file(FileKind::Text).unwrap().url, "https://mirror.example/gutenberg/cache/epub/1342/pg1342.txt");
+        assert_eq!(books[0].files.len(), 4);
+
+        assert_eq!(parse_csv(b"Text#,Type,Title\n1,Text,X\n", MIRROR).unwrap_err(), "catalog has no Issued column");
+    }
+
+    #[test]
+    fn search_and_facets() {
+        let catalog = catalog(
+            vec![
+                book(1342, "Pride and Prejudice", &["Austen, Jane"], "en", 50000),
+                book(42671, "Pride and Prejudice and Zombies", &["Grahame-Smith, Seth"], "en", 90000),
+                book(158, "Emma", &["Austen, Jane"], "en", 20000),
+                book(26000, "Orgullo y prejuicio", &["Austen, Jane"], "es", 1000),
+                book(1342, "Duplicate id", &["Nobody"], "en", 1),
+            ],
+            None,
+        );
+
+        // The exact title beats a more downloaded one that merely starts with it
+        let ids = |books: Vec<Book>| books.into_iter().map(|b| b.id).collect::<Vec<_>>();
+        assert_eq!(ids(catalog.search("Pride and Prejudice", None, 10)), vec![1342, 42671]);
+        assert_eq!(ids(catalog.search("PRIDE and prejudice!", None, 1)), vec![1342]);
+        // Every word has to match somewhere
+        assert!(catalog.search("pride zebra", None, 10).is_empty());
+        assert!(catalog.search("", None, 10).is_empty());
+        assert_eq!(ids(catalog.search("austen", None, 10)), vec![1342, 158, 26000]);
+        assert_eq!(ids(catalog.search("jane austen", Some("ES"), 10)), vec![26000]);
+        assert_eq!(ids(catalog.search("emma austen", None, 10)), vec![158]);
+
+        assert_eq!(catalog.book(1342).unwrap().title, "Pride and Prejudice");
+        assert_eq!(ids(catalog.popular(2)), vec![42671, 1342]);
+
+        let authors = catalog.facet_values(Facet::Author);
+        assert_eq!(authors[0], ("Austen, Jane".to_string(), 3));
+        assert_eq!(catalog.facet_values(Facet::Language), vec![("en".to_string(), 3), ("es".to_string(), 1)]);
+        let (page, total) = catalog.books_with(Facet::Author, "Austen, Jane", 1, 1);
+        assert_eq!((ids(page), total), (vec![158], 3));
+        assert_eq!(catalog.books_with(Facet::Subject, "Nothing", 0, 10).1, 0);
+    }
+
+    #[tokio::test]
+    async fn nested_rdf_changes_trigger_a_reload() {
+        let dir = std::env::temp_dir().join(format!("content-server-gutenberg-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("1342")).unwrap();
+        std::fs::create_dir_all(dir.join("158")).unwrap();
+        std::fs::write(dir.join("1342").join("pg1342.rdf"), rdf(1342, "Pride and Prejudice", "Text", "")).unwrap();
+
+        let catalog = catalog(vec![], Some(PlaylistLocation::File(dir.clone())));
+        catalog.refresh_due().await;
+        assert_eq!(catalog.popular(10).len(), 1);
+
+        // A record appearing two levels down leaves the top directory's mtime alone
+        let top = std::fs::metadata(&dir).unwrap().modified().unwrap();
+        let record = dir.join("158").join("pg158.rdf");
+        std::fs::write(&record, rdf(158, "Emma", "Text", "")).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&record).unwrap().set_modified(later).unwrap();
+        assert_eq!(std::fs::metadata(&dir).unwrap().modified().unwrap(), top);
+
+        catalog.refresh_due().await;
+        assert_eq!(catalog.popular(10).len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,487 @@
+// HEALTH - Liveness, readiness and a detailed report built from what the server has actually seen
+//
+// Nothing here probes upstreams on request: provider status comes from the
+// checks searches, test plans and jobs already ran, playlist status from the
+// last refresh, and background task status from heartbeats the loops leave
+// behind. A health request is cheap enough for an orchestrator to poll.
+use crate::config::HealthConfig;
+use crate::jobs::JobStatus;
+use crate::segment_cache::CacheStats;
+use crate::verify::{FailureClass, VerificationResult};
+use crate::AppState;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl Status {
+    fn worst(statuses: impl IntoIterator<Item = Status>) -> Status {
+        statuses.into_iter().max().unwrap_or(Status::Healthy)
+    }
+}
+
+// Left behind by a background loop each time it finishes a pass
+pub struct Heartbeat {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last: Mutex::new(None) }
+    }
+
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn report(&self, grace: Duration) -> TaskHealth {
+        let since = self.last.lock().unwrap().map(|last| last.elapsed());
+        // Lag is how far past its next expected pass the loop is
+        let lag = since.map(|s| s.saturating_sub(self.interval)).unwrap_or_default();
+        TaskHealth {
+            status: match since {
+                Some(_) if lag <= grace => Status::Healthy,
+                _ => Status::Degraded,
+            },
+            interval_secs: self.interval.as_secs(),
+            last_run_secs_ago: since.map(|s| s.as_secs()),
+            lag_secs: lag.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub category: String,
+    pub status: Status,
+    pub checks: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_checked_at: DateTime<Utc>,
+    pub last_ok_at: Option<DateTime<Utc>>,
+    pub last_failure: Option<FailureClass>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategoryHealth {
+    pub status: Status,
+    pub providers: BTreeMap<String, ProviderHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskHealth {
+    pub status: Status,
+    pub interval_secs: u64,
+    pub last_run_secs_ago: Option<u64>,
+    pub lag_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistHealth {
+    pub status: Status,
+    pub channels: usize,
+    pub refreshed_secs_ago: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheHealth {
+    pub status: Status,
+    pub segment_cache: CacheStats,
+    pub segment_cache_enabled: bool,
+    pub segment_cache_max_bytes: u64,
+    pub search_cache_entries: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobsHealth {
+    pub status: Status,
+    pub running: usize,
+    pub oldest_running_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub timestamp: DateTime<Utc>,
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub categories: BTreeMap<String, CategoryHealth>,
+    pub playlists: BTreeMap<String, PlaylistHealth>,
+    pub caches: CacheHealth,
+    pub tasks: BTreeMap<&'static str, TaskHealth>,
+    pub jobs: JobsHealth,
+    pub config_error: Option<String>,
+}
+
+// Outcome of the latest checks against each provider (a movie site, a playlist...)
+pub struct HealthMonitor {
+    started_at: Instant,
+    providers: Mutex<HashMap<String, ProviderHealth>>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> Self {
+        Self { started_at: Instant::now(), providers: Mutex::new(HashMap::new()) }
+    }
+
+    // Unverified results say nothing about the provider and are ignored
+    pub fn record(&self, category: &str, provider: &str, result: Option<&VerificationResult>) {
+        let Some(result) = result else { return };
+        let mut providers = self.providers.lock().unwrap();
+        let entry = providers.entry(provider.to_string()).or_insert_with(|| ProviderHealth {
+            category: category.to_string(),
+            status: Status::Healthy,
+            checks: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_checked_at: result.checked_at,
+            last_ok_at: None,
+            last_failure: None,
+            last_error: None,
+        });
+
+        entry.checks += 1;
+        entry.last_checked_at = result.checked_at;
+        entry.last_failure = result.failure;
+        entry.last_error = result.error.clone();
+        if result.ok {
+            entry.consecutive_failures = 0;
+            entry.last_ok_at = Some(result.checked_at);
+        } else {
+            entry.failures += 1;
+            entry.consecutive_failures += 1;
+        }
+    }
+
+    fn categories(&self, down_after: u32) -> BTreeMap<String, CategoryHealth> {
+        let mut categories: BTreeMap<String, CategoryHealth> = BTreeMap::new();
+        for (name, provider) in self.providers.lock().unwrap().iter() {
+            let mut provider = provider.clone();
+            provider.status = match provider.consecutive_failures {
+                0 => Status::Healthy,
+                n if n < down_after => Status::Degraded,
+                _ => Status::Down,
+            };
+            categories
+                .entry(provider.category.clone())
+                .or_insert_with(|| CategoryHealth { status: Status::Healthy, providers: BTreeMap::new() })
+                .providers
+                .insert(name.clone(), provider);
+        }
+
+        // A category is down only when none of its providers work
+        for category in categories.values_mut() {
+            let statuses: Vec<Status> = category.providers.values().map(|p| p.status).collect();
+            category.status = if statuses.iter().all(|s| *s == Status::Down) {
+                Status::Down
+            } else {
+                Status::worst(statuses).min(Status::Degraded)
+            };
+        }
+        categories
+    }
+}
+
+pub async fn report(state: &AppState) -> HealthReport {
+    let config = state.config.snapshot();
+    let grace = Duration::from_secs(config.health.heartbeat_grace_secs);
+    let categories = state.health.categories(config.health.down_after_failures);
+
+    let mut playlists = BTreeMap::new();
+    for source in state.playlists.status().await {
+        let status = match (&source.error, source.channels) {
+            (None, _) => Status::Healthy,
+            // Still serving the last good list
+            (Some(_), n) if n > 0 => Status::Degraded,
+            (Some(_), _) => Status::Down,
+        };
+        playlists.insert(
+            source.name,
+            PlaylistHealth { status, channels: source.channels, refreshed_secs_ago: source.refreshed_secs_ago, error: source.error },
+        );
+    }
+
+    let segment_cache = state.segments.stats();
+    let caches = CacheHealth {
+        status: cache_status(&segment_cache, &config.health),
+        segment_cache,
+        segment_cache_enabled: config.segment_cache.enabled,
+        segment_cache_max_bytes: config.segment_cache.max_bytes,
+        search_cache_entries: state.cache.read().await.len(),
+    };
+
+    let mut tasks = BTreeMap::new();
+    tasks.insert("config_watcher", state.config.heartbeat.report(grace));
+    tasks.insert("playlist_refresher", state.playlists.heartbeat.report(grace));
+    tasks.insert("dvr_janitor", state.dvr.heartbeat.report(grace));
+    tasks.insert("gutenberg_refresher", state.gutenberg.heartbeat.report(grace));
+    tasks.insert("mirror_worker", state.mirror.heartbeat.report(grace));
+    if let Some(heartbeat) = state.fulltext.heartbeat() {
+        tasks.insert("fulltext_indexer", heartbeat.report(grace));
+    }
+
+    let running: Vec<_> = state.jobs.list().await.into_iter().filter(|j| j.status == JobStatus::Running).collect();
+    let oldest_running_secs = running.iter().map(|j| (Utc::now() - j.created_at).num_seconds()).max();
+    let jobs = JobsHealth {
+        status: match oldest_running_secs.unwrap_or(0).max(0) as u64 {
+            secs if secs >= config.health.job_down_secs => Status::Down,
+            secs if secs >= config.health.job_lag_secs => Status::Degraded,
+            _ => Status::Healthy,
+        },
+        running: running.len(),
+        oldest_running_secs,
+    };
+
+    let config_error = state.config.last_error();
+
+    // Down when nothing can be served: every category we have seen checked is down
+    // and no playlist is handing out channels. Anything short of perfect is degraded.
+    let content_down = !categories.is_empty() && categories.values().all(|c| c.status == Status::Down);
+    let live_down = playlists.values().all(|p| p.channels == 0);
+    let status = if content_down && live_down {
+        Status::Down
+    } else {
+        Status::worst(
+            categories
+                .values()
+                .map(|c| c.status)
+                .chain(playlists.values().map(|p| p.status))
+                .chain(tasks.values().map(|t| t.status))
+                .chain([caches.status, jobs.status])
+                .chain(config_error.as_ref().map(|_| Status::Degraded)),
+        )
+        .min(Status::Degraded)
+    };
+
+    HealthReport {
+        status,
+        timestamp: Utc::now(),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: state.health.started_at.elapsed().as_secs(),
+        categories,
+        playlists,
+        caches,
+        tasks,
+        jobs,
+        config_error,
+    }
+}
+
+// Down when every recent fetch failed, degraded on a high error ratio or eviction churn
+fn cache_status(stats: &CacheStats, health: &HealthConfig) -> Status {
+    let fetches = stats.recent_fetches;
+    let error_ratio = if fetches == 0 { 0.0 } else { stats.recent_errors as f64 / fetches as f64 };
+    if fetches >= health.down_after_failures as usize && stats.recent_errors == fetches {
+        Status::Down
+    } else if (fetches > 0 && error_ratio >= health.cache_error_ratio) || stats.recent_evictions > health.cache_max_evictions {
+        Status::Degraded
+    } else {
+        Status::Healthy
+    }
+}
+
+// GET /health - detailed report; 503 only when the server is down
+pub async fn health(State(state): State<AppState>) -> Response {
+    let report = report(&state).await;
+    let code = if report.status == Status::Down { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+    (code, Json(report)).into_response()
+}
+
+// GET /health/live - the process is up and answering requests
+pub async fn live(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status": "alive",
+        "uptime_secs": state.health.started_at.elapsed().as_secs()
+    }))
+}
+
+// GET /health/ready - worth sending traffic to; degraded still counts as ready
+pub async fn ready(State(state): State<AppState>) -> Response {
+    let report = report(&state).await;
+    let ready = report.status != Status::Down;
+    let code = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(serde_json::json!({ "ready": ready, "status": report.status }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigStore, ServerConfig};
+    use crate::segment_cache::{CachedSegment, SegmentCache};
+    use axum::body::Bytes;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn result(ok: bool) -> VerificationResult {
+        VerificationResult {
+            url: "https://example.org/watch".to_string(),
+            ok,
+            status_code: Some(if ok { 200 } else { 503 }),
+            final_url: None,
+            latency_ms: 10,
+            ttfb_ms: None,
+            content_type: None,
+            content_kind: None,
+            content_length: None,
+            sha256: None,
+            failure: (!ok).then_some(FailureClass::Http5xx),
+            error: (!ok).then(|| "HTTP 503".to_string()),
+            checked_at: Utc::now(),
+        }
+    }
+
+    // A server with nothing on disk and no playlists, all of whose loops just ran
+    async fn state(dir: &Path) -> AppState {
+        let mut config = ServerConfig { playlists: vec![], ..ServerConfig::default() };
+        config.gutenberg.catalog = None;
+        config.dvr.directory = dir.join("recordings");
+        config.mirror.directory = dir.join("mirror");
+        config.epub.directory = dir.join("epub");
+        config.history.path = dir.join("history.jsonl");
+        let state = crate::state_from(Arc::new(ConfigStore::fixed(config))).await;
+        for heartbeat in [&state.config.heartbeat, &state.playlists.heartbeat, &state.dvr.heartbeat, &state.gutenberg.heartbeat, &state.mirror.heartbeat] {
+            heartbeat.beat();
+        }
+        state
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn heartbeats_go_stale() {
+        let heartbeat = Heartbeat::new(Duration::from_secs(60));
+        let grace = Duration::from_secs(30);
+        let never = heartbeat.report(grace);
+        assert_eq!((never.status, never.last_run_secs_ago), (Status::Degraded, None));
+
+        heartbeat.beat();
+        assert_eq!(heartbeat.report(grace).status, Status::Healthy);
+
+        // Within the grace period past the interval, then beyond it
+        *heartbeat.last.lock().unwrap() = Instant::now().checked_sub(Duration::from_secs(80));
+        assert_eq!(heartbeat.report(grace).status, Status::Healthy);
+        *heartbeat.last.lock().unwrap() = Instant::now().checked_sub(Duration::from_secs(100));
+        let stale = heartbeat.report(grace);
+        assert_eq!((stale.status, stale.last_run_secs_ago, stale.lag_secs), (Status::Degraded, Some(100), 40));
+    }
+
+    #[test]
+    fn providers_go_down_after_failures_in_a_row() {
+        let monitor = HealthMonitor::new();
+        let status = |monitor: &HealthMonitor| {
+            let categories = monitor.categories(3);
+            let movies = &categories["movie"];
+            (movies.status, movies.providers["one"].status, movies.providers["one"].consecutive_failures)
+        };
+
+        monitor.record("movie", "one", Some(&result(true)));
+        monitor.record("movie", "one", None);
+        assert_eq!(status(&monitor), (Status::Healthy, Status::Healthy, 0));
+        monitor.record("movie", "one", Some(&result(false)));
+        monitor.record("movie", "one", Some(&result(false)));
+        assert_eq!(status(&monitor), (Status::Degraded, Status::Degraded, 2));
+        monitor.record("movie", "one", Some(&result(false)));
+        assert_eq!(status(&monitor), (Status::Down, Status::Down, 3));
+
+        // A category with one working provider is only degraded, and one success resets the count
+        monitor.record("movie", "two", Some(&result(true)));
+        assert_eq!(status(&monitor), (Status::Degraded, Status::Down, 3));
+        monitor.record("movie", "one", Some(&result(true)));
+        assert_eq!(status(&monitor), (Status::Healthy, Status::Healthy, 0));
+        let one = &monitor.categories(3)["movie"].providers["one"];
+        assert_eq!((one.checks, one.failures), (5, 3));
+    }
+
+    #[tokio::test]
+    async fn cache_health_follows_errors_and_evictions() {
+        let health = HealthConfig { cache_max_evictions: 1, ..HealthConfig::default() };
+        let limits = crate::config::SegmentCacheConfig { enabled: true, max_bytes: 100, max_segment_bytes: 100, ttl_secs: 60 };
+        let segment = || async { Some(CachedSegment::new(Bytes::from(vec![0u8; 60]), None)) };
+
+        let cache = SegmentCache::new();
+        assert_eq!(cache_status(&cache.stats(), &health), Status::Healthy);
+        for key in ["a", "b"] {
+            cache.get_or_fetch(key, &limits, || async { None }).await;
+        }
+        // Failing, but not yet enough fetches to call it down
+        assert_eq!(cache_status(&cache.stats(), &health), Status::Degraded);
+        cache.get_or_fetch("c", &limits, || async { None }).await;
+        assert_eq!(cache_status(&cache.stats(), &health), Status::Down);
+
+        // One error in four meets the 25% ratio
+        let cache = SegmentCache::new();
+        cache.get_or_fetch("a", &limits, || async { None }).await;
+        for key in ["b", "c", "d"] {
+            cache.get_or_fetch(key, &limits, segment).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.recent_fetches, stats.recent_errors), (4, 1));
+        // Each 60-byte segment pushed out the one before it
+        assert_eq!(stats.recent_evictions, 2);
+        assert_eq!(cache_status(&stats, &health), Status::Degraded);
+        // Still degraded on errors alone once the evictions are allowed, healthy once both are
+        assert_eq!(cache_status(&stats, &HealthConfig { cache_max_evictions: 2, ..HealthConfig::default() }), Status::Degraded);
+        let relaxed = HealthConfig { cache_max_evictions: 2, cache_error_ratio: 0.5, ..HealthConfig::default() };
+        assert_eq!(cache_status(&stats, &relaxed), Status::Healthy);
+    }
+
+    #[tokio::test]
+    async fn only_a_down_server_is_unready() {
+        let dir = std::env::temp_dir().join(format!("content-server-health-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let state = state(&dir).await;
+
+        let live = live(State(state.clone())).await;
+        assert_eq!(live.0["status"], "alive");
+
+        let check = |state: AppState| async move {
+            let ready = ready(State(state.clone())).await;
+            let health = health(State(state)).await;
+            let code = (ready.status(), health.status());
+            (code, body(ready).await["status"].as_str().unwrap().to_string())
+        };
+        assert_eq!(check(state.clone()).await, ((StatusCode::OK, StatusCode::OK), "healthy".to_string()));
+
+        // A stale loop or a failing provider degrades the server but leaves it ready
+        *state.dvr.heartbeat.last.lock().unwrap() = None;
+        state.health.record("movie", "one", Some(&result(false)));
+        assert_eq!(check(state.clone()).await, ((StatusCode::OK, StatusCode::OK), "degraded".to_string()));
+        let report = report(&state).await;
+        assert_eq!((report.tasks["dvr_janitor"].status, report.categories["movie"].status), (Status::Degraded, Status::Degraded));
+
+        // Every category down with no live channels to fall back on
+        for _ in 0..2 {
+            state.health.record("movie", "one", Some(&result(false)));
+        }
+        state.health.record("book", "library", None);
+        for _ in 0..3 {
+            state.health.record("book", "library", Some(&result(false)));
+        }
+        let unavailable = StatusCode::SERVICE_UNAVAILABLE;
+        assert_eq!(check(state.clone()).await, ((unavailable, unavailable), "down".to_string()));
+
+        // One provider coming back is enough to take traffic again
+        state.health.record("book", "library", Some(&result(true)));
+        assert_eq!(check(state.clone()).await, ((StatusCode::OK, StatusCode::OK), "degraded".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
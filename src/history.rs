@@ -0,0 +1,443 @@
+// VERIFICATION HISTORY - Test plan runs kept on disk, with success-rate trends per category and source
+use crate::config::{ConfigStore, HistoryConfig};
+use crate::test_plan::TestReport;
+use crate::verify::FailureClass;
+use crate::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+const DEFAULT_WINDOW: &str = "7d";
+const DEFAULT_BUCKET: &str = "1h";
+const MAX_BUCKETS: i64 = 10_000;
+
+pub struct VerificationHistory {
+    config: Arc<ConfigStore>,
+    // Serializes appends and rewrites of the history file
+    file: Mutex<Option<Instant>>,
+}
+
+impl VerificationHistory {
+    pub fn new(config: Arc<ConfigStore>) -> Self {
+        Self { config, file: Mutex::new(None) }
+    }
+
+    fn settings(&self) -> HistoryConfig {
+        self.config.snapshot().history.clone()
+    }
+
+    // Append one run as a JSON line, dropping expired runs at most once an hour
+    pub async fn record(&self, report: &TestReport) -> Result<(), String> {
+        let settings = self.settings();
+        if !settings.enabled {
+            return Ok(());
+        }
+        let mut last_pruned = self.file.lock().await;
+
+        if let Some(dir) = settings.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+        }
+        let mut line = serde_json::to_string(report).map_err(|e| e.to_string())?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.write_all(line.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        if last_pruned.map(|at| at.elapsed() >= PRUNE_INTERVAL).unwrap_or(true) {
+            *last_pruned = Some(Instant::now());
+            let cutoff = Utc::now().checked_sub_signed(retention(&settings)).unwrap_or(DateTime::<Utc>::MIN_UTC);
+            let runs = read_runs(&settings, cutoff).await?;
+            let mut kept = String::new();
+            for run in &runs {
+                kept.push_str(&serde_json::to_string(run).map_err(|e| e.to_string())?);
+                kept.push('\n');
+            }
+            tokio::fs::write(&settings.path, kept).await.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Runs that started at or after `since`, oldest first
+    pub async fn runs(&self, since: DateTime<Utc>) -> Result<Vec<TestReport>, String> {
+        let settings = self.settings();
+        let _file = self.file.lock().await;
+        read_runs(&settings, since).await
+    }
+}
+
+// How far back history goes, and so how far back a window may look
+fn retention(settings: &HistoryConfig) -> Duration {
+    Duration::try_days(settings.retention_days as i64).unwrap_or(Duration::MAX)
+}
+
+// A line that doesn't parse (a write cut off by a crash) is skipped, not fatal
+async fn read_runs(settings: &HistoryConfig, since: DateTime<Utc>) -> Result<Vec<TestReport>, String> {
+    let raw = match tokio::fs::read_to_string(&settings.path).await {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut runs: Vec<TestReport> = raw
+        .lines()
+        .filter_map(|line| serde_json::from_str::<TestReport>(line).ok())
+        .filter(|run| run.started_at >= since)
+        .collect();
+    runs.sort_by_key(|run| run.started_at);
+    Ok(runs)
+}
+
+// "90s", "30m", "24h", "7d", "2w" or plain seconds
+fn parse_span(span: &str) -> Option<Duration> {
+    let span = span.trim();
+    let (number, unit) = match span.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => span.split_at(i),
+        None => (span, "s"),
+    };
+    let number: i64 = number.parse().ok().filter(|n| *n > 0)?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return None,
+    };
+    number.checked_mul(seconds).and_then(Duration::try_seconds)
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct TrendPoint {
+    pub start: DateTime<Utc>,
+    pub tested: usize,
+    pub working: usize,
+    pub success_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceTrend {
+    pub category: String,
+    // Start of the current run of failures, if the latest check failed
+    pub failing_since: Option<DateTime<Utc>>,
+    pub last_failure: Option<FailureClass>,
+    pub points: Vec<TrendPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Trends<T> {
+    pub window_secs: i64,
+    pub bucket_secs: i64,
+    pub runs: usize,
+    pub series: BTreeMap<String, T>,
+}
+
+#[derive(Deserialize)]
+pub struct TrendQuery {
+    window: Option<String>,   // how far back to look (default 7d)
+    bucket: Option<String>,   // width of each point (default 1h)
+    plan: Option<String>,     // only runs of this plan
+    category: Option<String>, // only this category
+}
+
+#[derive(Deserialize)]
+pub struct RunsQuery {
+    window: Option<String>,
+    plan: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub plan: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub passed: bool,
+    pub categories: BTreeMap<String, TrendPoint>,
+}
+
+struct Window {
+    since: DateTime<Utc>,
+    span: Duration,
+    bucket: Duration,
+}
+
+impl Window {
+    // Neither the window nor a bucket may be longer than the history kept
+    fn parse(window: Option<&str>, bucket: Option<&str>, kept: Duration) -> Result<Self, (StatusCode, String)> {
+        let invalid = |what: &str, value: &str| (StatusCode::BAD_REQUEST, format!("Invalid {}: {}", what, value));
+        let too_long = |what: &str, value: &str| {
+            (StatusCode::BAD_REQUEST, format!("{} {} is longer than the {} days of history kept", what, value, kept.num_days()))
+        };
+        let window = window.unwrap_or(DEFAULT_WINDOW);
+        let bucket = bucket.unwrap_or(DEFAULT_BUCKET);
+        let span = parse_span(window).ok_or_else(|| invalid("window", window))?;
+        let width = parse_span(bucket).ok_or_else(|| invalid("bucket", bucket))?;
+        if span > kept {
+            return Err(too_long("Window", window));
+        }
+        if width > kept {
+            return Err(too_long("Bucket", bucket));
+        }
+        if span.num_seconds() / width.num_seconds() > MAX_BUCKETS {
+            return Err((StatusCode::BAD_REQUEST, format!("Window {} holds more than {} buckets of {}", window, MAX_BUCKETS, bucket)));
+        }
+        let since = Utc::now().checked_sub_signed(span).ok_or_else(|| invalid("window", window))?;
+        Ok(Self { since, span, bucket: width })
+    }
+
+    // Buckets line up on multiples of their width so points are stable between calls
+    fn bucket_start(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let width = self.bucket.num_seconds();
+        let start = at.timestamp().div_euclid(width) * width;
+        Utc.timestamp_opt(start, 0).single().unwrap_or(at)
+    }
+}
+
+fn add(points: &mut BTreeMap<DateTime<Utc>, TrendPoint>, start: DateTime<Utc>, tested: usize, working: usize) {
+    let point = points.entry(start).or_insert_with(|| TrendPoint { start, ..Default::default() });
+    point.tested += tested;
+    point.working += working;
+    point.success_rate = if point.tested == 0 { 0.0 } else { point.working as f64 / point.tested as f64 * 100.0 };
+}
+
+fn kept(state: &AppState) -> Duration {
+    retention(&state.config.snapshot().history)
+}
+
+async fn load(state: &AppState, since: DateTime<Utc>, plan: Option<&str>) -> Result<Vec<TestReport>, (StatusCode, String)> {
+    let runs = state.history.runs(since).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(runs.into_iter().filter(|run| plan.map(|p| run.plan == p).unwrap_or(true)).collect())
+}
+
+// GET /api/history/runs - recent runs, newest first, without per-source detail
+pub async fn list_runs(
+    Query(params): Query<RunsQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<RunSummary>>, (StatusCode, String)> {
+    let window = Window::parse(params.window.as_deref(), None, kept(&state))?;
+    let runs = load(&state, window.since, params.plan.as_deref()).await?;
+    Ok(Json(
+        runs.into_iter()
+            .rev()
+            .take(params.limit.unwrap_or(50))
+            .map(|run| RunSummary {
+                categories: run
+                    .categories
+                    .iter()
+                    .map(|c| {
+                        let point = TrendPoint { start: run.started_at, tested: c.tested, working: c.working, success_rate: c.success_rate };
+                        (c.name.clone(), point)
+                    })
+                    .collect(),
+                plan: run.plan,
+                started_at: run.started_at,
+                finished_at: run.finished_at,
+                passed: run.passed,
+            })
+            .collect(),
+    ))
+}
+
+// GET /api/history/categories - success rate per category over time
+pub async fn category_trends(
+    Query(params): Query<TrendQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Trends<Vec<TrendPoint>>>, (StatusCode, String)> {
+    let window = Window::parse(params.window.as_deref(), params.bucket.as_deref(), kept(&state))?;
+    let runs = load(&state, window.since, params.plan.as_deref()).await?;
+    Ok(Json(Trends {
+        window_secs: window.span.num_seconds(),
+        bucket_secs: window.bucket.num_seconds(),
+        runs: runs.len(),
+        series: category_series(&runs, &window, params.category.as_deref()),
+    }))
+}
+
+fn category_series(runs: &[TestReport], window: &Window, only: Option<&str>) -> BTreeMap<String, Vec<TrendPoint>> {
+    let mut series: BTreeMap<String, BTreeMap<DateTime<Utc>, TrendPoint>> = BTreeMap::new();
+    for run in runs {
+        let start = window.bucket_start(run.started_at);
+        for category in &run.categories {
+            if only.is_some_and(|c| c != category.name) {
+                continue;
+            }
+            add(series.entry(category.name.clone()).or_default(), start, category.tested, category.working);
+        }
+    }
+    series.into_iter().map(|(name, points)| (name, points.into_values().collect())).collect()
+}
+
+// GET /api/history/sources - success rate per source over time, and when each started failing
+pub async fn source_trends(
+    Query(params): Query<TrendQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Trends<SourceTrend>>, (StatusCode, String)> {
+    let window = Window::parse(params.window.as_deref(), params.bucket.as_deref(), kept(&state))?;
+    let runs = load(&state, window.since, params.plan.as_deref()).await?;
+    Ok(Json(Trends {
+        window_secs: window.span.num_seconds(),
+        bucket_secs: window.bucket.num_seconds(),
+        runs: runs.len(),
+        series: source_series(&runs, &window, params.category.as_deref()),
+    }))
+}
+
+fn source_series(runs: &[TestReport], window: &Window, only: Option<&str>) -> BTreeMap<String, SourceTrend> {
+    let mut series: BTreeMap<String, (SourceTrend, BTreeMap<DateTime<Utc>, TrendPoint>)> = BTreeMap::new();
+    for run in runs {
+        let start = window.bucket_start(run.started_at);
+        for category in &run.categories {
+            if only.is_some_and(|c| c != category.name) {
+                continue;
+            }
+            for case in &category.cases {
+                let (trend, points) = series.entry(case.source.clone()).or_insert_with(|| {
+                    let trend = SourceTrend { category: category.name.clone(), failing_since: None, last_failure: None, points: vec![] };
+                    (trend, BTreeMap::new())
+                });
+                add(points, start, 1, usize::from(case.ok));
+                if case.ok {
+                    trend.failing_since = None;
+                    trend.last_failure = None;
+                } else {
+                    trend.failing_since.get_or_insert(run.started_at);
+                    trend.last_failure = case.failure;
+                }
+            }
+        }
+    }
+    series
+        .into_iter()
+        .map(|(source, (mut trend, points))| {
+            trend.points = points.into_values().collect();
+            (source, trend)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plan::{CategoryReport, TestCase};
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn window(bucket: &str) -> Window {
+        Window::parse(Some("30d"), Some(bucket), Duration::days(90)).unwrap()
+    }
+
+    fn case(source: &str, ok: bool) -> TestCase {
+        TestCase {
+            query: "q".to_string(),
+            id: format!("{}_1", source),
+            title: "t".to_string(),
+            source: source.to_string(),
+            url: "https://example.com/".to_string(),
+            ok,
+            latency_ms: 10,
+            failure: (!ok).then_some(FailureClass::Timeout),
+            error: None,
+        }
+    }
+
+    fn run(started: &str, categories: Vec<(&str, Vec<TestCase>)>) -> TestReport {
+        TestReport {
+            plan: "default".to_string(),
+            started_at: at(started),
+            finished_at: at(started),
+            passed: true,
+            categories: categories
+                .into_iter()
+                .map(|(name, cases)| {
+                    let working = cases.iter().filter(|c| c.ok).count();
+                    CategoryReport {
+                        name: name.to_string(),
+                        content_type: name.to_string(),
+                        tested: cases.len(),
+                        working,
+                        success_rate: working as f64 / cases.len() as f64 * 100.0,
+                        min_success_rate: 50.0,
+                        passed: true,
+                        cases,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn spans() {
+        assert_eq!(parse_span("90"), Some(Duration::seconds(90)));
+        assert_eq!(parse_span(" 30m "), Some(Duration::minutes(30)));
+        assert_eq!(parse_span("24h"), Some(Duration::hours(24)));
+        assert_eq!(parse_span("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_span("2w"), Some(Duration::weeks(2)));
+        for bad in ["", "0h", "-1h", "1y", "h", "1.5h", "1hh", "99999999999999999999s"] {
+            assert_eq!(parse_span(bad), None, "{}", bad);
+        }
+        // Fits in an i64 of seconds but not in a TimeDelta
+        assert_eq!(parse_span("9223372036854776s"), None);
+    }
+
+    #[test]
+    fn windows_stay_within_history() {
+        let kept = Duration::days(90);
+        assert!(Window::parse(None, None, kept).is_ok());
+        assert!(Window::parse(Some("90d"), Some("1d"), kept).is_ok());
+        for (window, bucket) in [("100000000w", "100000000w"), ("91d", "1d"), ("7d", "13w"), ("7d", "1s"), ("7d", "nope")] {
+            let error = Window::parse(Some(window), Some(bucket), kept).err();
+            assert_eq!(error.map(|(status, _)| status), Some(StatusCode::BAD_REQUEST), "{} / {}", window, bucket);
+        }
+    }
+
+    #[test]
+    fn buckets_line_up_on_their_width() {
+        let hourly = window("1h");
+        assert_eq!(hourly.bucket_start(at("2026-03-04T05:59:59Z")), at("2026-03-04T05:00:00Z"));
+        assert_eq!(hourly.bucket_start(at("2026-03-04T06:00:00Z")), at("2026-03-04T06:00:00Z"));
+        let daily = window("1d");
+        assert_eq!(daily.bucket_start(at("2026-03-04T23:10:00Z")), at("2026-03-04T00:00:00Z"));
+        assert_eq!(window("15m").bucket_start(at("2026-03-04T05:44:00Z")), at("2026-03-04T05:30:00Z"));
+    }
+
+    #[test]
+    fn trends() {
+        let runs = vec![
+            run("2026-03-04T05:10:00Z", vec![("books", vec![case("gutenberg", true), case("archive", false)]), ("live", vec![case("iptv", true)])]),
+            run("2026-03-04T05:40:00Z", vec![("books", vec![case("gutenberg", true), case("archive", false)])]),
+            run("2026-03-04T06:05:00Z", vec![("books", vec![case("gutenberg", false), case("archive", true)])]),
+        ];
+        let hourly = window("1h");
+
+        let categories = category_series(&runs, &hourly, None);
+        let books: Vec<(DateTime<Utc>, usize, usize, f64)> =
+            categories["books"].iter().map(|p| (p.start, p.tested, p.working, p.success_rate)).collect();
+        assert_eq!(books, vec![(at("2026-03-04T05:00:00Z"), 4, 2, 50.0), (at("2026-03-04T06:00:00Z"), 2, 1, 50.0)]);
+        assert_eq!(categories["live"].len(), 1);
+        assert_eq!(category_series(&runs, &hourly, Some("live")).keys().collect::<Vec<_>>(), vec!["live"]);
+
+        let sources = source_series(&runs, &hourly, Some("books"));
+        assert_eq!(sources.keys().collect::<Vec<_>>(), vec!["archive", "gutenberg"]);
+        // Archive recovered in the last run; Gutenberg started failing in it
+        assert_eq!(sources["archive"].failing_since, None);
+        assert_eq!(sources["gutenberg"].failing_since, Some(at("2026-03-04T06:05:00Z")));
+        assert_eq!(sources["gutenberg"].last_failure, Some(FailureClass::Timeout));
+        assert_eq!(sources["archive"].points[0].success_rate, 0.0);
+        assert_eq!(sources["archive"].points[1].success_rate, 100.0);
+    }
+}
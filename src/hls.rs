@@ -0,0 +1,195 @@
+// HLS PLAYLISTS - Detection and URI rewriting for master/media playlists
+use crate::media::{self, Rendition, RenditionKind, StreamMetadata, StreamProtocol};
+use reqwest::Url;
+
+pub fn is_playlist(content_type: Option<&str>, url: &Url) -> bool {
+    let by_type = content_type
+        .map(|t| t.to_ascii_lowercase().contains("mpegurl"))
+        .unwrap_or(false);
+    let path = url.path().to_ascii_lowercase();
+    by_type || path.ends_with(".m3u8") || path.ends_with(".m3u")
+}
+
+// Rewrite every variant, segment, key and map URI through `proxied`.
+// Relative URIs are resolved against `base`, the playlist's final URL.
+pub fn rewrite_uris(playlist: &str, base: &Url, mut proxied: impl FnMut(&Url) -> String) -> String {
+    let mut out = String::with_capacity(playlist.len() * 2);
+
+    for line in playlist.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            out.push_str(line);
+        } else if trimmed.starts_with('#') {
+            out.push_str(&rewrite_uri_attributes(trimmed, base, &mut proxied));
+        } else {
+            match base.join(trimmed) {
+                Ok(url) => out.push_str(&proxied(&url)),
+                Err(_) => out.push_str(trimmed),
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+// Tags such as EXT-X-KEY, EXT-X-MAP and EXT-X-MEDIA carry their URI in a quoted attribute
+fn rewrite_uri_attributes(tag: &str, base: &Url, proxied: &mut impl FnMut(&Url) -> String) -> String {
+    const MARKER: &str = "URI=\"";
+    let mut out = String::with_capacity(tag.len());
+    let mut rest = tag;
+
+    while let Some(start) = rest.find(MARKER) {
+        let value_start = start + MARKER.len();
+        let Some(len) = rest[value_start..].find('"') else { break };
+        let value = &rest[value_start..value_start + len];
+
+        out.push_str(&rest[..value_start]);
+        match base.join(value) {
+            // data: URIs (inline keys) are left alone
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => out.push_str(&proxied(&url)),
+            _ => out.push_str(value),
+        }
+        rest = &rest[value_start + len..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub uri: Url,
+    pub bandwidth: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaSegment {
+    pub uri: Url,
+    pub duration: f64,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaPlaylist {
+    pub target_duration: u64,
+    pub segments: Vec<MediaSegment>,
+    pub map_uri: Option<Url>,
+    pub encrypted: bool,
+    pub ended: bool,
+}
+
+pub fn is_master(playlist: &str) -> bool {
+    playlist.contains("#EXT-X-STREAM-INF")
+}
+
+pub fn parse_variants(playlist: &str, base: &Url) -> Vec<Variant> {
+    let mut variants = Vec::new();
+    let mut pending: Option<u64> = None;
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending = Some(attribute(attrs, "BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let (Some(bandwidth), Ok(uri)) = (pending.take(), base.join(line)) {
+                variants.push(Variant { uri, bandwidth });
+            }
+        }
+    }
+
+    variants
+}
+
+pub fn parse_media_playlist(playlist: &str, base: &Url) -> MediaPlaylist {
+    let mut parsed = MediaPlaylist::default();
+    let mut sequence = 0;
+    let mut duration: Option<f64> = None;
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            parsed.target_duration = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            duration = value.split(',').next().and_then(|d| d.trim().parse().ok());
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            parsed.encrypted = attribute(attrs, "METHOD").map(|m| m != "NONE").unwrap_or(false);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            parsed.map_uri = attribute(attrs, "URI").and_then(|u| base.join(&u).ok());
+        } else if line == "#EXT-X-ENDLIST" {
+            parsed.ended = true;
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Ok(uri) = base.join(line) {
+                parsed.segments.push(MediaSegment {
+                    uri,
+                    duration: duration.take().unwrap_or(parsed.target_duration as f64),
+                    sequence,
+                });
+            }
+            sequence += 1;
+        }
+    }
+
+    parsed
+}
+
+// Variants and alternate renditions of a master playlist. Master playlists
+// don't say whether they're live, so only media playlists with ENDLIST are VOD.
+pub fn parse_metadata(playlist: &str) -> StreamMetadata {
+    let mut metadata = StreamMetadata::new(StreamProtocol::Hls, !playlist.contains("#EXT-X-ENDLIST"));
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let (width, height) = attribute(attrs, "RESOLUTION")
+                .map(|r| media::parse_resolution(&r))
+                .unwrap_or((None, None));
+            metadata.renditions.push(Rendition {
+                kind: RenditionKind::Video,
+                bandwidth: attribute(attrs, "BANDWIDTH").and_then(|b| b.parse().ok()),
+                width,
+                height,
+                codecs: attribute(attrs, "CODECS"),
+                language: None,
+            });
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let kind = match attribute(attrs, "TYPE").as_deref() {
+                Some("AUDIO") => RenditionKind::Audio,
+                Some("SUBTITLES") | Some("CLOSED-CAPTIONS") => RenditionKind::Subtitles,
+                _ => continue,
+            };
+            metadata.renditions.push(Rendition {
+                kind,
+                bandwidth: None,
+                width: None,
+                height: None,
+                codecs: None,
+                language: attribute(attrs, "LANGUAGE"),
+            });
+        }
+    }
+
+    metadata.finish()
+}
+
+// Read one attribute from a tag's attribute list, e.g. BANDWIDTH=800000 or URI="init.mp4"
+pub fn attribute(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches(','))
+            }
+            None => match after_key.split_once(',') {
+                Some((value, tail)) => (value, tail),
+                None => (after_key, ""),
+            },
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value.to_string());
+        }
+        rest = after_value;
+    }
+    None
+}
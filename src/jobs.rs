@@ -0,0 +1,530 @@
+// VERIFICATION JOBS - Bulk URL and channel checks that run in the background
+use crate::config::ConfigStore;
+use crate::sniff::MediaKind;
+use crate::verify::{self, FailureClass, VerificationResult};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream};
+use crate::guard::GuardedClient;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::{watch, RwLock};
+use tokio::task::{self, JoinSet};
+
+// Finished jobs kept around for polling
+const MAX_FINISHED_JOBS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckKind {
+    // HLS or DASH manifest down to its first segment
+    Stream,
+    // Reachable and, when expected kinds are given, serving one of them
+    Url,
+    // Like Url, plus size and checksum of the file
+    Download,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobTarget {
+    pub content_id: Option<String>,
+    pub url: String,
+    pub check: CheckKind,
+    pub expected: Vec<MediaKind>,
+    #[serde(skip)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobItem {
+    #[serde(flatten)]
+    pub target: JobTarget,
+    pub result: Option<VerificationResult>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Completed,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobSummary {
+    pub total: usize,
+    pub done: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub failures: HashMap<FailureClass, usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub summary: JobSummary,
+    pub items: Vec<JobItem>,
+}
+
+// What pollers and event streams see after every finished check
+#[derive(Debug, Clone, Serialize)]
+pub struct JobProgress {
+    pub id: String,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    pub summary: JobSummary,
+    pub latest: Option<JobItem>,
+}
+
+#[derive(Deserialize)]
+pub struct JobRequest {
+    #[serde(default)]
+    urls: Vec<String>,
+    #[serde(default)]
+    content_ids: Vec<String>,
+    // Every channel of a configured playlist source
+    playlist: Option<String>,
+}
+
+struct JobHandle {
+    job: RwLock<Job>,
+    progress: watch::Sender<JobProgress>,
+}
+
+pub struct VerifyJobs {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    jobs: RwLock<HashMap<String, Arc<JobHandle>>>,
+}
+
+impl VerifyJobs {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self {
+            client,
+            config,
+            jobs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn submit(self: Arc<Self>, targets: Vec<JobTarget>) -> Job {
+        let created_at = Utc::now();
+        let id = format!("job_{}", &format!("{:x}", md5::compute(format!("{}|{}", created_at.timestamp_nanos_opt().unwrap_or(0), targets.len())))[..12]);
+        let job = Job {
+            id: id.clone(),
+            status: JobStatus::Running,
+            created_at,
+            finished_at: None,
+            summary: JobSummary { total: targets.len(), ..Default::default() },
+            items: targets.into_iter().map(|target| JobItem { target, result: None }).collect(),
+        };
+        let (progress, _) = watch::channel(progress_of(&job, None));
+        let handle = Arc::new(JobHandle { job: RwLock::new(job.clone()), progress });
+
+        self.prune().await;
+        self.jobs.write().await.insert(id, handle.clone());
+        tokio::spawn(self.clone().run(handle));
+        job
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        let handle = self.jobs.read().await.get(id).cloned()?;
+        let job = handle.job.read().await.clone();
+        Some(job)
+    }
+
+    pub async fn list(&self) -> Vec<JobProgress> {
+        let handles: Vec<Arc<JobHandle>> = self.jobs.read().await.values().cloned().collect();
+        let mut jobs = Vec::new();
+        for handle in handles {
+            jobs.push(handle.progress.borrow().clone());
+        }
+        jobs.sort_by_key(|j| std::cmp::Reverse(j.created_at));
+        jobs
+    }
+
+    async fn subscribe(&self, id: &str) -> Option<watch::Receiver<JobProgress>> {
+        self.jobs.read().await.get(id).map(|h| h.progress.subscribe())
+    }
+
+    // Drop the oldest finished jobs beyond the retention count
+    async fn prune(&self) {
+        let mut finished = Vec::new();
+        for (id, handle) in self.jobs.read().await.iter() {
+            let job = handle.job.read().await;
+            if let Some(at) = job.finished_at {
+                finished.push((at, id.clone()));
+            }
+        }
+        if finished.len() < MAX_FINISHED_JOBS {
+            return;
+        }
+        finished.sort();
+        let mut jobs = self.jobs.write().await;
+        for (_, id) in finished.iter().take(finished.len() + 1 - MAX_FINISHED_JOBS) {
+            jobs.remove(id);
+        }
+    }
+
+    async fn run(self: Arc<Self>, handle: Arc<JobHandle>) {
+        let config = self.config.snapshot().verification.clone();
+        let targets: Vec<JobTarget> = handle.job.read().await.items.iter().map(|i| i.target.clone()).collect();
+        log!("🧪 Verification job started: {} items", targets.len());
+
+        // At most job_concurrency checks exist at a time; each free slot goes to the next
+        // target whose host is under its limit, so a slow host can't hold up the rest
+        let limit = config.job_concurrency.max(1);
+        let per_host = config.per_host_concurrency.max(1);
+        let checksum_max_bytes = config.checksum_max_bytes;
+        let mut pending: VecDeque<(usize, String, JobTarget)> =
+            targets.into_iter().enumerate().map(|(index, target)| (index, host_of(&target.url), target)).collect();
+        let mut busy: HashMap<String, usize> = HashMap::new();
+        let mut running: HashMap<task::Id, String> = HashMap::new();
+        let mut tasks = JoinSet::new();
+
+        loop {
+            while tasks.len() < limit {
+                let Some(next) = pending.iter().position(|(_, host, _)| busy.get(host).copied().unwrap_or(0) < per_host) else { break };
+                let Some((index, host, target)) = pending.remove(next) else { break };
+                *busy.entry(host.clone()).or_insert(0) += 1;
+                let client = self.client.clone();
+                let id = tasks
+                    .spawn(async move {
+                        let result = match target.check {
+                            CheckKind::Stream => verify::check_live_stream(&client, &target.url, &target.headers).await.0,
+                            CheckKind::Url => verify::check_url(&client, &target.url, &target.expected).await,
+                            CheckKind::Download => verify::check_download(&client, &target.url, &target.expected, checksum_max_bytes).await,
+                        };
+                        (index, result)
+                    })
+                    .id();
+                running.insert(id, host);
+            }
+
+            let Some(joined) = tasks.join_next_with_id().await else { break };
+            let id = match &joined {
+                Ok((id, _)) => *id,
+                Err(e) => e.id(),
+            };
+            if let Some(count) = running.remove(&id).and_then(|host| busy.get_mut(&host)) {
+                *count -= 1;
+            }
+            let Ok((_, (index, result))) = joined else { continue };
+            let mut job = handle.job.write().await;
+            job.summary.done += 1;
+            match result.failure {
+                None => job.summary.passed += 1,
+                Some(failure) => {
+                    job.summary.failed += 1;
+                    *job.summary.failures.entry(failure).or_insert(0) += 1;
+                }
+            }
+            job.items[index].result = Some(result);
+            if job.summary.done == job.summary.total {
+                job.status = JobStatus::Completed;
+                job.finished_at = Some(Utc::now());
+            }
+            handle.progress.send_replace(progress_of(&job, Some(job.items[index].clone())));
+        }
+
+        let mut job = handle.job.write().await;
+        if job.status != JobStatus::Completed {
+            // Only reachable when checks panicked; report what finished
+            job.status = JobStatus::Completed;
+            job.finished_at = Some(Utc::now());
+            handle.progress.send_replace(progress_of(&job, None));
+        }
+        log!("✅ Verification job {}: {}/{} passed", job.id, job.summary.passed, job.summary.total);
+    }
+}
+
+fn host_of(url: &str) -> String {
+    Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)).unwrap_or_default()
+}
+
+fn progress_of(job: &Job, latest: Option<JobItem>) -> JobProgress {
+    JobProgress {
+        id: job.id.clone(),
+        status: job.status,
+        created_at: job.created_at,
+        summary: job.summary.clone(),
+        latest,
+    }
+}
+
+// Raw URLs: manifests get the stream check, anything else a plain reachability check
+fn url_target(url: &str) -> JobTarget {
+    let path = Url::parse(url).map(|u| u.path().to_ascii_lowercase()).unwrap_or_default();
+    let is_stream = [".m3u8", ".m3u", ".mpd"].iter().any(|ext| path.ends_with(ext));
+    JobTarget {
+        content_id: None,
+        url: url.to_string(),
+        check: if is_stream { CheckKind::Stream } else { CheckKind::Url },
+        expected: vec![],
+        headers: HashMap::new(),
+    }
+}
+
+// Content IDs are live channels or results from earlier searches
+async fn content_target(state: &AppState, id: &str) -> Option<JobTarget> {
+    if let Some(channel) = state.playlists.channel(id).await {
+        return Some(JobTarget {
+            content_id: Some(id.to_string()),
+            url: channel.url,
+            check: CheckKind::Stream,
+            expected: vec![],
+            headers: channel.headers,
+        });
+    }
+
+    let cache = state.cache.read().await;
+    let content = cache.values().flatten().find(|c| c.id == id)?;
+    let target = if content.audiobook.is_some() {
+        // The first chapter, checked like the others are
+        JobTarget {
+            content_id: Some(id.to_string()),
+            url: content.stream_url.clone(),
+            check: CheckKind::Url,
+            expected: vec![MediaKind::Mp3],
+            headers: HashMap::new(),
+        }
+    } else if content.book.is_some() {
+        JobTarget {
+            content_id: Some(id.to_string()),
+            url: content.download_url.clone(),
+            check: CheckKind::Download,
+            expected: MediaKind::from_format(&content.quality).into_iter().collect(),
+            headers: HashMap::new(),
+        }
+    } else {
+        // Movie and TV results are embed pages
+        JobTarget {
+            content_id: Some(id.to_string()),
+            url: content.stream_url.clone(),
+            check: CheckKind::Url,
+            expected: vec![MediaKind::Html],
+            headers: HashMap::new(),
+        }
+    };
+    Some(target)
+}
+
+// POST /api/verify/jobs
+pub async fn create_job(
+    State(state): State<AppState>,
+    Json(request): Json<JobRequest>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, String)> {
+    let mut targets: Vec<JobTarget> = request.urls.iter().map(|u| url_target(u)).collect();
+
+    let mut unknown = Vec::new();
+    for id in &request.content_ids {
+        match content_target(&state, id).await {
+            Some(target) => targets.push(target),
+            None => unknown.push(id.as_str()),
+        }
+    }
+    if !unknown.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, format!("Unknown content IDs: {}", unknown.join(", "))));
+    }
+
+    if let Some(playlist) = &request.playlist {
+        let channels: Vec<_> = state.playlists.channels().await.into_iter().filter(|c| &c.source == playlist).collect();
+        if channels.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, format!("Playlist {} has no channels", playlist)));
+        }
+        targets.extend(channels.into_iter().map(|channel| JobTarget {
+            content_id: Some(channel.id),
+            url: channel.url,
+            check: CheckKind::Stream,
+            expected: vec![],
+            headers: channel.headers,
+        }));
+    }
+
+    let max_items = state.config.snapshot().verification.max_job_items;
+    if targets.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Nothing to verify".to_string()));
+    }
+    if targets.len() > max_items {
+        return Err((StatusCode::BAD_REQUEST, format!("Too many items: {} (max {})", targets.len(), max_items)));
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(state.jobs.clone().submit(targets).await)))
+}
+
+// GET /api/verify/jobs
+pub async fn list_jobs(State(state): State<AppState>) -> Json<Vec<JobProgress>> {
+    Json(state.jobs.list().await)
+}
+
+// GET /api/verify/jobs/:id
+pub async fn get_job(Path(id): Path<String>, State(state): State<AppState>) -> Result<Json<Job>, StatusCode> {
+    state.jobs.get(&id).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// GET /api/verify/jobs/:id/events - server-sent progress until the job completes
+pub async fn job_events(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let receiver = state.jobs.subscribe(&id).await.ok_or(StatusCode::NOT_FOUND)?;
+
+    // The first event is the current state; after that one per change
+    let events = stream::unfold((receiver, true, false), |(mut receiver, first, finished)| async move {
+        if finished || (!first && receiver.changed().await.is_err()) {
+            return None;
+        }
+        let progress = receiver.borrow_and_update().clone();
+        let finished = progress.status == JobStatus::Completed;
+        let event = Event::default().event("progress").json_data(&progress).unwrap_or_default();
+        Some((Ok(event), (receiver, false, finished)))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::http::{header, HeaderMap};
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Requests in flight now and at most, per Host header and overall
+    #[derive(Default)]
+    struct Load {
+        hosts: HashMap<String, (usize, usize)>,
+        total: (usize, usize),
+    }
+
+    // Stand-in that answers every path after a short wait, except /missing
+    async fn stand_in(job_concurrency: usize, per_host_concurrency: usize) -> (Arc<VerifyJobs>, u16, Arc<Mutex<Load>>) {
+        let load = Arc::new(Mutex::new(Load::default()));
+        let seen = load.clone();
+        let app = Router::new().fallback(get(move |headers: HeaderMap, uri: axum::http::Uri| {
+            let load = seen.clone();
+            async move {
+                let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or_default().to_string();
+                {
+                    let mut load = load.lock().unwrap();
+                    let now = load.hosts.entry(host.clone()).or_default();
+                    now.0 += 1;
+                    now.1 = now.1.max(now.0);
+                    load.total.0 += 1;
+                    load.total.1 = load.total.1.max(load.total.0);
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                {
+                    let mut load = load.lock().unwrap();
+                    load.hosts.get_mut(&host).unwrap().0 -= 1;
+                    load.total.0 -= 1;
+                }
+                if uri.path() == "/missing" {
+                    (StatusCode::NOT_FOUND, "gone")
+                } else {
+                    (StatusCode::OK, "ok")
+                }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string(), "localhost".to_string()];
+        config.verification.job_concurrency = job_concurrency;
+        config.verification.per_host_concurrency = per_host_concurrency;
+        let config = Arc::new(ConfigStore::fixed(config));
+        (Arc::new(VerifyJobs::new(GuardedClient::new(config.clone()), config)), port, load)
+    }
+
+    fn target(url: String) -> JobTarget {
+        JobTarget { content_id: None, url, check: CheckKind::Url, expected: vec![], headers: HashMap::new() }
+    }
+
+    // Every progress update until the job completes
+    async fn updates(jobs: &VerifyJobs, id: &str) -> Vec<JobProgress> {
+        let mut receiver = jobs.subscribe(id).await.unwrap();
+        let mut seen = vec![receiver.borrow_and_update().clone()];
+        while seen.last().unwrap().status != JobStatus::Completed {
+            tokio::time::timeout(Duration::from_secs(30), receiver.changed()).await.unwrap().unwrap();
+            seen.push(receiver.borrow_and_update().clone());
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn checks_stay_within_global_and_host_limits() {
+        let (jobs, port, load) = stand_in(3, 2).await;
+        let targets = (0..8)
+            .flat_map(|i| [format!("http://127.0.0.1:{}/a{}", port, i), format!("http://localhost:{}/b{}", port, i)])
+            .map(target)
+            .collect();
+        let job = jobs.clone().submit(targets).await;
+        updates(&jobs, &job.id).await;
+        assert_eq!(jobs.get(&job.id).await.unwrap().summary.passed, 16);
+
+        let load = load.lock().unwrap();
+        assert_eq!(load.hosts.len(), 2);
+        for (host, (_, most)) in &load.hosts {
+            assert!(*most <= 2, "{} had {} requests at once", host, most);
+        }
+        // Both hosts were busy together, but never past the global limit
+        assert_eq!(load.total.1, 3);
+    }
+
+    #[tokio::test]
+    async fn progress_is_reported_until_completion() {
+        let (jobs, port, _) = stand_in(1, 1).await;
+        let urls = ["ok1", "missing", "ok2"].map(|path| format!("http://127.0.0.1:{}/{}", port, path));
+        let job = jobs.clone().submit(urls.iter().cloned().map(target).collect()).await;
+        assert_eq!((job.status, job.summary.total, job.summary.done), (JobStatus::Running, 3, 0));
+
+        let seen = updates(&jobs, &job.id).await;
+        let done: Vec<usize> = seen.iter().map(|p| p.summary.done).collect();
+        assert!(done.windows(2).all(|w| w[0] <= w[1]), "{:?}", done);
+        let last = seen.last().unwrap();
+        assert_eq!((last.summary.done, last.summary.passed, last.summary.failed), (3, 2, 1));
+        assert_eq!(last.summary.failures.get(&FailureClass::Http4xx), Some(&1));
+        assert!(last.latest.as_ref().is_some_and(|item| item.result.is_some()));
+
+        // Items keep their submission order and their own results
+        let job = jobs.get(&job.id).await.unwrap();
+        assert!(job.finished_at.is_some());
+        let failures: Vec<Option<FailureClass>> = job.items.iter().map(|i| i.result.as_ref().unwrap().failure).collect();
+        assert_eq!(failures, vec![None, Some(FailureClass::Http4xx), None]);
+        assert_eq!(job.items.iter().map(|i| i.target.url.as_str()).collect::<Vec<_>>(), urls.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_finished_jobs_are_kept() {
+        let (jobs, port, _) = stand_in(8, 2).await;
+        let mut ids = Vec::new();
+        for i in 0..MAX_FINISHED_JOBS + 5 {
+            let job = jobs.clone().submit(vec![target(format!("http://127.0.0.1:{}/{}", port, i))]).await;
+            updates(&jobs, &job.id).await;
+            ids.push(job.id);
+        }
+
+        assert_eq!(jobs.list().await.len(), MAX_FINISHED_JOBS);
+        for id in &ids[..5] {
+            assert!(jobs.get(id).await.is_none(), "{} should have been pruned", id);
+        }
+        for id in &ids[5..] {
+            assert!(jobs.get(id).await.is_some(), "{} should be kept", id);
+        }
+    }
+}
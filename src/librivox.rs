@@ -0,0 +1,417 @@
+// LIBRIVOX - Public-domain audiobooks with per-chapter MP3 streams, durations and readers
+use crate::config::LibriVoxConfig;
+use crate::guard::{BodyKind, FetchError, GuardedClient};
+use crate::sniff::MediaKind;
+use crate::verify::{self, VerificationResult};
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+const ITUNES_NS: &str = "http://www.itunes.com/dtds/podcast-1.0.dtd";
+const MAX_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub number: u32,
+    pub title: String,
+    pub duration_secs: Option<u64>,
+    pub readers: Vec<String>,
+    pub stream_url: String,
+    // Set for the chapters that were checked
+    pub verification: Option<VerificationResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audiobook {
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub genres: Vec<String>,
+    pub total_secs: Option<u64>,
+    // The text that was read, often a Gutenberg etext
+    pub text_url: Option<String>,
+    pub page_url: Option<String>,
+    pub rss_url: Option<String>,
+    // Every chapter in one archive
+    pub zip_url: Option<String>,
+    pub chapters: Vec<Chapter>,
+}
+
+impl Audiobook {
+    // "http://www.gutenberg.org/etext/1342" or ".../ebooks/1342"
+    pub fn gutenberg_id(&self) -> Option<String> {
+        let url = self.text_url.as_deref()?;
+        if !url.contains("gutenberg.org") {
+            return None;
+        }
+        let id = url.trim_end_matches('/').rsplit('/').next()?;
+        id.chars().all(|c| c.is_ascii_digit()).then(|| id.to_string())
+    }
+}
+
+// Titles containing the query, then books by authors with that last name
+pub async fn search(client: &GuardedClient, settings: &LibriVoxConfig, query: &str, limit: usize) -> Result<Vec<Audiobook>, String> {
+    if !settings.enabled || query.trim().is_empty() || limit == 0 {
+        return Ok(vec![]);
+    }
+    let query = query.trim();
+    let last_name = query.rsplit(' ').next().unwrap_or(query);
+    let (title, author) = (format!("title={}", urlencoding::encode(query)), format!("author={}", urlencoding::encode(last_name)));
+    let (by_title, by_author) = tokio::join!(books(client, settings, &title, limit), books(client, settings, &author, limit));
+    // One of the two failing still leaves results worth returning
+    if let (Err(e), Err(_)) = (&by_title, &by_author) {
+        return Err(e.clone());
+    }
+
+    let mut found: Vec<Value> = Vec::new();
+    for book in by_title.unwrap_or_default().into_iter().chain(by_author.unwrap_or_default()) {
+        if !found.iter().any(|f| string(&f["id"]) == string(&book["id"])) {
+            found.push(book);
+        }
+    }
+    let found = found.into_iter().take(limit).map(|book| audiobook(client, book));
+    Ok(join_all(found).await.into_iter().flatten().collect())
+}
+
+pub async fn book(client: &GuardedClient, settings: &LibriVoxConfig, id: &str) -> Result<Option<Audiobook>, String> {
+    if !id.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let found = books(client, settings, &format!("id={}", id), 1).await?;
+    match found.into_iter().next() {
+        Some(book) => Ok(audiobook(client, book).await),
+        None => Ok(None),
+    }
+}
+
+// The API answers a search without matches with a 404
+async fn books(client: &GuardedClient, settings: &LibriVoxConfig, filter: &str, limit: usize) -> Result<Vec<Value>, String> {
+    let url = format!(
+        "{}/api/feed/audiobooks/?{}&format=json&extended=1&limit={}",
+        settings.base_url.trim_end_matches('/'),
+        filter,
+        limit.min(MAX_LIMIT)
+    );
+    let found: Value = match client.get_json(&url).await {
+        Ok(found) => found,
+        Err(FetchError::Request(e)) if e.status().is_some_and(|s| s.as_u16() == 404) => return Ok(vec![]),
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(found["books"].as_array().cloned().unwrap_or_default())
+}
+
+// Sections come with the extended API response; without them the book's RSS feed lists the chapters
+async fn audiobook(client: &GuardedClient, book: Value) -> Option<Audiobook> {
+    let id = string(&book["id"])?;
+    let rss_url = string(&book["url_rss"]);
+    let mut chapters: Vec<Chapter> = book["sections"].as_array().into_iter().flatten().filter_map(section).collect();
+    if chapters.is_empty() {
+        if let Some(rss) = &rss_url {
+            match feed(client, rss).await {
+                Ok(body) => chapters = rss_chapters(&body),
+                Err(e) => eprintln!("❌ LibriVox RSS for {}: {}", id, e),
+            }
+        }
+    }
+    if chapters.is_empty() {
+        return None;
+    }
+    chapters.sort_by_key(|c| c.number);
+
+    let authors = book["authors"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|a| {
+            let name = format!("{} {}", string(&a["first_name"]).unwrap_or_default(), string(&a["last_name"]).unwrap_or_default());
+            let name = name.trim();
+            (!name.is_empty()).then(|| name.to_string())
+        })
+        .collect();
+    // A chapter without a duration, or a sum too large to be real, leaves the total unknown
+    let total_secs = number(&book["totaltimesecs"])
+        .filter(|secs| *secs > 0)
+        .or_else(|| chapters.iter().try_fold(0u64, |total, c| total.checked_add(c.duration_secs?)));
+
+    Some(Audiobook {
+        title: string(&book["title"]).unwrap_or_else(|| format!("LibriVox #{}", id)),
+        authors,
+        language: string(&book["language"]),
+        genres: book["genres"].as_array().into_iter().flatten().filter_map(|g| string(&g["name"])).collect(),
+        total_secs,
+        text_url: string(&book["url_text_source"]),
+        page_url: string(&book["url_librivox"]),
+        rss_url,
+        zip_url: string(&book["url_zip_file"]),
+        chapters,
+        id,
+    })
+}
+
+async fn feed(client: &GuardedClient, url: &str) -> Result<String, FetchError> {
+    let response = client.send(client.get(url)).await?.error_for_status()?;
+    client.read_text(response, BodyKind::Catalog).await
+}
+
+fn section(section: &Value) -> Option<Chapter> {
+    Some(Chapter {
+        number: number(&section["section_number"]).unwrap_or(0) as u32,
+        title: string(&section["title"]).unwrap_or_default(),
+        duration_secs: string(&section["playtime"]).and_then(|p| duration(&p)),
+        readers: section["readers"].as_array().into_iter().flatten().filter_map(|r| string(&r["display_name"])).collect(),
+        stream_url: string(&section["listen_url"])?,
+        verification: None,
+    })
+}
+
+// <item><title/><enclosure url=""/><itunes:duration/></item>; the feed doesn't name readers
+fn rss_chapters(body: &str) -> Vec<Chapter> {
+    let Ok(doc) = roxmltree::Document::parse(body) else { return vec![] };
+    doc.descendants()
+        .filter(|n| n.has_tag_name("item"))
+        .enumerate()
+        .filter_map(|(i, item)| {
+            let child = |ns: Option<&str>, name: &str| item.children().find(|n| n.tag_name().name() == name && n.tag_name().namespace() == ns);
+            let text = |node: Option<roxmltree::Node>| node.and_then(|n| n.text()).map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+            Some(Chapter {
+                number: i as u32 + 1,
+                title: text(child(None, "title")).unwrap_or_default(),
+                duration_secs: text(child(Some(ITUNES_NS), "duration")).and_then(|d| duration(&d)),
+                readers: vec![],
+                stream_url: child(None, "enclosure")?.attribute("url")?.to_string(),
+                verification: None,
+            })
+        })
+        .collect()
+}
+
+// Seconds, or "MM:SS" / "HH:MM:SS"; None rather than a wrapped number when it overflows
+fn duration(value: &str) -> Option<u64> {
+    value.trim().split(':').try_fold(0u64, |total, part| total.checked_mul(60)?.checked_add(part.trim().parse::<u64>().ok()?))
+}
+
+// The API sends numbers as strings as often as not
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| value.as_str()?.trim().parse().ok())
+}
+
+// Check the first `count` chapter streams, at most `concurrency` at a time
+pub async fn verify_chapters(client: &GuardedClient, book: &mut Audiobook, count: usize, concurrency: usize) {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let checks = book.chapters.iter().take(count).map(|chapter| {
+        let permits = permits.clone();
+        async move {
+            let _permit = permits.acquire().await;
+            verify::check_url(client, &chapter.stream_url, &[MediaKind::Mp3]).await
+        }
+    });
+    let results = join_all(checks).await;
+    for (chapter, result) in book.chapters.iter_mut().zip(results) {
+        chapter.verification = Some(result);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LibriVoxQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+// GET /api/librivox/search - audiobooks with their chapters, unchecked
+pub async fn search_audiobooks(
+    Query(params): Query<LibriVoxQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Audiobook>>, (StatusCode, String)> {
+    let settings = state.config.snapshot().librivox.clone();
+    search(&state.client, &settings, &params.q, params.limit.unwrap_or(10))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e))
+}
+
+#[derive(Deserialize)]
+pub struct AudiobookQuery {
+    verify: Option<bool>, // check every chapter stream (default true)
+}
+
+// GET /api/librivox/books/:id - one audiobook with every chapter stream checked
+pub async fn get_audiobook(
+    Path(id): Path<String>,
+    Query(params): Query<AudiobookQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Audiobook>, (StatusCode, String)> {
+    let config = state.config.snapshot();
+    let mut audiobook = match book(&state.client, &config.librivox, &id).await {
+        Ok(Some(audiobook)) => audiobook,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, format!("No LibriVox audiobook {}", id))),
+        Err(e) => return Err((StatusCode::BAD_GATEWAY, e)),
+    };
+    if params.verify.unwrap_or(true) {
+        let count = audiobook.chapters.len();
+        verify_chapters(&state.client, &mut audiobook, count, config.verification.per_host_concurrency).await;
+    }
+    Ok(Json(audiobook))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigStore, ServerConfig};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+        <rss xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd" version="2.0"><channel>
+            <title>Emma</title>
+            <item><title>Chapter 1</title><enclosure url="https://example.org/emma_01.mp3" type="audio/mpeg"/><itunes:duration>1:02:03</itunes:duration></item>
+            <item><title> Chapter 2 </title><enclosure url="https://example.org/emma_02.mp3" type="audio/mpeg"/><duration>99</duration></item>
+            <item><title>No enclosure</title></item>
+            <item><title>Chapter 3</title><enclosure url="https://example.org/emma_03.mp3"/><itunes:duration>45</itunes:duration></item>
+        </channel></rss>"#;
+
+    fn listing(id: u32, title: &str, sections: Value) -> Value {
+        json!({
+            "id": id.to_string(),
+            "title": title,
+            "authors": [{"first_name": "Jane", "last_name": "Austen"}],
+            "language": "English",
+            "totaltimesecs": 0,
+            "url_rss": format!("/rss/{}", id),
+            "sections": sections,
+        })
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration("45"), Some(45));
+        assert_eq!(duration("62:03"), Some(3723));
+        assert_eq!(duration(" 1:02:03 "), Some(3723));
+        assert_eq!(duration("1:xx"), None);
+        assert_eq!(duration(""), None);
+        assert_eq!(duration(&u64::MAX.to_string()), Some(u64::MAX));
+        assert_eq!(duration(&format!("{}:00", u64::MAX)), None);
+        assert_eq!(duration(&format!("1:{}", u64::MAX)), None);
+    }
+
+    #[test]
+    fn sections() {
+        let chapter = section(&json!({
+            "section_number": "2",
+            "title": " Chapter 2 ",
+            "playtime": "00:21:40",
+            "readers": [{"display_name": "Karen Savage"}, {"display_name": ""}],
+            "listen_url": "https://example.org/pride_02.mp3",
+        }))
+        .unwrap();
+        assert_eq!((chapter.number, chapter.title.as_str()), (2, "Chapter 2"));
+        assert_eq!(chapter.duration_secs, Some(1300));
+        assert_eq!(chapter.readers, vec!["Karen Savage"]);
+        assert_eq!(chapter.stream_url, "https://example.org/pride_02.mp3");
+
+        let untimed = section(&json!({"section_number": 1, "playtime": "", "listen_url": "https://example.org/a.mp3"})).unwrap();
+        assert_eq!((untimed.number, untimed.duration_secs), (1, None));
+        assert!(section(&json!({"section_number": 3, "title": "No stream"})).is_none());
+    }
+
+    #[test]
+    fn rss_items() {
+        let chapters = rss_chapters(RSS);
+        let found: Vec<(u32, &str, Option<u64>, &str)> =
+            chapters.iter().map(|c| (c.number, c.title.as_str(), c.duration_secs, c.stream_url.as_str())).collect();
+        // Only the iTunes duration counts, and items without an enclosure are skipped but keep their place
+        assert_eq!(
+            found,
+            vec![
+                (1, "Chapter 1", Some(3723), "https://example.org/emma_01.mp3"),
+                (2, "Chapter 2", None, "https://example.org/emma_02.mp3"),
+                (4, "Chapter 3", Some(45), "https://example.org/emma_03.mp3"),
+            ]
+        );
+        assert!(rss_chapters("<rss><channel>").is_empty());
+    }
+
+    // Stand-in for the audiobooks API and RSS feeds: the title search finds books 1 and 2,
+    // the author search 2 and 3, and book 3 only lists its chapters in its feed
+    async fn stand_in() -> (GuardedClient, LibriVoxConfig) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let stream = |n: u32| json!({"section_number": n, "title": format!("Chapter {}", n), "playtime": "10:00", "listen_url": format!("https://example.org/{}.mp3", n)});
+        let pride = listing(1, "Pride and Prejudice", json!([stream(2), stream(1)]));
+        let sense = listing(2, "Sense and Sensibility", json!([stream(1)]));
+        let mut emma = listing(3, "Emma", json!([]));
+        emma["url_rss"] = json!(format!("{}/rss/3", base_url));
+        let persuasion = listing(4, "Persuasion", json!([{"section_number": 1, "listen_url": "https://example.org/p.mp3", "playtime": format!("{}:00", u64::MAX / 60)}, stream(2)]));
+
+        let app = Router::new()
+            .route(
+                "/api/feed/audiobooks/",
+                get(move |Query(params): Query<HashMap<String, String>>| async move {
+                    let found = match (params.get("title"), params.get("author"), params.get("id")) {
+                        (Some(_), _, _) => vec![pride.clone(), sense.clone()],
+                        (_, Some(_), _) => vec![sense.clone(), emma.clone()],
+                        (_, _, Some(id)) if id == "4" => vec![persuasion.clone()],
+                        _ => return StatusCode::NOT_FOUND.into_response(),
+                    };
+                    Json(json!({"books": found})).into_response()
+                }),
+            )
+            .route("/rss/3", get(|| async { RSS }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        let settings = LibriVoxConfig { enabled: true, base_url, verify_chapters: 0 };
+        (GuardedClient::new(Arc::new(ConfigStore::fixed(config))), settings)
+    }
+
+    #[tokio::test]
+    async fn search_merges_title_and_author_matches() {
+        let (client, settings) = stand_in().await;
+        let found = search(&client, &settings, "jane austen", 10).await.unwrap();
+        let ids: Vec<&str> = found.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+
+        // Sections are put in order and their playtimes add up
+        let pride = &found[0];
+        assert_eq!(pride.chapters.iter().map(|c| c.number).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!((pride.total_secs, pride.authors.clone()), (Some(1200), vec!["Jane Austen".to_string()]));
+        // Chapters from the feed when the API sends no sections; one without a duration leaves the total unknown
+        let emma = &found[2];
+        assert_eq!(emma.chapters.len(), 3);
+        assert_eq!(emma.total_secs, None);
+
+        let limited = search(&client, &settings, "jane austen", 2).await.unwrap();
+        assert_eq!(limited.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(), vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn books_that_cant_be_found_or_summed() {
+        let (client, settings) = stand_in().await;
+        assert!(book(&client, &settings, "99").await.unwrap().is_none());
+        assert!(book(&client, &settings, "1 OR 2").await.unwrap().is_none());
+
+        let persuasion = book(&client, &settings, "4").await.unwrap().unwrap();
+        assert_eq!(persuasion.chapters[0].duration_secs, Some(u64::MAX / 60 * 60));
+        assert_eq!(persuasion.total_secs, None);
+    }
+}
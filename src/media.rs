@@ -0,0 +1,74 @@
+// STREAM METADATA - Quality and language details shared by HLS and DASH
+use crate::probe::CodecReport;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamProtocol {
+    Hls,
+    Dash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenditionKind {
+    Video,
+    Audio,
+    Subtitles,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rendition {
+    pub kind: RenditionKind,
+    pub bandwidth: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codecs: Option<String>,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    pub protocol: StreamProtocol,
+    pub live: bool,
+    pub quality: String,
+    pub languages: Vec<String>,
+    pub renditions: Vec<Rendition>,
+    // Codecs found in the first segment's container, when it could be probed
+    pub codecs: Option<CodecReport>,
+}
+
+impl StreamMetadata {
+    pub fn new(protocol: StreamProtocol, live: bool) -> Self {
+        Self {
+            protocol,
+            live,
+            quality: "HD".to_string(),
+            languages: vec![],
+            renditions: vec![],
+            codecs: None,
+        }
+    }
+
+    // Summarize the renditions once they're all collected: "1080p", "720p"... from
+    // the best video rendition ("HD" when unknown) and the distinct languages
+    pub fn finish(mut self) -> Self {
+        self.quality = match self.renditions.iter().filter_map(|r| r.height).max() {
+            Some(h) if h >= 2160 => "4K".to_string(),
+            Some(h) => format!("{}p", h),
+            None => "HD".to_string(),
+        };
+        self.languages = self.renditions.iter().filter_map(|r| r.language.clone()).collect();
+        self.languages.sort();
+        self.languages.dedup();
+        self
+    }
+}
+
+// "1920x1080" as used by HLS RESOLUTION attributes
+pub fn parse_resolution(value: &str) -> (Option<u32>, Option<u32>) {
+    match value.split_once(['x', 'X']) {
+        Some((w, h)) => (w.trim().parse().ok(), h.trim().parse().ok()),
+        None => (None, None),
+    }
+}
@@ -0,0 +1,1059 @@
+// LOCAL MIRROR - Resumable, checksum-validated local copies of open-licensed books and media
+use crate::config::{ConfigStore, MirrorConfig};
+use crate::dvr;
+use crate::guard::GuardedClient;
+use crate::health::Heartbeat;
+use crate::verify::VerificationResult;
+use crate::{AppState, Content};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, Notify, RwLock};
+
+const INDEX_FILE: &str = "mirror.json";
+const WORKER_TICK: Duration = Duration::from_secs(5);
+// A download that receives nothing for this long is dropped and resumed later
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+const READ_CHUNK: usize = 64 * 1024;
+
+// Mirrored files are served from our own origin, so nothing in them may run. PDFs
+// go without it: browsers won't open a PDF in a sandbox, and their viewers keep
+// PDF script away from the page's origin anyway.
+const CONTENT_POLICY: &str = "default-src 'none'; sandbox";
+
+// Providers whose results are public domain or openly licensed
+const OPEN_PROVIDERS: [(&str, &str); 4] = [("gutenberg_", "book"), ("archive_", "book"), ("opds_", "book"), ("librivox_", "audiobook")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorStatus {
+    Queued,
+    Downloading,
+    Mirrored,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorItem {
+    pub id: String,
+    pub url: String,
+    pub category: String,
+    pub content_id: Option<String>,
+    pub title: String,
+    pub status: MirrorStatus,
+    // On disk so far, and the full size once the server has said
+    pub bytes: u64,
+    pub total_bytes: Option<u64>,
+    // From verification, when it hashed the file; the download has to match it
+    pub expected_sha256: Option<String>,
+    pub sha256: Option<String>,
+    pub content_type: Option<String>,
+    // ETag or Last-Modified of the first response, so a resumed range comes from the same file
+    pub validator: Option<String>,
+    pub attempts: u32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub added_at: DateTime<Utc>,
+    pub mirrored_at: Option<DateTime<Utc>>,
+    file: String,
+}
+
+impl MirrorItem {
+    // Space the item takes or is about to take
+    fn reserved(&self) -> u64 {
+        match self.status {
+            MirrorStatus::Mirrored => self.bytes,
+            MirrorStatus::Downloading | MirrorStatus::Queued => self.total_bytes.unwrap_or(self.bytes),
+            MirrorStatus::Failed => self.bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryUsage {
+    pub category: String,
+    pub quota_bytes: u64,
+    pub used_bytes: u64,
+    pub mirrored: usize,
+    pub pending: usize,
+    pub failed: usize,
+}
+
+// One file to mirror, before it is queued
+pub struct MirrorTarget {
+    pub url: String,
+    pub category: String,
+    pub content_id: Option<String>,
+    pub title: String,
+    pub expected_sha256: Option<String>,
+    pub total_bytes: Option<u64>,
+}
+
+// How an attempt ended when it didn't finish
+enum Failure {
+    // Worth another try later
+    Retry(String),
+    // Trying again won't help (quota, gone upstream)
+    Permanent(String),
+}
+
+pub struct MirrorManager {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    items: RwLock<HashMap<String, MirrorItem>>,
+    wake: Notify,
+    // One index write at a time, each with the latest items
+    saving: Mutex<()>,
+    pub heartbeat: Heartbeat,
+}
+
+impl MirrorManager {
+    pub async fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        let manager = Self {
+            client,
+            config,
+            items: RwLock::new(HashMap::new()),
+            wake: Notify::new(),
+            saving: Mutex::new(()),
+            heartbeat: Heartbeat::new(WORKER_TICK),
+        };
+        manager.load_existing().await;
+        manager
+    }
+
+    fn settings(&self) -> MirrorConfig {
+        self.config.snapshot().mirror.clone()
+    }
+
+    fn path(&self, item: &MirrorItem) -> PathBuf {
+        self.settings().directory.join(&item.category).join(&item.file)
+    }
+
+    fn partial_path(&self, item: &MirrorItem) -> PathBuf {
+        self.settings().directory.join(&item.category).join(format!("{}.partial", item.file))
+    }
+
+    // Downloads cut off by a restart go back in the queue and resume from their partial file
+    async fn load_existing(&self) {
+        let Ok(raw) = tokio::fs::read_to_string(self.settings().directory.join(INDEX_FILE)).await else { return };
+        let Ok(loaded) = serde_json::from_str::<Vec<MirrorItem>>(&raw) else { return };
+        let mut items = self.items.write().await;
+        for mut item in loaded {
+            if item.status == MirrorStatus::Downloading {
+                item.status = MirrorStatus::Queued;
+                item.bytes = tokio::fs::metadata(self.partial_path(&item)).await.map(|m| m.len()).unwrap_or(0);
+            }
+            items.insert(item.id.clone(), item);
+        }
+        log!("🗄️  Loaded {} mirrored items", items.len());
+    }
+
+    pub async fn list(&self) -> Vec<MirrorItem> {
+        let mut items: Vec<MirrorItem> = self.items.read().await.values().cloned().collect();
+        items.sort_by_key(|i| std::cmp::Reverse(i.added_at));
+        items
+    }
+
+    pub async fn get(&self, id: &str) -> Option<MirrorItem> {
+        self.items.read().await.get(id).cloned()
+    }
+
+    pub async fn usage(&self) -> Vec<CategoryUsage> {
+        let settings = self.settings();
+        let items = self.items.read().await;
+        let mut usage: Vec<CategoryUsage> = settings
+            .quotas
+            .iter()
+            .map(|(category, quota)| {
+                let in_category = || items.values().filter(move |i| &i.category == category);
+                CategoryUsage {
+                    category: category.clone(),
+                    quota_bytes: *quota,
+                    used_bytes: in_category().map(MirrorItem::reserved).sum(),
+                    mirrored: in_category().filter(|i| i.status == MirrorStatus::Mirrored).count(),
+                    pending: in_category().filter(|i| matches!(i.status, MirrorStatus::Queued | MirrorStatus::Downloading)).count(),
+                    failed: in_category().filter(|i| i.status == MirrorStatus::Failed).count(),
+                }
+            })
+            .collect();
+        usage.sort_by(|a, b| a.category.cmp(&b.category));
+        usage
+    }
+
+    // Queue new files; ones already mirrored or on their way are left alone and failed ones start over
+    pub async fn enqueue(&self, targets: Vec<MirrorTarget>) -> Vec<MirrorItem> {
+        let mut queued = Vec::new();
+        {
+            let mut items = self.items.write().await;
+            for target in targets {
+                let id = item_id(&target.url);
+                let item = items.entry(id.clone()).or_insert_with(|| {
+                    let extension = Url::parse(&target.url).map(|url| dvr::extension(&url, "bin")).unwrap_or_else(|_| "bin".to_string());
+                    MirrorItem {
+                        file: format!("{}.{}", id, extension),
+                        id,
+                        url: target.url.clone(),
+                        category: target.category.clone(),
+                        content_id: target.content_id.clone(),
+                        title: target.title.clone(),
+                        status: MirrorStatus::Queued,
+                        bytes: 0,
+                        total_bytes: target.total_bytes,
+                        expected_sha256: target.expected_sha256.clone(),
+                        sha256: None,
+                        content_type: None,
+                        validator: None,
+                        attempts: 0,
+                        next_attempt_at: None,
+                        error: None,
+                        added_at: Utc::now(),
+                        mirrored_at: None,
+                    }
+                });
+                if item.status == MirrorStatus::Failed {
+                    item.status = MirrorStatus::Queued;
+                    item.attempts = 0;
+                    item.next_attempt_at = None;
+                    item.error = None;
+                }
+                queued.push(item.clone());
+            }
+        }
+        self.persist().await;
+        self.wake.notify_one();
+        queued
+    }
+
+    pub async fn remove(&self, id: &str) -> bool {
+        let Some(item) = self.items.write().await.remove(id) else { return false };
+        for path in [self.path(&item), self.partial_path(&item)] {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        self.persist().await;
+        true
+    }
+
+    // The local path of an upstream URL, when it has been mirrored
+    pub async fn local_url(&self, url: &str) -> Option<String> {
+        let id = item_id(url);
+        let items = self.items.read().await;
+        items.get(&id).filter(|i| i.status == MirrorStatus::Mirrored).map(|_| format!("/mirror/{}", id))
+    }
+
+    // Point download and stream URLs of results at local copies where there are some
+    pub async fn localize(&self, results: &mut [Content]) {
+        if self.items.read().await.is_empty() {
+            return;
+        }
+        for content in results.iter_mut() {
+            if let Some(local) = self.local_url(&content.download_url).await {
+                content.download_url = local;
+            }
+            if let Some(local) = self.local_url(&content.stream_url).await {
+                content.stream_url = local;
+            }
+            if let Some(audiobook) = content.audiobook.as_mut() {
+                for chapter in audiobook.chapters.iter_mut() {
+                    if let Some(local) = self.local_url(&chapter.stream_url).await {
+                        chapter.stream_url = local;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn spawn_worker(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                self.start_due().await;
+                self.heartbeat.beat();
+                tokio::select! {
+                    _ = self.wake.notified() => {}
+                    _ = tokio::time::sleep(WORKER_TICK) => {}
+                }
+            }
+        });
+    }
+
+    // Oldest due items first, as many as the concurrency setting leaves room for
+    async fn start_due(self: &Arc<Self>) {
+        let settings = self.settings();
+        if !settings.enabled {
+            return;
+        }
+        let now = Utc::now();
+        let started: Vec<MirrorItem> = {
+            let mut items = self.items.write().await;
+            let active = items.values().filter(|i| i.status == MirrorStatus::Downloading).count();
+            let mut due: Vec<&mut MirrorItem> = items
+                .values_mut()
+                .filter(|i| i.status == MirrorStatus::Queued && i.next_attempt_at.is_none_or(|at| at <= now))
+                .collect();
+            due.sort_by_key(|i| i.added_at);
+            due.into_iter()
+                .take(settings.concurrency.saturating_sub(active))
+                .map(|item| {
+                    item.status = MirrorStatus::Downloading;
+                    item.clone()
+                })
+                .collect()
+        };
+        for item in started {
+            let manager = self.clone();
+            tokio::spawn(async move { manager.mirror(item).await });
+        }
+    }
+
+    async fn mirror(&self, item: MirrorItem) {
+        log!("🗄️  Mirroring {} ({})", item.title, item.url);
+        let before = item.bytes;
+        let result = self.download(&item).await;
+        let settings = self.settings();
+        let updated = self
+            .update(&item.id, |item| match result {
+                Ok(sha256) => {
+                    item.status = MirrorStatus::Mirrored;
+                    item.sha256 = Some(sha256);
+                    item.error = None;
+                    item.next_attempt_at = None;
+                    item.mirrored_at = Some(Utc::now());
+                }
+                Err(Failure::Permanent(e)) => {
+                    item.status = MirrorStatus::Failed;
+                    item.error = Some(e);
+                }
+                Err(Failure::Retry(e)) => {
+                    // Attempts that got further don't count against the limit
+                    if item.bytes <= before {
+                        item.attempts += 1;
+                    }
+                    item.error = Some(e);
+                    if item.attempts >= settings.max_attempts {
+                        item.status = MirrorStatus::Failed;
+                    } else {
+                        let backoff = settings.retry_base_secs.saturating_mul(1 << item.attempts.saturating_sub(1).min(20)).min(settings.retry_max_secs);
+                        item.status = MirrorStatus::Queued;
+                        item.next_attempt_at = Some(Utc::now() + chrono::Duration::seconds(backoff as i64));
+                    }
+                }
+            })
+            .await;
+        match updated {
+            Some(item) if item.status == MirrorStatus::Mirrored => log!("✅ Mirrored {} ({} bytes)", item.title, item.bytes),
+            Some(item) if item.status == MirrorStatus::Queued => {
+                let at = item.next_attempt_at.map(|at| at.to_rfc3339()).unwrap_or_default();
+                eprintln!("⚠️  Mirroring {} interrupted ({}), retrying at {}", item.title, item.error.as_deref().unwrap_or(""), at);
+            }
+            Some(item) => eprintln!("❌ Mirroring {} failed: {}", item.title, item.error.as_deref().unwrap_or("")),
+            // Removed while it was downloading
+            None => {
+                let _ = tokio::fs::remove_file(self.path(&item)).await;
+                let _ = tokio::fs::remove_file(self.partial_path(&item)).await;
+            }
+        }
+        self.wake.notify_one();
+    }
+
+    // Fetch the rest of the file into its .partial, then check and move it into place
+    async fn download(&self, item: &MirrorItem) -> Result<String, Failure> {
+        let partial = self.partial_path(item);
+        if let Some(dir) = partial.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(|e| Failure::Retry(e.to_string()))?;
+        }
+        let mut offset = tokio::fs::metadata(&partial).await.map(|m| m.len()).unwrap_or(0);
+
+        let mut request = self.client.download(&item.url);
+        if offset > 0 {
+            request = request.header(header::RANGE.as_str(), format!("bytes={}-", offset));
+            if let Some(validator) = &item.validator {
+                request = request.header(header::IF_RANGE.as_str(), validator.as_str());
+            }
+        }
+        let mut response = self.client.send(request).await.map_err(|e| Failure::Retry(e.to_string()))?;
+        let status = response.status().as_u16();
+        match status {
+            // Our partial file is already the whole thing, or doesn't belong to this file any more
+            416 if item.total_bytes == Some(offset) => return self.finish(item).await,
+            416 => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(Failure::Retry("Partial download doesn't match the file upstream".to_string()));
+            }
+            206 => {}
+            200 => offset = 0,
+            404 | 410 | 451 => return Err(Failure::Permanent(format!("HTTP {}", status))),
+            _ => return Err(Failure::Retry(format!("HTTP {}", status))),
+        }
+
+        // A range that doesn't start where we asked means the partial file can't be trusted
+        let range = response.headers().get("content-range").and_then(|v| v.to_str().ok()).and_then(content_range);
+        if status == 206 && range.map(|(start, _)| start) != Some(offset) {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(Failure::Retry("Server resumed at the wrong offset".to_string()));
+        }
+        let total = match status {
+            206 => range.and_then(|(_, total)| total),
+            _ => response.content_length(),
+        };
+        let header_text = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let validator = header_text("etag").or_else(|| header_text("last-modified"));
+        let content_type = header_text("content-type");
+
+        self.reserve(item, total, |i| {
+            i.total_bytes = total.or(i.total_bytes);
+            i.validator = validator.or(i.validator.take());
+            i.content_type = content_type.or(i.content_type.take());
+            i.bytes = offset;
+        })
+        .await?;
+
+        let mut file = match offset {
+            0 => tokio::fs::File::create(&partial).await,
+            _ => tokio::fs::OpenOptions::new().append(true).open(&partial).await,
+        }
+        .map_err(|e| Failure::Retry(e.to_string()))?;
+        let mut written = offset;
+        loop {
+            let chunk = match tokio::time::timeout(STALL_TIMEOUT, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err(self.interrupted(item, &mut file, written, e.to_string()).await),
+                Err(_) => return Err(self.interrupted(item, &mut file, written, "Download stalled".to_string()).await),
+            };
+            written += chunk.len() as u64;
+            // Against live usage: other downloads may have claimed space since this one started
+            if written > self.progress(item, written).await {
+                drop(file);
+                let _ = tokio::fs::remove_file(&partial).await;
+                self.update_quietly(&item.id, |i| i.bytes = 0).await;
+                return Err(Failure::Permanent(format!("Disk quota for {} exceeded", item.category)));
+            }
+            file.write_all(&chunk).await.map_err(|e| Failure::Retry(e.to_string()))?;
+        }
+        file.flush().await.map_err(|e| Failure::Retry(e.to_string()))?;
+        self.update(&item.id, |i| i.bytes = written).await;
+
+        if total.is_some_and(|total| total != written) {
+            return Err(Failure::Retry(format!("Got {} of {} bytes", written, total.unwrap_or(0))));
+        }
+        self.finish(item).await
+    }
+
+    // Keep what arrived so the next attempt resumes from there
+    async fn interrupted(&self, item: &MirrorItem, file: &mut tokio::fs::File, written: u64, error: String) -> Failure {
+        let _ = file.flush().await;
+        self.update(&item.id, |i| i.bytes = written).await;
+        Failure::Retry(error)
+    }
+
+    // Hash the complete file; a mismatch throws the download away
+    async fn finish(&self, item: &MirrorItem) -> Result<String, Failure> {
+        let partial = self.partial_path(item);
+        let sha256 = hash_file(&partial).await.map_err(|e| Failure::Retry(e.to_string()))?;
+        if let Some(expected) = &item.expected_sha256 {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                let _ = tokio::fs::remove_file(&partial).await;
+                self.update(&item.id, |i| i.bytes = 0).await;
+                return Err(Failure::Retry(format!("Checksum mismatch: expected {}, got {}", expected, sha256)));
+            }
+        }
+        tokio::fs::rename(&partial, self.path(item)).await.map_err(|e| Failure::Retry(e.to_string()))?;
+        Ok(sha256)
+    }
+
+    // Bytes the item may still take up within its category's quota
+    fn room(&self, items: &HashMap<String, MirrorItem>, item: &MirrorItem) -> u64 {
+        let quota = self.settings().quotas.get(&item.category).copied().unwrap_or(0);
+        let used: u64 = items.values().filter(|i| i.category == item.category && i.id != item.id).map(MirrorItem::reserved).sum();
+        quota.saturating_sub(used)
+    }
+
+    // Check the quota and record the response under one lock, so downloads starting
+    // together can't each claim the same room
+    async fn reserve(&self, item: &MirrorItem, total: Option<u64>, change: impl FnOnce(&mut MirrorItem)) -> Result<(), Failure> {
+        {
+            let mut items = self.items.write().await;
+            if total.is_some_and(|total| total > self.room(&items, item)) {
+                return Err(Failure::Permanent(format!("Disk quota for {} exceeded", item.category)));
+            }
+            if let Some(item) = items.get_mut(&item.id) {
+                change(item);
+            }
+        }
+        self.persist().await;
+        Ok(())
+    }
+
+    // Publish how far the download got, so downloads of unknown size count in usage
+    // as they grow, and return the room left for it now
+    async fn progress(&self, item: &MirrorItem, written: u64) -> u64 {
+        let mut items = self.items.write().await;
+        if let Some(item) = items.get_mut(&item.id) {
+            item.bytes = written;
+        }
+        self.room(&items, item)
+    }
+
+    async fn update(&self, id: &str, change: impl FnOnce(&mut MirrorItem)) -> Option<MirrorItem> {
+        let item = self.update_quietly(id, change).await;
+        self.persist().await;
+        item
+    }
+
+    // Progress only lives in memory until the next real change is saved
+    async fn update_quietly(&self, id: &str, change: impl FnOnce(&mut MirrorItem)) -> Option<MirrorItem> {
+        let mut items = self.items.write().await;
+        let item = items.get_mut(id)?;
+        change(item);
+        Some(item.clone())
+    }
+
+    async fn persist(&self) {
+        let _saving = self.saving.lock().await;
+        let directory = self.settings().directory;
+        let items: Vec<MirrorItem> = self.items.read().await.values().cloned().collect();
+        let Ok(json) = serde_json::to_string_pretty(&items) else { return };
+        let tmp = directory.join(format!("{}.tmp", INDEX_FILE));
+        let result = async {
+            tokio::fs::create_dir_all(&directory).await?;
+            tokio::fs::write(&tmp, json).await?;
+            tokio::fs::rename(&tmp, directory.join(INDEX_FILE)).await
+        };
+        if let Err(e) = result.await {
+            eprintln!("❌ Failed to save the mirror index: {}", e);
+        }
+    }
+}
+
+fn item_id(url: &str) -> String {
+    format!("mir_{}", &format!("{:x}", md5::compute(url))[..16])
+}
+
+// "bytes 100-199/1000" or "bytes 100-199/*"
+fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.trim().parse().ok()?;
+    Some((start, total.trim().parse().ok()))
+}
+
+async fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_CHUNK];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// A single "bytes=" range within a file of `len` bytes; Err for one that can't be satisfied.
+// Multiple or malformed ranges aren't supported and get the whole file, as the spec allows.
+fn byte_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else { return Ok(None) };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else { return Ok(None) };
+    let last = len.saturating_sub(1);
+    let (start, end) = match (position(start), position(end)) {
+        // The last `suffix` bytes
+        (None, Some(suffix)) if start.trim().is_empty() => (len.saturating_sub(suffix), last),
+        (Some(start), None) if end.trim().is_empty() => (start, last),
+        (Some(start), Some(end)) if start <= end => (start, end.min(last)),
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+// A byte position; ones too big for u64 are past the end of any file anyway
+fn position(text: &str) -> Option<u64> {
+    let text = text.trim();
+    (!text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())).then(|| text.parse().unwrap_or(u64::MAX))
+}
+
+#[derive(Deserialize)]
+pub struct MirrorRequest {
+    #[serde(default)]
+    content_ids: Vec<String>,
+    #[serde(default)]
+    urls: Vec<String>,
+    // Quota category for the raw URLs (default "media")
+    category: Option<String>,
+}
+
+// Verified results from earlier searches by open-licensed providers: a book's
+// file, or every chapter of an audiobook
+async fn content_targets(state: &AppState, id: &str) -> Result<Vec<MirrorTarget>, String> {
+    let Some((_, category)) = OPEN_PROVIDERS.iter().find(|(prefix, _)| id.starts_with(prefix)) else {
+        return Err(format!("{} isn't from an open-licensed provider", id));
+    };
+    let cache = state.cache.read().await;
+    let content = cache.values().flatten().find(|c| c.id == id).ok_or_else(|| format!("Unknown content ID {}", id))?;
+    if !content.verified {
+        return Err(format!("{} hasn't passed verification", id));
+    }
+
+    // Verification only vouches for the checksum of the URL it checked
+    let checked = |url: &str, verification: Option<&VerificationResult>| verification.filter(|v| v.url == url).map(|v| (v.sha256.clone(), v.content_length));
+
+    if let Some(audiobook) = &content.audiobook {
+        return Ok(audiobook
+            .chapters
+            .iter()
+            .map(|chapter| {
+                let (expected_sha256, total_bytes) = checked(&chapter.stream_url, chapter.verification.as_ref()).unwrap_or_default();
+                MirrorTarget {
+                    url: chapter.stream_url.clone(),
+                    category: category.to_string(),
+                    content_id: Some(id.to_string()),
+                    title: format!("{} - {}", audiobook.title, chapter.title),
+                    expected_sha256,
+                    total_bytes,
+                }
+            })
+            .collect());
+    }
+    if content.download_url.is_empty() {
+        return Err(format!("{} has no file to download", id));
+    }
+    let (expected_sha256, total_bytes) = checked(&content.download_url, content.verification.as_ref()).unwrap_or_default();
+    Ok(vec![MirrorTarget {
+        url: content.download_url.clone(),
+        category: category.to_string(),
+        content_id: Some(id.to_string()),
+        title: content.title.clone(),
+        expected_sha256,
+        total_bytes,
+    }])
+}
+
+fn open_host(settings: &MirrorConfig, url: &str) -> bool {
+    let Some(host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase)) else { return false };
+    settings.open_hosts.iter().any(|open| {
+        let open = open.to_ascii_lowercase();
+        host == open || host.ends_with(&format!(".{}", open))
+    })
+}
+
+// POST /api/mirror/items - queue content IDs and URLs for mirroring
+pub async fn create_items(
+    State(state): State<AppState>,
+    Json(request): Json<MirrorRequest>,
+) -> Result<(StatusCode, Json<Vec<MirrorItem>>), (StatusCode, String)> {
+    let settings = state.config.snapshot().mirror.clone();
+    if !settings.enabled {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Mirroring is disabled".to_string()));
+    }
+
+    let mut targets = Vec::new();
+    for id in &request.content_ids {
+        targets.extend(content_targets(&state, id).await.map_err(|e| (StatusCode::BAD_REQUEST, e))?);
+    }
+    let category = request.category.unwrap_or_else(|| "media".to_string());
+    if !request.urls.is_empty() && !settings.quotas.contains_key(&category) {
+        return Err((StatusCode::BAD_REQUEST, format!("Unknown category {}", category)));
+    }
+    for url in &request.urls {
+        if !open_host(&settings, url) {
+            return Err((StatusCode::BAD_REQUEST, format!("{} isn't on an open-licensed host", url)));
+        }
+        targets.push(MirrorTarget {
+            url: url.clone(),
+            category: category.clone(),
+            content_id: None,
+            title: Url::parse(url).ok().and_then(|u| u.path_segments()?.next_back().map(str::to_string)).filter(|t| !t.is_empty()).unwrap_or_else(|| url.clone()),
+            expected_sha256: None,
+            total_bytes: None,
+        });
+    }
+    if targets.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Nothing to mirror".to_string()));
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(state.mirror.enqueue(targets).await)))
+}
+
+// GET /api/mirror/items
+pub async fn list_items(State(state): State<AppState>) -> Json<Vec<MirrorItem>> {
+    Json(state.mirror.list().await)
+}
+
+// GET /api/mirror/items/:id
+pub async fn get_item(Path(id): Path<String>, State(state): State<AppState>) -> Result<Json<MirrorItem>, StatusCode> {
+    state.mirror.get(&id).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// DELETE /api/mirror/items/:id - drop the item and its files
+pub async fn delete_item(Path(id): Path<String>, State(state): State<AppState>) -> StatusCode {
+    if state.mirror.remove(&id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// GET /api/mirror/usage - disk use against each category's quota
+pub async fn usage(State(state): State<AppState>) -> Json<Vec<CategoryUsage>> {
+    Json(state.mirror.usage().await)
+}
+
+// Audio, video, EPUB, PDF and plain text open in place; anything else (HTML, SVG,
+// whatever an upload claims to be) is a download that can't run on our origin
+fn served_type(content_type: Option<&str>) -> (String, bool) {
+    let content_type = content_type.unwrap_or("").trim().to_ascii_lowercase();
+    let base = content_type.split(';').next().unwrap_or("").trim();
+    let inline = base.starts_with("audio/") || base.starts_with("video/") || matches!(base, "application/epub+zip" | "application/pdf" | "text/plain");
+    match inline {
+        true if base == "text/plain" => (content_type.clone(), true),
+        true => (base.to_string(), true),
+        false => ("application/octet-stream".to_string(), false),
+    }
+}
+
+// GET /mirror/:id - the local copy with range support, or a redirect upstream until there is one
+pub async fn mirrored_file(Path(id): Path<String>, headers: HeaderMap, State(state): State<AppState>) -> Response {
+    let Some(item) = state.mirror.get(&id).await else { return StatusCode::NOT_FOUND.into_response() };
+    if item.status != MirrorStatus::Mirrored {
+        return Redirect::temporary(&item.url).into_response();
+    }
+    let Ok(mut file) = tokio::fs::File::open(state.mirror.path(&item)).await else {
+        return Redirect::temporary(&item.url).into_response();
+    };
+    let len = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let (content_type, inline) = served_type(item.content_type.as_deref());
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).map(|v| byte_range(v, len));
+    let (status, start, end) = match range {
+        Some(Err(())) => {
+            return (StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{}", len))]).into_response();
+        }
+        Some(Ok(Some((start, end)))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        _ => (StatusCode::OK, 0, len.saturating_sub(1)),
+    };
+    if start > 0 && file.seek(std::io::SeekFrom::Start(start)).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let length = if len == 0 { 0 } else { end - start + 1 };
+
+    let chunks = stream::unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buffer = vec![0; READ_CHUNK.min(remaining as usize)];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok::<_, std::io::Error>(Bytes::from(buffer)), (file, remaining - read as u64)))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    });
+
+    let mut response = (status, Body::from_stream(chunks)).into_response();
+    let response_headers = response.headers_mut();
+    let mut set = |name: header::HeaderName, value: String| {
+        if let Ok(value) = value.parse() {
+            response_headers.insert(name, value);
+        }
+    };
+    if content_type != "application/pdf" {
+        set(header::CONTENT_SECURITY_POLICY, CONTENT_POLICY.to_string());
+    }
+    set(header::CONTENT_TYPE, content_type);
+    set(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string());
+    if !inline {
+        set(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", item.file));
+    }
+    set(header::CONTENT_LENGTH, length.to_string());
+    set(header::ACCEPT_RANGES, "bytes".to_string());
+    if let Some(sha256) = &item.sha256 {
+        set(header::ETAG, format!("\"{}\"", sha256));
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        set(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::extract::Path as UrlPath;
+    use axum::routing::get;
+    use axum::Router;
+    use futures_util::StreamExt;
+    use std::sync::Mutex as StdMutex;
+
+    fn body() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Stand-in upstream: /ranged.bin honours ranges, /whole.bin ignores them, and a few
+    // paths fail. Also returns the Range header of every request.
+    async fn stand_in() -> (String, Arc<StdMutex<Vec<String>>>) {
+        let ranges = Arc::new(StdMutex::new(Vec::new()));
+        let seen = ranges.clone();
+        let app = Router::new()
+            .route(
+                "/:name",
+                get(move |UrlPath(name): UrlPath<String>, headers: HeaderMap| {
+                    let seen = seen.clone();
+                    async move {
+                        let range = headers.get(header::RANGE).and_then(|r| r.to_str().ok()).unwrap_or_default().to_string();
+                        seen.lock().unwrap().push(range.clone());
+                        let body = body();
+                        let start: Option<usize> = range.strip_prefix("bytes=").and_then(|r| r.trim_end_matches('-').parse().ok());
+                        match (name.as_str(), start) {
+                            ("ranged.bin", Some(start)) if start >= body.len() => {
+                                (StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{}", body.len()))]).into_response()
+                            }
+                            ("ranged.bin", Some(start)) => (
+                                StatusCode::PARTIAL_CONTENT,
+                                [(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, body.len() - 1, body.len()))],
+                                body[start..].to_vec(),
+                            )
+                                .into_response(),
+                            ("ranged.bin", None) | ("whole.bin", _) => body.into_response(),
+                            ("gone.bin", _) => StatusCode::GONE.into_response(),
+                            _ => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+                        }
+                    }
+                }),
+            )
+            // 1000 bytes, a pause, then 5000 more, with no length up front
+            .route(
+                "/stream.bin",
+                get(|| async {
+                    let chunks = stream::iter([(0, 1000), (300, 5000)]).then(|(pause, n)| async move {
+                        tokio::time::sleep(Duration::from_millis(pause)).await;
+                        Ok::<_, std::io::Error>(Bytes::from(vec![7u8; n]))
+                    });
+                    Body::from_stream(chunks)
+                }),
+            )
+            // The same as /whole.bin, once the stream above is partway through
+            .route(
+                "/late.bin",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    body()
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, ranges)
+    }
+
+    async fn manager(name: &str, change: impl FnOnce(&mut MirrorConfig)) -> (MirrorManager, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("content-server-mirror-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        config.mirror.directory = dir.clone();
+        change(&mut config.mirror);
+        let config = Arc::new(ConfigStore::fixed(config));
+        (MirrorManager::new(GuardedClient::new(config.clone()), config).await, dir)
+    }
+
+    async fn queue(manager: &MirrorManager, url: String, expected_sha256: Option<String>) -> MirrorItem {
+        let target = MirrorTarget { url, category: "book".to_string(), content_id: None, title: "Emma".to_string(), expected_sha256, total_bytes: None };
+        manager.enqueue(vec![target]).await.remove(0)
+    }
+
+    // Mirror the item once and return it as it ended up
+    async fn attempt(manager: &MirrorManager, item: &MirrorItem) -> MirrorItem {
+        manager.mirror(item.clone()).await;
+        manager.get(&item.id).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn downloads_resume_from_partial_files() {
+        let (base_url, ranges) = stand_in().await;
+        let (manager, dir) = manager("resume", |_| {}).await;
+        let body = body();
+
+        // 206: the rest is appended to what's there
+        let item = queue(&manager, format!("{}/ranged.bin", base_url), Some(sha256(&body))).await;
+        tokio::fs::create_dir_all(dir.join("book")).await.unwrap();
+        tokio::fs::write(manager.partial_path(&item), &body[..4000]).await.unwrap();
+        let done = attempt(&manager, &item).await;
+        assert_eq!((done.status, done.bytes, done.total_bytes), (MirrorStatus::Mirrored, 10_000, Some(10_000)));
+        assert_eq!(tokio::fs::read(manager.path(&item)).await.unwrap(), body);
+        assert_eq!(ranges.lock().unwrap().last().unwrap(), "bytes=4000-");
+
+        // 200: the server sent everything, so the partial file is started over
+        let item = queue(&manager, format!("{}/whole.bin", base_url), None).await;
+        tokio::fs::write(manager.partial_path(&item), vec![0u8; 4000]).await.unwrap();
+        let done = attempt(&manager, &item).await;
+        assert_eq!((done.status, done.sha256.clone()), (MirrorStatus::Mirrored, Some(sha256(&body))));
+        assert_eq!(tokio::fs::read(manager.path(&item)).await.unwrap(), body);
+
+        // 416 on a partial file that is already whole: just checked and moved into place
+        let item = queue(&manager, format!("{}/ranged.bin?complete", base_url), None).await;
+        manager.update(&item.id, |i| i.total_bytes = Some(10_000)).await;
+        tokio::fs::write(manager.partial_path(&item), &body).await.unwrap();
+        let item = manager.get(&item.id).await.unwrap();
+        assert_eq!(attempt(&manager, &item).await.status, MirrorStatus::Mirrored);
+
+        // 416 on one longer than the file: thrown away and tried again later
+        let item = queue(&manager, format!("{}/ranged.bin?stale", base_url), None).await;
+        tokio::fs::write(manager.partial_path(&item), vec![0u8; 12_000]).await.unwrap();
+        let retried = attempt(&manager, &item).await;
+        assert_eq!((retried.status, retried.attempts), (MirrorStatus::Queued, 1));
+        assert_eq!(retried.error.as_deref(), Some("Partial download doesn't match the file upstream"));
+        assert!(!manager.partial_path(&item).exists());
+        let done = attempt(&manager, &retried).await;
+        assert_eq!((done.status, done.sha256), (MirrorStatus::Mirrored, Some(sha256(&body))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatches_start_over() {
+        let (base_url, _) = stand_in().await;
+        let (manager, dir) = manager("checksum", |_| {}).await;
+        let item = queue(&manager, format!("{}/whole.bin", base_url), Some("0".repeat(64))).await;
+
+        let retried = attempt(&manager, &item).await;
+        assert_eq!((retried.status, retried.attempts, retried.bytes, retried.sha256), (MirrorStatus::Queued, 1, 0, None));
+        assert!(retried.error.as_deref().unwrap().starts_with(&format!("Checksum mismatch: expected {}, got {}", "0".repeat(64), sha256(&body()))));
+        assert!(!manager.partial_path(&item).exists() && !manager.path(&item).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_back_off() {
+        let (base_url, _) = stand_in().await;
+        let (manager, dir) = manager("backoff", |settings| {
+            settings.retry_base_secs = 60;
+            settings.retry_max_secs = 200;
+            settings.max_attempts = 4;
+        })
+        .await;
+
+        // 60s, doubling up to the 200s cap, until the fourth failure gives up
+        let mut item = queue(&manager, format!("{}/busy.bin", base_url), None).await;
+        for (attempts, backoff) in [(1, 60), (2, 120), (3, 200)] {
+            let started = Utc::now();
+            item = attempt(&manager, &item).await;
+            assert_eq!((item.status, item.attempts, item.error.as_deref()), (MirrorStatus::Queued, attempts, Some("HTTP 503")));
+            let wait = (item.next_attempt_at.unwrap() - started).num_seconds();
+            assert!((backoff..=backoff + 1).contains(&wait), "attempt {} waits {}s", attempts, wait);
+        }
+        assert_eq!(attempt(&manager, &item).await.status, MirrorStatus::Failed);
+
+        // Gone upstream is final straight away
+        let item = queue(&manager, format!("{}/gone.bin", base_url), None).await;
+        let failed = attempt(&manager, &item).await;
+        assert_eq!((failed.status, failed.attempts, failed.error.as_deref()), (MirrorStatus::Failed, 0, Some("HTTP 410")));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn downloads_starting_together_share_the_quota() {
+        let (base_url, _) = stand_in().await;
+        let (manager, dir) = manager("quota", |settings| {
+            settings.quotas = HashMap::from([("book".to_string(), 15_000)]);
+        })
+        .await;
+
+        // Both sizes are only known once the responses arrive, at about the same time
+        let first = queue(&manager, format!("{}/ranged.bin", base_url), None).await;
+        let second = queue(&manager, format!("{}/whole.bin", base_url), None).await;
+        let (first, second) = tokio::join!(attempt(&manager, &first), attempt(&manager, &second));
+        let mut statuses = [first.status, second.status];
+        statuses.sort_by_key(|s| *s as u8);
+        assert_eq!(statuses, [MirrorStatus::Mirrored, MirrorStatus::Failed]);
+        let failed = if first.status == MirrorStatus::Failed { first } else { second };
+        assert_eq!(failed.error.as_deref(), Some("Disk quota for book exceeded"));
+        assert_eq!(manager.usage().await[0].used_bytes, 10_000);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn downloads_of_unknown_size_count_as_they_grow() {
+        let (base_url, _) = stand_in().await;
+        let (manager, dir) = manager("growing", |settings| {
+            settings.quotas = HashMap::from([("book".to_string(), 15_000)]);
+        })
+        .await;
+
+        // The sized download claims its 10000 bytes while the stream has sent 1000, which
+        // leaves the stream too little room for the rest
+        let streamed = queue(&manager, format!("{}/stream.bin", base_url), None).await;
+        let sized = queue(&manager, format!("{}/late.bin", base_url), None).await;
+        let (streamed, sized) = tokio::join!(attempt(&manager, &streamed), attempt(&manager, &sized));
+        assert_eq!(sized.status, MirrorStatus::Mirrored);
+        assert_eq!((streamed.status, streamed.error.as_deref(), streamed.bytes), (MirrorStatus::Failed, Some("Disk quota for book exceeded"), 0));
+        assert!(!manager.partial_path(&streamed).exists());
+        assert_eq!(manager.usage().await[0].used_bytes, 10_000);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(byte_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(byte_range(" bytes= 10 - 20 ", 1000), Ok(Some((10, 20))));
+        // Ends past the file are cut to it, however far past
+        assert_eq!(byte_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(byte_range("bytes=0-99999999999999999999999", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(byte_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(byte_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(byte_range("bytes=-99999999999999999999999", 1000), Ok(Some((0, 999))));
+        assert_eq!(byte_range("bytes=-0", 1000), Err(()));
+        assert_eq!(byte_range("bytes=-1", 0), Err(()));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(byte_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(byte_range("bytes=1000-2000", 1000), Err(()));
+        assert_eq!(byte_range("bytes=99999999999999999999999-", 1000), Err(()));
+        assert_eq!(byte_range("bytes=0-", 0), Err(()));
+    }
+
+    #[test]
+    fn malformed_ranges_get_the_whole_file() {
+        for value in ["items=0-9", "bytes=", "bytes=-", "bytes=abc-def", "bytes=5-2", "bytes=0-1,5-9", "bytes=--5", "bytes=1-2-3", "bytes=+1-2"] {
+            assert_eq!(byte_range(value, 1000), Ok(None), "{}", value);
+        }
+    }
+
+    #[test]
+    fn upstream_content_ranges() {
+        assert_eq!(content_range("bytes 100-199/1000"), Some((100, Some(1000))));
+        assert_eq!(content_range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(content_range("bytes */1000"), None);
+        assert_eq!(content_range("bytes 100-199"), None);
+        assert_eq!(content_range("items 0-1/2"), None);
+        assert_eq!(content_range("bytes x-199/1000"), None);
+    }
+}
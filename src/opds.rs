@@ -0,0 +1,647 @@
+// OPDS CATALOG - Verified book results as OPDS 1.2 (Atom) and OPDS 2.0 (JSON) feeds for e-reader apps
+use crate::config::OpdsCatalogConfig;
+use crate::gutenberg::Facet;
+use crate::{book_results, gutenberg_source, search_open_books, AppState, Content, SearchOptions};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const NAVIGATION_PAGE: usize = 50;
+const BOOK_PAGE: usize = 20;
+// No facet has anywhere near this many pages; it keeps page arithmetic in range
+const MAX_PAGE: usize = 100_000;
+
+const ATOM_NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ATOM_ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPDS_JSON: &str = "application/opds+json";
+const OPENSEARCH: &str = "application/opensearchdescription+xml";
+const OPEN_ACCESS: &str = "http://opds-spec.org/acquisition/open-access";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn parse(version: &str) -> Option<Self> {
+        match version {
+            "v1" => Some(Version::V1),
+            "v2" => Some(Version::V2),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Version::V1 => "/opds/v1",
+            Version::V2 => "/opds/v2",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Navigation,
+    Acquisition,
+}
+
+struct NavigationEntry {
+    title: String,
+    href: String,
+    summary: String,
+    kind: Kind,
+}
+
+struct Feed {
+    href: String,
+    title: String,
+    kind: Kind,
+    // start, up, next and previous; self and search are added when rendering
+    links: Vec<(&'static str, String)>,
+    navigation: Vec<NavigationEntry>,
+    publications: Vec<Content>,
+}
+
+// Verified publications behind facet pages and searches. Kept apart from the search
+// cache and bounded, since every facet value and page makes a key.
+#[derive(Default)]
+pub struct FeedCache {
+    entries: Mutex<HashMap<String, (Instant, Vec<Content>)>>,
+}
+
+impl FeedCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, key: &str, settings: &OpdsCatalogConfig) -> Option<Vec<Content>> {
+        let entries = self.entries.lock().unwrap();
+        let (at, publications) = entries.get(key)?;
+        (at.elapsed() < Duration::from_secs(settings.cache_secs)).then(|| publications.clone())
+    }
+
+    fn insert(&self, key: String, publications: Vec<Content>, settings: &OpdsCatalogConfig) {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = Duration::from_secs(settings.cache_secs);
+        entries.retain(|_, (at, _)| at.elapsed() < ttl);
+        while !entries.is_empty() && entries.len() >= settings.max_cache_entries {
+            let Some(oldest) = entries.iter().min_by_key(|(_, (at, _))| *at).map(|(k, _)| k.clone()) else { break };
+            entries.remove(&oldest);
+        }
+        if settings.max_cache_entries > 0 {
+            entries.insert(key, (Instant::now(), publications));
+        }
+    }
+
+    // Cached publications for `key`, or the result of `load`, stored for next time
+    async fn get_or_load(&self, key: String, settings: &OpdsCatalogConfig, load: impl std::future::Future<Output = Vec<Content>>) -> Vec<Content> {
+        if let Some(publications) = self.get(&key, settings) {
+            return publications;
+        }
+        let publications = load.await;
+        self.insert(key, publications.clone(), settings);
+        publications
+    }
+}
+
+// The catalog facets as they appear in feed paths
+fn facet(name: &str) -> Option<(Facet, &'static str)> {
+    match name {
+        "subjects" => Some((Facet::Subject, "Subjects")),
+        "authors" => Some((Facet::Author, "Authors")),
+        "languages" => Some((Facet::Language, "Languages")),
+        _ => None,
+    }
+}
+
+// Download formats (the last word of a book source name) to their media types
+fn mime(format: &str) -> &'static str {
+    match format {
+        "EPUB" => "application/epub+zip",
+        "PDF" => "application/pdf",
+        "KINDLE" => "application/x-mobipocket-ebook",
+        "HTML" => "text/html",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+// Result titles read "Title by Author (FORMAT)"; feeds carry the author and format on their own
+fn book_title(content: &Content) -> String {
+    let title = content.title.strip_suffix(&format!(" ({})", content.quality)).unwrap_or(&content.title);
+    let author = content.book.as_ref().and_then(|b| b.authors.first());
+    author
+        .and_then(|a| title.strip_suffix(&format!(" by {}", a)))
+        .unwrap_or(title)
+        .to_string()
+}
+
+fn page_links(links: &mut Vec<(&'static str, String)>, href: &str, page: usize, more: bool) {
+    if page > 1 {
+        links.push(("previous", format!("{}?page={}", href, page - 1)));
+    }
+    if more {
+        links.push(("next", format!("{}?page={}", href, page + 1)));
+    }
+}
+
+fn root_feed(version: Version) -> Feed {
+    let prefix = version.prefix();
+    let navigation = [
+        ("subjects", "Subjects", "Browse books by subject"),
+        ("authors", "Authors", "Browse books by author"),
+        ("languages", "Languages", "Browse books by language"),
+    ]
+    .into_iter()
+    .map(|(path, title, summary)| NavigationEntry {
+        title: title.to_string(),
+        href: format!("{}/{}", prefix, path),
+        summary: summary.to_string(),
+        kind: Kind::Navigation,
+    })
+    .collect();
+    Feed {
+        href: prefix.to_string(),
+        title: "Content Server Books".to_string(),
+        kind: Kind::Navigation,
+        links: vec![("start", prefix.to_string())],
+        navigation,
+        publications: vec![],
+    }
+}
+
+fn facet_feed(state: &AppState, version: Version, name: &str, page: usize) -> Option<Feed> {
+    let (facet, title) = facet(name)?;
+    let prefix = version.prefix();
+    let href = format!("{}/{}", prefix, name);
+    let values = state.gutenberg.facet_values(facet);
+    let offset = page.saturating_sub(1).saturating_mul(NAVIGATION_PAGE);
+
+    let navigation = values
+        .iter()
+        .skip(offset)
+        .take(NAVIGATION_PAGE)
+        .map(|(value, count)| NavigationEntry {
+            title: value.clone(),
+            href: format!("{}/{}", href, urlencoding::encode(value)),
+            summary: format!("{} book{}", count, if *count == 1 { "" } else { "s" }),
+            kind: Kind::Acquisition,
+        })
+        .collect();
+    let mut links = vec![("start", prefix.to_string()), ("up", prefix.to_string())];
+    page_links(&mut links, &href, page, values.len() > offset.saturating_add(NAVIGATION_PAGE));
+    Some(Feed { href, title: title.to_string(), kind: Kind::Navigation, links, navigation, publications: vec![] })
+}
+
+// One page of catalog books under a subject, author or language, verified like any book search
+async fn facet_books(state: &AppState, version: Version, name: &str, value: &str, page: usize) -> Option<Feed> {
+    let (facet, _) = facet(name)?;
+    let prefix = version.prefix();
+    let up = format!("{}/{}", prefix, name);
+    let href = format!("{}/{}", up, urlencoding::encode(value));
+    let offset = page.saturating_sub(1).saturating_mul(BOOK_PAGE);
+    let (books, total) = state.gutenberg.books_with(facet, value, offset, BOOK_PAGE);
+    if total == 0 {
+        return None;
+    }
+
+    let settings = state.config.snapshot().opds_catalog.clone();
+    let load = async {
+        let sources = books.iter().filter_map(gutenberg_source).collect();
+        let options = SearchOptions { limit: BOOK_PAGE, include_failed: false, verify: true };
+        book_results(state, sources, &options).await
+    };
+    let publications = state.opds.get_or_load(format!("{}:{}:{}", name, value, page), &settings, load).await;
+
+    let mut links = vec![("start", prefix.to_string()), ("up", up)];
+    page_links(&mut links, &href, page, total > offset.saturating_add(BOOK_PAGE));
+    Some(Feed { href, title: value.to_string(), kind: Kind::Acquisition, links, navigation: vec![], publications })
+}
+
+async fn search_feed(state: &AppState, version: Version, query: &str) -> Feed {
+    let prefix = version.prefix();
+    // Not the /search cache entry: that also lists the LibGen and Z-Library fallbacks
+    let settings = state.config.snapshot().opds_catalog.clone();
+    let options = SearchOptions { limit: BOOK_PAGE, include_failed: false, verify: true };
+    let publications = state.opds.get_or_load(format!("search:{}", query), &settings, search_open_books(state, query, &options)).await;
+    Feed {
+        href: format!("{}/search?{}={}", prefix, if version == Version::V1 { "q" } else { "query" }, urlencoding::encode(query)),
+        title: format!("Search: {}", query),
+        kind: Kind::Acquisition,
+        links: vec![("start", prefix.to_string())],
+        navigation: vec![],
+        publications,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Only downloads that passed verification become acquisition links
+fn verified(feed: &Feed) -> impl Iterator<Item = &Content> {
+    feed.publications.iter().filter(|c| c.verified)
+}
+
+fn atom(feed: &Feed) -> String {
+    let updated = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let feed_type = |kind: Kind| if kind == Kind::Navigation { ATOM_NAVIGATION } else { ATOM_ACQUISITION };
+    let link = |rel: &str, href: &str, kind: &str| format!("  <link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n", rel, escape(href), kind);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n");
+    xml.push_str(&format!("  <id>urn:content-server:opds:{}</id>\n", escape(&feed.href)));
+    xml.push_str(&format!("  <title>{}</title>\n  <updated>{}</updated>\n", escape(&feed.title), updated));
+    xml.push_str("  <author><name>Content Server</name></author>\n");
+    xml.push_str(&link("self", &feed.href, feed_type(feed.kind)));
+    for (rel, href) in &feed.links {
+        let kind = if matches!(*rel, "start" | "up") { ATOM_NAVIGATION } else { feed_type(feed.kind) };
+        xml.push_str(&link(rel, href, kind));
+    }
+    xml.push_str(&link("search", "/opds/opensearch.xml", OPENSEARCH));
+
+    for entry in &feed.navigation {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+        xml.push_str(&format!("    <id>urn:content-server:opds:{}</id>\n", escape(&entry.href)));
+        xml.push_str(&format!("    <updated>{}</updated>\n", updated));
+        xml.push_str(&format!("    <content type=\"text\">{}</content>\n", escape(&entry.summary)));
+        xml.push_str(&format!("  {}", link("subsection", &entry.href, feed_type(entry.kind))));
+        xml.push_str("  </entry>\n");
+    }
+
+    for content in verified(feed) {
+        let book = content.book.clone().unwrap_or_default();
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&book_title(content))));
+        xml.push_str(&format!("    <id>urn:content-server:book:{}</id>\n", escape(&content.id)));
+        xml.push_str(&format!("    <updated>{}</updated>\n", updated));
+        for author in &book.authors {
+            xml.push_str(&format!("    <author><name>{}</name></author>\n", escape(author)));
+        }
+        for language in &book.language {
+            xml.push_str(&format!("    <dc:language>{}</dc:language>\n", escape(language)));
+        }
+        if let Some(year) = book.year {
+            xml.push_str(&format!("    <dc:issued>{}</dc:issued>\n", year));
+        }
+        if let Some(publisher) = &book.publisher {
+            xml.push_str(&format!("    <dc:publisher>{}</dc:publisher>\n", escape(publisher)));
+        }
+        for subject in &book.subjects {
+            xml.push_str(&format!("    <category term=\"{0}\" label=\"{0}\"/>\n", escape(subject)));
+        }
+        xml.push_str(&format!("    <content type=\"text\">{}</content>\n", escape(&content.source)));
+        if let Some(image) = &book.image_url {
+            for rel in ["http://opds-spec.org/image", "http://opds-spec.org/image/thumbnail"] {
+                xml.push_str(&format!("  {}", link(rel, image, "image/jpeg")));
+            }
+        }
+        let length = content.verification.as_ref().and_then(|v| v.content_length);
+        xml.push_str(&format!(
+            "    <link rel=\"{}\" href=\"{}\" type=\"{}\"{}/>\n",
+            OPEN_ACCESS,
+            escape(&content.download_url),
+            mime(&content.quality),
+            length.map(|l| format!(" length=\"{}\"", l)).unwrap_or_default()
+        ));
+        xml.push_str("  </entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn opds2(feed: &Feed) -> Value {
+    let mut links = vec![json!({ "rel": "self", "href": feed.href, "type": OPDS_JSON })];
+    links.extend(feed.links.iter().map(|(rel, href)| json!({ "rel": rel, "href": href, "type": OPDS_JSON })));
+    links.push(json!({ "rel": "search", "href": "/opds/v2/search{?query}", "type": OPDS_JSON, "templated": true }));
+
+    let mut document = json!({
+        "metadata": { "title": feed.title },
+        "links": links,
+    });
+    if feed.kind == Kind::Navigation {
+        document["navigation"] = feed
+            .navigation
+            .iter()
+            .map(|entry| json!({ "title": entry.title, "href": entry.href, "type": OPDS_JSON, "rel": "subsection" }))
+            .collect();
+    } else {
+        document["publications"] = verified(feed).map(publication).collect();
+    }
+    document
+}
+
+fn publication(content: &Content) -> Value {
+    let book = content.book.clone().unwrap_or_default();
+    let mut metadata = json!({
+        "@type": "http://schema.org/Book",
+        "identifier": format!("urn:content-server:book:{}", content.id),
+        "title": book_title(content),
+        "author": book.authors.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+        "language": book.language,
+        "subject": book.subjects.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+    });
+    if let Some(year) = book.year {
+        metadata["published"] = json!(year.to_string());
+    }
+    if let Some(publisher) = &book.publisher {
+        metadata["publisher"] = json!(publisher);
+    }
+
+    let mut link = json!({ "rel": OPEN_ACCESS, "href": content.download_url, "type": mime(&content.quality) });
+    if let Some(length) = content.verification.as_ref().and_then(|v| v.content_length) {
+        link["properties"] = json!({ "length": length });
+    }
+    let images: Vec<Value> = book.image_url.iter().map(|href| json!({ "href": href, "type": "image/jpeg" })).collect();
+    json!({ "metadata": metadata, "links": [link], "images": images })
+}
+
+fn render(version: Version, feed: &Feed) -> Response {
+    match version {
+        Version::V1 => {
+            let kind = if feed.kind == Kind::Navigation { ATOM_NAVIGATION } else { ATOM_ACQUISITION };
+            ([(header::CONTENT_TYPE, kind)], atom(feed)).into_response()
+        }
+        Version::V2 => ([(header::CONTENT_TYPE, OPDS_JSON)], opds2(feed).to_string()).into_response(),
+    }
+}
+
+fn not_found(what: &str) -> Response {
+    (StatusCode::NOT_FOUND, format!("No catalog feed {}", what)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    page: Option<usize>,
+}
+
+impl PageQuery {
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).clamp(1, MAX_PAGE)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: Option<String>,
+    query: Option<String>, // OPDS 2.0 templates name it query
+}
+
+// GET /opds - the OPDS 1.2 catalog root, where most readers start
+pub async fn catalog_root() -> Response {
+    render(Version::V1, &root_feed(Version::V1))
+}
+
+// GET /opds/:version - v1 (Atom) or v2 (JSON) catalog root
+pub async fn catalog(Path(version): Path<String>) -> Response {
+    match Version::parse(&version) {
+        Some(version) => render(version, &root_feed(version)),
+        None => not_found(&version),
+    }
+}
+
+// GET /opds/:version/:facet - subjects, authors or languages with their book counts
+pub async fn facet_values(
+    Path((version, name)): Path<(String, String)>,
+    Query(params): Query<PageQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(version) = Version::parse(&version) else { return not_found(&version) };
+    match facet_feed(&state, version, &name, params.page()) {
+        Some(feed) => render(version, &feed),
+        None => not_found(&name),
+    }
+}
+
+// GET /opds/:version/:facet/:value - verified downloads of the books under one value
+pub async fn facet_entries(
+    Path((version, name, value)): Path<(String, String, String)>,
+    Query(params): Query<PageQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(version) = Version::parse(&version) else { return not_found(&version) };
+    match facet_books(&state, version, &name, &value, params.page()).await {
+        Some(feed) => render(version, &feed),
+        None => not_found(&format!("{}/{}", name, value)),
+    }
+}
+
+// GET /opds/:version/search?q= - book search as an acquisition feed
+pub async fn search(
+    Path(version): Path<String>,
+    Query(params): Query<SearchQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(version) = Version::parse(&version) else { return not_found(&version) };
+    let Some(query) = params.q.or(params.query).filter(|q| !q.trim().is_empty()) else {
+        return (StatusCode::BAD_REQUEST, "Missing search terms".to_string()).into_response();
+    };
+    render(version, &search_feed(&state, version, query.trim()).await)
+}
+
+// GET /opds/opensearch.xml - OpenSearch templates must be absolute, so they're built from the Host header
+pub async fn opensearch(headers: HeaderMap) -> Response {
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("localhost:8080");
+    let scheme = headers.get("x-forwarded-proto").and_then(|h| h.to_str().ok()).unwrap_or("http");
+    let base = format!("{}://{}", scheme, host);
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\">\n\
+         \x20 <ShortName>Content Server</ShortName>\n\
+         \x20 <Description>Search verified public-domain and openly licensed books</Description>\n\
+         \x20 <InputEncoding>UTF-8</InputEncoding>\n\
+         \x20 <OutputEncoding>UTF-8</OutputEncoding>\n\
+         \x20 <Url type=\"{}\" template=\"{}{}/search?q={{searchTerms}}\"/>\n\
+         \x20 <Url type=\"{}\" template=\"{}{}/search?query={{searchTerms}}\"/>\n\
+         </OpenSearchDescription>\n",
+        escape(ATOM_ACQUISITION),
+        escape(&base),
+        Version::V1.prefix(),
+        OPDS_JSON,
+        escape(&base),
+        Version::V2.prefix()
+    );
+    ([(header::CONTENT_TYPE, OPENSEARCH)], xml).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openlibrary::BookMetadata;
+    use crate::verify::VerificationResult;
+
+    fn content(id: &str, title: &str, quality: &str, verified: bool) -> Content {
+        Content {
+            id: id.to_string(),
+            title: format!("{} by Jane <Doe> ({})", title, quality),
+            source: "Gutenberg & Friends".to_string(),
+            stream_url: String::new(),
+            download_url: format!("https://books.example/{}?a=1&b=2", id),
+            verified,
+            quality: quality.to_string(),
+            size: None,
+            rating: None,
+            proxy_url: None,
+            metadata: None,
+            book: Some(BookMetadata {
+                authors: vec!["Jane <Doe>".to_string()],
+                year: Some(1813),
+                language: vec!["en".to_string()],
+                subjects: vec!["Love & \"Marriage\"".to_string()],
+                image_url: Some("https://covers.example/1.jpg?s=M&x=1".to_string()),
+                ..Default::default()
+            }),
+            audiobook: None,
+            verification: Some(VerificationResult {
+                url: String::new(),
+                ok: verified,
+                status_code: Some(200),
+                final_url: None,
+                latency_ms: 10,
+                ttfb_ms: None,
+                content_type: None,
+                content_kind: None,
+                content_length: Some(4096),
+                sha256: None,
+                failure: None,
+                error: None,
+                checked_at: Utc::now(),
+            }),
+        }
+    }
+
+    fn acquisition(publications: Vec<Content>) -> Feed {
+        Feed {
+            href: "/opds/v1/subjects/Love%20%26%20Marriage?x=1&y=2".to_string(),
+            title: "Love & <Marriage>".to_string(),
+            kind: Kind::Acquisition,
+            links: vec![("start", "/opds/v1".to_string()), ("next", "/opds/v1/subjects/a?page=2".to_string())],
+            navigation: vec![],
+            publications,
+        }
+    }
+
+    #[test]
+    fn atom_escapes_and_lists_only_verified_books() {
+        let feed = acquisition(vec![content("good", "Pride & Prejudice", "EPUB", true), content("bad", "Broken", "PDF", false)]);
+        let xml = atom(&feed);
+        roxmltree::Document::parse(&xml).expect("feed is well-formed XML");
+
+        assert!(xml.contains("<title>Love &amp; &lt;Marriage&gt;</title>"));
+        assert!(xml.contains("href=\"/opds/v1/subjects/Love%20%26%20Marriage?x=1&amp;y=2\""));
+        assert!(xml.contains("<title>Pride &amp; Prejudice</title>"));
+        assert!(xml.contains("<author><name>Jane &lt;Doe&gt;</name></author>"));
+        assert!(xml.contains("<category term=\"Love &amp; &quot;Marriage&quot;\""));
+        assert!(xml.contains("<content type=\"text\">Gutenberg &amp; Friends</content>"));
+        assert!(xml.contains("href=\"https://covers.example/1.jpg?s=M&amp;x=1\""));
+        assert!(xml.contains(&format!(
+            "<link rel=\"{}\" href=\"https://books.example/good?a=1&amp;b=2\" type=\"application/epub+zip\" length=\"4096\"/>",
+            OPEN_ACCESS
+        )));
+        assert!(xml.contains("<dc:issued>1813</dc:issued>"));
+        assert!(!xml.contains("Broken"));
+        assert_eq!(xml.matches(OPEN_ACCESS).count(), 1);
+        assert!(xml.contains(&format!("rel=\"next\" href=\"/opds/v1/subjects/a?page=2\" type=\"{}\"", ATOM_ACQUISITION)));
+        assert!(xml.contains(&format!("rel=\"start\" href=\"/opds/v1\" type=\"{}\"", ATOM_NAVIGATION)));
+    }
+
+    #[test]
+    fn opds2_lists_only_verified_books() {
+        let feed = acquisition(vec![content("good", "Pride & Prejudice", "PDF", true), content("bad", "Broken", "EPUB", false)]);
+        let document = opds2(&feed);
+        assert_eq!(document["metadata"]["title"], "Love & <Marriage>");
+
+        let publications = document["publications"].as_array().unwrap();
+        assert_eq!(publications.len(), 1);
+        let publication = &publications[0];
+        assert_eq!(publication["metadata"]["title"], "Pride & Prejudice");
+        assert_eq!(publication["metadata"]["author"][0]["name"], "Jane <Doe>");
+        assert_eq!(publication["metadata"]["published"], "1813");
+        assert_eq!(publication["links"][0]["rel"], OPEN_ACCESS);
+        assert_eq!(publication["links"][0]["href"], "https://books.example/good?a=1&b=2");
+        assert_eq!(publication["links"][0]["type"], "application/pdf");
+        assert_eq!(publication["links"][0]["properties"]["length"], 4096);
+        assert_eq!(publication["images"][0]["href"], "https://covers.example/1.jpg?s=M&x=1");
+
+        let rels: Vec<&str> = document["links"].as_array().unwrap().iter().map(|l| l["rel"].as_str().unwrap()).collect();
+        assert_eq!(rels, vec!["self", "start", "next", "search"]);
+        assert!(document.get("navigation").is_none());
+
+        let root = opds2(&root_feed(Version::V2));
+        assert_eq!(root["navigation"].as_array().unwrap().len(), 3);
+        assert_eq!(root["navigation"][0]["href"], "/opds/v2/subjects");
+        assert!(root.get("publications").is_none());
+    }
+
+    #[test]
+    fn media_types() {
+        assert_eq!(mime("EPUB"), "application/epub+zip");
+        assert_eq!(mime("PDF"), "application/pdf");
+        assert_eq!(mime("KINDLE"), "application/x-mobipocket-ebook");
+        assert_eq!(mime("HTML"), "text/html");
+        assert_eq!(mime("TXT"), "text/plain; charset=utf-8");
+        assert_eq!(mime("MP3"), "text/plain; charset=utf-8");
+        assert_eq!(book_title(&content("x", "Emma", "EPUB", true)), "Emma");
+    }
+
+    #[test]
+    fn pagination() {
+        let links = |page, more| {
+            let mut links = vec![];
+            page_links(&mut links, "/opds/v1/authors", page, more);
+            links
+        };
+        assert!(links(1, false).is_empty());
+        assert_eq!(links(1, true), vec![("next", "/opds/v1/authors?page=2".to_string())]);
+        assert_eq!(
+            links(3, true),
+            vec![("previous", "/opds/v1/authors?page=2".to_string()), ("next", "/opds/v1/authors?page=4".to_string())]
+        );
+        assert_eq!(links(3, false), vec![("previous", "/opds/v1/authors?page=2".to_string())]);
+
+        let page = |page| PageQuery { page }.page();
+        assert_eq!(page(None), 1);
+        assert_eq!(page(Some(0)), 1);
+        assert_eq!(page(Some(7)), 7);
+        assert_eq!(page(Some(usize::MAX)), MAX_PAGE);
+        assert_eq!(links(page(Some(usize::MAX)), true)[1].1, format!("/opds/v1/authors?page={}", MAX_PAGE + 1));
+    }
+
+    #[test]
+    fn feed_cache_is_bounded() {
+        let cache = FeedCache::new();
+        let settings = OpdsCatalogConfig { cache_secs: 60, max_cache_entries: 2 };
+        cache.insert("a".to_string(), vec![content("a", "A", "EPUB", true)], &settings);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert("b".to_string(), vec![], &settings);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert("c".to_string(), vec![], &settings);
+
+        // The oldest made way
+        assert!(cache.get("a", &settings).is_none());
+        assert!(cache.get("b", &settings).is_some());
+        assert!(cache.get("c", &settings).is_some());
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+
+        // Expired entries aren't served
+        let expired = OpdsCatalogConfig { cache_secs: 0, ..settings.clone() };
+        assert!(cache.get("b", &expired).is_none());
+        let off = OpdsCatalogConfig { max_cache_entries: 0, ..settings };
+        cache.insert("d".to_string(), vec![], &off);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+}
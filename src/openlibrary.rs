@@ -0,0 +1,548 @@
+// OPEN LIBRARY - Cover, year, language, subject and identifier enrichment for book results
+use crate::config::{ConfigStore, OpenLibraryConfig};
+use crate::guard::{FetchError, GuardedClient};
+use crate::pdf::PdfInfo;
+use crate::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+const MAX_SUBJECTS: usize = 15;
+const MAX_ISBNS: usize = 5;
+
+// What we know about a book beyond its file; sources fill what they can, Open Library the rest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookMetadata {
+    pub authors: Vec<String>,
+    pub year: Option<i32>,
+    pub language: Vec<String>,
+    pub subjects: Vec<String>,
+    pub publisher: Option<String>,
+    pub image_url: Option<String>,
+    // isbn_10, isbn_13, openlibrary_edition, openlibrary_work, oclc, lccn, gutenberg, archive
+    pub identifiers: BTreeMap<String, Vec<String>>,
+    // Read from the file itself when a PDF result is verified
+    pub pdf: Option<PdfInfo>,
+}
+
+impl BookMetadata {
+    // Keep what the source said and take the rest from `other`
+    pub fn merge(&mut self, other: BookMetadata) {
+        if self.authors.is_empty() {
+            self.authors = other.authors;
+        }
+        self.year = self.year.or(other.year);
+        if self.language.is_empty() {
+            self.language = other.language;
+        }
+        if self.subjects.is_empty() {
+            self.subjects = other.subjects;
+        }
+        self.publisher = self.publisher.take().or(other.publisher);
+        self.image_url = self.image_url.take().or(other.image_url);
+        self.pdf = self.pdf.take().or(other.pdf);
+        for (scheme, values) in other.identifiers {
+            self.identifiers.entry(scheme).or_insert(values);
+        }
+    }
+
+    pub fn identifier(&mut self, scheme: &str, value: impl Into<String>) {
+        self.identifiers.entry(scheme.to_string()).or_default().push(value.into());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Lookup {
+    Isbn(String),
+    Olid(String),
+    Title { title: String, author: Option<String> },
+}
+
+// ISBN-10 (nine digits, then a digit or X) or ISBN-13, without hyphens
+fn valid_isbn(isbn: &str) -> bool {
+    let bytes = isbn.as_bytes();
+    match bytes.len() {
+        10 => bytes[..9].iter().all(u8::is_ascii_digit) && (bytes[9].is_ascii_digit() || bytes[9] == b'X'),
+        13 => bytes.iter().all(u8::is_ascii_digit),
+        _ => false,
+    }
+}
+
+// Edition IDs look like OL7353617M
+fn valid_olid(olid: &str) -> bool {
+    olid.strip_prefix("OL")
+        .and_then(|rest| rest.strip_suffix('M'))
+        .is_some_and(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+}
+
+impl Lookup {
+    // Both end up in an Open Library path, so anything but the plain format is refused
+    fn valid(&self) -> bool {
+        match self {
+            Lookup::Isbn(isbn) => valid_isbn(isbn),
+            Lookup::Olid(olid) => valid_olid(olid),
+            Lookup::Title { .. } => true,
+        }
+    }
+
+    fn cache_key(&self) -> String {
+        match self {
+            Lookup::Isbn(isbn) => format!("isbn:{}", isbn),
+            Lookup::Olid(olid) => format!("olid:{}", olid),
+            Lookup::Title { title, author } => format!("title:{}|{}", title.to_lowercase(), author.as_deref().unwrap_or("").to_lowercase()),
+        }
+    }
+}
+
+pub struct OpenLibrary {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    // Misses are cached too so unknown books don't cost a request every search
+    cache: RwLock<HashMap<String, (Instant, Option<BookMetadata>)>>,
+}
+
+impl OpenLibrary {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self { client, config, cache: RwLock::new(HashMap::new()) }
+    }
+
+    fn settings(&self) -> OpenLibraryConfig {
+        self.config.snapshot().openlibrary.clone()
+    }
+
+    pub async fn lookup(&self, lookup: &Lookup) -> Result<Option<BookMetadata>, String> {
+        let settings = self.settings();
+        if !settings.enabled || !lookup.valid() {
+            return Ok(None);
+        }
+        let key = lookup.cache_key();
+        if let Some((at, cached)) = self.cache.read().await.get(&key) {
+            if at.elapsed() < ttl(&settings, cached) {
+                return Ok(cached.clone());
+            }
+        }
+
+        let found = match lookup {
+            Lookup::Isbn(isbn) => self.edition(&settings, &format!("/isbn/{}.json", isbn)).await?,
+            Lookup::Olid(olid) => self.edition(&settings, &format!("/books/{}.json", olid)).await?,
+            Lookup::Title { title, author } => self.search(&settings, title, author.as_deref()).await?,
+        };
+
+        let mut cache = self.cache.write().await;
+        if cache.len() >= settings.max_cache_entries {
+            // Expired entries go first, then the oldest until this one fits
+            cache.retain(|_, (at, cached)| at.elapsed() < ttl(&settings, cached));
+            let excess = (cache.len() + 1).saturating_sub(settings.max_cache_entries);
+            if excess > 0 {
+                let mut by_age: Vec<(Instant, String)> = cache.iter().map(|(key, (at, _))| (*at, key.clone())).collect();
+                by_age.sort();
+                for (_, key) in by_age.into_iter().take(excess) {
+                    cache.remove(&key);
+                }
+            }
+        }
+        cache.insert(key, (Instant::now(), found.clone()));
+        Ok(found)
+    }
+
+    // Fill in each book from Open Library within the configured time budget. Lookups
+    // that miss the budget keep running and land in the cache for the next search.
+    pub async fn enrich(self: &Arc<Self>, books: Vec<(Lookup, &mut BookMetadata)>) {
+        let settings = self.settings();
+        if !settings.enabled || books.is_empty() {
+            return;
+        }
+        let (lookups, targets): (Vec<Lookup>, Vec<&mut BookMetadata>) = books.into_iter().unzip();
+        let tasks: Vec<_> = lookups
+            .into_iter()
+            .map(|lookup| {
+                let library = self.clone();
+                tokio::spawn(async move { library.lookup(&lookup).await })
+            })
+            .collect();
+
+        let budget = Duration::from_millis(settings.budget_ms);
+        let Ok(found) = tokio::time::timeout(budget, join_all(tasks)).await else {
+            log!("⏱️  Open Library enrichment passed {}ms, returning results without it", settings.budget_ms);
+            return;
+        };
+        for (target, found) in targets.into_iter().zip(found) {
+            match found {
+                Ok(Ok(Some(metadata))) => target.merge(metadata),
+                Ok(Err(e)) => eprintln!("❌ Open Library lookup failed: {}", e),
+                _ => {}
+            }
+        }
+    }
+
+    // search.json joins works, editions and authors, so one request covers a title
+    async fn search(&self, settings: &OpenLibraryConfig, title: &str, author: Option<&str>) -> Result<Option<BookMetadata>, String> {
+        let mut url = format!(
+            "{}/search.json?title={}&limit=1&fields=key,author_name,first_publish_year,language,subject,isbn,cover_i,cover_edition_key,publisher,oclc,lccn",
+            settings.base_url.trim_end_matches('/'),
+            urlencoding::encode(title)
+        );
+        if let Some(author) = author {
+            url.push_str(&format!("&author={}", urlencoding::encode(author)));
+        }
+        let found: Value = self.client.get_json(&url).await.map_err(|e| e.to_string())?;
+        let Some(doc) = found["docs"].as_array().and_then(|docs| docs.first()) else { return Ok(None) };
+
+        let mut book = BookMetadata {
+            authors: strings(&doc["author_name"]),
+            year: doc["first_publish_year"].as_i64().map(|y| y as i32),
+            language: strings(&doc["language"]),
+            subjects: strings(&doc["subject"]).into_iter().take(MAX_SUBJECTS).collect(),
+            publisher: strings(&doc["publisher"]).into_iter().next(),
+            image_url: doc["cover_i"].as_i64().map(|id| cover_url(settings, "id", &id.to_string())),
+            identifiers: BTreeMap::new(),
+            pdf: None,
+        };
+        for isbn in strings(&doc["isbn"]).into_iter().take(MAX_ISBNS * 2) {
+            let scheme = if isbn.len() == 13 { "isbn_13" } else { "isbn_10" };
+            if book.identifiers.get(scheme).map(|v| v.len()).unwrap_or(0) < MAX_ISBNS {
+                book.identifier(scheme, isbn);
+            }
+        }
+        if let Some(work) = doc["key"].as_str().and_then(|k| k.rsplit('/').next()) {
+            book.identifier("openlibrary_work", work);
+        }
+        if let Some(edition) = doc["cover_edition_key"].as_str() {
+            book.identifier("openlibrary_edition", edition);
+        }
+        for scheme in ["oclc", "lccn"] {
+            for value in strings(&doc[scheme]).into_iter().take(MAX_ISBNS) {
+                book.identifier(scheme, value);
+            }
+        }
+        Ok(Some(book))
+    }
+
+    // An edition by ISBN or OLID, then its work for subjects and authors for names
+    async fn edition(&self, settings: &OpenLibraryConfig, path: &str) -> Result<Option<BookMetadata>, String> {
+        let base = settings.base_url.trim_end_matches('/');
+        let edition: Value = match self.client.get_json(&format!("{}{}", base, path)).await {
+            Ok(edition) => edition,
+            Err(FetchError::Request(e)) if e.status().is_some_and(|s| s.as_u16() == 404) => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        let work: Value = match key(&edition["works"][0]["key"]) {
+            Some(work) => self.client.get_json(&format!("{}/works/{}.json", base, work)).await.unwrap_or(Value::Null),
+            None => Value::Null,
+        };
+
+        // Author keys sit on the edition or, more often, on the work
+        let mut author_keys: Vec<String> = edition["authors"].as_array().into_iter().flatten().filter_map(|a| key(&a["key"])).collect();
+        if author_keys.is_empty() {
+            author_keys = work["authors"].as_array().into_iter().flatten().filter_map(|a| key(&a["author"]["key"])).collect();
+        }
+        let author_urls: Vec<String> = author_keys.iter().map(|a| format!("{}/authors/{}.json", base, a)).collect();
+        let authors = join_all(author_urls.iter().map(|url| self.client.get_json::<Value>(url))).await;
+
+        let mut book = BookMetadata {
+            authors: authors.into_iter().filter_map(|a| a.ok()?["name"].as_str().map(str::to_string)).collect(),
+            year: edition["publish_date"].as_str().and_then(year).or_else(|| work["first_publish_date"].as_str().and_then(year)),
+            language: edition["languages"].as_array().into_iter().flatten().filter_map(|l| key(&l["key"])).collect(),
+            subjects: strings(&work["subjects"]).into_iter().chain(strings(&edition["subjects"])).take(MAX_SUBJECTS).collect(),
+            publisher: strings(&edition["publishers"]).into_iter().next(),
+            image_url: edition["covers"][0].as_i64().filter(|id| *id > 0).map(|id| cover_url(settings, "id", &id.to_string())),
+            identifiers: BTreeMap::new(),
+            pdf: None,
+        };
+        for (scheme, field) in [("isbn_10", "isbn_10"), ("isbn_13", "isbn_13"), ("oclc", "oclc_numbers"), ("lccn", "lccn")] {
+            for value in strings(&edition[field]) {
+                book.identifier(scheme, value);
+            }
+        }
+        if let Some(olid) = key(&edition["key"]) {
+            if book.image_url.is_none() {
+                book.image_url = Some(cover_url(settings, "olid", &olid));
+            }
+            book.identifier("openlibrary_edition", olid);
+        }
+        if let Some(work) = key(&work["key"]) {
+            book.identifier("openlibrary_work", work);
+        }
+        Ok(Some(book))
+    }
+}
+
+// How long a cached lookup is good for; misses expire sooner
+fn ttl(settings: &OpenLibraryConfig, cached: &Option<BookMetadata>) -> Duration {
+    Duration::from_secs(if cached.is_some() { settings.cache_secs } else { settings.miss_cache_secs })
+}
+
+// "/authors/OL23919A" -> "OL23919A"
+fn key(value: &Value) -> Option<String> {
+    value.as_str().and_then(|k| k.rsplit('/').next()).filter(|k| !k.is_empty()).map(str::to_string)
+}
+
+// Publish dates are free text ("1813", "January 28, 1813", "1813?"); take the first four-digit year
+fn year(date: &str) -> Option<i32> {
+    date.as_bytes()
+        .windows(4)
+        .find(|w| w.iter().all(u8::is_ascii_digit))
+        .and_then(|w| std::str::from_utf8(w).ok()?.parse().ok())
+}
+
+fn cover_url(settings: &OpenLibraryConfig, scheme: &str, value: &str) -> String {
+    format!("{}/b/{}/{}-M.jpg", settings.covers_url.trim_end_matches('/'), scheme, value)
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(values) => values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => vec![],
+    }
+}
+
+// Catalog names are "Last, First"; Open Library matches "First Last" better
+pub fn display_name(author: &str) -> String {
+    match author.split_once(", ") {
+        Some((last, first)) if !first.contains(',') => format!("{} {}", first, last),
+        _ => author.to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    isbn: Option<String>,
+    olid: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+}
+
+// GET /api/openlibrary/lookup?isbn=|olid=|title=&author=
+pub async fn lookup(
+    Query(params): Query<LookupQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<BookMetadata>, (StatusCode, String)> {
+    let lookup = match (params.isbn, params.olid, params.title) {
+        (Some(isbn), _, _) => Lookup::Isbn(isbn.replace(['-', ' '], "").to_ascii_uppercase()),
+        (_, Some(olid), _) => Lookup::Olid(olid.trim().to_string()),
+        (_, _, Some(title)) => Lookup::Title { title, author: params.author },
+        _ => return Err((StatusCode::BAD_REQUEST, "Give an isbn, olid or title".to_string())),
+    };
+    if !lookup.valid() {
+        return Err((StatusCode::BAD_REQUEST, "isbn must be 10 or 13 digits (X allowed last in ISBN-10), olid like OL7353617M".to_string()));
+    }
+    match state.openlibrary.lookup(&lookup).await {
+        Ok(Some(book)) => Ok(Json(book)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Not found on Open Library".to_string())),
+        Err(e) => Err((StatusCode::BAD_GATEWAY, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use axum::extract::Path;
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    // Stand-in for Open Library: Pride and Prejudice by ISBN, one ISBN it doesn't know, one
+    // that takes half a second, and a title search. Also counts the requests for each path.
+    async fn stand_in(change: impl FnOnce(&mut OpenLibraryConfig)) -> (Arc<OpenLibrary>, Arc<Mutex<HashMap<String, usize>>>) {
+        let requests: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+        let seen = requests.clone();
+        let count = move |path: String| *seen.lock().unwrap().entry(path).or_insert(0) += 1;
+        let (isbns, works, authors, search) = (count.clone(), count.clone(), count.clone(), count);
+        let edition = json!({
+            "key": "/books/OL1M",
+            "works": [{"key": "/works/OL1W"}],
+            "publish_date": "January 28, 1813",
+            "languages": [{"key": "/languages/eng"}],
+            "publishers": ["T. Egerton"],
+            "covers": [42],
+            "isbn_10": ["0141439513"],
+        });
+
+        let app = Router::new()
+            .route(
+                "/isbn/:file",
+                get(move |Path(file): Path<String>| {
+                    isbns(format!("/isbn/{}", file));
+                    let edition = edition.clone();
+                    async move {
+                        match file.as_str() {
+                            "0141439513.json" => Json(edition).into_response(),
+                            "0000000000.json" => {
+                                tokio::time::sleep(Duration::from_millis(500)).await;
+                                Json(edition).into_response()
+                            }
+                            _ => StatusCode::NOT_FOUND.into_response(),
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/works/:file",
+                get(move |Path(file): Path<String>| async move {
+                    works(format!("/works/{}", file));
+                    Json(json!({"key": "/works/OL1W", "subjects": ["Courtship", "England"], "authors": [{"author": {"key": "/authors/OL1A"}}]}))
+                }),
+            )
+            .route(
+                "/authors/:file",
+                get(move |Path(file): Path<String>| async move {
+                    authors(format!("/authors/{}", file));
+                    Json(json!({"name": "Jane Austen"}))
+                }),
+            )
+            .route(
+                "/search.json",
+                get(move |Query(params): Query<HashMap<String, String>>| async move {
+                    let title = params.get("title").cloned().unwrap_or_default();
+                    search(format!("/search.json?title={}", title));
+                    Json(json!({"docs": [{"key": "/works/OL2W", "author_name": ["Somebody"], "first_publish_year": 1900, "publisher": [title]}]}))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        config.openlibrary.base_url = base_url;
+        config.openlibrary.covers_url = "https://covers.example.org".to_string();
+        change(&mut config.openlibrary);
+        let config = Arc::new(ConfigStore::fixed(config));
+        (Arc::new(OpenLibrary::new(GuardedClient::new(config.clone()), config)), requests)
+    }
+
+    fn isbn(isbn: &str) -> Lookup {
+        Lookup::Isbn(isbn.to_string())
+    }
+
+    fn title(title: &str) -> Lookup {
+        Lookup::Title { title: title.to_string(), author: None }
+    }
+
+    #[test]
+    fn merging_keeps_what_the_source_said() {
+        let mut book = BookMetadata { authors: vec!["Austen, Jane".to_string()], year: Some(1815), ..Default::default() };
+        book.identifier("gutenberg", "1342");
+        book.identifier("isbn_13", "9780141439518");
+        let mut found = BookMetadata {
+            authors: vec!["Jane Austen".to_string()],
+            year: Some(1813),
+            language: vec!["eng".to_string()],
+            subjects: vec!["Courtship".to_string()],
+            publisher: Some("T. Egerton".to_string()),
+            image_url: Some("https://covers.example.org/b/id/42-M.jpg".to_string()),
+            ..Default::default()
+        };
+        found.identifier("isbn_13", "9780000000002");
+        found.identifier("openlibrary_work", "OL1W");
+
+        book.merge(found);
+        assert_eq!((book.authors, book.year), (vec!["Austen, Jane".to_string()], Some(1815)));
+        assert_eq!((book.language, book.subjects), (vec!["eng".to_string()], vec!["Courtship".to_string()]));
+        assert_eq!(book.publisher.as_deref(), Some("T. Egerton"));
+        let identifiers: Vec<(&str, Vec<&str>)> = book.identifiers.iter().map(|(k, v)| (k.as_str(), v.iter().map(String::as_str).collect())).collect();
+        assert_eq!(identifiers, vec![("gutenberg", vec!["1342"]), ("isbn_13", vec!["9780141439518"]), ("openlibrary_work", vec!["OL1W"])]);
+    }
+
+    #[tokio::test]
+    async fn enrichment_fills_in_from_the_edition() {
+        let (library, requests) = stand_in(|_| {}).await;
+        let mut book = BookMetadata { year: Some(1815), ..Default::default() };
+        library.enrich(vec![(isbn("0141439513"), &mut book)]).await;
+
+        assert_eq!((book.authors, book.year), (vec!["Jane Austen".to_string()], Some(1815)));
+        assert_eq!((book.language, book.subjects), (vec!["eng".to_string()], vec!["Courtship".to_string(), "England".to_string()]));
+        assert_eq!(book.image_url.as_deref(), Some("https://covers.example.org/b/id/42-M.jpg"));
+        assert_eq!(book.identifiers["openlibrary_edition"], vec!["OL1M"]);
+        assert_eq!(book.identifiers["openlibrary_work"], vec!["OL1W"]);
+        assert_eq!(book.identifiers["isbn_10"], vec!["0141439513"]);
+
+        // Found once, then served from the cache
+        let again = library.lookup(&isbn("0141439513")).await.unwrap().unwrap();
+        assert_eq!(again.publisher.as_deref(), Some("T. Egerton"));
+        assert_eq!(requests.lock().unwrap().values().sum::<usize>(), 3);
+    }
+
+    #[tokio::test]
+    async fn misses_are_cached() {
+        let (library, requests) = stand_in(|_| {}).await;
+        for _ in 0..3 {
+            assert!(library.lookup(&isbn("9999999999")).await.unwrap().is_none());
+        }
+        assert_eq!(requests.lock().unwrap()["/isbn/9999999999.json"], 1);
+
+        // Refused before any request
+        assert!(library.lookup(&isbn("../works/OL1W")).await.unwrap().is_none());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Only for miss_cache_secs, unlike hits
+        let (library, requests) = stand_in(|settings| settings.miss_cache_secs = 0).await;
+        for lookup in [isbn("9999999999"), isbn("9999999999"), isbn("0141439513"), isbn("0141439513")] {
+            library.lookup(&lookup).await.unwrap();
+        }
+        let requests = requests.lock().unwrap();
+        assert_eq!((requests["/isbn/9999999999.json"], requests["/isbn/0141439513.json"]), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn slow_lookups_are_left_for_the_next_search() {
+        let (library, requests) = stand_in(|settings| settings.budget_ms = 100).await;
+        let mut book = BookMetadata { authors: vec!["Austen, Jane".to_string()], ..Default::default() };
+        let started = Instant::now();
+        library.enrich(vec![(isbn("0000000000"), &mut book)]).await;
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!((book.authors, book.year, book.identifiers.len()), (vec!["Austen, Jane".to_string()], None, 0));
+
+        // The lookup carried on and the next search gets it from the cache
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let mut book = BookMetadata::default();
+        library.enrich(vec![(isbn("0000000000"), &mut book)]).await;
+        assert_eq!(book.year, Some(1813));
+        assert_eq!(requests.lock().unwrap()["/isbn/0000000000.json"], 1);
+    }
+
+    #[tokio::test]
+    async fn a_full_cache_drops_its_oldest_entries() {
+        let (library, requests) = stand_in(|settings| settings.max_cache_entries = 3).await;
+        for name in ["Emma", "Persuasion", "Sanditon", "Lady Susan"] {
+            library.lookup(&title(name)).await.unwrap();
+        }
+        let mut cached: Vec<String> = library.cache.read().await.keys().cloned().collect();
+        cached.sort();
+        assert_eq!(cached, vec!["title:lady susan|", "title:persuasion|", "title:sanditon|"]);
+
+        // The newer ones are still cached; Emma has to be looked up again
+        for name in ["Persuasion", "Sanditon", "Lady Susan", "Emma"] {
+            library.lookup(&title(name)).await.unwrap();
+        }
+        let requests = requests.lock().unwrap();
+        assert_eq!((requests["/search.json?title=Emma"], requests["/search.json?title=Sanditon"]), (2, 1));
+    }
+
+    #[test]
+    fn identifiers_must_be_plain() {
+        assert!(Lookup::Isbn("0141439513".to_string()).valid());
+        assert!(Lookup::Isbn("080442957X".to_string()).valid());
+        assert!(Lookup::Isbn("9780141439518".to_string()).valid());
+        assert!(Lookup::Olid("OL7353617M".to_string()).valid());
+        for isbn in ["../authors/OL1A", "x?y=", "01414395", "X141439513", "978014143951X"] {
+            assert!(!Lookup::Isbn(isbn.to_string()).valid(), "{}", isbn);
+        }
+        for olid in ["../authors/OL1A", "OL1A", "OLM", "OL1M?x=", "OL1M/../../works/OL1W"] {
+            assert!(!Lookup::Olid(olid.to_string()).valid(), "{}", olid);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// PDF INSPECTION - Title, author, creation date, page count and text layer of downloaded PDFs
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone};
+use lopdf::content::Content;
+use lopdf::{decode_text_string, Dictionary, Document, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Pages checked for text and images, spread over the document
+const SAMPLE_PAGES: usize = 24;
+// Form XObjects nested deeper than this aren't looked into
+const MAX_FORM_DEPTH: usize = 8;
+
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextLayer {
+    // Pages carry text, typed or OCRed, so the PDF can be searched and reflowed
+    Text,
+    // Pages are images only
+    Scanned,
+    // Neither text nor images on the sampled pages (vector drawings, or unreadable content)
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PdfInfo {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    // RFC 3339 when the PDF gives a time, otherwise as much of the date as it has
+    pub created: Option<String>,
+    pub producer: Option<String>,
+    pub pages: usize,
+    pub text_layer: TextLayer,
+    pub encrypted: bool,
+    pub version: String,
+}
+
+// XMP is what current tools keep up to date, so it wins over the older info dictionary
+pub fn inspect(data: &[u8]) -> Result<PdfInfo, String> {
+    let document = Document::load_mem(data).map_err(|e| format!("Unreadable PDF: {}", e))?;
+    let info = document
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|info| resolve(&document, info))
+        .and_then(|info| info.as_dict().ok());
+    let xmp = xmp_packet(&document);
+    let xmp = xmp.as_deref().and_then(|xml| roxmltree::Document::parse(xml).ok());
+
+    let info_text = |key: &[u8]| info.and_then(|info| text(&document, info, key));
+    let xmp_text = |ns: &str, name: &str| xmp.as_ref().and_then(|xml| xmp_values(xml, ns, name).into_iter().next());
+
+    let authors = match xmp.as_ref().map(|xml| xmp_values(xml, DC, "creator")).filter(|a| !a.is_empty()) {
+        Some(creators) => creators,
+        None => info_text(b"Author").map(|a| split_authors(&a)).unwrap_or_default(),
+    };
+    let created = xmp_text(XMP, "CreateDate")
+        .and_then(|date| iso_date(&date))
+        .or_else(|| info_text(b"CreationDate").and_then(|date| pdf_date(&date)));
+
+    let pages = document.get_pages();
+    Ok(PdfInfo {
+        title: xmp_text(DC, "title").or_else(|| info_text(b"Title")),
+        authors,
+        created,
+        producer: info_text(b"Producer").or_else(|| xmp_text("http://ns.adobe.com/pdf/1.3/", "Producer")),
+        pages: pages.len(),
+        text_layer: text_layer(&document, pages.values().copied().collect()),
+        encrypted: document.is_encrypted() || document.trailer.has(b"Encrypt"),
+        version: document.version.clone(),
+    })
+}
+
+fn resolve<'a>(document: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => document.get_object(*id).ok(),
+        other => Some(other),
+    }
+}
+
+fn text(document: &Document, dict: &Dictionary, key: &[u8]) -> Option<String> {
+    let value = resolve(document, dict.get(key).ok()?)?;
+    let text = decode_text_string(value).ok()?;
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn xmp_packet(document: &Document) -> Option<String> {
+    let catalog = document.catalog().ok()?;
+    let stream = resolve(document, catalog.get(b"Metadata").ok()?)?.as_stream().ok()?;
+    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+    let xml = String::from_utf8_lossy(&data);
+    // Parse from the first element; the xpacket wrapper and padding around it aren't needed
+    let start = xml.find("<x:xmpmeta").or_else(|| xml.find("<rdf:RDF"))?;
+    let end = xml.rfind("</x:xmpmeta>").map(|i| i + "</x:xmpmeta>".len()).or_else(|| xml.rfind("</rdf:RDF>").map(|i| i + "</rdf:RDF>".len()))?;
+    xml.get(start..end).map(str::to_string)
+}
+
+// A property is either an attribute of an rdf:Description or an element holding
+// plain text or an rdf:Alt/Seq/Bag of rdf:li values
+fn xmp_values(xml: &roxmltree::Document, ns: &str, name: &str) -> Vec<String> {
+    for node in xml.descendants().filter(|n| n.is_element()) {
+        if node.tag_name().namespace() == Some(RDF) && node.tag_name().name() == "Description" {
+            if let Some(value) = node.attributes().find(|a| a.namespace() == Some(ns) && a.name() == name) {
+                return vec![value.value().trim().to_string()].into_iter().filter(|v| !v.is_empty()).collect();
+            }
+        }
+        if node.tag_name().namespace() == Some(ns) && node.tag_name().name() == name {
+            let items: Vec<String> = node
+                .descendants()
+                .filter(|n| n.tag_name().namespace() == Some(RDF) && n.tag_name().name() == "li")
+                .filter_map(|li| li.text().map(str::trim).filter(|t| !t.is_empty()).map(str::to_string))
+                .collect();
+            if !items.is_empty() {
+                return items;
+            }
+            return node.text().map(str::trim).filter(|t| !t.is_empty()).map(|t| vec![t.to_string()]).unwrap_or_default();
+        }
+    }
+    Vec::new()
+}
+
+// Info dictionaries put every author in one string
+fn split_authors(authors: &str) -> Vec<String> {
+    let separator = if authors.contains(';') { ";" } else { " and " };
+    authors.split(separator).map(str::trim).filter(|a| !a.is_empty()).map(str::to_string).collect()
+}
+
+// "D:YYYYMMDDHHmmSSOHH'mm'", where everything after the year is optional
+fn pdf_date(value: &str) -> Option<String> {
+    let value = value.trim().trim_start_matches("D:");
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let part = |range: std::ops::Range<usize>, default: u32| digits.get(range).and_then(|d| d.parse().ok()).unwrap_or(default);
+    let year: i32 = digits.get(..4)?.parse().ok()?;
+    if digits.len() < 8 {
+        return Some(match digits.len() {
+            6.. => format!("{}-{:02}", year, part(4..6, 1)),
+            _ => year.to_string(),
+        });
+    }
+    let date = NaiveDate::from_ymd_opt(year, part(4..6, 1), part(6..8, 1))?;
+    if digits.len() < 12 {
+        return Some(date.to_string());
+    }
+    let time = date.and_hms_opt(part(8..10, 0), part(10..12, 0), part(12..14, 0))?;
+
+    // Z, +HH'mm' or -HH'mm'; a missing zone is taken as UTC
+    let zone = &value[digits.len()..];
+    let offset = match zone.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let numbers: String = zone[1..].chars().filter(char::is_ascii_digit).collect();
+            let hours: i32 = numbers.get(..2).and_then(|h| h.parse().ok()).unwrap_or(0);
+            let minutes: i32 = numbers.get(2..4).and_then(|m| m.parse().ok()).unwrap_or(0);
+            let seconds = (hours * 3600 + minutes * 60) * if sign == '-' { -1 } else { 1 };
+            FixedOffset::east_opt(seconds)?
+        }
+        _ => FixedOffset::east_opt(0)?,
+    };
+    Some(offset.from_local_datetime(&time).single()?.to_rfc3339())
+}
+
+// XMP dates are ISO 8601 already, possibly without a time or zone
+fn iso_date(value: &str) -> Option<String> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.to_rfc3339());
+    }
+    value.get(..4)?.parse::<i32>().ok()?;
+    Some(value.to_string())
+}
+
+// Text showing operators mean a text layer; image XObjects and inline images with
+// no text anywhere in the sample mean a scan. Form XObjects are looked into, since
+// both can sit inside them.
+fn text_layer(document: &Document, pages: Vec<ObjectId>) -> TextLayer {
+    let step = pages.len().div_ceil(SAMPLE_PAGES).max(1);
+    let mut marks = Marks::default();
+    for page in pages.into_iter().step_by(step) {
+        let Ok(content) = document.get_and_decode_page_content(page) else { continue };
+        let resources = match document.get_page_resources(page) {
+            Ok((direct, inherited)) => direct.into_iter().chain(inherited.into_iter().filter_map(|id| document.get_dictionary(id).ok())).collect(),
+            Err(_) => vec![],
+        };
+        scan(document, &content, &resources, &mut marks, &mut HashSet::new(), 0);
+        if marks.text {
+            return TextLayer::Text;
+        }
+    }
+    if marks.images {
+        TextLayer::Scanned
+    } else {
+        TextLayer::Unknown
+    }
+}
+
+#[derive(Default)]
+struct Marks {
+    text: bool,
+    images: bool,
+}
+
+// `resources` are searched in order for the XObjects a `Do` names
+fn scan(document: &Document, content: &Content, resources: &[&Dictionary], marks: &mut Marks, seen: &mut HashSet<ObjectId>, depth: usize) {
+    for operation in &content.operations {
+        match operation.operator.as_str() {
+            "Tj" | "TJ" | "'" | "\"" => marks.text = true,
+            "BI" => marks.images = true,
+            "Do" => {
+                let Some(name) = operation.operands.first().and_then(|n| n.as_name().ok()) else { continue };
+                let Some(reference) = resources.iter().find_map(|r| {
+                    let xobjects = resolve(document, r.get(b"XObject").ok()?)?.as_dict().ok()?;
+                    xobjects.get(name).ok()
+                }) else {
+                    continue;
+                };
+                // A form drawing itself, directly or not, is only followed once
+                if let Object::Reference(id) = reference {
+                    if !seen.insert(*id) {
+                        continue;
+                    }
+                }
+                let Some(stream) = resolve(document, reference).and_then(|x| x.as_stream().ok()) else { continue };
+                match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+                    Ok(b"Image") => marks.images = true,
+                    Ok(b"Form") if depth < MAX_FORM_DEPTH => {
+                        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                        let Ok(form) = Content::decode(&data) else { continue };
+                        // Forms without their own resources use the page's
+                        let own = stream.dict.get(b"Resources").ok().and_then(|r| resolve(document, r)).and_then(|r| r.as_dict().ok());
+                        let inner: Vec<&Dictionary> = own.into_iter().chain(resources.iter().copied()).collect();
+                        scan(document, &form, &inner, marks, seen, depth + 1);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        if marks.text {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream, StringFormat};
+
+    // A one-page PDF drawing `content`, with `xobjects` as the page's XObject resources
+    fn pdf(info: Dictionary, content: &str, xobjects: impl FnOnce(&mut Document) -> Dictionary) -> Vec<u8> {
+        let mut document = Document::with_version("1.5");
+        let xobjects = xobjects(&mut document);
+        let pages_id = document.new_object_id();
+        let content_id = document.add_object(Stream::new(dictionary! {}, content.as_bytes().to_vec()));
+        let page_id = document.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! { "XObject" => xobjects },
+        });
+        document.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page_id.into()], "Count" => 1 }));
+        let catalog_id = document.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        let info_id = document.add_object(info);
+        document.trailer.set("Root", catalog_id);
+        document.trailer.set("Info", info_id);
+
+        let mut data = Vec::new();
+        document.save_to(&mut data).unwrap();
+        data
+    }
+
+    fn image(document: &mut Document) -> ObjectId {
+        let dict = dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 1, "Height" => 1, "ColorSpace" => "DeviceGray", "BitsPerComponent" => 8 };
+        document.add_object(Stream::new(dict, vec![0]))
+    }
+
+    fn form(document: &mut Document, content: &str, resources: Dictionary) -> ObjectId {
+        let dict = dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => vec![0.into(), 0.into(), 10.into(), 10.into()], "Resources" => resources };
+        document.add_object(Stream::new(dict, content.as_bytes().to_vec()))
+    }
+
+    fn layer(content: &str, xobjects: impl FnOnce(&mut Document) -> Dictionary) -> TextLayer {
+        inspect(&pdf(dictionary! {}, content, xobjects)).unwrap().text_layer
+    }
+
+    #[test]
+    fn info_dictionary() {
+        let literal = |text: &str| Object::String(text.as_bytes().to_vec(), StringFormat::Literal);
+        let info = dictionary! {
+            "Title" => literal("  A Study in Scarlet\0"),
+            "Author" => literal("Arthur Conan Doyle and Joseph Bell"),
+            "CreationDate" => literal("D:18870101120000+01'00'"),
+            "Producer" => literal("Test"),
+        };
+        let info = inspect(&pdf(info, "BT /F1 12 Tf (Hello) Tj ET", |_| dictionary! {})).unwrap();
+        assert_eq!(info.title.as_deref(), Some("A Study in Scarlet"));
+        assert_eq!(info.authors, vec!["Arthur Conan Doyle", "Joseph Bell"]);
+        assert_eq!(info.created.as_deref(), Some("1887-01-01T12:00:00+01:00"));
+        assert_eq!(info.producer.as_deref(), Some("Test"));
+        assert_eq!(info.pages, 1);
+        assert_eq!(info.text_layer, TextLayer::Text);
+        assert!(!info.encrypted);
+        assert_eq!(info.version, "1.5");
+
+        assert!(inspect(b"%PDF-1.4 not really").is_err());
+    }
+
+    #[test]
+    fn text_layers() {
+        assert_eq!(layer("BT (Hi) Tj ET", |_| dictionary! {}), TextLayer::Text);
+        assert_eq!(layer("q 1 0 0 1 0 0 cm /Im1 Do Q", |d| dictionary! { "Im1" => image(d) }), TextLayer::Scanned);
+        assert_eq!(layer("BI /W 1 /H 1 /CS /DeviceGray /BPC 8 ID \x00 EI", |_| dictionary! {}), TextLayer::Scanned);
+        assert_eq!(layer("0 0 10 10 re f", |_| dictionary! {}), TextLayer::Unknown);
+        // Names that resolve to nothing aren't images
+        assert_eq!(layer("/Missing Do", |_| dictionary! {}), TextLayer::Unknown);
+
+        // Forms are drawings until something inside them says otherwise
+        assert_eq!(layer("/Fm1 Do", |d| dictionary! { "Fm1" => form(d, "0 0 10 10 re f", dictionary! {}) }), TextLayer::Unknown);
+        assert_eq!(layer("/Fm1 Do", |d| dictionary! { "Fm1" => form(d, "BT (Hi) Tj ET", dictionary! {}) }), TextLayer::Text);
+        assert_eq!(
+            layer("/Fm1 Do", |d| {
+                let im = image(d);
+                dictionary! { "Fm1" => form(d, "/Im1 Do", dictionary! { "XObject" => dictionary! { "Im1" => im } }) }
+            }),
+            TextLayer::Scanned
+        );
+        // A form without resources of its own finds the image among the page's
+        assert_eq!(
+            layer("/Fm1 Do", |d| dictionary! { "Fm1" => form(d, "/Im1 Do", dictionary! {}), "Im1" => image(d) }),
+            TextLayer::Scanned
+        );
+        // A form drawing itself doesn't loop
+        assert_eq!(layer("/Fm1 Do", |d| dictionary! { "Fm1" => form(d, "/Fm1 Do", dictionary! {}) }), TextLayer::Unknown);
+    }
+
+    #[test]
+    fn pdf_dates() {
+        assert_eq!(pdf_date("D:20240315103000Z").as_deref(), Some("2024-03-15T10:30:00+00:00"));
+        assert_eq!(pdf_date("D:20240315103000-05'30'").as_deref(), Some("2024-03-15T10:30:00-05:30"));
+        assert_eq!(pdf_date("20240315103000").as_deref(), Some("2024-03-15T10:30:00+00:00"));
+        assert_eq!(pdf_date("D:202403151030").as_deref(), Some("2024-03-15T10:30:00+00:00"));
+        assert_eq!(pdf_date("D:20240315").as_deref(), Some("2024-03-15"));
+        assert_eq!(pdf_date("D:202403").as_deref(), Some("2024-03"));
+        assert_eq!(pdf_date("D:2024").as_deref(), Some("2024"));
+        assert_eq!(pdf_date("D:20241345"), None);
+        assert_eq!(pdf_date("D:20240315253000Z"), None);
+        assert_eq!(pdf_date("D:20240315103000+99'00'"), None);
+        assert_eq!(pdf_date("D:24"), None);
+        assert_eq!(pdf_date("yesterday"), None);
+    }
+
+    #[test]
+    fn iso_dates() {
+        assert_eq!(iso_date("2024-03-15T10:30:00Z").as_deref(), Some("2024-03-15T10:30:00+00:00"));
+        assert_eq!(iso_date(" 2024-03-15T10:30:00+02:00 ").as_deref(), Some("2024-03-15T10:30:00+02:00"));
+        assert_eq!(iso_date("2024-03-15").as_deref(), Some("2024-03-15"));
+        assert_eq!(iso_date("2024").as_deref(), Some("2024"));
+        assert_eq!(iso_date("March 2024"), None);
+        assert_eq!(iso_date("20"), None);
+    }
+
+    #[test]
+    fn authors() {
+        assert_eq!(split_authors("Jane Austen"), vec!["Jane Austen"]);
+        assert_eq!(split_authors("Strunk, William; White, E. B.;"), vec!["Strunk, William", "White, E. B."]);
+        assert_eq!(split_authors("Marx and Engels"), vec!["Marx", "Engels"]);
+        assert!(split_authors("  ").is_empty());
+    }
+
+    #[test]
+    fn xmp_properties() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="2020-05-01T08:00:00Z" xmp:Empty=" "/>
+  <rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title><rdf:Alt><rdf:li xml:lang="x-default"> The Title </rdf:li></rdf:Alt></dc:title>
+    <dc:creator><rdf:Seq><rdf:li>First Author</rdf:li><rdf:li> </rdf:li><rdf:li>Second Author</rdf:li></rdf:Seq></dc:creator>
+    <dc:format>application/pdf</dc:format>
+  </rdf:Description>
+</rdf:RDF></x:xmpmeta>"#;
+        let xml = roxmltree::Document::parse(xml).unwrap();
+        assert_eq!(xmp_values(&xml, XMP, "CreateDate"), vec!["2020-05-01T08:00:00Z"]);
+        assert!(xmp_values(&xml, XMP, "Empty").is_empty());
+        assert_eq!(xmp_values(&xml, DC, "title"), vec!["The Title"]);
+        assert_eq!(xmp_values(&xml, DC, "creator"), vec!["First Author", "Second Author"]);
+        assert_eq!(xmp_values(&xml, DC, "format"), vec!["application/pdf"]);
+        assert!(xmp_values(&xml, DC, "subject").is_empty());
+    }
+}
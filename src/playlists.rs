@@ -0,0 +1,311 @@
+// PLAYLIST REGISTRY - Live TV channels from configured M3U sources
+use crate::config::{ConfigStore, PlaylistLocation, PlaylistSource};
+use crate::guard::{BodyKind, GuardedClient};
+use crate::health::Heartbeat;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::RwLock;
+
+const REFRESH_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Channel {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub logo: Option<String>,
+    pub group: String,
+    pub source: String,
+    pub headers: HashMap<String, String>,
+    pub recordable: bool,
+}
+
+struct SourceState {
+    source: PlaylistSource,
+    fetched_at: Instant,
+    file_modified: Option<SystemTime>,
+    channels: Vec<Channel>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub channels: usize,
+    pub refreshed_secs_ago: u64,
+    pub error: Option<String>,
+}
+
+pub struct PlaylistRegistry {
+    client: GuardedClient,
+    config: Arc<ConfigStore>,
+    sources: RwLock<HashMap<String, SourceState>>,
+    generation: AtomicU64,
+    pub heartbeat: Heartbeat,
+}
+
+impl PlaylistRegistry {
+    pub fn new(client: GuardedClient, config: Arc<ConfigStore>) -> Self {
+        Self {
+            client,
+            config,
+            sources: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            heartbeat: Heartbeat::new(REFRESH_TICK),
+        }
+    }
+
+    // All channels from enabled sources, in config order
+    pub async fn channels(&self) -> Vec<Channel> {
+        let config = self.config.snapshot();
+        let sources = self.sources.read().await;
+        config
+            .playlists
+            .iter()
+            .filter_map(|s| sources.get(&s.name))
+            .flat_map(|state| state.channels.iter().cloned())
+            .collect()
+    }
+
+    pub async fn channel(&self, id: &str) -> Option<Channel> {
+        self.sources
+            .read()
+            .await
+            .values()
+            .flat_map(|state| state.channels.iter())
+            .find(|c| c.id == id)
+            .cloned()
+    }
+
+    // Outcome of the last refresh of each loaded source, in config order
+    pub async fn status(&self) -> Vec<SourceStatus> {
+        let config = self.config.snapshot();
+        let sources = self.sources.read().await;
+        config
+            .playlists
+            .iter()
+            .filter_map(|s| sources.get(&s.name))
+            .map(|state| SourceStatus {
+                name: state.source.name.clone(),
+                channels: state.channels.len(),
+                refreshed_secs_ago: state.fetched_at.elapsed().as_secs(),
+                error: state.error.clone(),
+            })
+            .collect()
+    }
+
+    // Bumped whenever the channel list changes, so callers can key caches on it
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    pub fn spawn_refresher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_TICK);
+            loop {
+                interval.tick().await;
+                self.refresh_due().await;
+                self.heartbeat.beat();
+            }
+        });
+    }
+
+    // Fetch sources that are new, edited or past their refresh interval,
+    // and drop the ones that were removed or disabled in config.
+    pub async fn refresh_due(&self) {
+        let config = self.config.snapshot();
+        let wanted: Vec<&PlaylistSource> = config.playlists.iter().filter(|s| s.enabled).collect();
+
+        {
+            let mut sources = self.sources.write().await;
+            let before = sources.len();
+            sources.retain(|name, _| wanted.iter().any(|s| &s.name == name));
+            if sources.len() != before {
+                self.generation.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        for source in wanted {
+            let file_modified = match &source.location {
+                PlaylistLocation::File(path) => tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok(),
+                PlaylistLocation::Url(_) => None,
+            };
+
+            let due = match self.sources.read().await.get(&source.name) {
+                Some(state) => {
+                    state.source != *source
+                        || state.file_modified != file_modified
+                        || state.fetched_at.elapsed() >= Duration::from_secs(source.refresh_secs)
+                }
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+
+            let (channels, error) = match self.load_source(source).await {
+                Ok(channels) => {
+                    log!("📡 Playlist {}: {} channels", source.name, channels.len());
+                    (channels, None)
+                }
+                Err(e) => {
+                    eprintln!("❌ Playlist {} failed: {}", source.name, e);
+                    // Keep serving the last good list until the next attempt
+                    let channels = match self.sources.read().await.get(&source.name) {
+                        Some(state) if state.source == *source => state.channels.clone(),
+                        _ => vec![],
+                    };
+                    (channels, Some(e))
+                }
+            };
+
+            self.sources.write().await.insert(
+                source.name.clone(),
+                SourceState {
+                    source: source.clone(),
+                    fetched_at: Instant::now(),
+                    file_modified,
+                    channels,
+                    error,
+                },
+            );
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn load_source(&self, source: &PlaylistSource) -> Result<Vec<Channel>, String> {
+        let content = match &source.location {
+            PlaylistLocation::Url(url) => {
+                let mut request = self.client.get(url);
+                for (name, value) in &source.headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                let response = self.client.send(request).await.map_err(|e| e.to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("HTTP {}", response.status()));
+                }
+                self.client
+                    .read_text(response, BodyKind::Playlist)
+                    .await
+                    .map_err(|e| e.to_string())?
+            }
+            PlaylistLocation::File(path) => tokio::fs::read_to_string(path).await.map_err(|e| e.to_string())?,
+        };
+        Ok(parse_m3u(&content, source))
+    }
+}
+
+pub fn parse_m3u(content: &str, source: &PlaylistSource) -> Vec<Channel> {
+    let mut channels = Vec::new();
+    let mut pending: Option<&str> = None;
+    let mut vlc_headers = HashMap::new();
+
+    for line in content.lines().map(str::trim) {
+        if line.starts_with("#EXTINF:") {
+            pending = Some(line);
+        } else if let Some(option) = line.strip_prefix("#EXTVLCOPT:") {
+            if let Some((name, value)) = vlc_header(option) {
+                vlc_headers.insert(name.to_string(), value.to_string());
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            // Every URL line ends its entry, whether or not we can play it
+            let info_line = pending.take();
+            let options = std::mem::take(&mut vlc_headers);
+            let scheme = line.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+            if !matches!(scheme.as_deref(), Some("http" | "https")) {
+                // rtmp://, udp://, rtsp:// and the like can't go through the proxy or verifier
+                continue;
+            }
+            if let Some(info_line) = info_line {
+                // Per-channel options win over the source-wide headers
+                let mut headers = source.headers.clone();
+                headers.extend(options);
+
+                channels.push(Channel {
+                    id: channel_id(&source.name, line),
+                    name: extract_title(info_line),
+                    url: line.to_string(),
+                    logo: extract_attr(info_line, "tvg-logo"),
+                    group: extract_attr(info_line, "group-title").unwrap_or_else(|| "General".to_string()),
+                    source: source.name.clone(),
+                    headers,
+                    recordable: source.dvr,
+                });
+            }
+        }
+    }
+
+    channels
+}
+
+// Map VLC's `http-*` options onto the request headers they stand for
+fn vlc_header(option: &str) -> Option<(&'static str, &str)> {
+    let (key, value) = option.split_once('=')?;
+    let name = match key.trim().to_ascii_lowercase().as_str() {
+        "http-user-agent" => "User-Agent",
+        "http-referrer" | "http-referer" => "Referer",
+        "http-origin" => "Origin",
+        _ => return None,
+    };
+    Some((name, value.trim()))
+}
+
+// Stable across refreshes so ids stay valid for clients between reloads
+fn channel_id(source: &str, url: &str) -> String {
+    let digest = format!("{:x}", md5::compute(format!("{}|{}", source, url)));
+    format!("live_{}", &digest[..12])
+}
+
+fn extract_title(line: &str) -> String {
+    match line.rfind(',') {
+        Some(comma_pos) => line[comma_pos + 1..].trim().to_string(),
+        None => "Unknown Channel".to_string(),
+    }
+}
+
+fn extract_attr(line: &str, name: &str) -> Option<String> {
+    let marker = format!("{}=\"", name);
+    let start = line.find(&marker)? + marker.len();
+    let end = line[start..].find('"')?;
+    Some(line[start..start + end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PlaylistLocation;
+
+    #[test]
+    fn unsupported_entries_dont_leak_into_the_next() {
+        let source = PlaylistSource {
+            name: "test".to_string(),
+            location: PlaylistLocation::Url("http://example.com/list.m3u".to_string()),
+            refresh_secs: 60,
+            headers: HashMap::from([("User-Agent".to_string(), "source".to_string())]),
+            enabled: true,
+            dvr: false,
+        };
+        let content = "#EXTM3U
+#EXTINF:-1 group-title=\"News\",RTMP News
+#EXTVLCOPT:http-referrer=https://rtmp.example/
+rtmp://rtmp.example/live/news
+https://cdn.example/orphan.m3u8
+#EXTINF:-1,Sports
+#EXTVLCOPT:http-user-agent=SportsPlayer
+HTTPS://cdn.example/sports.m3u8
+#EXTINF:-1,Weather
+httpfoo://cdn.example/weather.m3u8
+#EXTINF:-1,Music
+http://cdn.example/music.m3u8
+";
+        let channels = parse_m3u(content, &source);
+        let names: Vec<&str> = channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Sports", "Music"]);
+        assert_eq!(channels[0].headers.get("User-Agent").map(String::as_str), Some("SportsPlayer"));
+        assert!(!channels[0].headers.contains_key("Referer"));
+        assert_eq!(channels[1].headers.get("User-Agent").map(String::as_str), Some("source"));
+    }
+}
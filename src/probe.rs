@@ -0,0 +1,414 @@
+// CODEC PROBE - Reads MPEG-TS and fMP4 headers to find the codecs a stream really uses
+use serde::{Deserialize, Serialize};
+
+const TS_PACKET: usize = 188;
+const TS_SYNC: u8 = 0x47;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    MpegTs,
+    Fmp4,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Codec {
+    pub name: String,
+    pub browser_compatible: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodecReport {
+    pub container: Container,
+    pub video: Vec<Codec>,
+    pub audio: Vec<Codec>,
+    pub browser_compatible: bool,
+}
+
+impl CodecReport {
+    fn new(container: Container, video: Vec<Codec>, audio: Vec<Codec>) -> Self {
+        let browser_compatible = !video.is_empty() || !audio.is_empty();
+        let browser_compatible = browser_compatible && video.iter().chain(&audio).all(|c| c.browser_compatible);
+        Self {
+            container,
+            video,
+            audio,
+            browser_compatible,
+        }
+    }
+}
+
+fn codec(name: &str, browser_compatible: bool) -> Codec {
+    Codec {
+        name: name.to_string(),
+        browser_compatible,
+    }
+}
+
+pub fn probe(data: &[u8]) -> Option<CodecReport> {
+    if is_mpeg_ts(data) {
+        probe_ts(data)
+    } else if is_mp4(data) {
+        probe_mp4(data)
+    } else {
+        None
+    }
+}
+
+pub fn is_mpeg_ts(data: &[u8]) -> bool {
+    data.len() >= TS_PACKET * 2 && data[0] == TS_SYNC && data[TS_PACKET] == TS_SYNC
+}
+
+pub fn is_mp4(data: &[u8]) -> bool {
+    data.len() >= 8 && matches!(&data[4..8], b"ftyp" | b"styp" | b"moov" | b"moof" | b"sidx")
+}
+
+// ---- MPEG-TS: PAT -> PMT -> elementary stream types ----
+
+struct TsPacket<'a> {
+    pid: u16,
+    unit_start: bool,
+    payload: &'a [u8],
+}
+
+fn ts_packets(data: &[u8]) -> impl Iterator<Item = TsPacket<'_>> {
+    data.chunks_exact(TS_PACKET)
+        .take_while(|p| p[0] == TS_SYNC)
+        .filter_map(|p| {
+            let pid = (((p[1] & 0x1F) as u16) << 8) | p[2] as u16;
+            let unit_start = p[1] & 0x40 != 0;
+            let adaptation = (p[3] >> 4) & 0x3;
+            let offset = match adaptation {
+                0b01 => 4,
+                0b11 => 5 + p[4] as usize,
+                _ => return None,
+            };
+            (offset < TS_PACKET).then(|| TsPacket {
+                pid,
+                unit_start,
+                payload: &p[offset..],
+            })
+        })
+}
+
+// PSI sections start after a pointer field; returns the section body after the 8-byte header
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    let length = ((*section.get(1)? as usize & 0x0F) << 8) | *section.get(2)? as usize;
+    // Section length counts from byte 3 and includes the trailing CRC
+    let end = (3 + length).min(section.len()).checked_sub(4)?;
+    section.get(8..end)
+}
+
+fn probe_ts(data: &[u8]) -> Option<CodecReport> {
+    let pmt_pid = ts_packets(data)
+        .filter(|p| p.pid == 0 && p.unit_start)
+        .find_map(|p| {
+            psi_section(p.payload)?
+                .chunks_exact(4)
+                .find(|entry| u16::from_be_bytes([entry[0], entry[1]]) != 0)
+                .map(|entry| u16::from_be_bytes([entry[2] & 0x1F, entry[3]]))
+        })?;
+
+    let streams = ts_packets(data)
+        .filter(|p| p.pid == pmt_pid && p.unit_start)
+        .find_map(|p| pmt_streams(p.payload))?;
+
+    let mut video = Vec::new();
+    let mut audio = Vec::new();
+    for (stream_type, pid, descriptors) in streams {
+        match stream_type {
+            0x1B => video.push(codec("H.264", true)),
+            0x24 => video.push(codec("HEVC", false)),
+            0x01 => video.push(codec("MPEG-1 Video", false)),
+            0x02 => video.push(codec("MPEG-2 Video", false)),
+            0x0F | 0x11 => audio.push(codec("AAC", true)),
+            0x03 | 0x04 => audio.push(match mpeg_audio_layer(data, pid) {
+                Some(3) => codec("MP3", true),
+                Some(2) => codec("MP2", false),
+                _ => codec("MPEG Audio", false),
+            }),
+            0x81 => audio.push(codec("AC-3", false)),
+            0x87 => audio.push(codec("E-AC-3", false)),
+            // Private data carries its real codec in a descriptor
+            0x06 if descriptors.contains(&0x6A) => audio.push(codec("AC-3", false)),
+            0x06 if descriptors.contains(&0x7A) => audio.push(codec("E-AC-3", false)),
+            _ => {}
+        }
+    }
+
+    Some(CodecReport::new(Container::MpegTs, video, audio))
+}
+
+// (stream_type, elementary PID, descriptor tags) for each stream in a PMT
+fn pmt_streams(payload: &[u8]) -> Option<Vec<(u8, u16, Vec<u8>)>> {
+    let body = psi_section(payload)?;
+    let program_info_length = ((*body.get(2)? as usize & 0x0F) << 8) | *body.get(3)? as usize;
+    let mut rest = body.get(4 + program_info_length..)?;
+    let mut streams = Vec::new();
+
+    while rest.len() >= 5 {
+        let stream_type = rest[0];
+        let pid = (((rest[1] & 0x1F) as u16) << 8) | rest[2] as u16;
+        let info_length = (((rest[3] & 0x0F) as usize) << 8) | rest[4] as usize;
+        let mut descriptors = Vec::new();
+        let mut info = rest.get(5..5 + info_length).unwrap_or_default();
+        while info.len() >= 2 {
+            descriptors.push(info[0]);
+            info = info.get(2 + info[1] as usize..).unwrap_or_default();
+        }
+        streams.push((stream_type, pid, descriptors));
+        rest = rest.get(5 + info_length..).unwrap_or_default();
+    }
+
+    Some(streams)
+}
+
+// MPEG audio stream types cover Layer I/II/III; the frame header tells them apart
+fn mpeg_audio_layer(data: &[u8], pid: u16) -> Option<u8> {
+    let payload = ts_packets(data).find(|p| p.pid == pid && p.unit_start)?.payload;
+    if payload.get(..3)? != [0, 0, 1] {
+        return None;
+    }
+    let header_length = *payload.get(8)? as usize;
+    let frame = payload.get(9 + header_length..)?;
+    let sync = frame.windows(2).position(|w| w[0] == 0xFF && w[1] & 0xE0 == 0xE0)?;
+    match (frame[sync + 1] >> 1) & 0x3 {
+        0b01 => Some(3),
+        0b10 => Some(2),
+        0b11 => Some(1),
+        _ => None,
+    }
+}
+
+// ---- fMP4: moov -> trak -> mdia -> hdlr + minf/stbl/stsd ----
+
+fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        if rest.len() < 8 {
+            return None;
+        }
+        let size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = &rest[4..8];
+        let (header, size) = match size {
+            0 => (8, rest.len()),
+            1 => {
+                let large = u64::from_be_bytes(rest.get(8..16)?.try_into().ok()?) as usize;
+                (16, large)
+            }
+            n => (8, n),
+        };
+        if size < header {
+            return None;
+        }
+        let body = rest.get(header..size.min(rest.len()))?;
+        rest = rest.get(size..).unwrap_or_default();
+        Some((kind, body))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| *k == kind).map(|(_, body)| body)
+}
+
+fn probe_mp4(data: &[u8]) -> Option<CodecReport> {
+    // Media segments (moof without moov) need their init segment to be probed
+    let moov = find_box(data, b"moov")?;
+    let mut video = Vec::new();
+    let mut audio = Vec::new();
+
+    for (kind, trak) in boxes(moov) {
+        if kind != b"trak" {
+            continue;
+        }
+        let Some(mdia) = find_box(trak, b"mdia") else { continue };
+        let handler = find_box(mdia, b"hdlr").and_then(|h| h.get(8..12));
+        let Some(entry) = find_box(mdia, b"minf")
+            .and_then(|m| find_box(m, b"stbl"))
+            .and_then(|s| find_box(s, b"stsd"))
+            .and_then(|stsd| stsd.get(8..))
+        else {
+            continue;
+        };
+        let Some(fourcc) = sample_entry_format(entry) else { continue };
+
+        match handler {
+            Some(b"vide") => video.push(video_codec(&fourcc)),
+            Some(b"soun") => audio.push(audio_codec(&fourcc, entry)),
+            _ => {}
+        }
+    }
+
+    Some(CodecReport::new(Container::Fmp4, video, audio))
+}
+
+// Encrypted entries (encv/enca) keep the original format in sinf/frma
+fn sample_entry_format(entry: &[u8]) -> Option<[u8; 4]> {
+    let fourcc: [u8; 4] = entry.get(4..8)?.try_into().ok()?;
+    if &fourcc != b"encv" && &fourcc != b"enca" {
+        return Some(fourcc);
+    }
+    let children_offset = if &fourcc == b"encv" { 8 + 78 } else { 8 + 28 };
+    let entry_size = u32::from_be_bytes(entry.get(..4)?.try_into().ok()?) as usize;
+    let children = entry.get(children_offset..entry_size.min(entry.len()))?;
+    find_box(children, b"sinf")
+        .and_then(|sinf| find_box(sinf, b"frma"))
+        .and_then(|frma| frma.get(..4))
+        .and_then(|f| f.try_into().ok())
+}
+
+fn video_codec(fourcc: &[u8; 4]) -> Codec {
+    match fourcc {
+        b"avc1" | b"avc3" => codec("H.264", true),
+        b"hvc1" | b"hev1" => codec("HEVC", false),
+        b"av01" => codec("AV1", true),
+        b"vp09" => codec("VP9", true),
+        other => codec(&String::from_utf8_lossy(other), false),
+    }
+}
+
+fn audio_codec(fourcc: &[u8; 4], entry: &[u8]) -> Codec {
+    match fourcc {
+        // mp4a can carry MP3 too; the esds object type says which
+        b"mp4a" => match esds_object_type(entry) {
+            Some(0x69) | Some(0x6B) => codec("MP3", true),
+            _ => codec("AAC", true),
+        },
+        b"Opus" => codec("Opus", true),
+        b"fLaC" => codec("FLAC", true),
+        b"ac-3" => codec("AC-3", false),
+        b"ec-3" => codec("E-AC-3", false),
+        other => codec(&String::from_utf8_lossy(other), false),
+    }
+}
+
+fn esds_object_type(entry: &[u8]) -> Option<u8> {
+    // Audio sample entry: 8-byte box header + 28 bytes of fields before child boxes
+    let entry_size = u32::from_be_bytes(entry.get(..4)?.try_into().ok()?) as usize;
+    let esds = find_box(entry.get(36..entry_size.min(entry.len()))?, b"esds")?;
+    // Skip full-box header, then walk ES_Descriptor (0x03) to DecoderConfigDescriptor (0x04)
+    let mut rest = esds.get(4..)?;
+    while let Some((&tag, after)) = rest.split_first() {
+        let mut len_bytes = 0;
+        while after.get(len_bytes).is_some_and(|b| b & 0x80 != 0) && len_bytes < 4 {
+            len_bytes += 1;
+        }
+        let body = after.get(len_bytes + 1..)?;
+        match tag {
+            0x03 => {
+                let flags = *body.get(2)?;
+                let mut skip = 3;
+                if flags & 0x80 != 0 {
+                    skip += 2;
+                }
+                if flags & 0x40 != 0 {
+                    skip += 1 + *body.get(skip)? as usize;
+                }
+                if flags & 0x20 != 0 {
+                    skip += 2;
+                }
+                rest = body.get(skip..)?;
+            }
+            0x04 => return body.first().copied(),
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts_packet(pid: u16, section: &[u8]) -> Vec<u8> {
+        let mut packet = vec![TS_SYNC, 0x40 | (pid >> 8) as u8, pid as u8, 0x10, 0];
+        packet.extend_from_slice(section);
+        packet.resize(TS_PACKET, 0xFF);
+        packet
+    }
+
+    // PSI section with its 8-byte header and a dummy CRC
+    fn psi(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let length = 5 + body.len() + 4;
+        let mut section = vec![table_id, 0xB0 | (length >> 8) as u8, length as u8, 0, 1, 0xC1, 0, 0];
+        section.extend_from_slice(body);
+        section.extend_from_slice(&[0; 4]);
+        section
+    }
+
+    fn transport_stream() -> Vec<u8> {
+        let pat = psi(0x00, &[0x00, 0x01, 0xE1, 0x00]);
+        let pmt = psi(0x02, &[0xE1, 0x01, 0xF0, 0x00, 0x1B, 0xE1, 0x01, 0xF0, 0x00, 0x0F, 0xE1, 0x02, 0xF0, 0x00]);
+        [ts_packet(0, &pat), ts_packet(0x100, &pmt), ts_packet(0x101, &[])].concat()
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((8 + body.len()) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn init_segment() -> Vec<u8> {
+        let entry = mp4_box(b"avc1", &[0; 78]);
+        let stsd = mp4_box(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1][..], &entry].concat());
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stsd));
+        let hdlr = mp4_box(b"hdlr", &[&[0; 8][..], b"vide", &[0; 12]].concat());
+        let trak = mp4_box(b"trak", &mp4_box(b"mdia", &[hdlr, minf].concat()));
+        [mp4_box(b"ftyp", b"isom\0\0\0\0"), mp4_box(b"moov", &trak)].concat()
+    }
+
+    #[test]
+    fn transport_stream_codecs() {
+        let report = probe(&transport_stream()).unwrap();
+        assert_eq!(report.container, Container::MpegTs);
+        assert_eq!(report.video.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["H.264"]);
+        assert_eq!(report.audio.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["AAC"]);
+        assert!(report.browser_compatible);
+    }
+
+    #[test]
+    fn truncated_transport_streams_dont_panic() {
+        let data = transport_stream();
+        for end in 0..data.len() {
+            probe(&data[..end]);
+        }
+        // Header fields that point past the end of the packet
+        for (at, value) in [(4, 0xFF), (5, 0xFF), (7, 0xFF), (TS_PACKET + 3, 0x30), (TS_PACKET + 4, 0xFF), (TS_PACKET + 16, 0xFF), (TS_PACKET + 19, 0xFF)] {
+            let mut data = transport_stream();
+            data[at] = value;
+            probe(&data);
+        }
+    }
+
+    #[test]
+    fn fragmented_mp4_codecs() {
+        let report = probe(&init_segment()).unwrap();
+        assert_eq!(report.container, Container::Fmp4);
+        assert_eq!(report.video[0].name, "H.264");
+        assert!(report.browser_compatible);
+    }
+
+    #[test]
+    fn malformed_boxes_dont_panic() {
+        let data = init_segment();
+        for end in 0..data.len() {
+            probe(&data[..end]);
+        }
+        // Sizes of 0 (to the end), 1 (64-bit size that isn't there), under the header and past the end
+        let moov = data.windows(4).position(|w| w == b"moov").unwrap() - 4;
+        for size in [0u32, 1, 4, 7, u32::MAX] {
+            let mut data = data.clone();
+            data[moov..moov + 4].copy_from_slice(&size.to_be_bytes());
+            probe(&data);
+        }
+        // An encrypted entry whose sinf would start past the end of the box
+        let mut data = data.clone();
+        let avc1 = data.windows(4).position(|w| w == b"avc1").unwrap();
+        data[avc1..avc1 + 4].copy_from_slice(b"encv");
+        data[avc1 - 4..avc1].copy_from_slice(&20u32.to_be_bytes());
+        assert!(probe(&data).unwrap().video.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+// HLS PROXY - Streams live channels through the server with their required headers
+use crate::guard::BodyKind;
+use crate::hls;
+use crate::playlists::Channel;
+use crate::segment_cache::{CacheStats, CachedSegment};
+use crate::AppState;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// SHA-256 block size, for HMAC
+const HMAC_BLOCK: usize = 64;
+
+// Upstream headers worth passing back to the player
+const FORWARDED_HEADERS: [&str; 5] = ["content-type", "content-length", "content-range", "accept-ranges", "cache-control"];
+
+#[derive(Deserialize)]
+pub struct ResourceQuery {
+    u: String,
+    s: String,
+}
+
+pub struct ProxySigner {
+    secret: Vec<u8>,
+}
+
+impl ProxySigner {
+    pub fn new() -> Self {
+        // Links only need to survive for the life of the process unless pinned via env
+        let secret = match std::env::var("PROXY_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                let mut secret = vec![0; 32];
+                getrandom::getrandom(&mut secret).expect("no OS random source for the proxy secret");
+                secret
+            }
+        };
+        Self { secret }
+    }
+
+    // Rewritten URIs are signed so the proxy can't be used to fetch arbitrary URLs
+    fn sign(&self, channel_id: &str, url: &str) -> String {
+        let mac = hmac_sha256(&self.secret, format!("{}\n{}", channel_id, url).as_bytes());
+        mac[..16].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn verify(&self, channel_id: &str, url: &str, signature: &str) -> bool {
+        let expected = self.sign(channel_id, url);
+        // Same time for every wrong guess, however much of it matches
+        expected.len() == signature.len() && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    fn resource_path(&self, channel_id: &str, url: &Url) -> String {
+        format!(
+            "/proxy/{}/fetch?u={}&s={}",
+            channel_id,
+            urlencoding::encode(url.as_str()),
+            self.sign(channel_id, url.as_str())
+        )
+    }
+}
+
+// RFC 2104 over SHA-256
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; HMAC_BLOCK];
+    if key.len() > HMAC_BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+pub fn playlist_path(channel_id: &str) -> String {
+    format!("/proxy/{}/index.m3u8", channel_id)
+}
+
+// GET /proxy/:channel/index.m3u8 - the channel's own playlist, rewritten
+pub async fn channel_playlist(
+    Path(channel_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(channel) = state.playlists.channel(&channel_id).await else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    let url = channel.url.clone();
+    proxy_resource(&state, &channel, &url, &headers).await
+}
+
+// GET /proxy/:channel/fetch?u=..&s=.. - variant playlists, segments and keys
+pub async fn channel_resource(
+    Path(channel_id): Path<String>,
+    Query(params): Query<ResourceQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.proxy.verify(&channel_id, &params.u, &params.s) {
+        return (StatusCode::FORBIDDEN, "Invalid proxy signature").into_response();
+    }
+    let Some(channel) = state.playlists.channel(&channel_id).await else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    proxy_resource(&state, &channel, &params.u, &headers).await
+}
+
+// GET /proxy/stats - shared segment cache effectiveness
+pub async fn cache_stats(State(state): State<AppState>) -> Json<CacheStats> {
+    Json(state.segments.stats())
+}
+
+fn upstream_request(state: &AppState, channel: &Channel, url: &str) -> reqwest::RequestBuilder {
+    let mut request = state.client.get(url);
+    for (name, value) in &channel.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request
+}
+
+async fn proxy_resource(state: &AppState, channel: &Channel, url: &str, client_headers: &HeaderMap) -> Response {
+    let range = client_headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+
+    // Whole-segment requests are shared between viewers; playlists and ranges always go upstream
+    let limits = state.config.snapshot().segment_cache.clone();
+    let cacheable = limits.enabled
+        && range.is_none()
+        && Url::parse(url).map(|u| !hls::is_playlist(None, &u)).unwrap_or(false);
+    if cacheable {
+        let fetch = || fetch_segment(state, channel, url, limits.max_segment_bytes);
+        if let Some(segment) = state.segments.get_or_fetch(url, &limits, fetch).await {
+            return segment_response(&segment);
+        }
+    }
+
+    let mut request = upstream_request(state, channel, url);
+    // Players seek inside segments with ranges; pass them upstream untouched
+    if let Some(range) = range {
+        request = request.header("Range", range);
+    }
+
+    let upstream = match state.client.send(request).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!("❌ Proxy fetch failed for {}: {}", url, e);
+            return (StatusCode::BAD_GATEWAY, "Upstream unavailable").into_response();
+        }
+    };
+
+    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    if !status.is_success() {
+        return (status, "Upstream error").into_response();
+    }
+
+    let final_url = upstream.url().clone();
+    let content_type = upstream
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    if hls::is_playlist(content_type.as_deref(), &final_url) {
+        let body = match state.client.read_text(upstream, BodyKind::Manifest).await {
+            Ok(body) => body,
+            Err(_) => return (StatusCode::BAD_GATEWAY, "Upstream read failed").into_response(),
+        };
+        let rewritten = hls::rewrite_uris(&body, &final_url, |u| state.proxy.resource_path(&channel.id, u));
+        return (
+            [
+                (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            rewritten,
+        )
+            .into_response();
+    }
+
+    // Segments and keys stream straight through without being buffered
+    let mut response = Response::builder().status(status);
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = upstream.headers().get(name) {
+            response = response.header(name, value.as_bytes());
+        }
+    }
+    response
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .unwrap_or_else(|_| StatusCode::BAD_GATEWAY.into_response())
+}
+
+// Buffer one segment for the cache, giving up on playlists, errors and oversized bodies
+async fn fetch_segment(state: &AppState, channel: &Channel, url: &str, max_bytes: u64) -> Option<CachedSegment> {
+    let mut upstream = state.client.send(upstream_request(state, channel, url)).await.ok()?;
+    if !upstream.status().is_success() || upstream.content_length().unwrap_or(0) > max_bytes {
+        return None;
+    }
+
+    let content_type = upstream
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    if hls::is_playlist(content_type.as_deref(), upstream.url()) {
+        return None;
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = upstream.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > max_bytes {
+            return None;
+        }
+    }
+    Some(CachedSegment::new(body.into(), content_type))
+}
+
+fn segment_response(segment: &CachedSegment) -> Response {
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, segment.body.len());
+    if let Some(content_type) = &segment.content_type {
+        response = response.header(header::CONTENT_TYPE, content_type.as_str());
+    }
+    response
+        .body(Body::from(segment.body.clone()))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(hex(&mac), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // Keys longer than a block are hashed first
+        let mac = hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(hex(&mac), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn signatures_are_bound_to_channel_and_url() {
+        let signer = ProxySigner { secret: b"secret".to_vec() };
+        let signature = signer.sign("news", "https://cdn.example/a.ts");
+        assert!(signer.verify("news", "https://cdn.example/a.ts", &signature));
+        assert!(!signer.verify("sports", "https://cdn.example/a.ts", &signature));
+        assert!(!signer.verify("news", "https://cdn.example/b.ts", &signature));
+        assert!(!signer.verify("news", "https://cdn.example/a.ts", &signature[..31]));
+    }
+}
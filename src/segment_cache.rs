@@ -0,0 +1,372 @@
+// SEGMENT CACHE - Short-lived shared cache with request coalescing for proxied live segments
+use crate::config::SegmentCacheConfig;
+use axum::body::Bytes;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+pub struct CachedSegment {
+    pub body: Bytes,
+    pub content_type: Option<String>,
+    stored_at: Instant,
+}
+
+impl CachedSegment {
+    pub fn new(body: Bytes, content_type: Option<String>) -> Self {
+        Self {
+            body,
+            content_type,
+            stored_at: Instant::now(),
+        }
+    }
+}
+
+// Fetch outcomes and evictions older than this no longer count toward cache health
+const RECENT_WINDOW: Duration = Duration::from_secs(300);
+
+// Resolved once per in-flight fetch; `None` means the segment can't be cached
+// (upstream error, too large) and every waiter should go upstream itself.
+type InFlight = Arc<OnceCell<Option<Arc<CachedSegment>>>>;
+
+#[derive(Default)]
+struct Entries {
+    segments: HashMap<String, Arc<CachedSegment>>,
+    order: VecDeque<String>,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    coalesced: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+    bytes_saved: AtomicU64,
+    upstream_bytes: AtomicU64,
+}
+
+// What happened within RECENT_WINDOW: upstream fetches (and whether they worked)
+// and segments pushed out by the memory budget before their TTL
+#[derive(Default)]
+struct Recent {
+    fetches: VecDeque<(Instant, bool)>,
+    evictions: VecDeque<Instant>,
+}
+
+impl Recent {
+    fn trim(&mut self) {
+        while self.fetches.front().is_some_and(|(at, _)| at.elapsed() > RECENT_WINDOW) {
+            self.fetches.pop_front();
+        }
+        while self.evictions.front().is_some_and(|at| at.elapsed() > RECENT_WINDOW) {
+            self.evictions.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub coalesced: u64,
+    pub misses: u64,
+    pub bypassed: u64,
+    pub hit_rate: f64,
+    pub bytes_saved: u64,
+    pub upstream_bytes: u64,
+    pub cached_segments: usize,
+    pub cached_bytes: u64,
+    // Over the last five minutes
+    pub recent_fetches: usize,
+    pub recent_errors: usize,
+    pub recent_evictions: usize,
+}
+
+#[derive(Default)]
+pub struct SegmentCache {
+    entries: Mutex<Entries>,
+    in_flight: Mutex<HashMap<String, InFlight>>,
+    counters: Counters,
+    recent: Mutex<Recent>,
+}
+
+impl SegmentCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Serve `key` from cache, join a fetch already running for it, or run `fetch`.
+    // N concurrent viewers of one segment cost one upstream request.
+    pub async fn get_or_fetch<F, Fut>(&self, key: &str, limits: &SegmentCacheConfig, fetch: F) -> Option<Arc<CachedSegment>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<CachedSegment>>,
+    {
+        let ttl = Duration::from_secs(limits.ttl_secs);
+        if let Some(segment) = self.lookup(key, ttl) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            self.counters.bytes_saved.fetch_add(segment.body.len() as u64, Ordering::Relaxed);
+            return Some(segment);
+        }
+
+        let cell = self.in_flight.lock().unwrap().entry(key.to_string()).or_default().clone();
+
+        let mut fetched_here = false;
+        let result = cell
+            .get_or_init(|| async {
+                fetched_here = true;
+                // Whether the fetch finishes, fails or is cancelled, the next request
+                // shouldn't join it
+                let _done = Landed { cache: self, key, cell: &cell };
+                let segment = fetch().await.map(Arc::new);
+                self.record_fetch(segment.is_some());
+                if let Some(segment) = &segment {
+                    self.counters.upstream_bytes.fetch_add(segment.body.len() as u64, Ordering::Relaxed);
+                    if segment.body.len() as u64 <= limits.max_segment_bytes {
+                        self.store(key, segment.clone(), limits.max_bytes);
+                    }
+                }
+                segment
+            })
+            .await
+            .clone();
+
+        match &result {
+            Some(_) if fetched_here => self.counters.misses.fetch_add(1, Ordering::Relaxed),
+            Some(segment) => {
+                self.counters.bytes_saved.fetch_add(segment.body.len() as u64, Ordering::Relaxed);
+                self.counters.coalesced.fetch_add(1, Ordering::Relaxed)
+            }
+            None => self.counters.bypassed.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    fn record_fetch(&self, ok: bool) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.trim();
+        recent.fetches.push_back((Instant::now(), ok));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (recent_fetches, recent_errors, recent_evictions) = {
+            let mut recent = self.recent.lock().unwrap();
+            recent.trim();
+            (recent.fetches.len(), recent.fetches.iter().filter(|(_, ok)| !ok).count(), recent.evictions.len())
+        };
+        let entries = self.entries.lock().unwrap();
+        let hits = self.counters.hits.load(Ordering::Relaxed);
+        let coalesced = self.counters.coalesced.load(Ordering::Relaxed);
+        let misses = self.counters.misses.load(Ordering::Relaxed);
+        let served = hits + coalesced + misses;
+
+        CacheStats {
+            hits,
+            coalesced,
+            misses,
+            bypassed: self.counters.bypassed.load(Ordering::Relaxed),
+            hit_rate: if served == 0 { 0.0 } else { (hits + coalesced) as f64 / served as f64 * 100.0 },
+            bytes_saved: self.counters.bytes_saved.load(Ordering::Relaxed),
+            upstream_bytes: self.counters.upstream_bytes.load(Ordering::Relaxed),
+            cached_segments: entries.segments.len(),
+            cached_bytes: entries.bytes,
+            recent_fetches,
+            recent_errors,
+            recent_evictions,
+        }
+    }
+
+    fn lookup(&self, key: &str, ttl: Duration) -> Option<Arc<CachedSegment>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.evict_expired(ttl);
+        entries.segments.get(key).cloned()
+    }
+
+    fn store(&self, key: &str, segment: Arc<CachedSegment>, max_bytes: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.segments.insert(key.to_string(), segment.clone()) {
+            entries.bytes -= old.body.len() as u64;
+            entries.order.retain(|k| k != key);
+        }
+        entries.bytes += segment.body.len() as u64;
+        entries.order.push_back(key.to_string());
+
+        // Oldest segments go first once the memory budget is exceeded
+        let mut evicted = 0;
+        while entries.bytes > max_bytes {
+            let Some(oldest) = entries.order.pop_front() else { break };
+            if let Some(removed) = entries.segments.remove(&oldest) {
+                entries.bytes -= removed.body.len() as u64;
+                evicted += 1;
+            }
+        }
+        drop(entries);
+        if evicted > 0 {
+            let mut recent = self.recent.lock().unwrap();
+            recent.trim();
+            recent.evictions.extend(std::iter::repeat_n(Instant::now(), evicted));
+        }
+    }
+}
+
+// Takes a fetch out of in_flight when it lands or is dropped, unless a newer one took its place
+struct Landed<'a> {
+    cache: &'a SegmentCache,
+    key: &'a str,
+    cell: &'a InFlight,
+}
+
+impl Drop for Landed<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.cache.in_flight.lock().unwrap();
+        if in_flight.get(self.key).is_some_and(|current| Arc::ptr_eq(current, self.cell)) {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+impl Entries {
+    fn evict_expired(&mut self, ttl: Duration) {
+        while let Some(oldest) = self.order.front() {
+            let expired = self
+                .segments
+                .get(oldest)
+                .map(|s| s.stored_at.elapsed() >= ttl)
+                .unwrap_or(true);
+            if !expired {
+                break;
+            }
+            let key = self.order.pop_front().unwrap_or_default();
+            if let Some(removed) = self.segments.remove(&key) {
+                self.bytes -= removed.body.len() as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn limits(max_bytes: u64, ttl_secs: u64) -> SegmentCacheConfig {
+        SegmentCacheConfig { enabled: true, max_bytes, max_segment_bytes: 150, ttl_secs }
+    }
+
+    fn segment(len: usize) -> Option<CachedSegment> {
+        Some(CachedSegment::new(Bytes::from(vec![b'x'; len]), Some("video/mp2t".to_string())))
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_fetch() {
+        let cache = SegmentCache::new();
+        let fetches = AtomicUsize::new(0);
+        let limits = limits(1000, 60);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            segment(100)
+        };
+
+        let results = futures_util::future::join_all((0..10).map(|_| cache.get_or_fetch("seg1", &limits, fetch))).await;
+        assert!(results.iter().all(|r| r.as_ref().is_some_and(|s| s.body.len() == 100)));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        let stats = cache.stats();
+        assert_eq!((stats.misses, stats.coalesced, stats.hits), (1, 9, 0));
+        assert_eq!(stats.upstream_bytes, 100);
+        assert_eq!(stats.bytes_saved, 900);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_arent_joined_later() {
+        let cache = SegmentCache::new();
+        let limits = limits(1000, 60);
+        let results = futures_util::future::join_all((0..3).map(|_| {
+            cache.get_or_fetch("seg1", &limits, || async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                None
+            })
+        }))
+        .await;
+        assert!(results.iter().all(Option::is_none));
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+        assert_eq!(cache.stats().bypassed, 3);
+        assert_eq!(cache.stats().recent_errors, 1);
+
+        // The next request goes upstream again
+        assert!(cache.get_or_fetch("seg1", &limits, || async { segment(100) }).await.is_some());
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_fetches_arent_joined_later() {
+        let cache = SegmentCache::new();
+        let limits = limits(1000, 60);
+        let stuck = cache.get_or_fetch("seg1", &limits, std::future::pending);
+        assert!(tokio::time::timeout(Duration::from_millis(20), stuck).await.is_err());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        let segment = tokio::time::timeout(Duration::from_secs(1), cache.get_or_fetch("seg1", &limits, || async { segment(100) })).await;
+        assert!(segment.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn segments_expire_after_their_ttl() {
+        let cache = SegmentCache::new();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            segment(100)
+        };
+
+        cache.get_or_fetch("seg1", &limits(1000, 60), fetch).await;
+        cache.get_or_fetch("seg1", &limits(1000, 60), fetch).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // With no time to live, every request goes upstream and the old copy is dropped
+        cache.get_or_fetch("seg1", &limits(1000, 0), fetch).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        cache.get_or_fetch("seg2", &limits(1000, 0), fetch).await;
+        assert_eq!(cache.stats().cached_segments, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_budget_evicts_the_oldest() {
+        let cache = SegmentCache::new();
+        let limits = limits(250, 60);
+        for key in ["seg1", "seg2", "seg3"] {
+            cache.get_or_fetch(key, &limits, || async { segment(100) }).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.cached_segments, stats.cached_bytes, stats.recent_evictions), (2, 200, 1));
+        assert!(cache.lookup("seg1", Duration::from_secs(60)).is_none());
+        assert!(cache.lookup("seg3", Duration::from_secs(60)).is_some());
+
+        // Segments over max_segment_bytes are served but not kept
+        assert!(cache.get_or_fetch("big", &limits, || async { segment(200) }).await.is_some());
+        assert!(cache.lookup("big", Duration::from_secs(60)).is_none());
+        assert_eq!(cache.stats().cached_bytes, 200);
+    }
+
+    #[tokio::test]
+    async fn hit_rate_and_bytes_saved() {
+        let cache = SegmentCache::new();
+        assert_eq!(cache.stats().hit_rate, 0.0);
+
+        let limits = limits(1000, 60);
+        for _ in 0..4 {
+            cache.get_or_fetch("seg1", &limits, || async { segment(100) }).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert_eq!(stats.hit_rate, 75.0);
+        assert_eq!(stats.bytes_saved, 300);
+        assert_eq!(stats.upstream_bytes, 100);
+        assert_eq!((stats.recent_fetches, stats.recent_errors), (1, 0));
+    }
+}
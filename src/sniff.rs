@@ -0,0 +1,140 @@
+// CONTENT SNIFFING - Magic bytes for the kinds of media we hand out
+use crate::probe;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Pdf,
+    Epub,
+    Zip,
+    Mp4,
+    Mkv,
+    MpegTs,
+    M3u,
+    Mp3,
+    Html,
+    Text,
+}
+
+impl MediaKind {
+    // An EPUB is a ZIP, so it satisfies a ZIP expectation but not the other way round
+    pub fn satisfies(self, expected: MediaKind) -> bool {
+        self == expected || (self == MediaKind::Epub && expected == MediaKind::Zip)
+    }
+
+    // The download formats book and audiobook results advertise ("PDF", "EPUB", "TXT", "HTML", "MP3")
+    pub fn from_format(format: &str) -> Option<MediaKind> {
+        match format.to_ascii_uppercase().as_str() {
+            "PDF" => Some(MediaKind::Pdf),
+            "EPUB" => Some(MediaKind::Epub),
+            "TXT" => Some(MediaKind::Text),
+            "HTML" => Some(MediaKind::Html),
+            "MP3" => Some(MediaKind::Mp3),
+            _ => None,
+        }
+    }
+}
+
+pub fn sniff(data: &[u8]) -> Option<MediaKind> {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+
+    if data.starts_with(b"%PDF-") {
+        Some(MediaKind::Pdf)
+    } else if data.starts_with(b"PK\x03\x04") {
+        // EPUB requires an uncompressed "mimetype" entry first in the archive
+        let is_epub = data.get(30..).map(|rest| rest.starts_with(b"mimetypeapplication/epub+zip")).unwrap_or(false);
+        Some(if is_epub { MediaKind::Epub } else { MediaKind::Zip })
+    } else if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some(MediaKind::Mkv)
+    } else if probe::is_mp4(data) {
+        Some(MediaKind::Mp4)
+    } else if probe::is_mpeg_ts(data) || is_single_ts_packet(data) {
+        Some(MediaKind::MpegTs)
+    } else if is_mp3(data) {
+        Some(MediaKind::Mp3)
+    } else if data.trim_ascii_start().starts_with(b"#EXTM3U") {
+        Some(MediaKind::M3u)
+    } else if is_html(data) {
+        Some(MediaKind::Html)
+    } else if is_text(data) {
+        Some(MediaKind::Text)
+    } else {
+        None
+    }
+}
+
+fn is_html(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(512)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let head = head.trim_start();
+    head.starts_with("<!doctype html") || head.starts_with("<html") || head.contains("<head") || head.contains("<body")
+}
+
+// Plain text: UTF-8 (a character may be cut off at the end of the sample) or a
+// single-byte encoding like Latin-1 with no control characters besides whitespace
+fn is_text(data: &[u8]) -> bool {
+    if data.is_empty() || data.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) if e.error_len().is_none() => true,
+        Err(_) => data.iter().all(|&b| b >= 0x20 || matches!(b, b'\t' | b'\n' | b'\r' | 0x0C)),
+    }
+}
+
+// An ID3 tag, or an MPEG audio frame header: 11 sync bits and a layer that isn't
+// the reserved 00 (which also keeps AAC's ADTS headers out)
+fn is_mp3(data: &[u8]) -> bool {
+    data.starts_with(b"ID3") || (data.len() >= 4 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0 && (data[1] >> 1) & 0x03 != 0)
+}
+
+// probe::is_mpeg_ts wants two packets in a row; tiny bodies may only hold one
+fn is_single_ts_packet(data: &[u8]) -> bool {
+    (188..376).contains(&data.len()) && data[0] == 0x47
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some(MediaKind::Pdf));
+        let epub = [&b"PK\x03\x04"[..], &[0; 26], b"mimetypeapplication/epub+zip"].concat();
+        assert_eq!(sniff(&epub), Some(MediaKind::Epub));
+        assert_eq!(sniff(b"\xEF\xBB\xBF#EXTM3U\n#EXT-X-VERSION:3"), Some(MediaKind::M3u));
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), Some(MediaKind::Html));
+        assert_eq!(sniff(b"ID3\x04\0\0\0\0\0\0"), Some(MediaKind::Mp3));
+        assert_eq!(sniff(b"Caf\xE9 au lait"), Some(MediaKind::Text));
+    }
+
+    #[test]
+    fn truncated_samples() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\xEF\xBB\xBF"), None);
+        // A ZIP cut off before its first file name is still a ZIP, not an EPUB
+        assert_eq!(sniff(b"PK\x03\x04"), Some(MediaKind::Zip));
+        assert_eq!(sniff(b"PK\x03\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0mimetypeapplic"), Some(MediaKind::Zip));
+        // A UTF-8 character split by the end of the sample
+        assert_eq!(sniff("Caf\u{e9}".as_bytes().split_last().unwrap().1), Some(MediaKind::Text));
+        // One TS packet, and less than one
+        let mut packet = vec![0x47; 188];
+        assert_eq!(sniff(&packet), Some(MediaKind::MpegTs));
+        packet.truncate(187);
+        assert_ne!(sniff(&packet), Some(MediaKind::MpegTs));
+        // Too short for an MP4 box header or an MPEG audio frame header
+        assert_eq!(sniff(b"\0\0\0\x18ftyp"), Some(MediaKind::Mp4));
+        assert_ne!(sniff(b"\0\0\0\x18fty"), Some(MediaKind::Mp4));
+        assert_ne!(sniff(b"\xFF\xFB"), Some(MediaKind::Mp3));
+    }
+
+    #[test]
+    fn binary_that_isnt_media() {
+        // AAC's ADTS header shares MP3's sync bits but has layer 00
+        assert_ne!(sniff(b"\xFF\xF1\x50\x80\x02\x1F\xFC"), Some(MediaKind::Mp3));
+        assert_eq!(sniff(b"\x7FELF\x02\x01\x01\0\0\0"), None);
+        assert_eq!(sniff(b"\x01\x02\x03\xFE"), None);
+    }
+}
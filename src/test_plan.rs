@@ -0,0 +1,402 @@
+// TEST PLANS - Declarative source checks with JSON and JUnit XML reports
+use crate::verify::FailureClass;
+use crate::{search, AppState, SearchOptions};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestPlan {
+    pub name: String,
+    pub categories: Vec<PlanCategory>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanCategory {
+    pub name: String,
+    // Search type: movie, tv, book or live
+    #[serde(rename = "type")]
+    pub content_type: String,
+    // Percentage of checked sources that must pass
+    pub min_success_rate: f64,
+    pub probes: Vec<Probe>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub query: String,
+    #[serde(default = "default_probe_limit")]
+    pub limit: usize,
+}
+
+fn default_probe_limit() -> usize {
+    3
+}
+
+impl Default for TestPlan {
+    // The checks /test always ran, with thresholds anyone can tighten in a plan file
+    fn default() -> Self {
+        let category = |name: &str, content_type: &str, query: &str, limit| PlanCategory {
+            name: name.to_string(),
+            content_type: content_type.to_string(),
+            min_success_rate: 50.0,
+            probes: vec![Probe { query: query.to_string(), limit }],
+        };
+        Self {
+            name: "default".to_string(),
+            categories: vec![
+                category("movies", "movie", "Avengers", 3),
+                category("tv_shows", "tv", "Breaking Bad", 3),
+                category("books", "book", "Harry Potter", 3),
+                category("live_tv", "live", "", 5),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestCase {
+    pub query: String,
+    pub id: String,
+    pub title: String,
+    pub source: String,
+    pub url: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub failure: Option<FailureClass>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryReport {
+    pub name: String,
+    pub content_type: String,
+    pub tested: usize,
+    pub working: usize,
+    pub success_rate: f64,
+    pub min_success_rate: f64,
+    pub passed: bool,
+    pub cases: Vec<TestCase>,
+}
+
+// Also the record kept for each run in the verification history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestReport {
+    pub plan: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub passed: bool,
+    pub categories: Vec<CategoryReport>,
+}
+
+// A missing plan file means the default plan; a broken one is an error
+pub fn load_plan(path: &Path) -> Result<TestPlan, String> {
+    match std::fs::read_to_string(path) {
+        Ok(raw) => serde_json::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TestPlan::default()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+pub async fn run_plan(state: &AppState, plan: &TestPlan) -> TestReport {
+    log!("🧪 Running test plan {}...", plan.name);
+    let started_at = Utc::now();
+    let mut categories = Vec::new();
+
+    for category in &plan.categories {
+        let mut cases = Vec::new();
+        for probe in &plan_probes(category) {
+            // Failed sources are what a test run is for, and the cache would hide regressions
+            let options = SearchOptions { limit: probe.limit, include_failed: true, verify: true };
+            for content in search(state, &category.content_type, &probe.query, &options).await {
+                let verification = content.verification.as_ref();
+                cases.push(TestCase {
+                    query: probe.query.clone(),
+                    id: content.id.clone(),
+                    title: content.title.clone(),
+                    source: content.source.clone(),
+                    url: if content.stream_url.is_empty() { content.download_url.clone() } else { content.stream_url.clone() },
+                    ok: content.verified,
+                    latency_ms: verification.map(|v| v.latency_ms).unwrap_or(0),
+                    failure: verification.and_then(|v| v.failure),
+                    error: verification.and_then(|v| v.error.clone()),
+                });
+            }
+        }
+
+        let tested = cases.len();
+        let working = cases.iter().filter(|c| c.ok).count();
+        let success_rate = if tested == 0 { 0.0 } else { working as f64 / tested as f64 * 100.0 };
+        let passed = tested > 0 && success_rate >= category.min_success_rate;
+        log!(
+            "   {} {}: {}/{} working ({:.1}%, need {:.1}%)",
+            if passed { "✅" } else { "❌" },
+            category.name,
+            working,
+            tested,
+            success_rate,
+            category.min_success_rate
+        );
+        categories.push(CategoryReport {
+            name: category.name.clone(),
+            content_type: category.content_type.clone(),
+            tested,
+            working,
+            success_rate,
+            min_success_rate: category.min_success_rate,
+            passed,
+            cases,
+        });
+    }
+
+    let report = TestReport {
+        plan: plan.name.clone(),
+        started_at,
+        finished_at: Utc::now(),
+        passed: categories.iter().all(|c| c.passed),
+        categories,
+    };
+    if let Err(e) = state.history.record(&report).await {
+        eprintln!("❌ Failed to save test run to history: {}", e);
+    }
+    report
+}
+
+// A category without probes still gets one default search
+fn plan_probes(category: &PlanCategory) -> Vec<Probe> {
+    if category.probes.is_empty() {
+        vec![Probe { query: String::new(), limit: default_probe_limit() }]
+    } else {
+        category.probes.clone()
+    }
+}
+
+// One testsuite per category: a testcase per checked source plus one for the threshold
+pub fn junit_xml(report: &TestReport) -> String {
+    let seconds = |ms: u64| format!("{:.3}", ms as f64 / 1000.0);
+    let total_ms = (report.finished_at - report.started_at).num_milliseconds().max(0) as u64;
+    let tests: usize = report.categories.iter().map(|c| c.cases.len() + 1).sum();
+    let failures: usize = report.categories.iter().map(|c| c.cases.iter().filter(|t| !t.ok).count() + usize::from(!c.passed)).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{}\">\n",
+        escape(&report.plan),
+        tests,
+        failures,
+        seconds(total_ms)
+    ));
+
+    for category in &report.categories {
+        let suite_failures = category.cases.iter().filter(|t| !t.ok).count() + usize::from(!category.passed);
+        let suite_ms: u64 = category.cases.iter().map(|t| t.latency_ms).sum();
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{}\" timestamp=\"{}\">\n",
+            escape(&category.name),
+            category.cases.len() + 1,
+            suite_failures,
+            seconds(suite_ms),
+            report.started_at.format("%Y-%m-%dT%H:%M:%S")
+        ));
+
+        for case in &category.cases {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{}\">",
+                escape(&category.name),
+                escape(&case.title),
+                seconds(case.latency_ms)
+            ));
+            if !case.ok {
+                let failure = case.failure.and_then(|f| serde_json::to_value(f).ok()).and_then(|v| v.as_str().map(str::to_string));
+                xml.push_str(&format!(
+                    "<failure type=\"{}\" message=\"{}\">{}</failure>",
+                    escape(failure.as_deref().unwrap_or("unverified")),
+                    escape(case.error.as_deref().unwrap_or("Source did not verify")),
+                    escape(&case.url)
+                ));
+            }
+            xml.push_str("</testcase>\n");
+        }
+
+        xml.push_str(&format!(
+            "    <testcase classname=\"{}\" name=\"success rate &gt;= {:.1}%\" time=\"0.000\">",
+            escape(&category.name),
+            category.min_success_rate
+        ));
+        if !category.passed {
+            xml.push_str(&format!(
+                "<failure type=\"threshold\" message=\"{}/{} working ({:.1}%)\"/>",
+                category.working, category.tested, category.success_rate
+            ));
+        }
+        xml.push_str("</testcase>\n  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// `content-server test [plan.json] [--json report.json] [--junit report.xml]`
+// Prints the JSON report, and nothing else, on stdout (logs go to stderr) and exits 0 when every threshold is met, 1 when one is
+// missed and 2 when the plan or arguments are unusable.
+pub async fn run_cli(state: &AppState, args: &[String]) -> i32 {
+    let mut plan_path: Option<PathBuf> = None;
+    let mut json_out: Option<PathBuf> = None;
+    let mut junit_out: Option<PathBuf> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json_out = args.next().map(PathBuf::from),
+            "--junit" => junit_out = args.next().map(PathBuf::from),
+            other if plan_path.is_none() && !other.starts_with("--") => plan_path = Some(PathBuf::from(other)),
+            other => {
+                eprintln!("❌ Unexpected argument: {}", other);
+                return 2;
+            }
+        }
+    }
+
+    let path = plan_path.unwrap_or_else(|| state.config.snapshot().test_plan.clone());
+    let plan = match load_plan(&path) {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("❌ Invalid test plan {}", e);
+            return 2;
+        }
+    };
+
+    let report = run_plan(state, &plan).await;
+    let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+    // The report is the only thing on stdout, so it can be piped
+    println!("{}", json);
+
+    let outputs = [(json_out, json), (junit_out, junit_xml(&report))];
+    for (path, body) in outputs {
+        if let Some(path) = path {
+            if let Err(e) = std::fs::write(&path, body) {
+                eprintln!("❌ Failed to write {}: {}", path.display(), e);
+                return 2;
+            }
+        }
+    }
+
+    if report.passed {
+        0
+    } else {
+        1
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    format: Option<String>, // json (default) or junit
+}
+
+fn report_response(report: &TestReport, format: Option<&str>) -> Response {
+    // Ops checks read the status code before the body
+    let status = if report.passed { StatusCode::OK } else { StatusCode::EXPECTATION_FAILED };
+    match format {
+        Some("junit") | Some("xml") => (status, [(header::CONTENT_TYPE, "application/xml")], junit_xml(report)).into_response(),
+        _ => (status, Json(report)).into_response(),
+    }
+}
+
+// GET /test - run the configured plan
+pub async fn run_configured(Query(params): Query<ReportQuery>, State(state): State<AppState>) -> Response {
+    let path = state.config.snapshot().test_plan.clone();
+    match load_plan(&path) {
+        Ok(plan) => report_response(&run_plan(&state, &plan).await, params.format.as_deref()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid test plan {}", e)).into_response(),
+    }
+}
+
+// POST /test - run a plan sent in the request body
+pub async fn run_posted(Query(params): Query<ReportQuery>, State(state): State<AppState>, Json(plan): Json<TestPlan>) -> Response {
+    report_response(&run_plan(&state, &plan).await, params.format.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn case(title: &str, ok: bool, latency_ms: u64, failure: Option<FailureClass>, error: Option<&str>) -> TestCase {
+        TestCase {
+            query: "q".to_string(),
+            id: title.to_string(),
+            title: title.to_string(),
+            source: "stand-in".to_string(),
+            url: "https://example.org/watch?a=1&b=<2>".to_string(),
+            ok,
+            latency_ms,
+            failure,
+            error: error.map(str::to_string),
+        }
+    }
+
+    fn category(name: &str, min_success_rate: f64, cases: Vec<TestCase>) -> CategoryReport {
+        let tested = cases.len();
+        let working = cases.iter().filter(|c| c.ok).count();
+        let success_rate = if tested == 0 { 0.0 } else { working as f64 / tested as f64 * 100.0 };
+        CategoryReport {
+            name: name.to_string(),
+            content_type: "movie".to_string(),
+            tested,
+            working,
+            success_rate,
+            min_success_rate,
+            passed: tested > 0 && success_rate >= min_success_rate,
+            cases,
+        }
+    }
+
+    #[test]
+    fn junit_reports() {
+        let started_at = Utc::now();
+        let report = TestReport {
+            plan: "nightly <\"r&d\">".to_string(),
+            started_at,
+            finished_at: started_at + Duration::milliseconds(2500),
+            passed: false,
+            categories: vec![
+                category(
+                    "movies & 'tv'",
+                    50.0,
+                    vec![
+                        case("Tom & Jerry <1940>", true, 120, None, None),
+                        case("Broken \"stream\"", false, 30, Some(FailureClass::Http4xx), Some("HTTP 404 <gone>")),
+                    ],
+                ),
+                category("books", 100.0, vec![case("Emma", false, 0, None, None)]),
+            ],
+        };
+        let xml = junit_xml(&report);
+        roxmltree::Document::parse(&xml).expect("well-formed XML");
+
+        // Every source plus one threshold case per category; the books threshold and two sources failed
+        assert!(xml.contains(r#"<testsuites name="nightly &lt;&quot;r&amp;d&quot;&gt;" tests="5" failures="3" time="2.500">"#));
+        assert!(xml.contains(r#"<testsuite name="movies &amp; &apos;tv&apos;" tests="3" failures="1" time="0.150""#));
+        assert!(xml.contains(r#"<testsuite name="books" tests="2" failures="2" time="0.000""#));
+        assert!(xml.contains(r#"name="Tom &amp; Jerry &lt;1940&gt;" time="0.120"></testcase>"#));
+        assert!(xml.contains(
+            r#"<failure type="http_4xx" message="HTTP 404 &lt;gone&gt;">https://example.org/watch?a=1&amp;b=&lt;2&gt;</failure>"#
+        ));
+        assert!(xml.contains(r#"<failure type="unverified" message="Source did not verify">"#));
+        assert!(xml.contains(r#"name="success rate &gt;= 100.0%" time="0.000"><failure type="threshold" message="0/1 working (0.0%)"/>"#));
+        assert!(xml.contains(r#"name="success rate &gt;= 50.0%" time="0.000"></testcase>"#));
+    }
+}
@@ -0,0 +1,720 @@
+// STREAM VERIFICATION - Structured results for URL and live stream checks
+use crate::dash;
+use crate::hls;
+use crate::media::StreamMetadata;
+use crate::pdf::{self, PdfInfo};
+use crate::probe::{self, CodecReport};
+use crate::sniff::{self, MediaKind};
+use chrono::{DateTime, Utc};
+use crate::guard::{self, BodyKind, Blocked, FetchError, GuardedClient, Limit, LimitExceeded};
+use reqwest::{RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Instant;
+
+const PROBE_BYTES: usize = 512 * 1024;
+const SNIFF_BYTES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    #[serde(rename = "http_4xx")]
+    Http4xx,
+    #[serde(rename = "http_5xx")]
+    Http5xx,
+    GeoBlocked,
+    NotAPlaylist,
+    EmptySegment,
+    UnexpectedContent,
+    // Refused by the outbound policy before any connection was made
+    Blocked,
+    // Upstream went past a configured resource limit
+    BodyTooLarge,
+    DecompressionLimit,
+    TooManyRedirects,
+    HeadersTooLarge,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationResult {
+    pub url: String,
+    pub ok: bool,
+    pub status_code: Option<u16>,
+    pub final_url: Option<String>,
+    pub latency_ms: u64,
+    pub ttfb_ms: Option<u64>,
+    pub content_type: Option<String>,
+    // What the body's magic bytes say it is, when it was sniffed
+    pub content_kind: Option<MediaKind>,
+    pub content_length: Option<u64>,
+    pub sha256: Option<String>,
+    pub failure: Option<FailureClass>,
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+// Timing and response details gathered while a check runs
+struct Check {
+    result: VerificationResult,
+    started: Instant,
+}
+
+impl Check {
+    fn start(url: &str) -> Self {
+        Self {
+            result: VerificationResult {
+                url: url.to_string(),
+                ok: false,
+                status_code: None,
+                final_url: None,
+                latency_ms: 0,
+                ttfb_ms: None,
+                content_type: None,
+                content_kind: None,
+                content_length: None,
+                sha256: None,
+                failure: None,
+                error: None,
+                checked_at: Utc::now(),
+            },
+            started: Instant::now(),
+        }
+    }
+
+    // Record the first response's headers; the check's own URL stays the one asked about
+    fn saw_response(&mut self, response: &Response) {
+        if self.result.ttfb_ms.is_none() {
+            self.result.ttfb_ms = Some(self.started.elapsed().as_millis() as u64);
+            self.result.status_code = Some(response.status().as_u16());
+            self.result.final_url = Some(response.url().to_string());
+            self.result.content_type = content_type(response);
+        }
+    }
+
+    // Let the next response replace the recorded one (HEAD falling back to GET)
+    fn follow_up(&mut self) {
+        self.result.ttfb_ms = None;
+    }
+
+    fn pass(mut self) -> VerificationResult {
+        self.result.ok = true;
+        self.result.latency_ms = self.started.elapsed().as_millis() as u64;
+        self.result
+    }
+
+    fn fail(mut self, failure: FailureClass, error: impl Into<String>) -> VerificationResult {
+        self.result.ok = false;
+        self.result.failure = Some(failure);
+        self.result.error = Some(error.into());
+        self.result.latency_ms = self.started.elapsed().as_millis() as u64;
+        self.result
+    }
+}
+
+pub fn with_headers(mut request: RequestBuilder, headers: &HashMap<String, String>) -> RequestBuilder {
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request
+}
+
+fn content_type(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+// reqwest folds DNS and TLS failures into connect errors; the source chain says which
+pub fn classify_error(error: &reqwest::Error) -> FailureClass {
+    if guard::find_cause::<Blocked>(error).is_some() {
+        return FailureClass::Blocked;
+    }
+    if let Some(exceeded) = guard::find_cause::<LimitExceeded>(error) {
+        return limit_class(exceeded.limit);
+    }
+    if error.is_timeout() {
+        return FailureClass::Timeout;
+    }
+    let mut chain = error.to_string().to_lowercase();
+    let mut source = std::error::Error::source(error);
+    while let Some(inner) = source {
+        chain.push(' ');
+        chain.push_str(&inner.to_string().to_lowercase());
+        source = inner.source();
+    }
+
+    if chain.contains("dns error") || chain.contains("failed to lookup address") || chain.contains("name or service not known") {
+        FailureClass::Dns
+    } else if chain.contains("certificate") || chain.contains("ssl") || chain.contains("tls") || chain.contains("handshake") {
+        FailureClass::Tls
+    } else if chain.contains("timed out") {
+        FailureClass::Timeout
+    } else {
+        FailureClass::Connect
+    }
+}
+
+// 451 is explicit; 403s only count when the response says why
+fn classify_status(status: u16, final_url: &Url, body: &str) -> FailureClass {
+    let body = body.to_lowercase();
+    let path = final_url.as_str().to_lowercase();
+    let geo_hint = ["geo", "not available in your", "your country", "your region", "territory"]
+        .iter()
+        .any(|hint| body.contains(hint) || path.contains(hint));
+
+    match status {
+        451 => FailureClass::GeoBlocked,
+        403 if geo_hint => FailureClass::GeoBlocked,
+        500..=599 => FailureClass::Http5xx,
+        _ => FailureClass::Http4xx,
+    }
+}
+
+fn classify_fetch(error: &FetchError) -> FailureClass {
+    match error {
+        FetchError::Blocked(_) => FailureClass::Blocked,
+        FetchError::Limit(exceeded) => limit_class(exceeded.limit),
+        FetchError::Decode(_) => FailureClass::UnexpectedContent,
+        FetchError::Request(e) => classify_error(e),
+    }
+}
+
+fn limit_class(limit: Limit) -> FailureClass {
+    match limit {
+        Limit::BodySize => FailureClass::BodyTooLarge,
+        Limit::Decompression => FailureClass::DecompressionLimit,
+        Limit::Redirects => FailureClass::TooManyRedirects,
+        Limit::HeaderSize => FailureClass::HeadersTooLarge,
+    }
+}
+
+async fn send(check: &mut Check, client: &GuardedClient, request: RequestBuilder) -> Result<Response, (FailureClass, String)> {
+    match client.send(request).await {
+        Ok(response) => {
+            check.saw_response(&response);
+            Ok(response)
+        }
+        Err(e) => Err((classify_fetch(&e), e.to_string())),
+    }
+}
+
+async fn failed_status(client: &GuardedClient, response: Response) -> (FailureClass, String) {
+    let status = response.status();
+    let final_url = response.url().clone();
+    let body = client.error_text(response).await;
+    (classify_status(status.as_u16(), &final_url, &body), format!("HTTP {}", status))
+}
+
+// A URL passes only when its final response (after redirects) is a success and
+// its first bytes look like one of the expected kinds. HEAD is tried first; many
+// servers refuse it, so a small ranged GET settles the rest and does the sniffing.
+pub async fn check_url(client: &GuardedClient, url: &str, expected: &[MediaKind]) -> VerificationResult {
+    let mut check = Check::start(url);
+    match fetch_and_sniff(&mut check, client, url, expected).await {
+        Ok(()) => check.pass(),
+        Err((failure, error)) => check.fail(failure, error),
+    }
+}
+
+// Downloads get the same check plus their size, and small files are fetched
+// whole so the result carries a SHA-256 of what a user would actually get
+pub async fn check_download(client: &GuardedClient, url: &str, expected: &[MediaKind], checksum_max_bytes: u64) -> VerificationResult {
+    let mut check = Check::start(url);
+    if let Err((failure, error)) = fetch_and_sniff(&mut check, client, url, expected).await {
+        return check.fail(failure, error);
+    }
+
+    match hash_small(&mut check, client, url, checksum_max_bytes).await {
+        Ok(()) => check.pass(),
+        Err((failure, error)) => check.fail(failure, error),
+    }
+}
+
+async fn hash_small(check: &mut Check, client: &GuardedClient, url: &str, checksum_max_bytes: u64) -> Result<(), (FailureClass, String)> {
+    let small = check.result.content_length.map(|len| len <= checksum_max_bytes).unwrap_or(false);
+    if small {
+        if let Some((digest, length)) = checksum(client, url, checksum_max_bytes).await.map_err(|e| (classify_fetch(&e), e.to_string()))? {
+            check.result.sha256 = Some(digest);
+            check.result.content_length = Some(length);
+        }
+    }
+    Ok(())
+}
+
+// PDFs up to pdf_max_bytes are downloaded whole once, hashed when small enough
+// and opened for their metadata. A file that passes the check but can't be
+// parsed still passes; it just comes back without PdfInfo.
+pub async fn check_pdf(client: &GuardedClient, url: &str, checksum_max_bytes: u64, pdf_max_bytes: u64) -> (VerificationResult, Option<PdfInfo>) {
+    let mut check = Check::start(url);
+    if let Err((failure, error)) = fetch_and_sniff(&mut check, client, url, &[MediaKind::Pdf]).await {
+        return (check.fail(failure, error), None);
+    }
+
+    // Too big to inspect: the plain download check
+    let fits = check.result.content_length.map(|len| len <= pdf_max_bytes).unwrap_or(false);
+    let data = match fits {
+        true => download(client, url, pdf_max_bytes).await,
+        false => Ok(None),
+    };
+    let data = match data {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            return match hash_small(&mut check, client, url, checksum_max_bytes).await {
+                Ok(()) => (check.pass(), None),
+                Err((failure, error)) => (check.fail(failure, error), None),
+            }
+        }
+        Err(e) => return (check.fail(classify_fetch(&e), e.to_string()), None),
+    };
+    check.result.content_length = Some(data.len() as u64);
+    if data.len() as u64 <= checksum_max_bytes {
+        check.result.sha256 = Some(Sha256::digest(&data).iter().map(|b| format!("{:02x}", b)).collect());
+    }
+
+    let info = match tokio::task::spawn_blocking(move || pdf::inspect(&data)).await {
+        Ok(Ok(info)) => Some(info),
+        Ok(Err(e)) => {
+            eprintln!("⚠️  {}: {}", url, e);
+            None
+        }
+        Err(_) => None,
+    };
+    (check.pass(), info)
+}
+
+async fn fetch_and_sniff(check: &mut Check, client: &GuardedClient, url: &str, expected: &[MediaKind]) -> Result<(), (FailureClass, String)> {
+    // Some servers drop or reset a HEAD they don't support, so only the policy's
+    // refusal is final here; any other failure is left for the GET to confirm
+    let head = match send(check, client, client.head(url)).await {
+        Ok(head) => Some(head),
+        Err((FailureClass::Blocked, error)) => return Err((FailureClass::Blocked, error)),
+        Err(_) => None,
+    };
+
+    // Gone is gone; anything else might just be a server that doesn't do HEAD
+    if let Some(head) = head {
+        if matches!(head.status().as_u16(), 404 | 410 | 451) {
+            return Err(failed_status(client, head).await);
+        }
+        if head.status().is_success() {
+            check.result.content_length = header_length(&head);
+            if expected.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    check.follow_up();
+    let request = client.get(url).header("Range", format!("bytes=0-{}", SNIFF_BYTES - 1));
+    let response = send(check, client, request).await?;
+    if !response.status().is_success() {
+        return Err(failed_status(client, response).await);
+    }
+    // A 206 only knows the full size through Content-Range ("bytes 0-4095/123456")
+    let length = match response.status().as_u16() {
+        206 => response
+            .headers()
+            .get("content-range")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok()),
+        _ => header_length(&response),
+    };
+    check.result.content_length = length.or(check.result.content_length);
+
+    let data = read_prefix(response, SNIFF_BYTES)
+        .await
+        .map_err(|e| (classify_error(&e), e.to_string()))?;
+    let kind = sniff::sniff(&data);
+    check.result.content_kind = kind;
+
+    match kind {
+        _ if expected.is_empty() => Ok(()),
+        Some(kind) if expected.iter().any(|e| kind.satisfies(*e)) => Ok(()),
+        Some(kind) => Err((FailureClass::UnexpectedContent, format!("Expected {:?}, got {:?}", expected, kind))),
+        None if data.is_empty() => Err((FailureClass::UnexpectedContent, "Empty response body".to_string())),
+        None => Err((FailureClass::UnexpectedContent, format!("Expected {:?}, got unrecognized content", expected))),
+    }
+}
+
+fn header_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+// Hash the whole body, giving up if it turns out bigger than advertised
+async fn checksum(client: &GuardedClient, url: &str, max: u64) -> Result<Option<(String, u64)>, FetchError> {
+    let mut response = client.send(client.download(url)).await?.error_for_status()?;
+    let mut hasher = Sha256::new();
+    let mut length = 0u64;
+    while let Some(chunk) = response.chunk().await? {
+        length += chunk.len() as u64;
+        if length > max {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+    }
+    let digest = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(Some((digest, length)))
+}
+
+// The whole body, or None if it turns out bigger than max
+async fn download(client: &GuardedClient, url: &str, max: u64) -> Result<Option<Vec<u8>>, FetchError> {
+    let response = client.send(client.download(url)).await?.error_for_status()?;
+    if response.content_length().is_some_and(|length| length > max) {
+        return Ok(None);
+    }
+    match client.read_body(response, BodyKind::Book).await {
+        Ok(data) if data.len() as u64 <= max => Ok(Some(data)),
+        Ok(_) | Err(FetchError::Limit(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Live channels are HLS or DASH. Either way the first segment (or initialization
+// segment) has to load and be non-empty; its container is probed for codecs.
+pub async fn check_live_stream(
+    client: &GuardedClient,
+    url: &str,
+    headers: &HashMap<String, String>,
+) -> (VerificationResult, Option<StreamMetadata>) {
+    let mut check = Check::start(url);
+    match live_stream(&mut check, client, url, headers).await {
+        Ok(metadata) => (check.pass(), Some(metadata)),
+        Err((failure, error)) => (check.fail(failure, error), None),
+    }
+}
+
+async fn live_stream(
+    check: &mut Check,
+    client: &GuardedClient,
+    url: &str,
+    headers: &HashMap<String, String>,
+) -> Result<StreamMetadata, (FailureClass, String)> {
+    let response = send(check, client, with_headers(client.get(url), headers)).await?;
+    if !response.status().is_success() {
+        return Err(failed_status(client, response).await);
+    }
+    let final_url = response.url().clone();
+    let kind = content_type(&response);
+    let content = client
+        .read_text(response, BodyKind::Manifest)
+        .await
+        .map_err(|e| (classify_fetch(&e), e.to_string()))?;
+
+    if dash::is_dash(kind.as_deref(), &final_url, &content) {
+        let manifest = dash::parse_mpd(&content, &final_url).map_err(|e| (FailureClass::NotAPlaylist, e))?;
+        let segment = manifest
+            .probe_request()
+            .ok_or((FailureClass::NotAPlaylist, "MPD has no representations".to_string()))?;
+        let init = fetch_segment(check, client, segment.url, segment.range, headers).await?;
+        let mut metadata = manifest.metadata();
+        metadata.codecs = probe::probe(&init);
+        return Ok(metadata);
+    }
+
+    if !content.contains("#EXTM3U") && !content.contains("#EXT-X-VERSION") {
+        return Err((FailureClass::NotAPlaylist, "Response is not an HLS or DASH manifest".to_string()));
+    }
+
+    let mut metadata = hls::parse_metadata(&content);
+    metadata.codecs = probe_hls(check, client, &content, &final_url, headers).await?;
+    Ok(metadata)
+}
+
+// Probe the first segment (or EXT-X-MAP init segment) of the best variant
+async fn probe_hls(
+    check: &mut Check,
+    client: &GuardedClient,
+    playlist: &str,
+    url: &Url,
+    headers: &HashMap<String, String>,
+) -> Result<Option<CodecReport>, (FailureClass, String)> {
+    let media = if hls::is_master(playlist) {
+        let variant = hls::parse_variants(playlist, url)
+            .into_iter()
+            .max_by_key(|v| v.bandwidth)
+            .ok_or((FailureClass::NotAPlaylist, "Master playlist has no variants".to_string()))?;
+        let response = send(check, client, with_headers(client.get(variant.uri.clone()), headers)).await?;
+        if !response.status().is_success() {
+            return Err(failed_status(client, response).await);
+        }
+        let body = client
+            .read_text(response, BodyKind::Manifest)
+            .await
+            .map_err(|e| (classify_fetch(&e), e.to_string()))?;
+        hls::parse_media_playlist(&body, &variant.uri)
+    } else {
+        hls::parse_media_playlist(playlist, url)
+    };
+
+    let target = media
+        .map_uri
+        .or_else(|| media.segments.first().map(|s| s.uri.clone()))
+        .ok_or((FailureClass::EmptySegment, "Media playlist has no segments".to_string()))?;
+    let data = fetch_segment(check, client, target, None, headers).await?;
+    Ok(probe::probe(&data))
+}
+
+async fn fetch_segment(
+    check: &mut Check,
+    client: &GuardedClient,
+    url: Url,
+    range: Option<String>,
+    headers: &HashMap<String, String>,
+) -> Result<Vec<u8>, (FailureClass, String)> {
+    let mut request = with_headers(client.get(url), headers);
+    if let Some(range) = range {
+        request = request.header("Range", range);
+    }
+    let response = send(check, client, request).await?;
+    if !response.status().is_success() {
+        return Err(failed_status(client, response).await);
+    }
+    let data = read_prefix(response, PROBE_BYTES)
+        .await
+        .map_err(|e| (classify_error(&e), e.to_string()))?;
+    if data.is_empty() {
+        return Err((FailureClass::EmptySegment, "First segment is empty".to_string()));
+    }
+    Ok(data)
+}
+
+// Container headers sit at the start of a segment, so only the first bytes are read
+pub async fn read_prefix(mut response: Response, max: usize) -> Result<Vec<u8>, reqwest::Error> {
+    let mut data = Vec::new();
+    while data.len() < max {
+        match response.chunk().await? {
+            Some(chunk) => data.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigStore, ServerConfig};
+    use axum::http::{HeaderMap, Method, StatusCode, Uri};
+    use axum::response::{IntoResponse, Redirect};
+    use axum::routing::any;
+    use axum::Router;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn client(allow: bool) -> GuardedClient {
+        let mut config = ServerConfig::default();
+        if allow {
+            config.outbound.allow = vec!["127.0.0.1".to_string()];
+        }
+        GuardedClient::new(Arc::new(ConfigStore::fixed(config)))
+    }
+
+    // Stand-in that hangs up on every HEAD, and on GETs too unless `serve_get`
+    async fn hangs_up_on_head(serve_get: bool) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/book.pdf", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = vec![0u8; 4096];
+                    let read = socket.read(&mut request).await.unwrap_or(0);
+                    if serve_get && request[..read].starts_with(b"GET ") {
+                        let body = b"%PDF-1.7\n%stand-in\n";
+                        let head = format!("HTTP/1.1 200 OK\r\nContent-Type: application/pdf\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                        let _ = socket.write_all(head.as_bytes()).await;
+                        let _ = socket.write_all(body).await;
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn dropped_heads_fall_back_to_get() {
+        let url = hangs_up_on_head(true).await;
+        let result = check_url(&client(true), &url, &[MediaKind::Pdf]).await;
+        assert!(result.ok, "{:?}", result.error);
+        assert_eq!((result.status_code, result.content_kind, result.content_length), (Some(200), Some(MediaKind::Pdf), Some(19)));
+
+        // A server that hangs up on everything fails on the GET
+        let url = hangs_up_on_head(false).await;
+        let result = check_url(&client(true), &url, &[]).await;
+        assert_eq!((result.ok, result.failure, result.status_code), (false, Some(FailureClass::Connect), None));
+
+        // The outbound policy's refusal needs no second opinion
+        let result = check_url(&client(false), &url, &[]).await;
+        assert_eq!(result.failure, Some(FailureClass::Blocked));
+    }
+
+    #[test]
+    fn statuses() {
+        let url = |u: &str| Url::parse(u).unwrap();
+        let page = url("https://example.org/watch/1");
+        assert_eq!(classify_status(451, &page, ""), FailureClass::GeoBlocked);
+        assert_eq!(classify_status(403, &page, "Access denied"), FailureClass::Http4xx);
+        assert_eq!(classify_status(403, &page, "This video is NOT AVAILABLE IN YOUR country"), FailureClass::GeoBlocked);
+        assert_eq!(classify_status(403, &url("https://example.org/geo-restricted"), ""), FailureClass::GeoBlocked);
+        assert_eq!(classify_status(404, &page, "geo"), FailureClass::Http4xx);
+        assert_eq!(classify_status(429, &page, ""), FailureClass::Http4xx);
+        assert_eq!(classify_status(500, &page, ""), FailureClass::Http5xx);
+        assert_eq!(classify_status(503, &page, "your region"), FailureClass::Http5xx);
+    }
+
+    #[tokio::test]
+    async fn fetch_errors() {
+        let limit = |limit| FetchError::Limit(LimitExceeded { limit, max: 1 });
+        assert_eq!(classify_fetch(&FetchError::Blocked(Blocked("10.0.0.1".to_string()))), FailureClass::Blocked);
+        assert_eq!(classify_fetch(&limit(Limit::BodySize)), FailureClass::BodyTooLarge);
+        assert_eq!(classify_fetch(&limit(Limit::Decompression)), FailureClass::DecompressionLimit);
+        assert_eq!(classify_fetch(&limit(Limit::Redirects)), FailureClass::TooManyRedirects);
+        assert_eq!(classify_fetch(&limit(Limit::HeaderSize)), FailureClass::HeadersTooLarge);
+        let decode = FetchError::Decode(std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt deflate stream"));
+        assert_eq!(classify_fetch(&decode), FailureClass::UnexpectedContent);
+
+        // Nothing listens on a port that was just given back
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let client = client(true);
+        let refused = client.send(client.get(&closed)).await.unwrap_err();
+        assert!(matches!(refused, FetchError::Request(_)));
+        assert_eq!(classify_fetch(&refused), FailureClass::Connect);
+    }
+
+    // Just the start of an EPUB (its uncompressed mimetype entry), padded to 2000 bytes
+    fn epub() -> Vec<u8> {
+        let mut data = b"PK\x03\x04".to_vec();
+        data.resize(30, 0);
+        data.extend_from_slice(b"mimetypeapplication/epub+zip");
+        data.resize(2000, b'x');
+        data
+    }
+
+    // Stand-in with one path per outcome; also returns the method and path of every request
+    async fn stand_in() -> (GuardedClient, String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let app = Router::new().fallback(any(move |method: Method, uri: Uri, headers: HeaderMap| {
+            let seen = seen.clone();
+            async move {
+                seen.lock().unwrap().push(format!("{} {}", method, uri.path()));
+                let ranged = headers.get("range").and_then(|r| r.to_str().ok()) == Some("bytes=0-4095");
+                match (method, uri.path()) {
+                    (Method::HEAD, "/no-head.pdf") => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+                    (_, "/no-head.pdf") if ranged => {
+                        (StatusCode::PARTIAL_CONTENT, [("content-range", "bytes 0-8/123456")], "%PDF-1.7\n").into_response()
+                    }
+                    (_, "/book.epub") => ([("content-type", "application/epub+zip")], epub()).into_response(),
+                    (_, "/page.html") => ([("content-type", "text/html")], "<!DOCTYPE html><html><body>Watch</body></html>").into_response(),
+                    (_, "/gone") => StatusCode::GONE.into_response(),
+                    (_, "/geo") => (StatusCode::FORBIDDEN, "Sorry, this title is not available in your country").into_response(),
+                    (_, "/forbidden") => (StatusCode::FORBIDDEN, "No").into_response(),
+                    (_, "/broken") => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    (_, "/loop") => Redirect::temporary("/loop").into_response(),
+                    (_, "/slow") => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        StatusCode::OK.into_response()
+                    }
+                    _ => StatusCode::BAD_REQUEST.into_response(),
+                }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = ServerConfig::default();
+        config.outbound.allow = vec!["127.0.0.1".to_string()];
+        config.limits.max_redirects = 3;
+        config.limits.request_timeout_secs = 1;
+        (GuardedClient::new(Arc::new(ConfigStore::fixed(config))), base_url, requests)
+    }
+
+    #[tokio::test]
+    async fn refused_heads_fall_back_to_a_ranged_get() {
+        let (client, base_url, requests) = stand_in().await;
+        let result = check_url(&client, &format!("{}/no-head.pdf", base_url), &[MediaKind::Pdf]).await;
+        assert!(result.ok, "{:?}", result.error);
+        // The GET's response replaces the refused HEAD's, and the full size comes from Content-Range
+        assert_eq!((result.status_code, result.content_kind, result.content_length), (Some(206), Some(MediaKind::Pdf), Some(123456)));
+        assert_eq!(*requests.lock().unwrap(), vec!["HEAD /no-head.pdf", "GET /no-head.pdf"]);
+
+        // The sniffed content has to be one of the expected kinds
+        let result = check_url(&client, &format!("{}/page.html", base_url), &[MediaKind::Pdf]).await;
+        assert_eq!((result.failure, result.content_kind), (Some(FailureClass::UnexpectedContent), Some(MediaKind::Html)));
+        // Without expected kinds a successful HEAD is enough
+        requests.lock().unwrap().clear();
+        assert!(check_url(&client, &format!("{}/page.html", base_url), &[]).await.ok);
+        assert_eq!(*requests.lock().unwrap(), vec!["HEAD /page.html"]);
+    }
+
+    #[tokio::test]
+    async fn failures_are_classified() {
+        let (client, base_url, requests) = stand_in().await;
+        let check = |path: &str| {
+            let url = format!("{}{}", base_url, path);
+            let client = client.clone();
+            async move { check_url(&client, &url, &[]).await }
+        };
+
+        let gone = check("/gone").await;
+        assert_eq!((gone.failure, gone.status_code, gone.error.as_deref()), (Some(FailureClass::Http4xx), Some(410), Some("HTTP 410 Gone")));
+        // Gone needs no GET to confirm it
+        assert_eq!(*requests.lock().unwrap(), vec!["HEAD /gone"]);
+
+        assert_eq!(check("/geo").await.failure, Some(FailureClass::GeoBlocked));
+        assert_eq!(check("/forbidden").await.failure, Some(FailureClass::Http4xx));
+        assert_eq!(check("/broken").await.failure, Some(FailureClass::Http5xx));
+        assert_eq!(check("/loop").await.failure, Some(FailureClass::TooManyRedirects));
+        assert_eq!(check("/slow").await.failure, Some(FailureClass::Timeout));
+    }
+
+    #[tokio::test]
+    async fn downloads_are_hashed_only_when_small() {
+        let (client, base_url, requests) = stand_in().await;
+        let url = format!("{}/book.epub", base_url);
+        let digest: String = Sha256::digest(epub()).iter().map(|b| format!("{:02x}", b)).collect();
+
+        let hashed = check_download(&client, &url, &[MediaKind::Epub], 2000).await;
+        assert!(hashed.ok, "{:?}", hashed.error);
+        assert_eq!((hashed.content_kind, hashed.content_length), (Some(MediaKind::Epub), Some(2000)));
+        assert_eq!(hashed.sha256, Some(digest));
+        assert_eq!(requests.lock().unwrap().iter().filter(|r| *r == "GET /book.epub").count(), 2);
+
+        // Over the limit it passes on size and sniffing alone, without the whole-file GET
+        requests.lock().unwrap().clear();
+        let unhashed = check_download(&client, &url, &[MediaKind::Zip], 1999).await;
+        assert!(unhashed.ok, "{:?}", unhashed.error);
+        assert_eq!((unhashed.content_length, unhashed.sha256), (Some(2000), None));
+        assert_eq!(*requests.lock().unwrap(), vec!["HEAD /book.epub", "GET /book.epub"]);
+    }
+
+    #[tokio::test]
+    async fn mislabeled_downloads_are_reported() {
+        let (client, base_url, requests) = stand_in().await;
+        let result = check_download(&client, &format!("{}/page.html", base_url), &[MediaKind::Epub], 1 << 20).await;
+        assert!(!result.ok);
+        assert_eq!(result.failure, Some(FailureClass::UnexpectedContent));
+        assert_eq!(result.error.as_deref(), Some("Expected [Epub], got Html"));
+        assert_eq!((result.content_kind, result.sha256), (Some(MediaKind::Html), None));
+        assert_eq!(*requests.lock().unwrap(), vec!["HEAD /page.html", "GET /page.html"]);
+
+        // Every expected kind is named when none of them matches
+        let result = check_download(&client, &format!("{}/book.epub", base_url), &[MediaKind::Pdf, MediaKind::Text], 0).await;
+        assert_eq!(result.error.as_deref(), Some("Expected [Pdf, Text], got Epub"));
+    }
+}